@@ -0,0 +1,117 @@
+//! Throughput and latency of requests through the proxy, against a local echo
+//! server reached directly as a baseline. Run with `cargo bench`, Criterion
+//! reports each mode of each body size in its own group.
+//!
+//! Each case sends requests one after the other on a single tunnel, in two
+//! modes: forwarding only, where bodies stream through the proxy, and with
+//! the exchanges captured, where bodies are buffered to be recorded.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::StreamExt;
+use hyper::client::conn::SendRequest;
+use hyper::{service::Service, Body, Request, Response};
+use std::time::{Duration, Instant};
+use tls_interceptor_proxy::third_wheel::certificates::CertificateAuthority;
+use tls_interceptor_proxy::third_wheel::proxy::mitm::{mitm_layer, ThirdWheel};
+
+/// Body sizes to send and get echoed back, with the number of samples taken
+const CASES: [(&str, usize, usize); 2] = [("64 B", 64, 100), ("1 MiB", 1024 * 1024, 10)];
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Direct,
+    Forward,
+    Capture,
+}
+
+impl Mode {
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Direct => "direct",
+            Mode::Forward => "forward",
+            Mode::Capture => "capture",
+        }
+    }
+}
+
+/// A client for the echo server, through a proxy started in `mode` unless
+/// the server is reached directly
+async fn client(ca: &CertificateAuthority, port: u16, mode: Mode) -> SendRequest<Body> {
+    let mitm = mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+    let proxy = match mode {
+        Mode::Direct => {
+            let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+            let connector = native_tls::TlsConnector::builder()
+                .add_root_certificate(trusted_certificate(ca))
+                .build()
+                .unwrap();
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect("localhost", stream)
+                .await
+                .unwrap();
+            let (client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+            tokio::spawn(connection);
+            return client;
+        }
+        Mode::Forward => spawn_proxy(proxy_builder(mitm, ca).build()),
+        Mode::Capture => {
+            let (builder, mut entries) = proxy_builder(mitm, ca).capture_stream();
+            tokio::spawn(async move { while entries.next().await.is_some() {} });
+            spawn_proxy(builder.build())
+        }
+    };
+    client_through_proxy(proxy, "localhost", port, ca).await
+}
+
+/// Send `requests` requests with a body of `size` bytes, returning the
+/// total time they took
+async fn run(client: &mut SendRequest<Body>, size: usize, requests: usize) -> Duration {
+    let body = vec![b'x'; size];
+    let started = Instant::now();
+    for _ in 0..requests {
+        // Wait for the connection to be done with the previous exchange
+        futures::future::poll_fn(|cx| client.poll_ready(cx))
+            .await
+            .unwrap();
+        let request = Request::post("/")
+            .header("host", "localhost")
+            .body(Body::from(body.clone()))
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let echoed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(echoed.len(), size);
+    }
+    started.elapsed()
+}
+
+fn proxy(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let ca = test_ca();
+    let upstream = runtime.block_on(spawn_upstream(
+        &ca,
+        "localhost",
+        |req: Request<Body>| async move { Response::new(req.into_body()) },
+    ));
+
+    for (label, size, samples) in CASES {
+        let mut group = c.benchmark_group(format!("echo {}", label));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.sample_size(samples);
+        for mode in [Mode::Direct, Mode::Forward, Mode::Capture] {
+            let mut client = runtime.block_on(client(&ca, upstream.port(), mode));
+            group.bench_function(mode.name(), |b| {
+                b.iter_custom(|iters| runtime.block_on(run(&mut client, size, iters as usize)))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, proxy);
+criterion_main!(benches);
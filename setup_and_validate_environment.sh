@@ -5,8 +5,8 @@ trap 'kill $(jobs -p)' EXIT
 set -e  # Exit on error
 
 # Generate a self-signed certificate
-openssl req -x509 -newkey rsa:4096 -keyout ca/ca_certs/key.pem -out ca/ca_certs/cert.pem \
-    -days 365 -passout pass:"third-wheel" -subj "/C=US/ST=private/L=province/O=city/CN=hostname.example.com" &>/dev/null
+cargo run -- generate-ca --force --key-type rsa4096 --passphrase third-wheel \
+    --common-name hostname.example.com &>/dev/null
 
 # Sign a certificate for the server
 cargo run --example sign_cert_for_site -- my_test_site.com -o ca/simple_server/localhost.pem -p third-wheel &>/dev/null
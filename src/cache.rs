@@ -0,0 +1,432 @@
+use chrono::DateTime;
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE,
+    ETAG, EXPIRES, HOST, IF_NONE_MATCH, RANGE, TRANSFER_ENCODING, VARY,
+};
+use hyper::{service::Service, Body, Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::third_wheel::{error::Error, proxy::mitm::ThirdWheel};
+
+/// Responses kept by `ResponseCache::default`
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// Largest body kept, larger responses are forwarded without being cached
+const MAX_CACHED_BODY_SIZE: u64 = 1024 * 1024;
+
+/// Statuses whose responses may be cached, as listed by RFC 9110
+const CACHEABLE_STATUSES: [StatusCode; 10] = [
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::NO_CONTENT,
+    StatusCode::MULTIPLE_CHOICES,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
+    StatusCode::GONE,
+    StatusCode::URI_TOO_LONG,
+    StatusCode::NOT_IMPLEMENTED,
+];
+
+/// The directives of the `Cache-Control` headers of a request or response
+/// which the cache acts on
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<Duration>,
+    s_maxage: Option<Duration>,
+}
+
+impl CacheControl {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let mut cache_control = Self::default();
+        let values = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        for directive in values.flat_map(|value| value.split(',')) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = argument
+                .and_then(|argument| argument.parse().ok())
+                .map(Duration::from_secs);
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                "max-age" => cache_control.max_age = seconds,
+                "s-maxage" => cache_control.s_maxage = seconds,
+                _ => {}
+            }
+        }
+        cache_control
+    }
+}
+
+/// A response kept by the cache, with what is needed to know whether it is
+/// still fresh and which requests it answers
+#[derive(Clone, Debug)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// the values the request had for the headers named by `Vary`
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    /// when the response was stored or last revalidated
+    stored: Instant,
+    /// the age the response already had then, from its `Age` header
+    initial_age: Duration,
+    /// how long the response is fresh for since the target generated it
+    freshness_lifetime: Duration,
+    /// whether the target asked for the response to be revalidated each time
+    no_cache: bool,
+    last_used: u64,
+}
+
+impl CachedResponse {
+    fn new(
+        parts: &hyper::http::response::Parts,
+        body: Bytes,
+        vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    ) -> Self {
+        let mut cached = Self {
+            status: parts.status,
+            headers: HeaderMap::new(),
+            body,
+            vary,
+            stored: Instant::now(),
+            initial_age: Duration::ZERO,
+            freshness_lifetime: Duration::ZERO,
+            no_cache: false,
+            last_used: 0,
+        };
+        cached.refresh(&parts.headers);
+        cached
+    }
+
+    /// Update the response with the headers the target sent along with it or
+    /// with the `304 Not Modified` revalidating it
+    fn refresh(&mut self, headers: &HeaderMap) {
+        for name in headers.keys() {
+            if name != CONTENT_LENGTH && name != TRANSFER_ENCODING {
+                let values: Vec<HeaderValue> = headers.get_all(name).iter().cloned().collect();
+                self.headers.remove(name);
+                for value in values {
+                    self.headers.append(name.clone(), value);
+                }
+            }
+        }
+        let cache_control = CacheControl::from_headers(&self.headers);
+        self.stored = Instant::now();
+        self.initial_age =
+            Duration::from_secs(header_number(&self.headers, AGE).unwrap_or_default());
+        self.freshness_lifetime = freshness_lifetime(&self.headers, &cache_control);
+        self.no_cache = cache_control.no_cache;
+    }
+
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored.elapsed()
+    }
+
+    /// Whether the response may be served without asking the target, to a
+    /// request with `request_cache_control`
+    fn is_fresh(&self, request_cache_control: &CacheControl) -> bool {
+        let age = self.age();
+        !self.no_cache
+            && !request_cache_control.no_cache
+            && age < self.freshness_lifetime
+            && !request_cache_control
+                .max_age
+                .is_some_and(|max_age| age > max_age)
+    }
+
+    /// Whether the response was stored for a request with these headers
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+
+    /// The response to a request of the client: a `304 Not Modified` when the
+    /// request has an `If-None-Match` holding the `ETag` of the response, the
+    /// whole response otherwise
+    fn answer(&self, if_none_match: Option<&HeaderValue>) -> Response<Body> {
+        let age = HeaderValue::from(self.age().as_secs());
+        let not_modified = match (if_none_match, self.headers.get(ETAG)) {
+            (Some(if_none_match), Some(etag)) => etag_matches(if_none_match, etag),
+            _ => false,
+        };
+        let mut response = if not_modified {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            // The headers RFC 9110 has sent with a `304 Not Modified`
+            for name in [CACHE_CONTROL, DATE, ETAG, EXPIRES, VARY] {
+                for value in self.headers.get_all(&name) {
+                    response.headers_mut().append(name.clone(), value.clone());
+                }
+            }
+            response
+        } else {
+            let mut response = Response::new(Body::from(self.body.clone()));
+            *response.status_mut() = self.status;
+            *response.headers_mut() = self.headers.clone();
+            response
+        };
+        response.headers_mut().insert(AGE, age);
+        response
+    }
+}
+
+/// Identifies the resource of a request: its host and its path and query
+type CacheKey = (String, String);
+
+/// A shared HTTP cache of the responses to `GET` requests, honoring their
+/// `Cache-Control`, `Expires`, `ETag` and `Vary` headers. A fresh response is
+/// served without asking the target, a stale one with an `ETag` is
+/// revalidated with `If-None-Match`. When full, the least recently used
+/// response is evicted.
+///
+/// Unlike a `replay::Cassette`, which answers a request with whatever was
+/// recorded for it, only the responses the target allows to be cached are
+/// kept, for as long as the target allows.
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, CachedResponse>,
+    /// incremented on every use, to know which entry was used last
+    clock: u64,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl ResponseCache {
+    /// A cache holding at most `capacity` responses, none if it is 0
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The number of responses held
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The response stored for the resource, if it was for a request with
+    /// these headers
+    fn get(&mut self, key: &CacheKey, request_headers: &HeaderMap) -> Option<CachedResponse> {
+        self.clock += 1;
+        let cached = self.entries.get_mut(key)?;
+        if !cached.matches(request_headers) {
+            return None;
+        }
+        cached.last_used = self.clock;
+        Some(cached.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, mut cached: CachedResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                self.entries.remove(&least_recently_used);
+            }
+        }
+        self.clock += 1;
+        cached.last_used = self.clock;
+        self.entries.insert(key, cached);
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        self.entries.remove(key);
+    }
+}
+
+/// Answer a `GET` request from the cache when it holds a fresh response for
+/// it, otherwise forward it with `third_wheel`, revalidating the stale
+/// response the cache holds if it has an `ETag`, and keep the response if it
+/// may be cached. A request holding an `If-None-Match` matching the `ETag` of
+/// the cached response is answered with `304 Not Modified`.
+///
+/// Requests with `Authorization` or `Range` headers are forwarded as they
+/// are, as are the responses without a `Content-Length` or larger than
+/// 1 MiB. The requests of other methods are forwarded, a successful one
+/// changing the resource evicting its cached response.
+pub async fn serve_cached(
+    cache: &Mutex<ResponseCache>,
+    mut request: Request<Body>,
+    third_wheel: &mut ThirdWheel,
+) -> Result<Response<Body>, Error> {
+    let key = cache_key(&request, third_wheel);
+    if request.method() != Method::GET {
+        let safe = matches!(
+            *request.method(),
+            Method::HEAD | Method::OPTIONS | Method::TRACE
+        );
+        let response = third_wheel.call(request).await?;
+        if !safe && (response.status().is_success() || response.status().is_redirection()) {
+            cache.lock().unwrap().remove(&key);
+        }
+        return Ok(response);
+    }
+
+    let request_cache_control = CacheControl::from_headers(request.headers());
+    let headers = request.headers();
+    if request_cache_control.no_store
+        || headers.contains_key(AUTHORIZATION)
+        || headers.contains_key(RANGE)
+    {
+        return third_wheel.call(request).await;
+    }
+
+    let if_none_match = headers.get(IF_NONE_MATCH).cloned();
+    let cached = cache.lock().unwrap().get(&key, headers);
+    if let Some(cached) = &cached {
+        if cached.is_fresh(&request_cache_control) {
+            return Ok(cached.answer(if_none_match.as_ref()));
+        }
+    }
+
+    // Ask the target whether the stale response still holds
+    let revalidated = cached.filter(|cached| cached.headers.contains_key(ETAG));
+    if let Some(cached) = &revalidated {
+        request
+            .headers_mut()
+            .insert(IF_NONE_MATCH, cached.headers[ETAG].clone());
+    }
+    let request_headers = request.headers().clone();
+    let response = third_wheel.call(request).await?;
+    if let Some(mut cached) = revalidated {
+        if response.status() == StatusCode::NOT_MODIFIED {
+            cached.refresh(response.headers());
+            let answer = cached.answer(if_none_match.as_ref());
+            cache.lock().unwrap().insert(key, cached);
+            return Ok(answer);
+        }
+    }
+
+    if !is_cacheable(&response) {
+        return Ok(response);
+    }
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let vary = vary_values(&parts.headers, &request_headers);
+    let cached = CachedResponse::new(&parts, body.clone(), vary);
+    cache.lock().unwrap().insert(key, cached);
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// The resource of a request, whose host is the one of the tunnel when the
+/// request names none
+fn cache_key(request: &Request<Body>, third_wheel: &ThirdWheel) -> CacheKey {
+    let host = request
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| request.headers().get(HOST)?.to_str().ok())
+        .unwrap_or(third_wheel.get_target_host());
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    (host.to_ascii_lowercase(), path.to_string())
+}
+
+/// The values the request had for the headers named by the `Vary` of its
+/// response
+fn vary_values(
+    response_headers: &HeaderMap,
+    request_headers: &HeaderMap,
+) -> Vec<(HeaderName, Option<HeaderValue>)> {
+    response_headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .map(|name| {
+            let value = request_headers.get(&name).cloned();
+            (name, value)
+        })
+        .collect()
+}
+
+/// Whether the target allows a response to be kept, and it is small enough.
+/// A response which is never fresh is only kept when it can be revalidated.
+fn is_cacheable(response: &Response<Body>) -> bool {
+    let headers = response.headers();
+    let cache_control = CacheControl::from_headers(headers);
+    let vary_all = headers
+        .get_all(VARY)
+        .iter()
+        .any(|value| value.to_str().is_ok_and(|value| value.trim() == "*"));
+    CACHEABLE_STATUSES.contains(&response.status())
+        && !cache_control.no_store
+        && !cache_control.private
+        && !vary_all
+        && (headers.contains_key(ETAG) || !freshness_lifetime(headers, &cache_control).is_zero())
+        && header_number(headers, CONTENT_LENGTH)
+            .is_some_and(|length| length <= MAX_CACHED_BODY_SIZE)
+}
+
+/// How long a response is fresh for since the target generated it, from its
+/// `s-maxage` or `max-age` directive, or else from its `Expires` and `Date`
+/// headers. Zero when it names none, or an invalid `Expires`.
+fn freshness_lifetime(headers: &HeaderMap, cache_control: &CacheControl) -> Duration {
+    if let Some(max_age) = cache_control.s_maxage.or(cache_control.max_age) {
+        return max_age;
+    }
+    let http_date = |name| {
+        let value = headers.get(name)?.to_str().ok()?;
+        DateTime::parse_from_rfc2822(value).ok()
+    };
+    let Some(expires) = http_date(EXPIRES) else {
+        return Duration::ZERO;
+    };
+    let date = http_date(DATE).unwrap_or_else(|| chrono::Utc::now().fixed_offset());
+    (expires - date).to_std().unwrap_or_default()
+}
+
+/// The value of a header holding a number, such as `Age` or `Content-Length`
+fn header_number(headers: &HeaderMap, name: HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Whether an `If-None-Match` holds an `ETag`, compared weakly as RFC 9110
+/// has it for `If-None-Match`
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|candidate| opaque(candidate) == opaque(etag))
+}
@@ -0,0 +1,589 @@
+use chrono::{DateTime, Local};
+use har::v1_2::{self, Entries};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::third_wheel::error::Error;
+use crate::utilities::{extract_prompt, har_content_bytes, har_post_data_bytes, PROMPT_ONLY};
+
+/// Version of the mitmproxy flow format written by `MitmproxyFlowSink`, the
+/// one used by mitmproxy 10. Newer mitmproxy versions upgrade it when loading.
+pub const MITMPROXY_FLOW_FORMAT_VERSION: i64 = 20;
+
+/// Somewhere to store the captured exchanges
+pub trait CaptureSink: Send {
+    /// Store one captured exchange
+    fn record(&mut self, entry: &Entries) -> Result<(), Error>;
+}
+
+/// The format the captured exchanges are written in
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureFormat {
+    /// A HTTP ARchive holding every entry
+    #[default]
+    Har,
+    /// A mitmproxy flow file, readable by mitmdump and mitmweb
+    Mitmproxy,
+    /// Only the prompts sent to the LLM, one JSON object per line
+    Prompts,
+}
+
+impl FromStr for CaptureFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "har" => Ok(CaptureFormat::Har),
+            "mitmproxy" => Ok(CaptureFormat::Mitmproxy),
+            "prompts" => Ok(CaptureFormat::Prompts),
+            _ => Err(format!(
+                "unknown capture format {}, expected har, mitmproxy or prompts",
+                s
+            )),
+        }
+    }
+}
+
+impl CaptureFormat {
+    /// Create the file at `path` and return a sink writing this format to it
+    pub fn create_sink<P: AsRef<Path>>(&self, path: P) -> Result<Box<dyn CaptureSink>, Error> {
+        Ok(match self {
+            CaptureFormat::Har => Box::new(HarFileSink::create(path)?),
+            CaptureFormat::Mitmproxy => Box::new(MitmproxyFlowSink::new(File::create(path)?)),
+            CaptureFormat::Prompts => Box::new(PromptSink::new(File::create(path)?)),
+        })
+    }
+
+    /// Whether the sink also wants the requests that were forwarded, not only
+    /// the blocked ones
+    pub fn records_forwarded_requests(&self) -> bool {
+        *self == CaptureFormat::Prompts
+    }
+
+    /// Whether the file written is a HAR archive
+    pub fn writes_har(&self) -> bool {
+        *self == CaptureFormat::Har
+    }
+}
+
+/// The archive written by the HAR sinks, holding `entries`
+fn har_archive(entries: Vec<Entries>) -> har::Har {
+    har::Har {
+        log: har::Spec::V1_2(v1_2::Log {
+            entries,
+            browser: None,
+            comment: Some("Confidential disclosure blocked".to_string()),
+            pages: None,
+            creator: v1_2::Creator {
+                name: "SentineLLM".to_string(),
+                version: "0.5".to_string(),
+                comment: Some("The IA at the service of confidentiality".to_string()),
+            },
+        }),
+    }
+}
+
+/// Writes a pretty-printed HAR file one entry at a time, without keeping the
+/// entries in memory. Each entry is written over the end of the archive,
+/// closing the `entries` array and the log again after it, so the file is a
+/// valid archive between two entries and once the capture stops.
+pub struct StreamingHarSink<W> {
+    writer: W,
+    /// where the end of the archive starts, to be overwritten by the next entry
+    end_position: u64,
+    /// the end of the archive after the `entries` array
+    end: String,
+    has_entries: bool,
+}
+
+impl<W: Write + Seek + Send> StreamingHarSink<W> {
+    /// Start the archive with no entries
+    pub fn new(writer: W) -> Result<Self, Error> {
+        Self::with_entries(writer, &[])
+    }
+
+    /// Start the archive with `entries`, the next ones being written after them
+    pub fn with_entries(mut writer: W, entries: &[Entries]) -> Result<Self, Error> {
+        let empty = serde_json::to_string_pretty(&har_archive(Vec::new()))
+            .map_err(|e| Error::ServerError(e.to_string()))?;
+        let entries_start = empty
+            .find("\"entries\": [")
+            .map(|start| start + "\"entries\": [".len())
+            .ok_or_else(|| Error::ServerError("HAR log without entries".to_string()))?;
+        let (start, end) = empty.split_at(entries_start);
+
+        writer.write_all(start.as_bytes())?;
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b",")?;
+            }
+            serde_json::to_writer_pretty(&mut writer, entry)
+                .map_err(|e| Error::ServerError(e.to_string()))?;
+        }
+        let end_position = writer.stream_position()?;
+        writer.write_all(end.as_bytes())?;
+        writer.flush()?;
+        Ok(Self {
+            writer,
+            end_position,
+            end: end.to_string(),
+            has_entries: !entries.is_empty(),
+        })
+    }
+
+    /// The writer the archive is written to
+    #[allow(dead_code)]
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Give back the writer the archive was written to
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Seek + Send> CaptureSink for StreamingHarSink<W> {
+    fn record(&mut self, entry: &Entries) -> Result<(), Error> {
+        self.writer.seek(SeekFrom::Start(self.end_position))?;
+        if self.has_entries {
+            self.writer.write_all(b",")?;
+        }
+        serde_json::to_writer_pretty(&mut self.writer, entry)
+            .map_err(|e| Error::ServerError(e.to_string()))?;
+        self.end_position = self.writer.stream_position()?;
+        self.writer.write_all(self.end.as_bytes())?;
+        self.writer.flush()?;
+        self.has_entries = true;
+        Ok(())
+    }
+}
+
+/// Writes the entries to a HAR file as they are received, each one only once
+pub type HarFileSink = StreamingHarSink<File>;
+
+impl HarFileSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Continue the archive at `path` which already holds `entries`. The file
+    /// is written again with them, the next entries being added after them.
+    pub fn append<P: AsRef<Path>>(path: P, entries: Vec<Entries>) -> Result<Self, Error> {
+        Self::with_entries(File::create(path)?, &entries)
+    }
+}
+
+/// Writes each entry as an HTTP flow in the tnetstring based format of
+/// mitmproxy, so captures can be opened with `mitmweb -r` or `mitmdump -r`
+pub struct MitmproxyFlowSink<W> {
+    writer: W,
+}
+
+impl<W: Write + Send> MitmproxyFlowSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Give back the writer the flows were written to
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> CaptureSink for MitmproxyFlowSink<W> {
+    fn record(&mut self, entry: &Entries) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        flow_from_entry(entry).encode(&mut bytes);
+        self.writer.write_all(&bytes)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the prompt of each entry holding one as a line of JSON with the
+/// host it was sent to, the client and the time, for auditing what is sent
+/// to the LLM without keeping whole exchanges. Entries without a prompt are
+/// skipped.
+///
+/// ```json
+/// {"timestamp":"01/02/2024 10:00:00","host":"chatgpt.com","client_ip":"127.0.0.1:1234","prompt":"Hello"}
+/// ```
+pub struct PromptSink<W> {
+    writer: W,
+}
+
+impl<W: Write + Send> PromptSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Give back the writer the prompts were written to
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> CaptureSink for PromptSink<W> {
+    fn record(&mut self, entry: &Entries) -> Result<(), Error> {
+        let prompt = entry.request.post_data.as_ref().and_then(|post_data| {
+            match post_data.comment.as_deref() {
+                Some(PROMPT_ONLY) => post_data.text.clone(),
+                _ => extract_prompt(post_data.text.as_deref()?.as_bytes()),
+            }
+        });
+        let Some(prompt) = prompt else {
+            return Ok(());
+        };
+        let host = entry
+            .request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("host"))
+            .map(|header| header.value.as_str());
+
+        let line = serde_json::json!({
+            "timestamp": entry.started_date_time,
+            "host": host,
+            "client_ip": entry.connection,
+            "prompt": prompt,
+        });
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// What was captured of the exchanges with one host
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HostStats {
+    pub requests: u64,
+    /// bytes of the request headers and bodies
+    pub bytes_sent: u64,
+    /// bytes of the response headers and bodies
+    pub bytes_received: u64,
+    /// exchanges answered with a server error, or with no response at all
+    pub errors: u64,
+    /// milliseconds the exchanges took, added up
+    total_time: f64,
+}
+
+impl HostStats {
+    /// How long the exchanges took on average, `None` before the first one
+    pub fn average_latency(&self) -> Option<Duration> {
+        (self.requests > 0)
+            .then(|| Duration::from_secs_f64(self.total_time / self.requests as f64 / 1000.0))
+    }
+}
+
+/// Statistics of the captured exchanges by host, reported when the session
+/// ends. The host is the one of the `Host` header without its port.
+#[derive(Debug, Default)]
+pub struct SessionSummary {
+    hosts: BTreeMap<String, HostStats>,
+}
+
+impl SessionSummary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The statistics of each host, sorted by host
+    #[allow(dead_code)]
+    pub fn hosts(&self) -> &BTreeMap<String, HostStats> {
+        &self.hosts
+    }
+
+    /// The report of the session, one line per host
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for (host, stats) in &self.hosts {
+            let average_latency = stats.average_latency().unwrap_or_default();
+            report.push_str(&format!(
+                "{}: {} requests, {} bytes sent, {} bytes received, {} errors, {:.1} ms average latency\n",
+                host,
+                stats.requests,
+                stats.bytes_sent,
+                stats.bytes_received,
+                stats.errors,
+                average_latency.as_secs_f64() * 1000.0
+            ));
+        }
+        report
+    }
+
+    /// Write the report of the session to `path`
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.report())?;
+        Ok(())
+    }
+}
+
+impl CaptureSink for SessionSummary {
+    fn record(&mut self, entry: &Entries) -> Result<(), Error> {
+        // Sizes a HAR entry does not know are -1
+        let size =
+            |headers_size: i64, body_size: i64| (headers_size.max(0) + body_size.max(0)) as u64;
+        let (host, _) = host_and_port(&entry_authority(&entry.request));
+        let stats = self.hosts.entry(host).or_default();
+        stats.requests += 1;
+        stats.bytes_sent += size(entry.request.headers_size, entry.request.body_size);
+        stats.bytes_received += size(entry.response.headers_size, entry.response.body_size);
+        if entry.response.status == 0 || entry.response.status >= 500 {
+            stats.errors += 1;
+        }
+        stats.total_time += entry.time.max(0.0);
+        Ok(())
+    }
+}
+
+/// A value of the tnetstring serialization used by mitmproxy
+enum TNetString {
+    Bytes(Vec<u8>),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+    List(Vec<TNetString>),
+    Dict(Vec<(&'static str, TNetString)>),
+}
+
+impl TNetString {
+    fn bytes(value: &str) -> Self {
+        TNetString::Bytes(value.as_bytes().to_vec())
+    }
+
+    fn str(value: &str) -> Self {
+        TNetString::Str(value.to_string())
+    }
+
+    /// Write the value as `<length>:<payload><type>`
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut payload = Vec::new();
+        let tag = match self {
+            TNetString::Bytes(value) => {
+                payload.extend_from_slice(value);
+                b','
+            }
+            TNetString::Str(value) => {
+                payload.extend_from_slice(value.as_bytes());
+                b';'
+            }
+            TNetString::Int(value) => {
+                payload.extend_from_slice(value.to_string().as_bytes());
+                b'#'
+            }
+            TNetString::Float(value) => {
+                payload.extend_from_slice(format!("{:?}", value).as_bytes());
+                b'^'
+            }
+            TNetString::Bool(value) => {
+                payload.extend_from_slice(if *value { b"true" } else { b"false" });
+                b'!'
+            }
+            TNetString::Null => b'~',
+            TNetString::List(values) => {
+                for value in values {
+                    value.encode(&mut payload);
+                }
+                b']'
+            }
+            TNetString::Dict(fields) => {
+                for (key, value) in fields {
+                    TNetString::str(key).encode(&mut payload);
+                    value.encode(&mut payload);
+                }
+                b'}'
+            }
+        };
+        out.extend_from_slice(payload.len().to_string().as_bytes());
+        out.push(b':');
+        out.extend_from_slice(&payload);
+        out.push(tag);
+    }
+}
+
+/// The state of a mitmproxy connection we know nothing about besides its peer
+fn connection(
+    id: String,
+    peer: Option<(String, i64)>,
+    timestamp: f64,
+) -> Vec<(&'static str, TNetString)> {
+    let address = match peer {
+        Some((host, port)) => TNetString::List(vec![TNetString::Str(host), TNetString::Int(port)]),
+        None => TNetString::Null,
+    };
+    vec![
+        ("id", TNetString::Str(id)),
+        ("peername", address),
+        ("sockname", TNetString::Null),
+        ("error", TNetString::Null),
+        ("state", TNetString::Int(0)),
+        ("transport_protocol", TNetString::str("tcp")),
+        ("tls", TNetString::Bool(true)),
+        ("certificate_list", TNetString::List(Vec::new())),
+        ("alpn", TNetString::Null),
+        ("alpn_offers", TNetString::List(Vec::new())),
+        ("cipher", TNetString::Null),
+        ("cipher_list", TNetString::List(Vec::new())),
+        ("tls_version", TNetString::Null),
+        ("sni", TNetString::Null),
+        ("timestamp_start", TNetString::Float(timestamp)),
+        ("timestamp_end", TNetString::Null),
+        ("timestamp_tls_setup", TNetString::Null),
+    ]
+}
+
+/// Convert HAR headers to the list of byte pairs mitmproxy stores
+fn headers(headers: &[v1_2::Headers]) -> TNetString {
+    TNetString::List(
+        headers
+            .iter()
+            .map(|header| {
+                TNetString::List(vec![
+                    TNetString::bytes(&header.name),
+                    TNetString::bytes(&header.value),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Split a host header value into its host and port, HTTPS being the default
+fn host_and_port(authority: &str) -> (String, i64) {
+    match authority.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (authority.to_string(), 443),
+        },
+        None => (authority.to_string(), 443),
+    }
+}
+
+/// The target of a request, from its host header, or from the URL of HTTP/2
+/// requests which have none
+fn entry_authority(request: &v1_2::Request) -> String {
+    request
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("host"))
+        .map(|header| header.value.clone())
+        .or_else(|| {
+            request
+                .url
+                .parse::<hyper::Uri>()
+                .ok()
+                .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+        })
+        .unwrap_or_default()
+}
+
+/// Build the mitmproxy HTTP flow state of a HAR entry
+fn flow_from_entry(entry: &Entries) -> TNetString {
+    let request = &entry.request;
+    let response = &entry.response;
+
+    let timestamp = DateTime::parse_from_rfc3339(&entry.started_date_time)
+        .map(|date| date.timestamp_millis() as f64 / 1000.0)
+        .unwrap_or_else(|_| Local::now().timestamp() as f64);
+
+    let uri = request.url.parse::<hyper::Uri>().ok();
+    let authority = entry_authority(request);
+    let (host, port) = host_and_port(&authority);
+    let path = match &uri {
+        Some(uri) => uri
+            .path_and_query()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| "/".to_string()),
+        None => request.url.clone(),
+    };
+
+    let request_content = request
+        .post_data
+        .as_ref()
+        .map(har_post_data_bytes)
+        .unwrap_or_default();
+    let response_content = har_content_bytes(&response.content);
+
+    let peer = |address: Option<&str>| {
+        address
+            .and_then(|address| address.parse::<std::net::SocketAddr>().ok())
+            .map(|address| (address.ip().to_string(), address.port() as i64))
+    };
+    let client_peer = peer(entry.connection.as_deref());
+    let server_peer = peer(entry.server_ip_address.as_deref());
+
+    let mut server_conn = connection(uuid::Uuid::new_v4().to_string(), server_peer, timestamp);
+    server_conn.extend([
+        (
+            "address",
+            TNetString::List(vec![TNetString::Str(host.clone()), TNetString::Int(port)]),
+        ),
+        ("timestamp_tcp_setup", TNetString::Null),
+        ("via", TNetString::Null),
+    ]);
+    let mut client_conn = connection(uuid::Uuid::new_v4().to_string(), client_peer, timestamp);
+    client_conn.extend([
+        ("mitmcert", TNetString::Null),
+        ("proxy_mode", TNetString::str("regular")),
+    ]);
+
+    TNetString::Dict(vec![
+        ("id", TNetString::Str(uuid::Uuid::new_v4().to_string())),
+        ("error", TNetString::Null),
+        ("client_conn", TNetString::Dict(client_conn)),
+        ("server_conn", TNetString::Dict(server_conn)),
+        ("type", TNetString::str("http")),
+        ("intercepted", TNetString::Bool(false)),
+        ("is_replay", TNetString::Null),
+        ("marked", TNetString::str("")),
+        ("metadata", TNetString::Dict(Vec::new())),
+        (
+            "comment",
+            TNetString::str(entry.comment.as_deref().unwrap_or("")),
+        ),
+        ("timestamp_created", TNetString::Float(timestamp)),
+        ("version", TNetString::Int(MITMPROXY_FLOW_FORMAT_VERSION)),
+        (
+            "request",
+            TNetString::Dict(vec![
+                ("http_version", TNetString::bytes(&request.http_version)),
+                ("headers", headers(&request.headers)),
+                ("content", TNetString::Bytes(request_content)),
+                ("trailers", TNetString::Null),
+                ("timestamp_start", TNetString::Float(timestamp)),
+                ("timestamp_end", TNetString::Float(timestamp)),
+                ("host", TNetString::Str(host)),
+                ("port", TNetString::Int(port)),
+                ("method", TNetString::bytes(&request.method)),
+                ("scheme", TNetString::bytes("https")),
+                ("authority", TNetString::bytes(&authority)),
+                ("path", TNetString::bytes(&path)),
+            ]),
+        ),
+        (
+            "response",
+            TNetString::Dict(vec![
+                ("http_version", TNetString::bytes(&response.http_version)),
+                ("headers", headers(&response.headers)),
+                ("content", TNetString::Bytes(response_content)),
+                ("trailers", TNetString::Null),
+                ("timestamp_start", TNetString::Float(timestamp)),
+                ("timestamp_end", TNetString::Float(timestamp)),
+                ("status_code", TNetString::Int(response.status)),
+                ("reason", TNetString::bytes(&response.status_text)),
+            ]),
+        ),
+        ("websocket", TNetString::Null),
+    ])
+}
@@ -0,0 +1,79 @@
+use futures::future::BoxFuture;
+
+use crate::third_wheel::error::Error;
+
+/// Words marking a prompt as sensitive when no classifier is configured
+pub const DEFAULT_SENSITIVE_KEYWORDS: [&str; 1] = ["confidential"];
+
+/// Decides whether a prompt sent to an LLM holds sensitive content. The
+/// blocking rules which list no keywords ask the classifier of the proxy.
+///
+/// `is_sensitive` returns a boxed future so classifiers can be shared as
+/// `Arc<dyn PromptClassifier>`. A classifier asking a detection service over
+/// HTTP can be written with a hyper client:
+///
+/// ```ignore
+/// struct HttpClassifier {
+///     client: hyper::Client<hyper::client::HttpConnector>,
+///     endpoint: hyper::Uri,
+/// }
+///
+/// impl PromptClassifier for HttpClassifier {
+///     fn is_sensitive<'a>(&'a self, prompt: &'a str) -> BoxFuture<'a, Result<bool, Error>> {
+///         Box::pin(async move {
+///             let request = Request::post(self.endpoint.clone())
+///                 .header(CONTENT_TYPE, "application/json")
+///                 .body(Body::from(json!({ "prompt": prompt }).to_string()))
+///                 .map_err(|e| Error::RequestError(e.to_string()))?;
+///             let response = self.client.request(request).await?;
+///             let verdict = hyper::body::to_bytes(response.into_body()).await?;
+///             Ok(serde_json::from_slice::<Value>(&verdict)
+///                 .ok()
+///                 .and_then(|verdict| verdict["sensitive"].as_bool())
+///                 .unwrap_or(true))
+///         })
+///     }
+/// }
+/// ```
+pub trait PromptClassifier: Send + Sync {
+    /// Whether `prompt` is sensitive. Prompts whose classification fails are
+    /// blocked by the proxy.
+    fn is_sensitive<'a>(&'a self, prompt: &'a str) -> BoxFuture<'a, Result<bool, Error>>;
+}
+
+/// Whether `prompt` contains any of `keywords`, compared case-sensitively
+pub fn contains_keyword(keywords: &[String], prompt: &str) -> bool {
+    keywords.iter().any(|word| prompt.contains(word.as_str()))
+}
+
+/// Finds the prompts containing one of its keywords
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeywordClassifier {
+    keywords: Vec<String>,
+}
+
+impl KeywordClassifier {
+    pub fn new(keywords: Vec<String>) -> Self {
+        Self { keywords }
+    }
+}
+
+impl Default for KeywordClassifier {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_SENSITIVE_KEYWORDS
+                .iter()
+                .map(|word| word.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl PromptClassifier for KeywordClassifier {
+    fn is_sensitive<'a>(&'a self, prompt: &'a str) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(futures::future::ready(Ok(contains_keyword(
+            &self.keywords,
+            prompt,
+        ))))
+    }
+}
@@ -0,0 +1,436 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::capture::CaptureFormat;
+use crate::replay::DiffOptions;
+use crate::rewrite::JsonRewriteRule;
+use crate::rules::{BlockRules, Rule, RuleEngine};
+use crate::third_wheel::{
+    certificates::CertificateAuthority,
+    error::Error,
+    proxy::{ListenerOptions, DEFAULT_SHUTDOWN_TIMEOUT},
+};
+use crate::utilities::{default_redacted_headers, BlockMessages, CaptureOptions, ExternalBodies};
+
+pub const DEFAULT_PORT: u16 = 8081;
+pub const DEFAULT_OUTFILE: &str = "logs.har";
+pub const DEFAULT_CERT_FILE: &str = "ca/ca_certs/cert.pem";
+pub const DEFAULT_KEY_FILE: &str = "ca/ca_certs/key.pem";
+pub const DEFAULT_PASSPHRASE: &str = "third-wheel";
+pub const DEFAULT_BODIES_DIR: &str = "bodies";
+
+/// All the options needed to run the proxy, as read from a TOML file.
+///
+/// Every field is optional so a file only has to list what it changes. The
+/// accessor methods fall back to the same defaults as the command line.
+///
+/// ```toml
+/// port = 8081
+/// bind = "[::1]:8081"
+/// outfile = "logs.har"
+/// cert_file = "ca/ca_certs/cert.pem"
+/// key_file = "ca/ca_certs/key.pem"
+/// passphrase_env = "CA_PASSPHRASE"
+/// shutdown_timeout = 30
+/// listen_backlog = 1024
+/// reuse_address = true
+/// reuse_port = false
+/// tcp_nodelay = true
+/// record_bodies = true
+/// body_preview = 1024
+/// external_body_threshold = 1048576
+/// bodies_dir = "bodies"
+/// redact = true
+/// format = "har"
+/// log_connections_only = false
+/// diff_against = "recording.har"
+/// cassette = "cassette.har"
+/// cache_responses = true
+/// diff_ignore_headers = ["date", "etag"]
+/// validate = true
+/// rules_file = "rules.toml"
+/// no_intercept = ["bank.example.com", "*.pinned.example.com"]
+/// starttls_smtp_ports = [25, 587]
+/// prewarm_hosts = ["api.example.com", "example.com:8443"]
+/// dump_requests = "transcripts"
+/// summary = "summary.txt"
+/// plugin = "inspector.wasm"
+///
+/// [host_mappings]
+/// "example.com" = "127.0.0.1"
+/// "api.example.com" = "127.0.0.1:8443"
+/// "sidecar.example.com" = "unix+plain:/run/sidecar.sock"
+/// "~^api-[0-9]+\\.example\\.com$" = "127.0.0.1"
+///
+/// [latency_sla]
+/// "api.example.com" = 500
+///
+/// [block_messages]
+/// "de" = "Diese Anfrage wurde blockiert."
+///
+/// [[json_rewrites]]
+/// path = "$.user.role"
+/// value = "admin"
+///
+/// [[rules]]
+/// host = "*.example.com"
+/// response_content_types = ["application/zip"]
+/// action = "block"
+/// ```
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// port to bind the proxy to on the IPv4 loopback
+    pub port: Option<u16>,
+    /// address to bind the proxy to, any interface or IPv6 address, taking
+    /// precedence over `port`
+    pub bind: Option<SocketAddr>,
+    /// output file to save the HAR to
+    pub outfile: Option<String>,
+    /// pem file for the certificate authority certificate
+    pub cert_file: Option<String>,
+    /// pem file for the certificate authority private key
+    pub key_file: Option<String>,
+    /// passphrase protecting the private key
+    pub passphrase: Option<String>,
+    /// name of an environment variable holding the passphrase, used when
+    /// `passphrase` is not set
+    pub passphrase_env: Option<String>,
+    /// hosts to redirect to another address, optionally with a port, or to a
+    /// Unix domain socket when connecting upstream
+    pub host_mappings: HashMap<String, String>,
+    /// milliseconds the hosts matching each pattern may take to answer before
+    /// their responses are flagged as SLA violations
+    pub latency_sla: HashMap<String, u64>,
+    /// messages answering blocked prompts by language, added to the built-in
+    /// English and French ones
+    pub block_messages: HashMap<String, String>,
+    /// seconds given to open connections to finish when shutting down
+    pub shutdown_timeout: Option<u64>,
+    /// connections waiting to be accepted before new ones are refused
+    pub listen_backlog: Option<u32>,
+    /// set `SO_REUSEADDR` on the listening socket
+    pub reuse_address: Option<bool>,
+    /// set `SO_REUSEPORT` on the listening socket
+    pub reuse_port: Option<bool>,
+    /// set `TCP_NODELAY` on the accepted connections
+    pub tcp_nodelay: Option<bool>,
+    /// record the decrypted bodies, only their sizes are recorded otherwise
+    pub record_bodies: Option<bool>,
+    /// only record the first bytes of each body
+    pub body_preview: Option<usize>,
+    /// store the bodies larger than this number of bytes in their own file
+    pub external_body_threshold: Option<usize>,
+    /// directory holding the bodies stored externally
+    pub bodies_dir: Option<String>,
+    /// replace the values of the credentials headers by `[REDACTED]`
+    pub redact: Option<bool>,
+    /// format to write the captured exchanges in
+    pub format: Option<CaptureFormat>,
+    /// only record the target of each tunnel and relay it without decrypting
+    pub log_connections_only: Option<bool>,
+    /// HAR recording to compare the live responses with
+    pub diff_against: Option<String>,
+    /// HAR file replaying the responses it holds and recording the others
+    pub cassette: Option<String>,
+    /// answer the `GET` requests from the responses the targets allow to be
+    /// cached
+    pub cache_responses: Option<bool>,
+    /// headers left out when comparing with the recording
+    pub diff_ignore_headers: Option<Vec<String>>,
+    /// check the HAR file written against HAR 1.2 once the proxy stops
+    pub validate: Option<bool>,
+    /// TOML or JSON file of the rules blocking prompts, replacing the
+    /// built-in ones
+    pub rules_file: Option<String>,
+    /// directory to write a transcript of every decrypted request to
+    pub dump_requests: Option<String>,
+    /// directory of transcripts to send to their targets instead of running
+    /// the proxy
+    pub replay_requests: Option<String>,
+    /// file to write the per-host statistics of the session to when it ends
+    pub summary: Option<String>,
+    /// WASM module deciding whether each request is forwarded, blocked or
+    /// rewritten, used with the `wasm-plugins` feature
+    pub plugin: Option<String>,
+    /// hosts relayed without being decrypted, exactly or as glob patterns
+    pub no_intercept: Vec<String>,
+    /// ports of SMTP targets whose sessions are decrypted once upgraded with
+    /// `STARTTLS`
+    pub starttls_smtp_ports: Vec<u16>,
+    /// hosts whose spoofed certificates are signed when the proxy starts, as
+    /// `host` or `host:port`
+    pub prewarm_hosts: Vec<String>,
+    /// rules rewriting the JSON bodies of forwarded requests
+    pub json_rewrites: Vec<JsonRewriteRule>,
+    /// rules deciding which exchanges are blocked
+    pub rules: Vec<Rule>,
+}
+
+impl Config {
+    /// Load a configuration from a TOML file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_toml_str(&contents)
+    }
+
+    /// Parse a configuration from a TOML string
+    pub fn from_toml_str(contents: &str) -> Result<Self, Error> {
+        toml::from_str(contents).map_err(|e| Error::ConfigError(e.to_string()))
+    }
+
+    /// Merge two configurations, values set in `overrides` win over the ones in
+    /// `self`. Host mappings, latency SLAs and block messages are combined,
+    /// with `overrides` replacing any entry for the same host, the hosts not
+    /// intercepted are combined, and the rewrite and blocking rules of
+    /// `overrides` are applied after the ones of `self`.
+    pub fn merge(self, overrides: Config) -> Config {
+        let mut host_mappings = self.host_mappings;
+        host_mappings.extend(overrides.host_mappings);
+        let mut latency_sla = self.latency_sla;
+        latency_sla.extend(overrides.latency_sla);
+        let mut block_messages = self.block_messages;
+        block_messages.extend(overrides.block_messages);
+        let mut no_intercept = self.no_intercept;
+        no_intercept.extend(overrides.no_intercept);
+        let mut starttls_smtp_ports = self.starttls_smtp_ports;
+        starttls_smtp_ports.extend(overrides.starttls_smtp_ports);
+        let mut prewarm_hosts = self.prewarm_hosts;
+        prewarm_hosts.extend(overrides.prewarm_hosts);
+        let mut json_rewrites = self.json_rewrites;
+        json_rewrites.extend(overrides.json_rewrites);
+        let mut rules = self.rules;
+        rules.extend(overrides.rules);
+
+        Config {
+            port: overrides.port.or(self.port),
+            bind: overrides.bind.or(self.bind),
+            outfile: overrides.outfile.or(self.outfile),
+            cert_file: overrides.cert_file.or(self.cert_file),
+            key_file: overrides.key_file.or(self.key_file),
+            passphrase: overrides.passphrase.or(self.passphrase),
+            passphrase_env: overrides.passphrase_env.or(self.passphrase_env),
+            host_mappings,
+            latency_sla,
+            block_messages,
+            shutdown_timeout: overrides.shutdown_timeout.or(self.shutdown_timeout),
+            listen_backlog: overrides.listen_backlog.or(self.listen_backlog),
+            reuse_address: overrides.reuse_address.or(self.reuse_address),
+            reuse_port: overrides.reuse_port.or(self.reuse_port),
+            tcp_nodelay: overrides.tcp_nodelay.or(self.tcp_nodelay),
+            record_bodies: overrides.record_bodies.or(self.record_bodies),
+            body_preview: overrides.body_preview.or(self.body_preview),
+            external_body_threshold: overrides
+                .external_body_threshold
+                .or(self.external_body_threshold),
+            bodies_dir: overrides.bodies_dir.or(self.bodies_dir),
+            redact: overrides.redact.or(self.redact),
+            format: overrides.format.or(self.format),
+            log_connections_only: overrides.log_connections_only.or(self.log_connections_only),
+            diff_against: overrides.diff_against.or(self.diff_against),
+            cassette: overrides.cassette.or(self.cassette),
+            cache_responses: overrides.cache_responses.or(self.cache_responses),
+            diff_ignore_headers: overrides.diff_ignore_headers.or(self.diff_ignore_headers),
+            validate: overrides.validate.or(self.validate),
+            rules_file: overrides.rules_file.or(self.rules_file),
+            dump_requests: overrides.dump_requests.or(self.dump_requests),
+            replay_requests: overrides.replay_requests.or(self.replay_requests),
+            summary: overrides.summary.or(self.summary),
+            plugin: overrides.plugin.or(self.plugin),
+            no_intercept,
+            starttls_smtp_ports,
+            prewarm_hosts,
+            json_rewrites,
+            rules,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    /// The address to listen on: the one to bind to, or else the port on the
+    /// IPv4 loopback
+    pub fn bind_address(&self) -> SocketAddr {
+        self.bind
+            .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, self.port())))
+    }
+
+    pub fn outfile(&self) -> &str {
+        self.outfile.as_deref().unwrap_or(DEFAULT_OUTFILE)
+    }
+
+    pub fn cert_file(&self) -> &str {
+        self.cert_file.as_deref().unwrap_or(DEFAULT_CERT_FILE)
+    }
+
+    pub fn key_file(&self) -> &str {
+        self.key_file.as_deref().unwrap_or(DEFAULT_KEY_FILE)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+    }
+
+    /// The tuning of the listening socket, the unset options keeping their
+    /// defaults
+    pub fn listener_options(&self) -> ListenerOptions {
+        let defaults = ListenerOptions::default();
+        ListenerOptions {
+            backlog: self.listen_backlog.unwrap_or(defaults.backlog),
+            reuse_address: self.reuse_address.unwrap_or(defaults.reuse_address),
+            reuse_port: self.reuse_port.unwrap_or(defaults.reuse_port),
+            nodelay: self.tcp_nodelay.unwrap_or(defaults.nodelay),
+        }
+    }
+
+    /// The latency SLAs as given to `MitmProxyBuilder::latency_sla`
+    pub fn latency_sla(&self) -> HashMap<String, Duration> {
+        self.latency_sla
+            .iter()
+            .map(|(host, millis)| (host.clone(), Duration::from_millis(*millis)))
+            .collect()
+    }
+
+    /// The messages answering blocked prompts, chosen from the
+    /// `Accept-Language` of the request
+    pub fn block_messages(&self) -> BlockMessages {
+        BlockMessages::new(self.block_messages.clone())
+    }
+
+    pub fn format(&self) -> CaptureFormat {
+        self.format.unwrap_or_default()
+    }
+
+    pub fn log_connections_only(&self) -> bool {
+        self.log_connections_only.unwrap_or(false)
+    }
+
+    /// How to compare live responses with the recording, ignoring the
+    /// default headers unless others are listed
+    pub fn diff_options(&self) -> DiffOptions {
+        match &self.diff_ignore_headers {
+            Some(ignore_headers) => DiffOptions {
+                ignore_headers: ignore_headers.clone(),
+            },
+            None => DiffOptions::default(),
+        }
+    }
+
+    pub fn validate(&self) -> bool {
+        self.validate.unwrap_or(false)
+    }
+
+    pub fn cache_responses(&self) -> bool {
+        self.cache_responses.unwrap_or(false)
+    }
+
+    pub fn bodies_dir(&self) -> &str {
+        self.bodies_dir.as_deref().unwrap_or(DEFAULT_BODIES_DIR)
+    }
+
+    /// The engine evaluating the blocking rules
+    pub fn rule_engine(&self) -> RuleEngine {
+        RuleEngine::new(self.rules.clone())
+    }
+
+    /// The rules blocking prompts, loaded from the rules file or the built-in
+    /// ones without one
+    pub fn block_rules(&self) -> Result<BlockRules, Error> {
+        match &self.rules_file {
+            Some(path) => BlockRules::load(path),
+            None => Ok(BlockRules::builtin()),
+        }
+    }
+
+    /// Whether the decrypted bodies are recorded, off unless asked for
+    pub fn record_bodies(&self) -> bool {
+        self.record_bodies.unwrap_or(false)
+    }
+
+    /// Whether the credentials headers are redacted from the HAR entries, on
+    /// unless turned off
+    pub fn redact(&self) -> bool {
+        self.redact.unwrap_or(true)
+    }
+
+    /// What to record in the HAR entries. Bodies are only recorded when asked
+    /// for, and only stored externally when a threshold is set. The prompts
+    /// format keeps the prompts of the requests without their bodies.
+    pub fn capture_options(&self) -> CaptureOptions {
+        CaptureOptions {
+            record_bodies: self.record_bodies(),
+            body_preview: self.body_preview,
+            external_bodies: self
+                .external_body_threshold
+                .map(|threshold| ExternalBodies {
+                    dir: PathBuf::from(self.bodies_dir()),
+                    threshold,
+                }),
+            redact_headers: if self.redact() {
+                default_redacted_headers()
+            } else {
+                Vec::new()
+            },
+            record_prompts: self.format() == CaptureFormat::Prompts,
+        }
+    }
+
+    /// The passphrase for the private key. An explicit `passphrase` wins over
+    /// `passphrase_env`, and if neither is set the default passphrase is used.
+    pub fn passphrase(&self) -> Result<String, Error> {
+        Ok(self
+            .configured_passphrase()?
+            .unwrap_or_else(|| DEFAULT_PASSPHRASE.to_string()))
+    }
+
+    /// The passphrase given for the private key, from `passphrase` or else
+    /// `passphrase_env`. `None` if neither is set.
+    pub fn configured_passphrase(&self) -> Result<Option<String>, Error> {
+        if let Some(passphrase) = &self.passphrase {
+            return Ok(Some(passphrase.clone()));
+        }
+        match &self.passphrase_env {
+            Some(var) => std::env::var(var).map(Some).map_err(|_| {
+                Error::ConfigError(format!("environment variable {} is not set", var))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Load the certificate authority described by this configuration. Without
+    /// a passphrase, an unencrypted key is loaded as it is and an encrypted one
+    /// with the default passphrase.
+    pub fn load_ca(&self) -> Result<CertificateAuthority, Error> {
+        let passphrase = match self.configured_passphrase()? {
+            Some(passphrase) => Some(passphrase),
+            None => CertificateAuthority::is_key_file_encrypted(self.key_file())?
+                .then(|| DEFAULT_PASSPHRASE.to_string()),
+        };
+        match passphrase {
+            Some(passphrase) => CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+                self.cert_file(),
+                self.key_file(),
+                &passphrase,
+            ),
+            None => CertificateAuthority::load_from_pem_files(self.cert_file(), self.key_file()),
+        }
+    }
+}
+
+/// Parse the address to bind the proxy to, an IP address and a port such as
+/// `0.0.0.0:8081` or `[::1]:8081`, telling what is expected when it is not one
+pub fn parse_bind_address(value: &str) -> Result<SocketAddr, String> {
+    value.trim().parse().map_err(|_| {
+        format!(
+            "invalid address to bind to {:?}, expected an IP address and a port such as \
+             127.0.0.1:8081, 0.0.0.0:8081 or [::1]:8081",
+            value
+        )
+    })
+}
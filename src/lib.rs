@@ -1,2 +1,13 @@
+pub mod cache;
+pub mod capture;
+pub mod classifier;
+pub mod config;
+#[cfg(feature = "wasm-plugins")]
+pub mod plugin;
+pub mod replay;
+pub mod rewrite;
+pub mod rules;
+pub mod third_wheel;
+pub mod transcript;
 pub mod utilities;
-pub mod third_wheel;
\ No newline at end of file
+pub mod validation;
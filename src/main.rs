@@ -1,19 +1,52 @@
 use argh::FromArgs;
-use har::v1_2;
-use hyper::{header::HOST, Body, Request};
-use std::fs::File;
-use std::io::prelude::*;
-use tokio::join;
-use tokio::sync::mpsc;
+use har::v1_2::Entries;
+use hyper::{header::HOST, Body, Request, Response};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
 use tower::Service;
 
 mod utilities;
 use crate::utilities::*;
 
+mod cache;
+use crate::cache::{serve_cached, ResponseCache};
+
+mod capture;
+use crate::capture::{CaptureFormat, CaptureSink, SessionSummary};
+
+mod classifier;
+use crate::classifier::{KeywordClassifier, PromptClassifier};
+
+mod config;
+use crate::config::{parse_bind_address, Config, DEFAULT_CERT_FILE, DEFAULT_KEY_FILE};
+
+mod replay;
+use crate::replay::{diff_responses, load_har, play_cassette, Cassette, RecordedResponses};
+
+mod rewrite;
+use crate::rewrite::{is_json_request, rewrite_json_request_body};
+
+mod rules;
+use crate::rules::{block_page, RuleAction};
+
+#[cfg(feature = "wasm-plugins")]
+mod plugin;
+#[cfg(feature = "wasm-plugins")]
+use crate::plugin::{Plugin, PluginVerdict};
+
+mod validation;
+use crate::validation::validate_har_file;
+
+mod transcript;
+use crate::transcript::{load_transcripts, Transcript, TranscriptDump, TranscriptReplayer};
+
 mod third_wheel;
 use crate::third_wheel::{
-    certificates::CertificateAuthority,
+    certificates::{
+        CertificateAuthority, KeyType, DEFAULT_CA_COMMON_NAME, DEFAULT_CA_VALIDITY_DAYS,
+    },
     error::Error,
+    host_mapping::HostMappingEntry,
     proxy::{
         mitm::{mitm_layer, ThirdWheel},
         MitmProxy,
@@ -21,24 +54,357 @@ use crate::third_wheel::{
 };
 
 /// Run a TLS mitm proxy that records a HTTP ARchive (HAR) file of the session.
-/// Currently this is a proof-of-concept and won't handle binary data or non-utf8 encodings
+/// Currently this is a proof-of-concept
 #[derive(FromArgs)]
 struct StartMitm {
-    /// port to bind proxy to
-    #[argh(option, short = 'p', default = "8081")]
-    port: u16,
+    /// TOML file holding the proxy options, flags given on the command line take precedence
+    #[argh(option)]
+    config: Option<String>,
+
+    /// port to bind proxy to on 127.0.0.1 (default: 8081)
+    #[argh(option, short = 'p')]
+    port: Option<u16>,
+
+    /// address to bind proxy to, such as 0.0.0.0:8081 or [::1]:8081, taking precedence over
+    /// --port (default: 127.0.0.1:8081)
+    #[argh(option, from_str_fn(parse_bind_address))]
+    bind: Option<std::net::SocketAddr>,
+
+    /// output file to save the HAR to (default: logs.har)
+    #[argh(option, short = 'o')]
+    outfile: Option<String>,
+
+    /// redirect a host to another address, as host=address[:port] or
+    /// host=unix:/path/to.sock, the host being a name, a glob or a regex
+    /// starting with ~, can be repeated
+    #[argh(option)]
+    host_map: Vec<HostMappingEntry>,
+
+    /// pem file for self-signed certificate authority certificate (default: ca/ca_certs/cert.pem)
+    #[argh(option, short = 'c')]
+    cert_file: Option<String>,
+
+    /// pem file for private signing key for the certificate authority (default: ca/ca_certs/key.pem)
+    #[argh(option, short = 'k')]
+    key_file: Option<String>,
+
+    /// passphrase of the private signing key, to leave out for an unencrypted key (default: third-wheel for an encrypted key)
+    #[argh(option)]
+    passphrase: Option<String>,
+
+    /// seconds to let open connections finish after Ctrl-C before exiting (default: 30)
+    #[argh(option)]
+    shutdown_timeout: Option<u64>,
+
+    /// connections waiting to be accepted before new ones are refused (default: 128)
+    #[argh(option)]
+    listen_backlog: Option<u32>,
+
+    /// set SO_REUSEADDR on the listening socket, true or false, letting the proxy bind again
+    /// right after a restart (default: true on Unix)
+    #[argh(option)]
+    reuse_address: Option<bool>,
+
+    /// set SO_REUSEPORT on the listening socket, letting several proxies share the port
+    #[argh(switch)]
+    reuse_port: bool,
+
+    /// set TCP_NODELAY on the accepted connections
+    #[argh(switch)]
+    tcp_nodelay: bool,
+
+    /// record the decrypted bodies, which may hold personal data; only their sizes are recorded otherwise
+    #[argh(switch)]
+    record_bodies: bool,
+
+    /// only read and record the first given number of bytes of each body, streaming the rest on
+    #[argh(option)]
+    body_preview: Option<usize>,
+
+    /// store the bodies larger than this number of bytes in their own file, referenced from the HAR
+    #[argh(option)]
+    external_body_threshold: Option<usize>,
+
+    /// directory to store the external bodies in (default: bodies)
+    #[argh(option)]
+    bodies_dir: Option<String>,
+
+    /// record the Authorization, Cookie, Set-Cookie and Proxy-Authorization headers as they are
+    /// instead of redacting their values
+    #[argh(switch)]
+    no_redact: bool,
+
+    /// format of the output file, har, mitmproxy or prompts (default: har)
+    #[argh(option)]
+    format: Option<CaptureFormat>,
+
+    /// only record the target of each tunnel, relaying it without decrypting anything
+    #[argh(switch)]
+    log_connections_only: bool,
+
+    /// HAR recording to compare the live responses with, reporting the differences
+    #[argh(option)]
+    diff_against: Option<String>,
+
+    /// HAR cassette: requests recorded in it are replayed, the others are forwarded and recorded into it
+    #[argh(option)]
+    cassette: Option<String>,
+
+    /// answer GET requests from a cache of the responses the targets allow to be cached,
+    /// honoring Cache-Control, Expires and ETag
+    #[argh(switch)]
+    cache_responses: bool,
+
+    /// check the HAR file written against HAR 1.2 when the proxy stops, reporting what is wrong
+    #[argh(switch)]
+    validate: bool,
+
+    /// TOML or JSON file of the rules blocking prompts by host, path, method and keyword (default: built-in ChatGPT rule)
+    #[argh(option)]
+    rules: Option<String>,
+
+    /// directory to write a transcript of every decrypted request to, for a fuzzer, curl or --replay-requests
+    #[argh(option)]
+    dump_requests: Option<String>,
 
-    /// output file to save the HAR to
-    #[argh(option, short = 'o', default = "\"logs.har\".to_string()")]
-    outfile: String,
+    /// directory of request transcripts to send to their targets, printing the status of each, instead of running the proxy
+    #[argh(option)]
+    replay_requests: Option<String>,
 
-    /// pem file for self-signed certificate authority certificate
-    #[argh(option, short = 'c', default = "\"ca/ca_certs/cert.pem\".to_string()")]
+    /// file to write the requests, bytes, errors and average latency of each host to when the proxy stops
+    #[argh(option)]
+    summary: Option<String>,
+
+    /// WASM module deciding whether each request is forwarded, blocked or rewritten, needs the
+    /// proxy built with the wasm-plugins feature
+    #[argh(option)]
+    plugin: Option<String>,
+
+    /// host to relay without decrypting it, e.g. a bank or an app pinning its certificate, as a
+    /// name or a glob such as *.example.com, can be repeated; no HAR entries are recorded for it
+    #[argh(option)]
+    no_intercept: Vec<String>,
+
+    /// port of SMTP targets whose plaintext session is relayed until it is upgraded with
+    /// STARTTLS, then decrypted like a tunnel, can be repeated
+    #[argh(option)]
+    starttls_smtp_port: Vec<u16>,
+
+    /// host, or host:port, whose spoofed certificate is signed on startup so its first
+    /// connection does not wait for it, can be repeated
+    #[argh(option)]
+    prewarm_host: Vec<String>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+/// Commands run instead of the proxy
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    GenerateCa(GenerateCa),
+}
+
+/// Generate a new certificate authority for the proxy to sign its certificates with
+#[derive(FromArgs)]
+#[argh(subcommand, name = "generate-ca")]
+struct GenerateCa {
+    /// file to write the certificate to (default: ca/ca_certs/cert.pem)
+    #[argh(option, default = "DEFAULT_CERT_FILE.to_string()")]
     cert_file: String,
 
-    /// pem file for private signing key for the certificate authority
-    #[argh(option, short = 'k', default = "\"ca/ca_certs/key.pem\".to_string()")]
+    /// file to write the private key to (default: ca/ca_certs/key.pem)
+    #[argh(option, default = "DEFAULT_KEY_FILE.to_string()")]
     key_file: String,
+
+    /// passphrase encrypting the private key, which is written unencrypted without one
+    #[argh(option)]
+    passphrase: Option<String>,
+
+    /// common name of the certificate (default: tls_interceptor_proxy CA)
+    #[argh(option, default = "DEFAULT_CA_COMMON_NAME.to_string()")]
+    common_name: String,
+
+    /// number of days the certificate is valid for (default: 365)
+    #[argh(option, default = "DEFAULT_CA_VALIDITY_DAYS")]
+    days: u32,
+
+    /// type of the private key, rsa2048, rsa4096 or ecdsa-p256 (default: rsa2048)
+    #[argh(option, default = "KeyType::default()")]
+    key_type: KeyType,
+
+    /// overwrite the certificate and key files if they exist
+    #[argh(switch)]
+    force: bool,
+}
+
+impl GenerateCa {
+    /// Generate the certificate authority and write it to its files, which
+    /// are left alone if they exist unless `--force` is given.
+    ///
+    /// # Returns
+    /// A `Result<(), Error>` failing if the files exist or could not be written.
+    fn run(&self) -> Result<(), Error> {
+        if !self.force {
+            for path in [&self.cert_file, &self.key_file] {
+                if std::path::Path::new(path).exists() {
+                    return Err(Error::ConfigError(format!(
+                        "{} already exists, give --force to overwrite it",
+                        path
+                    )));
+                }
+            }
+        }
+        let ca = CertificateAuthority::generate(&self.common_name, self.days, self.key_type)?;
+        ca.save_to_pem_files(&self.cert_file, &self.key_file, self.passphrase.as_deref())?;
+        println!(
+            "Wrote {} and {}, fingerprint {}",
+            self.cert_file,
+            self.key_file,
+            ca.fingerprint_sha256()?
+        );
+        Ok(())
+    }
+}
+
+impl StartMitm {
+    /// The options given on the command line, as a `Config` to merge over the file
+    fn to_config(&self) -> Config {
+        Config {
+            port: self.port,
+            bind: self.bind,
+            outfile: self.outfile.clone(),
+            cert_file: self.cert_file.clone(),
+            key_file: self.key_file.clone(),
+            passphrase: self.passphrase.clone(),
+            host_mappings: self
+                .host_map
+                .iter()
+                .map(|entry| (entry.host.clone(), entry.target()))
+                .collect(),
+            shutdown_timeout: self.shutdown_timeout,
+            listen_backlog: self.listen_backlog,
+            reuse_address: self.reuse_address,
+            reuse_port: self.reuse_port.then_some(true),
+            tcp_nodelay: self.tcp_nodelay.then_some(true),
+            record_bodies: self.record_bodies.then_some(true),
+            body_preview: self.body_preview,
+            external_body_threshold: self.external_body_threshold,
+            bodies_dir: self.bodies_dir.clone(),
+            redact: self.no_redact.then_some(false),
+            format: self.format,
+            log_connections_only: self.log_connections_only.then_some(true),
+            diff_against: self.diff_against.clone(),
+            cassette: self.cassette.clone(),
+            cache_responses: self.cache_responses.then_some(true),
+            validate: self.validate.then_some(true),
+            rules_file: self.rules.clone(),
+            dump_requests: self.dump_requests.clone(),
+            replay_requests: self.replay_requests.clone(),
+            summary: self.summary.clone(),
+            plugin: self.plugin.clone(),
+            no_intercept: self.no_intercept.clone(),
+            starttls_smtp_ports: self.starttls_smtp_port.clone(),
+            prewarm_hosts: self.prewarm_host.clone(),
+            ..Config::default()
+        }
+    }
+}
+
+/// The host a request is sent to, from the authority of its URI as HTTP/2
+/// requests carry it, or else from its `Host` header. Empty when the request
+/// names none.
+fn request_host(parts: &hyper::http::request::Parts) -> &str {
+    parts
+        .uri
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| parts.headers.get(HOST).and_then(|h| h.to_str().ok()))
+        .unwrap_or("")
+}
+
+/// Ask the plugin what becomes of a request. A request the plugin fails on
+/// is blocked, as the prompts which could not be classified are. The plugin
+/// runs on a blocking thread, as it may use its whole fuel on a request.
+///
+/// # Arguments
+/// * `plugin` - The plugin inspecting the request.
+/// * `parts` - The parts of the request.
+/// * `body` - The body of the request.
+/// * `port` - The port of the target of the request.
+///
+/// # Returns
+/// The request to forward, or the response blocking it.
+#[cfg(feature = "wasm-plugins")]
+async fn apply_plugin(
+    plugin: &Arc<Plugin>,
+    mut parts: hyper::http::request::Parts,
+    body: Vec<u8>,
+    port: u16,
+) -> Result<(hyper::http::request::Parts, Vec<u8>), Response<Body>> {
+    // Requests naming no host cannot be serialized, nor sent anywhere
+    let Some(transcript) = Transcript::from_request(&parts, &body, port) else {
+        return Ok((parts, body));
+    };
+    let inspected = {
+        let plugin = Arc::clone(plugin);
+        let request = transcript.request.clone();
+        tokio::task::spawn_blocking(move || plugin.inspect(&request)).await
+    };
+    let verdict = inspected.unwrap_or_else(|e| Err(Error::RequestError(format!("plugin: {}", e))));
+    let modified = match verdict {
+        Ok(PluginVerdict::Allow) => return Ok((parts, body)),
+        Ok(PluginVerdict::Modify(request)) => Transcript {
+            request,
+            ..transcript
+        }
+        .to_request(),
+        Ok(PluginVerdict::Block) => {
+            println!("Blocked by the plugin");
+            return Err(block_page());
+        }
+        Err(e) => Err(e),
+    };
+    let modified = match modified {
+        Ok(modified) => modified,
+        Err(e) => {
+            eprintln!("Plugin failed, blocking the request: {}", e);
+            return Err(block_page());
+        }
+    };
+
+    // The rewritten request keeps what the proxy attached to the original
+    let (mut modified_parts, modified_body) = modified.into_parts();
+    modified_parts.extensions = std::mem::take(&mut parts.extensions);
+    let modified_body = hyper::body::to_bytes(modified_body)
+        .await
+        .map(|body| body.to_vec())
+        .unwrap_or_default();
+    Ok((modified_parts, modified_body))
+}
+
+/// Send the request transcripts of `dir` to their targets one after the
+/// other, printing the status of each response.
+///
+/// # Arguments
+/// * `dir` - The directory holding the transcripts.
+/// * `config` - The options, whose host mappings are followed.
+///
+/// # Returns
+/// A `Result<(), Error>` failing if the transcripts could not be read.
+async fn replay_transcripts(dir: &str, config: &Config) -> Result<(), Error> {
+    let replayer = TranscriptReplayer {
+        host_mappings: config.host_mappings.clone(),
+        ..TranscriptReplayer::default()
+    };
+    for (path, transcript) in load_transcripts(dir)? {
+        match replayer.replay(&transcript).await {
+            Ok(response) => println!("Replayed {}: {}", path.display(), response.status()),
+            Err(e) => eprintln!("Failed to replay {}: {}", path.display(), e),
+        }
+    }
+    Ok(())
 }
 
 /// The main entry point for running the TLS MITM proxy.
@@ -47,67 +413,297 @@ struct StartMitm {
 /// A `Result<(), Error>` indicating success or failure of the operation.
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    // Load the MITM certificate and key
+    // Load the options, the command line overriding the config file
     let args: StartMitm = argh::from_env();
-    let ca = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
-        &args.cert_file,
-        &args.key_file,
-        "third-wheel", // Passphrase for the private key
-    )?;
+    if let Some(Command::GenerateCa(generate_ca)) = &args.command {
+        return generate_ca.run();
+    }
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    }
+    .merge(args.to_config());
+
+    // Send the transcripts instead of running the proxy
+    if let Some(dir) = &config.replay_requests {
+        return replay_transcripts(dir, &config).await;
+    }
+
+    // Load the MITM certificate and key
+    let ca = config.load_ca()?;
+
+    // What to record of the blocked requests
+    let capture_options = config.capture_options();
+
+    // The message answering blocked prompts in each language
+    let block_messages = config.block_messages();
+
+    // Whether the capture also records the prompts which were not blocked
+    let capture_forwarded = config.format().records_forwarded_requests();
+
+    // How to rewrite the JSON bodies of forwarded requests
+    let json_rewrites = config.json_rewrites.clone();
+
+    // The rules deciding which responses are blocked
+    let rule_engine = config.rule_engine();
+
+    // The rules deciding which prompts are blocked
+    let block_rules = config.block_rules()?;
+
+    // What finds the sensitive prompts for the rules listing no keywords nor patterns
+    let classifier: Arc<dyn PromptClassifier> = Arc::new(KeywordClassifier::default());
+
+    // The recorded responses to compare the live ones with, when validating a replay
+    let recorded = match &config.diff_against {
+        Some(path) => Some(Arc::new(Mutex::new(RecordedResponses::from_entries(
+            load_har(path)?,
+        )))),
+        None => None,
+    };
+    let diff_options = config.diff_options();
+
+    // The cassette answering the requests it recorded, recording the others
+    let cassette = match &config.cassette {
+        Some(path) => Some(Arc::new(Mutex::new(Cassette::open(path)?))),
+        None => None,
+    };
+
+    // The responses answering the requests the targets allow to be cached
+    let response_cache = config
+        .cache_responses()
+        .then(|| Arc::new(Mutex::new(ResponseCache::default())));
+
+    // Where to write the transcripts of the decrypted requests
+    let transcript_dump = match &config.dump_requests {
+        Some(dir) => Some(Arc::new(TranscriptDump::create(dir)?)),
+        None => None,
+    };
+
+    // The plugin deciding what becomes of the forwarded requests
+    #[cfg(feature = "wasm-plugins")]
+    let plugin = match &config.plugin {
+        Some(path) => Some(Arc::new(Plugin::load(path)?)),
+        None => None,
+    };
+    #[cfg(not(feature = "wasm-plugins"))]
+    if config.plugin.is_some() {
+        return Err(Error::ConfigError(
+            "plugins need the proxy built with the wasm-plugins feature".to_string(),
+        ));
+    }
 
     // Create a channel for sending HAR log entries
     let (sender, mut receiver) = mpsc::channel(100);
+    let connection_sender = sender.clone();
+    let forwarded_sender = sender.clone();
 
     // Create a middleware layer to intercept requests
     let make_har_sender = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
         let sender = sender.clone();
+        let capture_options = capture_options.clone();
+        let block_messages = block_messages.clone();
+        let json_rewrites = json_rewrites.clone();
+        let rule_engine = rule_engine.clone();
+        let block_rules = block_rules.clone();
+        let classifier = classifier.clone();
+        let recorded = recorded.clone();
+        let diff_options = diff_options.clone();
+        let cassette = cassette.clone();
+        let response_cache = response_cache.clone();
+        let transcript_dump = transcript_dump.clone();
+        #[cfg(feature = "wasm-plugins")]
+        let plugin = plugin.clone();
 
         // Define the async block to process requests and responses
         let fut = async move {
             // Get the client IP from the request extensions
             let ip_client = third_wheel.get_client_ip();
+            let ip_server = third_wheel.get_server_ip();
+
+            // Intercept the request parts and body. With a body preview, the
+            // requests nothing here has to read are streamed on, only the
+            // start of their body being read to record it
+            let (mut req_parts, req_body) = req.into_parts();
+            let reads_body = capture_options.body_preview.is_none()
+                || transcript_dump.is_some()
+                || !json_rewrites.is_empty()
+                || recorded.is_some()
+                || cassette.is_some()
+                || block_rules.watches(
+                    request_host(&req_parts),
+                    req_parts.method.as_str(),
+                    req_parts.uri.path(),
+                );
+            #[cfg(feature = "wasm-plugins")]
+            let reads_body = reads_body || plugin.is_some();
+            let (body_bytes, streamed_body) = if reads_body {
+                let body_bytes = hyper::body::to_bytes(req_body).await.unwrap().to_vec();
+                (body_bytes, None)
+            } else {
+                (Vec::new(), Some(req_body))
+            };
 
-            // Intercept the request parts and body
-            let (req_parts, req_body) = req.into_parts();
-            let body_bytes = hyper::body::to_bytes(req_body).await.unwrap().to_vec();
+            // Keep a transcript of the request as the client sent it
+            if let Some(transcript_dump) = &transcript_dump {
+                let port = third_wheel.get_target_port();
+                if let Some(transcript) = Transcript::from_request(&req_parts, &body_bytes, port) {
+                    if let Err(e) = transcript_dump.record(&transcript) {
+                        eprintln!("Failed to write the transcript of a request: {}", e);
+                    }
+                }
+            }
 
             // Extract host and request method from headers and URI
-            let host = req_parts
-                .headers
-                .get(HOST)
-                .map(|h| h.to_str().unwrap_or(""))
-                .unwrap();
+            let host = request_host(&req_parts);
             let method = req_parts.method.to_string();
             let url_request = req_parts.uri.path();
-            // Check if the request matches certain conditions to block
-            if host.eq("chatgpt.com")
-                && url_request.eq("/backend-api/conversation")
-                && method == "POST"
-            {
+            // Check the prompt of the requests sent to the watched endpoints
+            if block_rules.watches(host, &method, url_request) {
                 // Extract the message write by the user in his prompt
                 let prompt = parse_request(body_bytes.clone());
                 println!("Prompt {}", prompt);
+                let body_json =
+                    is_json_request(&req_parts).then(|| convert_body_to_json(body_bytes.clone()));
 
-                // Block requests containing the word "confidential"
-                // TODO : Change the condition by the IA detection
-                if prompt.contains("confidential") {
+                // Block the requests whose prompt a rule forbids, and the ones
+                // which could not be classified
+                let action = block_rules
+                    .check(
+                        host,
+                        &method,
+                        url_request,
+                        body_json.as_ref(),
+                        &prompt,
+                        classifier.as_ref(),
+                    )
+                    .await
+                    .unwrap_or_else(|e| {
+                        eprintln!("Failed to classify the prompt: {}", e);
+                        RuleAction::Block
+                    });
+                if action == RuleAction::Block {
                     println!("Blocked");
 
                     // Get the tuple containing the HAR log entries and the HTTP response for the blocked request
-                    let (entries, response) =
-                        log_blocked_request(&req_parts, body_bytes.clone(), ip_client).await;
+                    let (entries, response) = log_blocked_request(
+                        &req_parts,
+                        body_bytes.clone(),
+                        ip_client,
+                        ip_server,
+                        &capture_options,
+                        &block_messages,
+                    )
+                    .await;
 
                     // Send the HAR entries over the channel
                     sender.send(entries).await.unwrap();
 
                     return Ok(response); // Return the response
                 }
+
+                // Record the prompt of the forwarded request if the capture wants it
+                if capture_forwarded {
+                    let entries = log_forwarded_request(
+                        &req_parts,
+                        body_bytes.clone(),
+                        ip_client,
+                        ip_server,
+                        &capture_options,
+                    )
+                    .await;
+                    sender.send(entries).await.unwrap();
+                }
             }
 
             // Forward the request if it doesn't contain blocked content
-            let body = Body::from(hyper::body::Bytes::from(body_bytes));
+            let body_bytes = rewrite_json_request_body(&mut req_parts, body_bytes, &json_rewrites);
+            let target_host = request_host(&req_parts).to_string();
+
+            // Block the request if a rule forbids it for this client
+            if rule_engine.check_request(&target_host, ip_client.ip()) == RuleAction::Block {
+                println!("Blocked request from {} to {}", ip_client, target_host);
+                return Ok(block_page());
+            }
+
+            // Let the plugin forward, block or rewrite the request
+            #[cfg(feature = "wasm-plugins")]
+            let (req_parts, body_bytes) = match &plugin {
+                Some(plugin) => {
+                    match apply_plugin(plugin, req_parts, body_bytes, third_wheel.get_target_port())
+                        .await
+                    {
+                        Ok(request) => request,
+                        Err(response) => return Ok(response),
+                    }
+                }
+                None => (req_parts, body_bytes),
+            };
+
+            // Keep the request as recorded in HAR to find its recorded response
+            let har_request = match &recorded {
+                Some(_) => {
+                    Some(copy_from_http_request_to_har(&req_parts, body_bytes.clone()).await)
+                }
+                None => None,
+            };
+
+            let body = match streamed_body {
+                Some(body) => body,
+                None => Body::from(hyper::body::Bytes::from(body_bytes)),
+            };
             let req = Request::<Body>::from_parts(req_parts, body);
-            let response = third_wheel.call(req).await.unwrap();
+            let response = match (&cassette, &response_cache) {
+                (Some(cassette), _) => play_cassette(cassette, req, &mut third_wheel).await?,
+                (None, Some(response_cache)) => {
+                    serve_cached(response_cache, req, &mut third_wheel).await?
+                }
+                (None, None) => third_wheel.call(req).await?,
+            };
+
+            // Block the response from its headers, before its body is downloaded
+            let (mut response, blocked) =
+                rule_engine.filter_response(&target_host, ip_client.ip(), response);
+            if blocked {
+                println!("Blocked response from {}", target_host);
+                return Ok(response);
+            }
+
+            // Report how the live response differs from the recorded one
+            if let (Some(recorded), Some(har_request)) = (&recorded, har_request) {
+                let (res_parts, res_body) = response.into_parts();
+                // Streams are compared on what arrives in the capture window,
+                // without holding the response back
+                let (live, res_body) = match record_response(&res_parts, res_body).await {
+                    Ok(recorded_response) => recorded_response,
+                    Err(e) => {
+                        eprintln!("Failed to read the response to compare: {}", e);
+                        return Ok(bad_gateway(&e.to_string()));
+                    }
+                };
+                let recorded = recorded.clone();
+                let diff_options = diff_options.clone();
+                record_when_read(async move {
+                    let live = live.await;
+                    let recorded_response = recorded.lock().unwrap().take(&har_request);
+                    match recorded_response {
+                        Some(recorded_response) => {
+                            for difference in
+                                diff_responses(&recorded_response, &live, &diff_options)
+                            {
+                                println!(
+                                    "Diff {} {}: {}",
+                                    har_request.method, har_request.url, difference
+                                );
+                            }
+                        }
+                        None => println!(
+                            "No recording for {} {}",
+                            har_request.method, har_request.url
+                        ),
+                    }
+                });
+                response = Response::from_parts(res_parts, res_body);
+            }
 
             Ok(response) // Return the response
         };
@@ -115,9 +711,50 @@ async fn main() -> Result<(), Error> {
     });
 
     // Set up and bind the MITM proxy
-    let mitm_proxy = MitmProxy::builder(make_har_sender, ca).build();
-    let addr = format!("127.0.0.1:{}", args.port).parse().unwrap();
-    let (_, mitm_proxy) = mitm_proxy.bind(addr);
+    let mut mitm_proxy = MitmProxy::builder(make_har_sender, ca)
+        .additional_host_mappings(config.host_mappings.clone())
+        .latency_sla(config.latency_sla())
+        .listener_options(config.listener_options())
+        .passthrough_hosts(config.no_intercept.clone())
+        .starttls_smtp_ports(config.starttls_smtp_ports.clone())
+        .shutdown_timeout(config.shutdown_timeout());
+    if config.log_connections_only() {
+        mitm_proxy = mitm_proxy.log_connections_only(move |connection| {
+            if let Err(e) = connection_sender.try_send(log_connection(&connection)) {
+                eprintln!("Dropped connection entry: {}", e);
+            }
+        });
+    } else if !capture_forwarded {
+        // Record every exchange forwarded to a target, the failed ones and
+        // each hop of a redirect included, the prompts format only keeping
+        // the prompts recorded by the closure
+        let forwarded_options = config.capture_options();
+        mitm_proxy = mitm_proxy
+            .record_bodies(forwarded_options.record_bodies)
+            .redact_headers(forwarded_options.redact_headers);
+        if let Some(limit) = forwarded_options.body_preview {
+            mitm_proxy = mitm_proxy.body_preview(limit);
+        }
+        let (builder, mut forwarded) = mitm_proxy.capture_stream();
+        mitm_proxy = builder;
+        tokio::spawn(async move {
+            while let Some(entry) = forwarded.recv().await {
+                if forwarded_sender.send(entry).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    let mitm_proxy = mitm_proxy.build();
+    let prewarm_hosts: Vec<&str> = config.prewarm_hosts.iter().map(String::as_str).collect();
+    for (host, e) in mitm_proxy.prewarm_certs(&prewarm_hosts).await {
+        eprintln!("Could not prewarm the certificate of {}: {}", host, e);
+    }
+    let addr = config.bind_address();
+    let (_, mitm_proxy) = mitm_proxy.bind_with_graceful_shutdown(addr, async {
+        let _ = tokio::signal::ctrl_c().await;
+        println!("Shutting down");
+    });
 
     // Spawn a task to run the proxy
     let proxy_task = tokio::spawn(async {
@@ -125,48 +762,80 @@ async fn main() -> Result<(), Error> {
         println!("Proxy is running");
     });
 
-    // Store the intercepted HAR entries
-    let mut entries = Vec::new();
+    // Open the file to write the captured entries to
+    let mut sink = config.format().create_sink(config.outfile())?;
 
-    // Open a file to write HAR logs
-    let mut file = File::create(&args.outfile).unwrap();
+    // The statistics of each host, reported once the proxy stops
+    let summary = Arc::new(Mutex::new(SessionSummary::new()));
+    let session_summary = summary.clone();
 
-    // Spawn a task to receive and log entries
+    // Spawn a task to receive and log entries until the proxy stops, returning
+    // how many were written
+    let (stop_sender, mut stop_receiver) = oneshot::channel::<()>();
     let receiver_task = tokio::spawn(async move {
-        while let Some(entry) = receiver.recv().await {
-            entries.push(entry.clone());
-
-            let out = har::Har {
-                log: har::Spec::V1_2(v1_2::Log {
-                    entries: entries.clone(),
-                    browser: None,
-                    comment: Some("Confidential disclosure blocked".to_string()),
-                    pages: None,
-                    creator: v1_2::Creator {
-                        name: "SentineLLM".to_string(),
-                        version: "0.5".to_string(),
-                        comment: Some("The IA at the service of confidentiality".to_string()),
-                    },
-                }),
-            };
+        let mut written = 0;
+        let mut record = |entry: &Entries| {
+            match sink.record(entry) {
+                Ok(()) => written += 1,
+                Err(e) => eprintln!("Error writing captured entry: {:?}", e),
+            }
+            let _ = session_summary.lock().unwrap().record(entry);
+        };
+        loop {
+            tokio::select! {
+                entry = receiver.recv() => match entry {
+                    Some(entry) => record(&entry),
+                    None => break,
+                },
+                _ = &mut stop_receiver => break,
+            }
+        }
 
-            // Write the HAR log to the file
-            file.write_all(har::to_json(&out).unwrap().as_bytes())
-                .unwrap();
-            file.write_all(b",\n").unwrap();
+        // Connections still hung after the shutdown timeout keep their
+        // senders, so refuse new entries and write the ones already sent
+        receiver.close();
+        while let Some(entry) = receiver.recv().await {
+            record(&entry);
         }
+        written
     });
 
-    // Wait for both proxy and logging tasks to complete
-    let (proxy_result, receiver_result) = join!(proxy_task, receiver_task);
-
-    // Handle errors from the proxy or logging task
-    if let Err(e) = proxy_result {
+    // Wait for the proxy to shut down
+    if let Err(e) = proxy_task.await {
         eprintln!("Error in proxy task: {:?}", e);
     }
 
-    if let Err(e) = receiver_result {
-        eprintln!("Error in receiver task: {:?}", e);
+    // Let the receiver write the pending entries, the file being closed with
+    // the sink
+    let _ = stop_sender.send(());
+    match receiver_task.await {
+        Ok(written) => println!("Wrote {} entries to {}", written, config.outfile()),
+        Err(e) => eprintln!("Error in receiver task: {:?}", e),
+    }
+
+    // Report what was captured of each host
+    let summary = summary.lock().unwrap();
+    print!("{}", summary.report());
+    if let Some(path) = &config.summary {
+        summary.write_to_file(path)?;
+        println!("Wrote the session summary to {}", path);
+    }
+
+    // Check the archive written, now that it holds every entry
+    if config.validate() {
+        if config.format().writes_har() {
+            let errors = validate_har_file(config.outfile())?;
+            for error in &errors {
+                eprintln!("Invalid HAR: {}", error);
+            }
+            println!(
+                "{} HAR validation errors in {}",
+                errors.len(),
+                config.outfile()
+            );
+        } else {
+            eprintln!("Only HAR files can be validated");
+        }
     }
 
     Ok(()) // Exit the function
@@ -0,0 +1,105 @@
+use std::path::Path;
+use wasmtime::{Config, Engine, Instance, Module, Store};
+
+use crate::third_wheel::error::Error;
+
+/// Instructions a plugin may run for one request, so a plugin stuck in a
+/// loop fails the request rather than holding its connection forever
+const FUEL_PER_REQUEST: u64 = 100_000_000;
+
+/// Verdicts returned by `inspect`
+const ALLOW: i32 = 0;
+const BLOCK: i32 = 1;
+const MODIFY: i32 = 2;
+
+/// What a plugin decided for a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PluginVerdict {
+    /// forward the request as it is
+    Allow,
+    /// answer the client with the block page instead of forwarding it
+    Block,
+    /// forward this request instead, as raw HTTP/1.1 bytes
+    Modify(Vec<u8>),
+}
+
+/// A WASM module inspecting the decrypted requests before they are forwarded,
+/// available with the `wasm-plugins` feature.
+///
+/// The module imports nothing and exports:
+/// * `memory`, where the requests are exchanged
+/// * `alloc(len: i32) -> i32`, the address of `len` bytes the host writes the
+///   request to
+/// * `inspect(ptr: i32, len: i32) -> i32`, the verdict for the request written
+///   at `ptr`: 0 to allow it, 1 to block it, 2 to modify it
+/// * `modified() -> i64`, only called once `inspect` returned 2, the address
+///   of the request to forward instead in its high 32 bits and its length in
+///   its low 32 bits
+///
+/// Requests are raw HTTP/1.1 bytes, as saved in a `Transcript`. Each request
+/// is inspected by a new instance of the module, so nothing is kept from one
+/// request to the next.
+pub struct Plugin {
+    engine: Engine,
+    module: Module,
+}
+
+impl Plugin {
+    /// Load the plugin of a `.wasm` file, or of a `.wat` one in the text format
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Load a plugin from its module, binary or in the text format
+    pub fn from_bytes(module: &[u8]) -> Result<Self, Error> {
+        let invalid = |e: wasmtime::Error| Error::ConfigError(format!("invalid plugin: {}", e));
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(invalid)?;
+        let module = Module::new(&engine, module).map_err(invalid)?;
+        Ok(Self { engine, module })
+    }
+
+    /// Ask the plugin what to do with a request, given as raw HTTP/1.1 bytes
+    pub fn inspect(&self, request: &[u8]) -> Result<PluginVerdict, Error> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL_PER_REQUEST).map_err(plugin_error)?;
+        let instance = Instance::new(&mut store, &self.module, &[]).map_err(plugin_error)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| plugin_error("no memory exported"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(plugin_error)?;
+        let inspect = instance
+            .get_typed_func::<(i32, i32), i32>(&mut store, "inspect")
+            .map_err(plugin_error)?;
+
+        let len = i32::try_from(request.len()).map_err(|_| plugin_error("request too large"))?;
+        let ptr = alloc.call(&mut store, len).map_err(plugin_error)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, request)
+            .map_err(plugin_error)?;
+        match inspect.call(&mut store, (ptr, len)).map_err(plugin_error)? {
+            ALLOW => Ok(PluginVerdict::Allow),
+            BLOCK => Ok(PluginVerdict::Block),
+            MODIFY => {
+                let modified = instance
+                    .get_typed_func::<(), i64>(&mut store, "modified")
+                    .map_err(plugin_error)?
+                    .call(&mut store, ())
+                    .map_err(plugin_error)? as u64;
+                let mut request = vec![0; (modified & 0xffff_ffff) as usize];
+                memory
+                    .read(&store, (modified >> 32) as usize, &mut request)
+                    .map_err(plugin_error)?;
+                Ok(PluginVerdict::Modify(request))
+            }
+            verdict => Err(plugin_error(format!("unknown verdict {}", verdict))),
+        }
+    }
+}
+
+fn plugin_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::RequestError(format!("plugin: {}", e))
+}
@@ -0,0 +1,310 @@
+use har::v1_2::{self, Entries};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{service::Service, Body, Request, Response, StatusCode};
+use log::error;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::capture::{CaptureSink, HarFileSink};
+use crate::third_wheel::{
+    error::Error,
+    proxy::mitm::{RequestId, ThirdWheel},
+};
+use crate::utilities::{
+    copy_from_http_request_to_har, har_content_bytes, har_entry, record_response, record_when_read,
+    redact_headers,
+};
+
+/// Headers expected to change between two runs of the same exchange
+pub const DEFAULT_IGNORED_HEADERS: [&str; 3] = ["date", "age", "x-request-id"];
+
+/// Load the entries of a HAR file
+pub fn load_har<P: AsRef<Path>>(path: P) -> Result<Vec<Entries>, Error> {
+    let har = har::from_path(path).map_err(|e| Error::ConfigError(e.to_string()))?;
+    match har.log {
+        har::Spec::V1_2(log) => Ok(log.entries),
+        har::Spec::V1_3(_) => Err(Error::ConfigError(
+            "only HAR 1.2 recordings can be replayed".to_string(),
+        )),
+    }
+}
+
+/// Identifies the same request across recordings: method, host and URL
+fn request_key(request: &v1_2::Request) -> (String, String, String) {
+    let host = request
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("host"))
+        .map(|header| header.value.to_ascii_lowercase())
+        .unwrap_or_default();
+    (request.method.clone(), host, request.url.clone())
+}
+
+/// The recorded responses of a HAR file, looked up by the request they
+/// answered. A request made several times gets its responses in the order
+/// they were recorded.
+#[derive(Debug, Default)]
+pub struct RecordedResponses {
+    responses: HashMap<(String, String, String), VecDeque<v1_2::Response>>,
+}
+
+impl RecordedResponses {
+    pub fn from_entries(entries: Vec<Entries>) -> Self {
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+        for entry in entries {
+            responses
+                .entry(request_key(&entry.request))
+                .or_default()
+                .push_back(entry.response);
+        }
+        Self { responses }
+    }
+
+    /// Take the next recorded response for this request, if any is left
+    pub fn take(&mut self, request: &v1_2::Request) -> Option<v1_2::Response> {
+        self.responses.get_mut(&request_key(request))?.pop_front()
+    }
+}
+
+/// How to compare a live response with a recorded one
+#[derive(Clone, Debug)]
+pub struct DiffOptions {
+    /// names of the headers not compared, in any case
+    pub ignore_headers: Vec<String>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            ignore_headers: DEFAULT_IGNORED_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// One way a live response differs from the recorded one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    Status {
+        recorded: i64,
+        live: i64,
+    },
+    /// a header whose values differ, `None` when it is missing on one side
+    Header {
+        name: String,
+        recorded: Option<String>,
+        live: Option<String>,
+    },
+    Body {
+        recorded_size: usize,
+        live_size: usize,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Status { recorded, live } => {
+                write!(f, "status: recorded {}, live {}", recorded, live)
+            }
+            Difference::Header {
+                name,
+                recorded,
+                live,
+            } => write!(
+                f,
+                "header {}: recorded {}, live {}",
+                name,
+                recorded.as_deref().unwrap_or("<missing>"),
+                live.as_deref().unwrap_or("<missing>")
+            ),
+            Difference::Body {
+                recorded_size,
+                live_size,
+            } => write!(
+                f,
+                "body: recorded {} bytes, live {} bytes",
+                recorded_size, live_size
+            ),
+        }
+    }
+}
+
+/// The values of each header, comma separated when it is repeated
+fn header_values(response: &v1_2::Response, options: &DiffOptions) -> HashMap<String, String> {
+    let mut values: HashMap<String, String> = HashMap::new();
+    for header in &response.headers {
+        let name = header.name.to_ascii_lowercase();
+        if options
+            .ignore_headers
+            .iter()
+            .any(|ignored| ignored.eq_ignore_ascii_case(&name))
+        {
+            continue;
+        }
+        values
+            .entry(name)
+            .and_modify(|value| {
+                value.push_str(", ");
+                value.push_str(&header.value);
+            })
+            .or_insert_with(|| header.value.clone());
+    }
+    values
+}
+
+/// Compare the status, headers and body of a live response with the recorded
+/// one. Returns no difference when they match.
+pub fn diff_responses(
+    recorded: &v1_2::Response,
+    live: &v1_2::Response,
+    options: &DiffOptions,
+) -> Vec<Difference> {
+    let mut differences = Vec::new();
+
+    if recorded.status != live.status {
+        differences.push(Difference::Status {
+            recorded: recorded.status,
+            live: live.status,
+        });
+    }
+
+    let recorded_headers = header_values(recorded, options);
+    let mut live_headers = header_values(live, options);
+    let mut names: Vec<&String> = recorded_headers.keys().collect();
+    names.sort();
+    for name in names {
+        let recorded_value = &recorded_headers[name];
+        match live_headers.remove(name) {
+            Some(live_value) if &live_value == recorded_value => {}
+            live_value => differences.push(Difference::Header {
+                name: name.clone(),
+                recorded: Some(recorded_value.clone()),
+                live: live_value,
+            }),
+        }
+    }
+    let mut added: Vec<(String, String)> = live_headers.into_iter().collect();
+    added.sort();
+    for (name, live_value) in added {
+        differences.push(Difference::Header {
+            name,
+            recorded: None,
+            live: Some(live_value),
+        });
+    }
+
+    let recorded_body = har_content_bytes(&recorded.content);
+    let live_body = har_content_bytes(&live.content);
+    if recorded_body != live_body {
+        differences.push(Difference::Body {
+            recorded_size: recorded_body.len(),
+            live_size: live_body.len(),
+        });
+    }
+
+    differences
+}
+
+/// A HAR recording used like a VCR cassette: requests with a recorded
+/// response are answered from it, the others are forwarded to the target and
+/// their exchange is added to it. Each recorded response is replayed once.
+pub struct Cassette {
+    recorded: RecordedResponses,
+    sink: HarFileSink,
+}
+
+impl Cassette {
+    /// Open the cassette at `path`, starting an empty one if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let entries = if path.as_ref().exists() {
+            load_har(&path)?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            recorded: RecordedResponses::from_entries(entries.clone()),
+            sink: HarFileSink::append(path, entries)?,
+        })
+    }
+
+    /// Take the recorded response for this request, if it has one
+    pub fn replay(&mut self, request: &v1_2::Request) -> Option<v1_2::Response> {
+        self.recorded.take(request)
+    }
+
+    /// Add an exchange to the cassette, saving it to its file as given, its
+    /// headers already redacted as `play_cassette` does
+    pub fn record(&mut self, entry: &Entries) -> Result<(), Error> {
+        self.sink.record(entry)
+    }
+}
+
+/// Answer a request from the cassette on a hit, otherwise forward it with
+/// `third_wheel` and record the exchange in the cassette once the response
+/// was read, without holding it back. The headers the proxy redacts are
+/// redacted in the cassette too, so it replays `[REDACTED]` for them.
+pub async fn play_cassette(
+    cassette: &Arc<Mutex<Cassette>>,
+    request: Request<Body>,
+    third_wheel: &mut ThirdWheel,
+) -> Result<Response<Body>, Error> {
+    let (parts, body) = request.into_parts();
+    let body_bytes = hyper::body::to_bytes(body).await?;
+    let har_request = copy_from_http_request_to_har(&parts, body_bytes.to_vec()).await;
+
+    let recorded = cassette.lock().unwrap().replay(&har_request);
+    if let Some(recorded) = recorded {
+        return Ok(response_from_har(&recorded));
+    }
+
+    let request_id = parts.extensions.get::<RequestId>().cloned();
+    let response = third_wheel
+        .call(Request::from_parts(parts, Body::from(body_bytes)))
+        .await?;
+    let (res_parts, res_body) = response.into_parts();
+    let (har_response, res_body) = record_response(&res_parts, res_body).await?;
+    let client_ip = third_wheel.get_client_ip();
+    let server_ip = third_wheel.get_server_ip();
+    let redacted_headers = third_wheel.get_redacted_headers().to_vec();
+    let cassette = cassette.clone();
+    record_when_read(async move {
+        let mut entry = har_entry(
+            har_request,
+            har_response.await,
+            client_ip,
+            server_ip,
+            request_id.as_ref(),
+        );
+        redact_headers(&mut entry, &redacted_headers);
+        if let Err(e) = cassette.lock().unwrap().record(&entry) {
+            error!("Failed to record the exchange in the cassette: {}", e);
+        }
+    });
+    Ok(Response::from_parts(res_parts, res_body))
+}
+
+/// Rebuild a response recorded in HAR, decoding a base64 body. The body
+/// length is the one of the recorded body, so the recorded framing headers
+/// are left out, as is the `Content-Encoding` of a body recorded decoded.
+pub fn response_from_har(recorded: &v1_2::Response) -> Response<Body> {
+    let mut response = Response::new(Body::from(har_content_bytes(&recorded.content)));
+    *response.status_mut() = StatusCode::from_u16(recorded.status as u16).unwrap_or(StatusCode::OK);
+    for header in &recorded.headers {
+        let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(header.name.as_bytes()),
+            HeaderValue::from_str(&header.value),
+        ) else {
+            continue;
+        };
+        let decoded = recorded.content.compression.is_some() && name == CONTENT_ENCODING;
+        if name != CONTENT_LENGTH && name != TRANSFER_ENCODING && !decoded {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response
+}
@@ -0,0 +1,266 @@
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::third_wheel::error::Error;
+use crate::utilities::{content_codings, convert_body_to_json, decode_content, encode_content};
+
+/// One step of a JSONPath
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    /// `.name` or `['name']`
+    Key(String),
+    /// `[0]`
+    Index(usize),
+    /// `.*` or `[*]`, every child of an object or array
+    Wildcard,
+}
+
+/// A JSONPath selecting nodes of a JSON document.
+///
+/// Only the subset needed to point at nodes is supported: the root `$`
+/// followed by `.name`, `['name']`, `[index]` and the `*` wildcard, e.g.
+/// `$.messages[0].content` or `$.items[*].price`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct JsonPath {
+    path: String,
+    segments: Vec<Segment>,
+}
+
+impl FromStr for JsonPath {
+    type Err = Error;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: &str| Error::ConfigError(format!("invalid JSONPath {}: {}", path, reason));
+
+        let mut rest = path
+            .strip_prefix('$')
+            .ok_or_else(|| invalid("it must start with $"))?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after_dot) = rest.strip_prefix('.') {
+                // A member name runs until the next segment
+                let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+                let name = &after_dot[..end];
+                segments.push(match name {
+                    "" => return Err(invalid("empty member name")),
+                    "*" => Segment::Wildcard,
+                    _ => Segment::Key(name.to_string()),
+                });
+                rest = &after_dot[end..];
+            } else if let Some(after_bracket) = rest.strip_prefix('[') {
+                let end = after_bracket
+                    .find(']')
+                    .ok_or_else(|| invalid("unclosed ["))?;
+                let selector = &after_bracket[..end];
+                let quoted = selector
+                    .strip_prefix('\'')
+                    .and_then(|s| s.strip_suffix('\''))
+                    .or_else(|| selector.strip_prefix('"').and_then(|s| s.strip_suffix('"')));
+                segments.push(if selector == "*" {
+                    Segment::Wildcard
+                } else if let Some(name) = quoted {
+                    Segment::Key(name.to_string())
+                } else {
+                    Segment::Index(
+                        selector
+                            .parse()
+                            .map_err(|_| invalid("expected an index, a quoted name or *"))?,
+                    )
+                });
+                rest = &after_bracket[end + 1..];
+            } else {
+                return Err(invalid("expected . or ["));
+            }
+        }
+
+        Ok(JsonPath {
+            path: path.to_string(),
+            segments,
+        })
+    }
+}
+
+impl TryFrom<String> for JsonPath {
+    type Error = Error;
+
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        path.parse()
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+impl JsonPath {
+    /// Replace every node selected by the path with `value`, returning how
+    /// many nodes were replaced. Paths selecting nothing leave `json` unchanged.
+    pub fn replace(&self, json: &mut Value, value: &Value) -> usize {
+        replace_nodes(json, &self.segments, value)
+    }
+
+    /// The nodes selected by the path, none if it selects nothing
+    pub fn select<'a>(&self, json: &'a Value) -> Vec<&'a Value> {
+        let mut nodes = Vec::new();
+        select_nodes(json, &self.segments, &mut nodes);
+        nodes
+    }
+}
+
+fn select_nodes<'a>(node: &'a Value, segments: &[Segment], nodes: &mut Vec<&'a Value>) {
+    let Some((segment, rest)) = segments.split_first() else {
+        nodes.push(node);
+        return;
+    };
+    match (segment, node) {
+        (Segment::Key(name), Value::Object(object)) => {
+            if let Some(child) = object.get(name) {
+                select_nodes(child, rest, nodes);
+            }
+        }
+        (Segment::Index(index), Value::Array(array)) => {
+            if let Some(child) = array.get(*index) {
+                select_nodes(child, rest, nodes);
+            }
+        }
+        (Segment::Wildcard, Value::Object(object)) => {
+            for child in object.values() {
+                select_nodes(child, rest, nodes);
+            }
+        }
+        (Segment::Wildcard, Value::Array(array)) => {
+            for child in array {
+                select_nodes(child, rest, nodes);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn replace_nodes(node: &mut Value, segments: &[Segment], value: &Value) -> usize {
+    let Some((segment, rest)) = segments.split_first() else {
+        *node = value.clone();
+        return 1;
+    };
+    match (segment, node) {
+        (Segment::Key(name), Value::Object(object)) => object
+            .get_mut(name)
+            .map_or(0, |child| replace_nodes(child, rest, value)),
+        (Segment::Index(index), Value::Array(array)) => array
+            .get_mut(*index)
+            .map_or(0, |child| replace_nodes(child, rest, value)),
+        (Segment::Wildcard, Value::Object(object)) => object
+            .values_mut()
+            .map(|child| replace_nodes(child, rest, value))
+            .sum(),
+        (Segment::Wildcard, Value::Array(array)) => array
+            .iter_mut()
+            .map(|child| replace_nodes(child, rest, value))
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// Sets the nodes of JSON request bodies matched by `path` to `value`
+///
+/// ```toml
+/// [[json_rewrites]]
+/// path = "$.user.role"
+/// value = "admin"
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JsonRewriteRule {
+    pub path: JsonPath,
+    pub value: Value,
+}
+
+/// Whether the body of a request is declared as JSON, e.g. `application/json`
+/// or `application/ld+json`
+pub fn is_json_request(req_parts: &hyper::http::request::Parts) -> bool {
+    req_parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            let mime = value.split(';').next().unwrap_or("").trim();
+            mime == "application/json" || mime.ends_with("+json")
+        })
+        .unwrap_or(false)
+}
+
+/// Applies a transform to a body sent with the `Content-Encoding` of
+/// `headers`. An encoded body is decoded before the transform and its result
+/// encoded again with the same codings, so it still matches the header the
+/// other side expects. Bodies whose codings cannot be undone, such as `br`,
+/// are not transformed.
+///
+/// # Arguments
+/// * `headers` - The headers of the message, its `Content-Length` is updated
+///   when the body is transformed.
+/// * `body` - The body as it was sent.
+/// * `transform` - Gives the new decoded body, or `None` to leave it unchanged.
+///
+/// # Returns
+/// The body to forward, encoded as the original one.
+pub fn transform_body<F>(headers: &mut HeaderMap, body: Vec<u8>, transform: F) -> Vec<u8>
+where
+    F: FnOnce(&[u8]) -> Option<Vec<u8>>,
+{
+    let transformed = if !content_codings(headers).is_empty() {
+        let Some(decoded) = decode_content(headers, &body) else {
+            return body;
+        };
+        transform(&decoded).and_then(|transformed| encode_content(headers, &transformed))
+    } else {
+        transform(&body)
+    };
+    let Some(transformed) = transformed else {
+        return body;
+    };
+
+    // The new body is sent whole, with its length
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(transformed.len()));
+    transformed
+}
+
+/// Applies the rewrite rules to a request body if it is JSON. A compressed
+/// body is rewritten decoded and compressed again.
+///
+/// # Arguments
+/// * `req_parts` - The parts of the HTTP request, its `Content-Length` is
+///   updated when the body is rewritten.
+/// * `body_bytes` - The body of the HTTP request as a byte vector.
+/// * `rules` - The rules to apply, in order.
+///
+/// # Returns
+/// The body to forward, unchanged if it is not JSON or no rule matched.
+pub fn rewrite_json_request_body(
+    req_parts: &mut hyper::http::request::Parts,
+    body_bytes: Vec<u8>,
+    rules: &[JsonRewriteRule],
+) -> Vec<u8> {
+    if rules.is_empty() || !is_json_request(req_parts) {
+        return body_bytes;
+    }
+
+    transform_body(&mut req_parts.headers, body_bytes, |body| {
+        let mut body_json = convert_body_to_json(body.to_vec());
+        if body_json.is_null() {
+            return None;
+        }
+        let replaced: usize = rules
+            .iter()
+            .map(|rule| rule.path.replace(&mut body_json, &rule.value))
+            .sum();
+        (replaced > 0).then(|| serde_json::to_vec(&body_json).unwrap())
+    })
+}
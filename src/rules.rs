@@ -0,0 +1,458 @@
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::classifier::{contains_keyword, PromptClassifier};
+use crate::rewrite::JsonPath;
+use crate::third_wheel::{error::Error, host_mapping::glob_matches};
+
+/// Parse a network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare
+/// address is a network holding only that address.
+pub fn parse_network(network: &str) -> Result<IpNet, Error> {
+    network
+        .parse()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| Error::ConfigError(format!("invalid CIDR {}", network)))
+}
+
+/// Read the networks of a rule, see `parse_network`
+fn deserialize_networks<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|network| parse_network(network).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// A regular expression matched against the prompts, e.g. `(?i)project\s+x`.
+/// Two patterns are equal when their sources are.
+#[derive(Clone, Debug)]
+pub struct PromptPattern(Regex);
+
+impl PromptPattern {
+    pub fn new(pattern: &str) -> Result<Self, Error> {
+        Regex::new(pattern)
+            .map(Self)
+            .map_err(|e| Error::ConfigError(format!("invalid pattern {}: {}", pattern, e)))
+    }
+
+    /// Whether the pattern matches anywhere in `prompt`
+    pub fn is_match(&self, prompt: &str) -> bool {
+        self.0.is_match(prompt)
+    }
+}
+
+impl PartialEq for PromptPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for PromptPattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        Self::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+/// What to do with an exchange matched by a rule
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// replace the exchange with a block page
+    #[default]
+    Block,
+    /// let the exchange through
+    Allow,
+}
+
+/// A rule of the engine. Every predicate that is set must match for the rule
+/// to apply, an unset predicate matches everything.
+///
+/// Rules on response content types are evaluated once the response headers
+/// arrived, the others before the request is forwarded.
+///
+/// ```toml
+/// [[rules]]
+/// host = "*.example.com"
+/// response_content_types = ["application/zip"]
+/// action = "block"
+///
+/// [[rules]]
+/// client_cidrs = ["192.168.100.0/24"]
+/// host = "*.social.example"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// glob pattern of the hosts the rule applies to
+    pub host: Option<String>,
+    /// networks of the clients the rule applies to
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub client_cidrs: Vec<IpNet>,
+    /// media types of the responses the rule applies to, e.g.
+    /// `application/zip`. Parameters like `charset` are not compared.
+    pub response_content_types: Vec<String>,
+    /// what to do with the matched exchanges
+    pub action: RuleAction,
+}
+
+impl Rule {
+    /// Whether the rule is evaluated once the response headers arrived
+    fn is_response_rule(&self) -> bool {
+        !self.response_content_types.is_empty()
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        self.host
+            .as_ref()
+            .is_none_or(|pattern| glob_matches(pattern, host))
+    }
+
+    /// IPv4 clients mapped in IPv6, `::ffff:10.0.0.1`, are matched as IPv4
+    /// clients
+    fn matches_client(&self, client_ip: IpAddr) -> bool {
+        let client_ip = client_ip.to_canonical();
+        self.client_cidrs.is_empty()
+            || self
+                .client_cidrs
+                .iter()
+                .any(|cidr| cidr.contains(&client_ip))
+    }
+
+    fn matches_content_type(&self, content_type: Option<&HeaderValue>) -> bool {
+        let media_type = content_type
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(str::trim)
+            .unwrap_or("");
+        self.response_content_types
+            .iter()
+            .any(|banned| banned.eq_ignore_ascii_case(media_type))
+    }
+}
+
+/// Evaluates the rules in order, the first matching rule deciding the action
+#[derive(Clone, Debug, Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    /// The action for a request to `host` from `client_ip`, decided before
+    /// it is forwarded. The rules on response content types are left for
+    /// `check_response`, and the request is allowed when no rule matches.
+    pub fn check_request(&self, host: &str, client_ip: IpAddr) -> RuleAction {
+        self.rules
+            .iter()
+            .filter(|rule| !rule.is_response_rule())
+            .find(|rule| rule.matches_host(host) && rule.matches_client(client_ip))
+            .map_or(RuleAction::Allow, |rule| rule.action)
+    }
+
+    /// The action for a response of `host` to `client_ip`, decided from its
+    /// headers alone. Only the rules on response content types are
+    /// evaluated, and the response is allowed when none of them matches.
+    pub fn check_response(
+        &self,
+        host: &str,
+        client_ip: IpAddr,
+        response: &hyper::http::response::Parts,
+    ) -> RuleAction {
+        self.rules
+            .iter()
+            .filter(|rule| rule.is_response_rule())
+            .find(|rule| {
+                rule.matches_host(host)
+                    && rule.matches_client(client_ip)
+                    && rule.matches_content_type(response.headers.get(CONTENT_TYPE))
+            })
+            .map_or(RuleAction::Allow, |rule| rule.action)
+    }
+
+    /// Replace a blocked response with the block page. The body of the
+    /// blocked response is dropped without being read.
+    ///
+    /// # Returns
+    /// The response to send to the client and whether it was blocked.
+    pub fn filter_response(
+        &self,
+        host: &str,
+        client_ip: IpAddr,
+        response: Response<Body>,
+    ) -> (Response<Body>, bool) {
+        let (parts, body) = response.into_parts();
+        match self.check_response(host, client_ip, &parts) {
+            RuleAction::Allow => (Response::from_parts(parts, body), false),
+            RuleAction::Block => (block_page(), true),
+        }
+    }
+}
+
+/// How a `JsonPredicate` compares the selected nodes with its value
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub enum JsonOperator {
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    /// a string holding the value, or an array holding an equal element
+    #[serde(rename = "contains")]
+    Contains,
+    /// the path selects a node, the value is ignored
+    #[serde(rename = "exists")]
+    Exists,
+}
+
+/// A condition on the JSON body of a request, holding when a node selected by
+/// `path` compares with `value` as `op` says. Numbers are compared by value,
+/// other values only for equality and containment.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct JsonPredicate {
+    pub path: JsonPath,
+    pub op: JsonOperator,
+    #[serde(default)]
+    pub value: Value,
+}
+
+impl JsonPredicate {
+    pub fn matches(&self, json: &Value) -> bool {
+        let nodes = self.path.select(json);
+        if self.op == JsonOperator::Exists {
+            return !nodes.is_empty();
+        }
+        nodes.into_iter().any(|node| self.compare(node))
+    }
+
+    fn compare(&self, node: &Value) -> bool {
+        let ordering = match (node.as_f64(), self.value.as_f64()) {
+            (Some(node), Some(value)) => node.partial_cmp(&value),
+            _ => None,
+        };
+        match self.op {
+            JsonOperator::Eq => ordering.map_or(*node == self.value, Ordering::is_eq),
+            JsonOperator::Ne => ordering.map_or(*node != self.value, Ordering::is_ne),
+            JsonOperator::Gt => ordering.is_some_and(Ordering::is_gt),
+            JsonOperator::Ge => ordering.is_some_and(Ordering::is_ge),
+            JsonOperator::Lt => ordering.is_some_and(Ordering::is_lt),
+            JsonOperator::Le => ordering.is_some_and(Ordering::is_le),
+            JsonOperator::Contains => match (node, &self.value) {
+                (Value::String(text), Value::String(part)) => text.contains(part.as_str()),
+                (Value::Array(items), value) => items.contains(value),
+                _ => false,
+            },
+            JsonOperator::Exists => true,
+        }
+    }
+}
+
+/// A rule deciding whether a prompt sent to an LLM endpoint is blocked. The
+/// requests matching the host, path and method of a rule have their prompt
+/// extracted, and the rule applies when the prompt holds one of its keywords
+/// or matches one of its patterns, or when the `PromptClassifier` of the
+/// proxy finds it sensitive if it lists neither.
+///
+/// A rule can also require conditions on the JSON body of the requests, all
+/// of which must hold. A rule with such conditions and no keywords nor
+/// patterns applies on them alone, without asking the classifier.
+///
+/// ```toml
+/// [[rules]]
+/// host = "chatgpt.com"
+/// path = "/backend-api/conversation"
+/// method = "POST"
+/// keywords = ["confidential"]
+/// patterns = ['\bproject-\d+\b']
+/// action = "block"
+///
+/// [[rules]]
+/// host = "api.openai.com"
+/// json = [
+///     { path = "$.model", op = "==", value = "gpt-4" },
+///     { path = "$.temperature", op = ">", value = 1.0 },
+/// ]
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockRule {
+    /// glob pattern of the hosts the rule applies to
+    pub host: Option<String>,
+    /// glob pattern of the paths the rule applies to
+    pub path: Option<String>,
+    /// method of the requests the rule applies to, in any case
+    pub method: Option<String>,
+    /// words any of which the prompt must contain for the rule to apply,
+    /// the classifier deciding when there are none nor patterns
+    pub keywords: Vec<String>,
+    /// regular expressions any of which the prompt may match instead of
+    /// holding a keyword for the rule to apply
+    pub patterns: Vec<PromptPattern>,
+    /// conditions the JSON body of the request must all meet
+    pub json: Vec<JsonPredicate>,
+    /// what to do with the matched requests
+    pub action: RuleAction,
+}
+
+impl BlockRule {
+    /// Whether the rule applies to the requests sent to this endpoint
+    fn matches_endpoint(&self, host: &str, method: &str, path: &str) -> bool {
+        self.host
+            .as_ref()
+            .is_none_or(|pattern| glob_matches(pattern, host))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|pattern| glob_matches(pattern, path))
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|expected| expected.eq_ignore_ascii_case(method))
+    }
+
+    /// Whether the prompt holds one of the keywords of the rule or matches
+    /// one of its patterns, `None` if the rule lists neither
+    fn matches_prompt(&self, prompt: &str) -> Option<bool> {
+        if self.keywords.is_empty() && self.patterns.is_empty() {
+            return None;
+        }
+        Some(
+            contains_keyword(&self.keywords, prompt)
+                || self.patterns.iter().any(|pattern| pattern.is_match(prompt)),
+        )
+    }
+
+    /// Whether the JSON body meets the conditions of the rule. A body which
+    /// is not JSON meets none.
+    fn matches_json(&self, json: Option<&Value>) -> bool {
+        self.json.is_empty()
+            || json.is_some_and(|json| self.json.iter().all(|predicate| predicate.matches(json)))
+    }
+}
+
+/// The file given with `--rules`, in TOML or, with a `.json` extension, in
+/// JSON as `{"rules": [...]}`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockRulesFile {
+    rules: Vec<BlockRule>,
+}
+
+/// The rules deciding which prompts are blocked, evaluated in order, the
+/// first matching rule deciding the action
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockRules {
+    rules: Vec<BlockRule>,
+}
+
+impl BlockRules {
+    pub fn new(rules: Vec<BlockRule>) -> Self {
+        Self { rules }
+    }
+
+    /// The rules used without a rules file: the ChatGPT conversations whose
+    /// prompt the classifier finds sensitive are blocked
+    pub fn builtin() -> Self {
+        Self::new(vec![BlockRule {
+            host: Some("chatgpt.com".to_string()),
+            path: Some("/backend-api/conversation".to_string()),
+            method: Some("POST".to_string()),
+            keywords: Vec::new(),
+            patterns: Vec::new(),
+            json: Vec::new(),
+            action: RuleAction::Block,
+        }])
+    }
+
+    /// Load the rules from a TOML file, or a JSON one if its extension is
+    /// `.json`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let invalid =
+            |e: String| Error::ConfigError(format!("invalid rules file {}: {}", path.display(), e));
+        let file: BlockRulesFile = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => {
+                serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?
+            }
+            _ => toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?,
+        };
+        Ok(Self::new(file.rules))
+    }
+
+    /// Whether a rule applies to the requests sent to this endpoint, which
+    /// need their prompt extracted to be checked
+    pub fn watches(&self, host: &str, method: &str, path: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.matches_endpoint(host, method, path))
+    }
+
+    /// The action for a request to this endpoint holding `prompt`, with its
+    /// body parsed when it is JSON. The request is allowed when no rule
+    /// matches. The classifier is only asked, once, when a rule without
+    /// keywords, patterns nor JSON conditions is reached, and its errors are returned.
+    pub async fn check(
+        &self,
+        host: &str,
+        method: &str,
+        path: &str,
+        json: Option<&Value>,
+        prompt: &str,
+        classifier: &dyn PromptClassifier,
+    ) -> Result<RuleAction, Error> {
+        let mut sensitive = None;
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches_endpoint(host, method, path) && rule.matches_json(json))
+        {
+            let matches = match (rule.matches_prompt(prompt), rule.json.is_empty()) {
+                (Some(matches), _) => matches,
+                (None, false) => true,
+                (None, true) => match sensitive {
+                    Some(sensitive) => sensitive,
+                    None => *sensitive.insert(classifier.is_sensitive(prompt).await?),
+                },
+            };
+            if matches {
+                return Ok(rule.action);
+            }
+        }
+        Ok(RuleAction::Allow)
+    }
+}
+
+/// The page sent to the client in place of a blocked exchange
+pub fn block_page() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(
+            "<html><body><h1>Blocked</h1><p>This content was blocked by the proxy.</p></body></html>",
+        ))
+        .expect("Infallible: hardcoded response")
+}
@@ -8,6 +8,20 @@ pub enum Error {
     ServerError(String),
     #[error("an error handling client requests")]
     RequestError(String),
+    #[error("client sent plaintext over the CONNECT tunnel instead of starting a TLS handshake")]
+    PlaintextInTunnel,
+    #[error("invalid configuration: {0}")]
+    ConfigError(String),
+    #[error("could not spoof the target certificate: {0}")]
+    CertificateError(String),
+    #[error("too many certificates signed, not signing one for {0}")]
+    SigningRateExceeded(String),
+    #[error("timed out {0}")]
+    Timeout(String),
+    #[error("the upstream proxy answered the CONNECT to {0} with {1}")]
+    UpstreamProxyRefused(String, hyper::StatusCode),
+    #[error("HTTP/3 interception failed: {0}")]
+    Http3Error(String),
     #[error(transparent)]
     HyperError(#[from] hyper::Error),
     #[error(transparent)]
@@ -20,4 +34,4 @@ pub enum Error {
     OpenSslErrorStack(#[from] openssl::error::ErrorStack),
     #[error(transparent)]
     InvalidUri(#[from] hyper::http::uri::InvalidUri),
-}
\ No newline at end of file
+}
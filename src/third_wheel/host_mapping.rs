@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use regex::{Regex, RegexBuilder};
+
+use super::error::Error;
+
+/// Prefix of the hosts that are regular expressions rather than glob
+/// patterns, e.g. `~^api-[0-9]+\.example\.com$`
+pub const REGEX_PREFIX: &str = "~";
+
+/// Prefix of the mapped addresses that are Unix domain sockets spoken to over
+/// TLS, e.g. `unix:/run/sidecar.sock`
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Prefix of the mapped addresses that are Unix domain sockets spoken to in
+/// plaintext, e.g. `unix+plain:/run/sidecar.sock`
+pub const PLAIN_UNIX_SOCKET_PREFIX: &str = "unix+plain:";
+
+/// Where to connect to reach a host
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+    /// an `address:port` reached over TCP
+    Tcp(String),
+    /// a Unix domain socket, and whether TLS is spoken over it
+    Unix { path: PathBuf, tls: bool },
+}
+
+/// The path of a mapped Unix domain socket, and whether TLS is spoken over it
+fn unix_socket(address: &str) -> Option<(&str, bool)> {
+    address
+        .strip_prefix(PLAIN_UNIX_SOCKET_PREFIX)
+        .map(|path| (path, false))
+        .or_else(|| {
+            address
+                .strip_prefix(UNIX_SOCKET_PREFIX)
+                .map(|path| (path, true))
+        })
+}
+
+/// One host mapping, parsed from `host=address` or `host=address:port`, e.g.
+/// `example.com=127.0.0.1:8443`. Without a port the tunnel's port is kept.
+/// IPv6 addresses with a port are written in brackets, `[::1]:8443`. The
+/// address can also be a Unix domain socket, `unix:/run/sidecar.sock` or
+/// `unix+plain:/run/sidecar.sock` for one not speaking TLS, which has no port.
+/// The host can be a glob pattern, or a regular expression prefixed with `~`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostMappingEntry {
+    /// the host, or glob or regex pattern of hosts, being redirected
+    pub host: String,
+    /// the address to connect to instead
+    pub address: String,
+    /// the port to connect to instead of the one of the tunnel
+    pub port: Option<u16>,
+}
+
+impl HostMappingEntry {
+    /// The address as stored in the host mappings, with its port if it has one
+    pub fn target(&self) -> String {
+        let address = if self.address.contains(':') && unix_socket(&self.address).is_none() {
+            format!("[{}]", self.address)
+        } else {
+            self.address.clone()
+        };
+        match self.port {
+            Some(port) => format!("{}:{}", address, port),
+            None => address,
+        }
+    }
+}
+
+impl FromStr for HostMappingEntry {
+    type Err = Error;
+
+    fn from_str(entry: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            Error::ConfigError(format!("invalid host mapping {}: {}", entry, reason))
+        };
+
+        let (host, target) = entry
+            .split_once('=')
+            .ok_or_else(|| invalid("expected host=address[:port]"))?;
+        let host = host.trim();
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            return Err(invalid("missing or malformed host"));
+        }
+        if let Some(pattern) = host.strip_prefix(REGEX_PREFIX) {
+            host_regex(pattern).map_err(|e| invalid(&e.to_string()))?;
+        }
+        let target = target.trim();
+        let (address, port) = match unix_socket(target) {
+            Some(("", _)) => return Err(invalid("missing socket path")),
+            Some(_) => (target, None),
+            None => split_port(target).map_err(invalid)?,
+        };
+        if address.is_empty() || address.contains(char::is_whitespace) {
+            return Err(invalid("missing or malformed address"));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            address: address.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for HostMappingEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.host, self.target())
+    }
+}
+
+/// Split the optional port from an address. A bare IPv6 address has no port,
+/// it needs brackets to be given one.
+fn split_port(target: &str) -> Result<(&str, Option<u16>), &'static str> {
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| "invalid port");
+    if let Some(bracketed) = target.strip_prefix('[') {
+        let (address, rest) = bracketed.split_once(']').ok_or("unclosed [")?;
+        return match rest {
+            "" => Ok((address, None)),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => Ok((address, Some(parse_port(port)?))),
+                None => Err("unexpected characters after ]"),
+            },
+        };
+    }
+    match target.split_once(':') {
+        Some((address, port)) if !port.contains(':') => Ok((address, Some(parse_port(port)?))),
+        _ => Ok((target, None)),
+    }
+}
+
+/// Find the address a host is mapped to. An exact entry for the host wins,
+/// otherwise the keys are tried as glob patterns where `*` matches any run of
+/// characters and `?` a single one, e.g. `*.staging.example.com`, or as
+/// regular expressions for the keys starting with `~`, e.g.
+/// `~^api-[0-9]+\.example\.com$`. When several patterns match the longest one
+/// is used, being the most specific.
+///
+/// Host names are compared ignoring case.
+pub fn lookup<'a>(mappings: &'a HashMap<String, String>, host: &str) -> Option<&'a str> {
+    most_specific(mappings, host).map(String::as_str)
+}
+
+/// The value of the entry matching `host` the most specifically, as for
+/// `lookup`: the exact entry, otherwise the longest matching glob pattern
+pub(crate) fn most_specific<'a, V>(entries: &'a HashMap<String, V>, host: &str) -> Option<&'a V> {
+    if let Some(value) = entries.get(host) {
+        return Some(value);
+    }
+    entries
+        .iter()
+        .filter(|(pattern, _)| pattern_matches(pattern, host))
+        .max_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| b.cmp(a)))
+        .map(|(_, value)| value)
+}
+
+/// Where to connect to for `host` reached through a tunnel on `port`. A
+/// mapped address without a port keeps the tunnel's port, and a host not
+/// mapped is connected to directly.
+pub(crate) fn target(mappings: &HashMap<String, String>, host: &str, port: &str) -> Target {
+    let target = match lookup(mappings, host) {
+        Some(target) => target,
+        None => return Target::Tcp(format!("{}:{}", host, port)),
+    };
+    if let Some((path, tls)) = unix_socket(target) {
+        return Target::Unix {
+            path: PathBuf::from(path),
+            tls,
+        };
+    }
+    Target::Tcp(match split_port(target) {
+        Ok((_, Some(_))) => target.to_string(),
+        Ok((address, None)) if address.contains(':') => format!("[{}]:{}", address, port),
+        _ => format!("{}:{}", target, port),
+    })
+}
+
+/// Whether the key of an entry is a glob or regex pattern matching `host`
+fn pattern_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix(REGEX_PREFIX) {
+        // Invalid expressions are refused when parsing the entries, those
+        // given directly match nothing
+        Some(pattern) => host_regex(pattern).is_ok_and(|regex| regex.is_match(host)),
+        None => pattern.contains(['*', '?']) && glob_matches(pattern, host),
+    }
+}
+
+/// The regular expression of a host pattern, matching ignoring case
+fn host_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+pub(crate) fn glob_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase().into_bytes();
+    let host = host.to_ascii_lowercase().into_bytes();
+
+    // Match from left to right, backtracking to the last `*` on a mismatch
+    let (mut p, mut h) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while h < host.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == host[h]) {
+            p += 1;
+            h += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            last_star = Some((p, h));
+            p += 1;
+        } else if let Some((star, matched)) = last_star {
+            p = star + 1;
+            h = matched + 1;
+            last_star = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
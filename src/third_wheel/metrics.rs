@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of `ProxyMetrics::signing_time_histogram`, a
+/// last bucket counting the signings slower than all of them
+pub const SIGNING_TIME_BUCKETS: [Duration; 7] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+/// Counters describing what the proxy did, shared by all of its connections.
+/// Get them from `MitmProxy::metrics` before binding the proxy.
+#[derive(Debug, Default)]
+pub struct ProxyMetrics {
+    capped_connections: AtomicU64,
+    forwarded_requests: AtomicU64,
+    processing_time_micros: AtomicU64,
+    sla_violations: AtomicU64,
+    signed_certificates: AtomicU64,
+    signing_time_micros: AtomicU64,
+    longest_signing_micros: AtomicU64,
+    signing_time_buckets: [AtomicU64; SIGNING_TIME_BUCKETS.len() + 1],
+    signing_timeouts: AtomicU64,
+    throttled_signings: AtomicU64,
+    rate_limited_requests: AtomicU64,
+    hsts_hosts: Mutex<HashSet<String>>,
+}
+
+impl ProxyMetrics {
+    /// Number of connections closed because they reached the maximum number
+    /// of requests per connection
+    #[allow(dead_code)]
+    pub fn capped_connections(&self) -> u64 {
+        self.capped_connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_capped_connection(&self) {
+        self.capped_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of requests forwarded to their target
+    #[allow(dead_code)]
+    pub fn forwarded_requests(&self) -> u64 {
+        self.forwarded_requests.load(Ordering::Relaxed)
+    }
+
+    /// Average time requests spent in the proxy before being forwarded, see
+    /// `ProxyTiming`. `None` until a request was forwarded.
+    #[allow(dead_code)]
+    pub fn average_processing_time(&self) -> Option<Duration> {
+        let forwarded_requests = self.forwarded_requests();
+        (forwarded_requests > 0).then(|| {
+            Duration::from_micros(
+                self.processing_time_micros.load(Ordering::Relaxed) / forwarded_requests,
+            )
+        })
+    }
+
+    pub(crate) fn record_forwarded_request(&self, processing_time: Duration) {
+        self.processing_time_micros
+            .fetch_add(processing_time.as_micros() as u64, Ordering::Relaxed);
+        self.forwarded_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of responses that arrived after the latency SLA of their host
+    #[allow(dead_code)]
+    pub fn sla_violations(&self) -> u64 {
+        self.sla_violations.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_sla_violation(&self) {
+        self.sla_violations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of certificates signed for clients, the others being reused
+    /// from the certificate cache
+    #[allow(dead_code)]
+    pub fn signed_certificates(&self) -> u64 {
+        self.signed_certificates.load(Ordering::Relaxed)
+    }
+
+    /// Average time taken to sign a certificate. `None` until one was signed.
+    #[allow(dead_code)]
+    pub fn average_signing_time(&self) -> Option<Duration> {
+        let signed_certificates = self.signed_certificates();
+        (signed_certificates > 0).then(|| {
+            Duration::from_micros(
+                self.signing_time_micros.load(Ordering::Relaxed) / signed_certificates,
+            )
+        })
+    }
+
+    /// Longest time taken to sign a certificate. `None` until one was signed.
+    #[allow(dead_code)]
+    pub fn longest_signing_time(&self) -> Option<Duration> {
+        (self.signed_certificates() > 0)
+            .then(|| Duration::from_micros(self.longest_signing_micros.load(Ordering::Relaxed)))
+    }
+
+    /// How many certificates were signed in the time of each bucket, as the
+    /// upper bound of the bucket with its count, the signings slower than
+    /// all the `SIGNING_TIME_BUCKETS` being counted in a last bucket without
+    /// a bound
+    #[allow(dead_code)]
+    pub fn signing_time_histogram(&self) -> Vec<(Option<Duration>, u64)> {
+        SIGNING_TIME_BUCKETS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain([None])
+            .zip(&self.signing_time_buckets)
+            .map(|(bound, count)| (bound, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub(crate) fn record_signed_certificate(&self, signing_time: Duration) {
+        let signing_micros = signing_time.as_micros() as u64;
+        self.signing_time_micros
+            .fetch_add(signing_micros, Ordering::Relaxed);
+        self.longest_signing_micros
+            .fetch_max(signing_micros, Ordering::Relaxed);
+        let bucket = SIGNING_TIME_BUCKETS
+            .iter()
+            .position(|bound| signing_time <= *bound)
+            .unwrap_or(SIGNING_TIME_BUCKETS.len());
+        self.signing_time_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.signed_certificates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of tunnels closed because their certificate took longer than
+    /// the signing timeout to sign
+    #[allow(dead_code)]
+    pub fn signing_timeouts(&self) -> u64 {
+        self.signing_timeouts.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_signing_timeout(&self) {
+        self.signing_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of tunnels closed because their certificate could not be signed
+    /// within the signing rate
+    #[allow(dead_code)]
+    pub fn throttled_signings(&self) -> u64 {
+        self.throttled_signings.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_throttled_signing(&self) {
+        self.throttled_signings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of requests answered with `429 Too Many Requests` because their
+    /// client exceeded the rate limit
+    #[allow(dead_code)]
+    pub fn rate_limited_requests(&self) -> u64 {
+        self.rate_limited_requests.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_rate_limited_request(&self) {
+        self.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of distinct hosts that answered with `Strict-Transport-Security`,
+    /// whose clients may refuse the spoofed certificates once they saw it
+    #[allow(dead_code)]
+    pub fn hsts_hosts(&self) -> usize {
+        self.hsts_hosts.lock().unwrap().len()
+    }
+
+    pub(crate) fn record_hsts_host(&self, host: &str) {
+        let mut hsts_hosts = self.hsts_hosts.lock().unwrap();
+        if !hsts_hosts.contains(host) {
+            hsts_hosts.insert(host.to_string());
+        }
+    }
+}
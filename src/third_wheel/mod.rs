@@ -1,3 +1,6 @@
 pub mod certificates;
 pub mod error;
-pub mod proxy;
\ No newline at end of file
+pub mod host_mapping;
+pub mod metrics;
+pub mod proxy;
+pub mod tls_profile;
@@ -1,84 +1,409 @@
-use futures::Future;
+use futures::{Future, StreamExt};
 use futures_util::FutureExt;
-use hyper::client::conn::Builder;
-use hyper::server::conn::{AddrStream, Http};
+use hyper::client::conn::{Builder, SendRequest};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE, HOST, PROXY_AUTHORIZATION};
+use hyper::http::uri::Authority;
+use hyper::server::conn::{AddrIncoming, AddrStream, Http};
 use hyper::server::Server;
 use hyper::service::Service;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response};
-use log::error;
-use native_tls::Certificate;
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use log::{error, warn};
+use native_tls::{Certificate, Identity};
+use openssl::hash::MessageDigest;
 use openssl::x509::X509;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWrite;
-use tokio::net::TcpStream;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpSocket;
+use tokio::sync::{oneshot, Notify};
 use tokio_native_tls::{TlsAcceptor, TlsStream};
 use tower::Layer;
 
+mod cert_cache;
+pub(crate) mod compression;
+mod header_order;
+mod host_resolution;
+#[cfg(feature = "http3")]
+mod http3;
+mod http_connect;
 pub mod mitm;
+mod rate_limit;
+mod rewind;
+mod signing_limit;
+mod sni;
+mod socks;
+mod starttls;
+mod tls_parameters;
+mod upstream;
 use super::{
-    certificates::{native_identity, spoof_certificate, CertificateAuthority},
+    certificates::{
+        create_signed_certificate_for_domain, native_identity, spoof_certificate_with_options,
+        CertificateAuthority, SerialStrategy, SpoofOptions, DEFAULT_SPOOFED_CERT_VALIDITY,
+    },
     error::Error,
-    proxy::mitm::{RequestSendingSynchronizer, ThirdWheel},
+    host_mapping,
+    metrics::ProxyMetrics,
+    proxy::cert_cache::CertificateCache,
+    proxy::compression::ForceCompression,
+    proxy::header_order::{HeaderOrderTap, HeaderOrders, RecordHeaderOrder},
+    proxy::host_resolution::{resolve_host, ResolveHost},
+    proxy::http_connect::HttpConnectProxy,
+    proxy::mitm::{
+        CappedService, CaptureSender, CaptureStream, ConnectionState, RequestSendingSynchronizer,
+        ThirdWheel, TimedBody, TlsParameters,
+    },
+    proxy::rate_limit::{RateLimited, RateLimiter},
+    proxy::rewind::Rewind,
+    proxy::signing_limit::{SigningLimiter, SigningPermit},
+    proxy::socks::Socks5Proxy,
+    proxy::starttls::relay_smtp_until_starttls,
+    proxy::tls_parameters::RecordTlsParameters,
+    proxy::upstream::{TargetStream, UpstreamProxy, UpstreamStream},
+    tls_profile::TlsProfile,
+};
+use crate::utilities::{
+    default_redacted_headers, raw_tunnel_entry, strip_bodies, STREAM_CAPTURE_LIMIT,
 };
 
-// TODO: do this without macro hackery
-// The idea of using of a macro here is borrowed from warp after hitting my head against it for some time.
-// We want to be able to return a make service for reuse of code. But the return
-// type is inordinately complex and/or hidden by hyper's module privacy so instead we inline the code twice.
-// either we should replace this with a private function on MitmProxy, or we should do *something else*
-macro_rules! make_service {
-    ($this:ident) => {{
-        let mitm_proxy = $this.clone();
-        make_service_fn(move |conn: &AddrStream| {
-            let client_ip = conn.remote_addr();
-            let mitm_proxy = mitm_proxy.clone();
-
-            async move {
-                Ok::<_, Error>(service_fn(move |mut req: Request<Body>| {
-                    log::info!("Received request to connect: {}", req.uri());
-                    let mut res = Response::new(Body::empty());
-
-                    if req.method() == hyper::Method::CONNECT {
-                        let target = target_host_port_from_connect(&req);
-                        match target {
-                            Ok((host, port)) => {
-                                let mitm_proxy = mitm_proxy.clone();
-                                tokio::task::spawn(async move {
-                                    match hyper::upgrade::on(&mut req).await {
-                                        Ok(upgraded) => {
-                                            if let Err(e) = run_mitm_on_connection(
-                                                upgraded, mitm_proxy, &host, &port, client_ip,
-                                            )
-                                            .await
-                                            {
-                                                error!("Proxy failed: {}", e)
-                                            }
-                                        }
-                                        Err(e) => error!("Failed to upgrade to TLS: {}", e),
-                                    }
-                                });
-                                *res.status_mut() = hyper::StatusCode::OK;
-                            }
+/// A function adjusting the settings of the HTTP server facing the client
+type HttpConfig = Arc<dyn Fn(&mut Http) + Send + Sync>;
+
+/// A function creating the state of a client connection from the host it
+/// tunnels to and the address of the client
+type ConnectionStateFactory = Arc<dyn Fn(&str, SocketAddr) -> ConnectionState + Send + Sync>;
+
+/// A function called with every tunnel opened when only logging connections
+type ConnectionLogger = Arc<dyn Fn(ConnectionInfo) + Send + Sync>;
+
+/// What is known of a tunnel without decrypting it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// host the client asked to connect to
+    pub host: String,
+    /// port the client asked to connect to
+    pub port: String,
+    /// address of the client
+    pub client_ip: SocketAddr,
+    /// server name sent by the client in its TLS ClientHello, if any
+    pub server_name: Option<String>,
+    /// when the tunnel was opened
+    pub timestamp: SystemTime,
+}
+
+/// Credentials for a SOCKS5 proxy, see `MitmProxyBuilder::upstream_socks5`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Socks5Auth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Default number of connections waiting to be accepted, the one of the
+/// standard library
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 128;
+
+/// Tuning of the socket the proxy listens on and of the connections it
+/// accepts, for when bursts of connections get refused with the defaults
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListenerOptions {
+    /// connections waiting to be accepted before new ones are refused
+    pub backlog: u32,
+    /// set `SO_REUSEADDR`, letting the proxy bind again while connections of
+    /// a previous run linger. Set by default on Unix, as the standard library
+    /// does.
+    pub reuse_address: bool,
+    /// set `SO_REUSEPORT`, letting several proxies share the port. Unix only.
+    pub reuse_port: bool,
+    /// set `TCP_NODELAY` on the accepted connections
+    pub nodelay: bool,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self {
+            backlog: DEFAULT_LISTEN_BACKLOG,
+            reuse_address: cfg!(unix),
+            reuse_port: false,
+            nodelay: false,
+        }
+    }
+}
+
+impl ListenerOptions {
+    /// Listen on `addr` with these options, returning the connections to serve
+    pub fn listen(&self, addr: SocketAddr) -> Result<AddrIncoming, Error> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(self.reuse_address)?;
+        #[cfg(unix)]
+        socket.set_reuseport(self.reuse_port)?;
+        #[cfg(not(unix))]
+        if self.reuse_port {
+            return Err(Error::ConfigError(
+                "SO_REUSEPORT is only supported on Unix".to_string(),
+            ));
+        }
+        socket.bind(addr)?;
+
+        let mut incoming = AddrIncoming::from_listener(socket.listen(self.backlog)?)?;
+        incoming.set_nodelay(self.nodelay);
+        Ok(incoming)
+    }
+}
+
+/// Default number of spoofed certificates kept, see
+/// `MitmProxyBuilder::cert_cache_size`
+pub const DEFAULT_CERT_CACHE_SIZE: usize = 1024;
+
+/// How fast certificates may be signed for new hosts, see
+/// `MitmProxyBuilder::certificate_signing_rate`
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SigningRate {
+    /// certificates which may be signed in every `per` period, also the
+    /// number which may be signed at once. 0 disables signing, only the
+    /// certificates already cached are presented.
+    pub certificates: u32,
+    /// period in which `certificates` may be signed
+    pub per: Duration,
+    /// longest time a tunnel waits for its certificate to be signed when the
+    /// rate is exceeded, before it is refused
+    pub max_wait: Duration,
+}
+
+/// Default time given to open connections to finish during a graceful shutdown
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time given to targets to accept a connection
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time given to targets to complete the TLS handshake
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time given to sign the certificate presented to a client
+pub const DEFAULT_SIGNING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Protocols offered to the targets of the intercepted HTTP exchanges, HTTP/2
+/// first
+const HTTP_ALPN_PROTOCOLS: &[&str] = &["h2", "http/1.1"];
+
+/// Hosts connected to at once by `MitmProxy::prewarm_certs`
+const PREWARM_CONCURRENCY: usize = 8;
+
+/// Port of the targets of `CONNECT` requests, and of the hosts given to
+/// `MitmProxy::prewarm_certs`, naming none
+const DEFAULT_TLS_PORT: u16 = 443;
+
+/// How long connecting to a target may take
+#[derive(Clone, Copy, Debug)]
+struct UpstreamTimeouts {
+    connect: Duration,
+    tls_handshake: Duration,
+}
+
+/// Counts the upgraded `CONNECT` tunnels still being served. The server stops
+/// tracking a connection once it is upgraded, so a graceful shutdown waits for
+/// the tunnels through this count.
+#[derive(Clone, Default)]
+struct TunnelTracker {
+    open: Arc<AtomicUsize>,
+    closed: Arc<Notify>,
+}
+
+impl TunnelTracker {
+    /// Count a tunnel as open until the returned guard is dropped
+    fn track(&self) -> TunnelGuard {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        TunnelGuard(self.clone())
+    }
+
+    /// Wait until no tunnel is open
+    async fn drained(&self) {
+        loop {
+            // Created before checking the count so a tunnel closing in
+            // between is not missed
+            let closed = self.closed.notified();
+            if self.open.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+/// An open tunnel, counted by its `TunnelTracker` until dropped
+struct TunnelGuard(TunnelTracker);
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        if self.0.open.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.closed.notify_waiters();
+        }
+    }
+}
+
+/// The service handling the requests of one client connection to the proxy:
+/// each `CONNECT` request is answered with `200 OK` and its upgraded
+/// connection is intercepted by the `MitmProxy`. Plain HTTP requests in
+/// absolute form, such as `GET http://example.com/`, go through the mitm
+/// layer to be sent to their target, other requests are refused with
+/// `400 Bad Request`.
+///
+/// `MitmProxy::bind` serves it for every accepted connection. It can also be
+/// served by another server, as long as it handles upgrades, e.g.
+/// `Http::new().serve_connection(stream, service).with_upgrades()`.
+#[derive(Clone)]
+pub struct ProxyService<T, U>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + Clone
+        + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+    <U as Service<Request<Body>>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    mitm_proxy: MitmProxy<T, U>,
+    client_ip: SocketAddr,
+    /// The connections to the targets of the plain HTTP requests of the
+    /// client, by target
+    plain_http_targets: Arc<Mutex<HashMap<Authority, PlainHttpTarget>>>,
+}
 
-                            Err(e) => {
-                                error!(
-                                    "Bad request: unable to parse host from connect request: {}",
-                                    e
-                                );
-                                *res.status_mut() = hyper::StatusCode::BAD_REQUEST;
+impl<T, U> ProxyService<T, U>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + Clone
+        + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+    <U as Service<Request<Body>>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// The service for a connection from `client_ip`, intercepting the
+    /// tunnels as configured by the builder of `mitm_proxy`
+    pub fn new(mitm_proxy: MitmProxy<T, U>, client_ip: SocketAddr) -> Self {
+        Self {
+            mitm_proxy,
+            client_ip,
+            plain_http_targets: Arc::default(),
+        }
+    }
+}
+
+impl<T, U> Service<Request<Body>> for ProxyService<T, U>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + Clone
+        + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+    <U as Service<Request<Body>>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        log::info!("Received request to connect: {}", req.uri());
+        let mut res = Response::new(Body::empty());
+
+        if req.method() != hyper::Method::CONNECT && req.uri().scheme_str() == Some("http") {
+            let mitm_proxy = self.mitm_proxy.clone();
+            let client_ip = self.client_ip;
+            let targets = self.plain_http_targets.clone();
+            return Box::pin(async move {
+                match intercept_plain_http(req, &mitm_proxy, client_ip, &targets).await {
+                    Ok(response) => Ok(response),
+                    Err(err) => {
+                        error!("Plain HTTP request failed: {}", err);
+                        let mut res = Response::new(Body::from(err.to_string()));
+                        *res.status_mut() = upstream_failure_status(&err);
+                        Ok(res)
+                    }
+                }
+            });
+        }
+
+        if req.method() == hyper::Method::CONNECT {
+            let target = target_host_port_from_connect(&req);
+            match target {
+                Ok((host, port)) => {
+                    let mitm_proxy = self.mitm_proxy.clone();
+                    let client_ip = self.client_ip;
+                    let logical_host =
+                        resolve_host(req.headers(), &mitm_proxy.host_resolution_headers);
+                    // Throttle the new hosts before accepting their tunnel, so
+                    // the client is told to retry rather than failing its
+                    // handshake
+                    let signing_permit = match mitm_proxy
+                        .reserve_signing(logical_host.as_deref().unwrap_or(&host))
+                    {
+                        Ok(signing_permit) => signing_permit,
+                        Err(e) => {
+                            warn!("Refusing the tunnel to {}: {}", host, e);
+                            *res.status_mut() = hyper::StatusCode::SERVICE_UNAVAILABLE;
+                            return Box::pin(futures::future::ready(Ok(res)));
+                        }
+                    };
+                    let tunnel = mitm_proxy.tunnels.track();
+                    tokio::task::spawn(async move {
+                        let _tunnel = tunnel;
+                        match hyper::upgrade::on(&mut req).await {
+                            Ok(upgraded) => {
+                                if let Err(e) = run_mitm_on_connection(
+                                    upgraded,
+                                    mitm_proxy,
+                                    &host,
+                                    &port,
+                                    client_ip,
+                                    logical_host.as_deref(),
+                                    signing_permit,
+                                )
+                                .await
+                                {
+                                    error!("Proxy failed: {}", e)
+                                }
                             }
+                            Err(e) => error!("Failed to upgrade to TLS: {}", e),
                         }
-                    } else {
-                        *res.status_mut() = hyper::StatusCode::BAD_REQUEST;
-                    }
-                    async move { Ok::<_, Error>(res) }
-                }))
+                    });
+                    *res.status_mut() = hyper::StatusCode::OK;
+                }
+
+                Err(e) => {
+                    error!(
+                        "Bad request: unable to parse host from connect request: {}",
+                        e
+                    );
+                    *res.status_mut() = hyper::StatusCode::BAD_REQUEST;
+                }
             }
-        })
-    }};
+        } else {
+            *res.status_mut() = hyper::StatusCode::BAD_REQUEST;
+        }
+        Box::pin(futures::future::ready(Ok(res)))
+    }
 }
 
 /// The main struct of the crate::third_wheel. Start here.
@@ -103,6 +428,43 @@ where
     ca: CertificateAuthority,
     additional_root_certificates: Vec<Certificate>,
     additional_host_mappings: HashMap<String, String>, // TODO: this should be more restrictively typed
+    upstream_client_identity: Option<Identity>,
+    upstream_client_identities: HashMap<String, Identity>,
+    shutdown_timeout: Duration,
+    tunnels: TunnelTracker,
+    plaintext_fallback: bool,
+    client_http_config: Option<HttpConfig>,
+    max_requests_per_connection: Option<usize>,
+    metrics: Arc<ProxyMetrics>,
+    connection_logger: Option<ConnectionLogger>,
+    http1_keep_alive: bool,
+    force_response_compression: bool,
+    verify_hostname: bool,
+    tls_profiles: HashMap<String, TlsProfile>,
+    capture: Option<CaptureSender>,
+    certificate_fallback: bool,
+    latency_sla: HashMap<String, Duration>,
+    connection_state_factory: Option<ConnectionStateFactory>,
+    preserve_header_order: bool,
+    preserve_header_case: bool,
+    listener_options: ListenerOptions,
+    upstream_proxy: Option<UpstreamProxy>,
+    record_bodies: bool,
+    body_preview: Option<usize>,
+    redact_headers: Arc<[HeaderName]>,
+    certificate_cache: Arc<Mutex<CertificateCache>>,
+    signing_limiter: Option<Arc<SigningLimiter>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    passthrough_hosts: Vec<String>,
+    max_redirects: usize,
+    raw_hosts: Vec<String>,
+    copy_extended_key_usages: bool,
+    upstream_timeouts: UpstreamTimeouts,
+    signing_timeout: Duration,
+    spoofed_cert_validity: Duration,
+    host_resolution_headers: Vec<HeaderName>,
+    starttls_smtp_ports: Vec<u16>,
+    spoofed_cert_serial_strategy: SerialStrategy,
 }
 
 /// Builder interface for constructing `MitmProxy`'s
@@ -121,6 +483,43 @@ where
     ca: CertificateAuthority,
     additional_root_certificates: Vec<Certificate>,
     additional_host_mappings: HashMap<String, String>,
+    upstream_client_identity: Option<Identity>,
+    upstream_client_identities: HashMap<String, Identity>,
+    shutdown_timeout: Duration,
+    plaintext_fallback: bool,
+    client_http_config: Option<HttpConfig>,
+    max_requests_per_connection: Option<usize>,
+    metrics: Arc<ProxyMetrics>,
+    connection_logger: Option<ConnectionLogger>,
+    http1_keep_alive: bool,
+    force_response_compression: bool,
+    verify_hostname: bool,
+    tls_profiles: HashMap<String, TlsProfile>,
+    capture: Option<CaptureSender>,
+    certificate_fallback: bool,
+    latency_sla: HashMap<String, Duration>,
+    connection_state_factory: Option<ConnectionStateFactory>,
+    preserve_header_order: bool,
+    preserve_header_case: bool,
+    listener_options: ListenerOptions,
+    upstream_proxy: Option<UpstreamProxy>,
+    record_bodies: bool,
+    body_preview: Option<usize>,
+    redact_headers: Vec<HeaderName>,
+    cert_cache_size: usize,
+    signing_rate: Option<SigningRate>,
+    rate_limit: Option<(u32, Duration)>,
+    passthrough_hosts: Vec<String>,
+    max_redirects: usize,
+    raw_hosts: Vec<String>,
+    copy_extended_key_usages: bool,
+    connect_timeout: Duration,
+    tls_handshake_timeout: Duration,
+    signing_timeout: Duration,
+    spoofed_cert_validity: Duration,
+    host_resolution_headers: Vec<HeaderName>,
+    starttls_smtp_ports: Vec<u16>,
+    spoofed_cert_serial_strategy: SerialStrategy,
 }
 
 // impl MitmProxyBuilder
@@ -141,6 +540,50 @@ where
             ca: self.ca,
             additional_root_certificates: self.additional_root_certificates,
             additional_host_mappings: self.additional_host_mappings,
+            upstream_client_identity: self.upstream_client_identity,
+            upstream_client_identities: self.upstream_client_identities,
+            shutdown_timeout: self.shutdown_timeout,
+            tunnels: TunnelTracker::default(),
+            plaintext_fallback: self.plaintext_fallback,
+            client_http_config: self.client_http_config,
+            max_requests_per_connection: self.max_requests_per_connection,
+            metrics: self.metrics,
+            connection_logger: self.connection_logger,
+            http1_keep_alive: self.http1_keep_alive,
+            force_response_compression: self.force_response_compression,
+            verify_hostname: self.verify_hostname,
+            tls_profiles: self.tls_profiles,
+            capture: self.capture,
+            certificate_fallback: self.certificate_fallback,
+            latency_sla: self.latency_sla,
+            connection_state_factory: self.connection_state_factory,
+            preserve_header_order: self.preserve_header_order,
+            preserve_header_case: self.preserve_header_case,
+            listener_options: self.listener_options,
+            upstream_proxy: self.upstream_proxy,
+            record_bodies: self.record_bodies,
+            body_preview: self.body_preview,
+            redact_headers: self.redact_headers.into(),
+            certificate_cache: Arc::new(Mutex::new(CertificateCache::new(self.cert_cache_size))),
+            signing_limiter: self
+                .signing_rate
+                .map(|signing_rate| Arc::new(SigningLimiter::new(signing_rate))),
+            rate_limiter: self.rate_limit.map(|(per_ip_requests, window)| {
+                Arc::new(RateLimiter::new(per_ip_requests, window))
+            }),
+            passthrough_hosts: self.passthrough_hosts,
+            max_redirects: self.max_redirects,
+            raw_hosts: self.raw_hosts,
+            copy_extended_key_usages: self.copy_extended_key_usages,
+            upstream_timeouts: UpstreamTimeouts {
+                connect: self.connect_timeout,
+                tls_handshake: self.tls_handshake_timeout,
+            },
+            signing_timeout: self.signing_timeout,
+            spoofed_cert_validity: self.spoofed_cert_validity,
+            host_resolution_headers: self.host_resolution_headers,
+            starttls_smtp_ports: self.starttls_smtp_ports,
+            spoofed_cert_serial_strategy: self.spoofed_cert_serial_strategy,
         }
     }
 
@@ -157,6 +600,10 @@ where
     }
 
     /// Add mappings for particular hosts to IP addresses. Useful for testing against local TLS servers.
+    /// Hosts can be glob patterns such as `*.example.com`, see `host_mapping::lookup`,
+    /// and addresses can carry a port, e.g. `127.0.0.1:8443`, to use instead of the tunnel's.
+    /// Unix domain sockets are given as `unix:/path/to.sock`, or `unix+plain:/path/to.sock`
+    /// for a server not speaking TLS, see `host_mapping::HostMappingEntry`.
     #[allow(dead_code)]
     pub fn additional_host_mappings(
         mut self,
@@ -165,6 +612,403 @@ where
         self.additional_host_mappings = additional_host_mappings;
         self
     }
+
+    /// Client certificate to present to target servers that require mutual
+    /// TLS. Used for every host without a more specific identity set with
+    /// `upstream_client_identity_for_host`.
+    #[allow(dead_code)]
+    pub fn upstream_client_identity(mut self, identity: Identity) -> Self {
+        self.upstream_client_identity = Some(identity);
+        self
+    }
+
+    /// Client certificate to present when connecting to a particular host
+    #[allow(dead_code)]
+    pub fn upstream_client_identity_for_host(mut self, host: &str, identity: Identity) -> Self {
+        self.upstream_client_identities
+            .insert(host.to_string(), identity);
+        self
+    }
+
+    /// How long to wait for open connections to finish after a graceful
+    /// shutdown is signalled, see `MitmProxy::bind_with_graceful_shutdown`.
+    /// Defaults to 30 seconds.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// How long to wait for a target, or the SOCKS5 proxy in front of it, to
+    /// accept a connection before giving up on the tunnel with
+    /// `Error::Timeout`. Defaults to 10 seconds.
+    #[allow(dead_code)]
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How long to wait for a target to complete the TLS handshake before
+    /// giving up on the tunnel with `Error::Timeout`. Defaults to 10 seconds.
+    #[allow(dead_code)]
+    pub fn tls_handshake_timeout(mut self, tls_handshake_timeout: Duration) -> Self {
+        self.tls_handshake_timeout = tls_handshake_timeout;
+        self
+    }
+
+    /// How long signing the certificate presented to a client may take
+    /// before its tunnel is closed with `Error::Timeout`. Signing durations
+    /// are reported by `ProxyMetrics::average_signing_time` and
+    /// `ProxyMetrics::signing_time_histogram`. Defaults to 5 seconds.
+    #[allow(dead_code)]
+    pub fn signing_timeout(mut self, signing_timeout: Duration) -> Self {
+        self.signing_timeout = signing_timeout;
+        self
+    }
+
+    /// Treat clients sending plaintext HTTP over a CONNECT tunnel as plain
+    /// HTTP clients instead of rejecting them. Requests are still forwarded
+    /// to the target over TLS.
+    #[allow(dead_code)]
+    pub fn plaintext_fallback(mut self, plaintext_fallback: bool) -> Self {
+        self.plaintext_fallback = plaintext_fallback;
+        self
+    }
+
+    /// Adjust the HTTP server reading the decrypted requests of the client,
+    /// for instance to bound the buffer of the request heads:
+    /// ```ignore
+    /// let mitm_proxy = MitmProxy::builder(mitm, ca)
+    ///     .client_http_config(|http| {
+    ///         http.max_buf_size(64 * 1024);
+    ///     })
+    ///     .build();
+    /// ```
+    #[allow(dead_code)]
+    pub fn client_http_config<F>(mut self, client_http_config: F) -> Self
+    where
+        F: Fn(&mut Http) + Send + Sync + 'static,
+    {
+        self.client_http_config = Some(Arc::new(client_http_config));
+        self
+    }
+
+    /// Close a client connection once it served this many requests, so a
+    /// single tunnel cannot be used to send an unbounded number of requests
+    #[allow(dead_code)]
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Self {
+        self.max_requests_per_connection = Some(max_requests);
+        self
+    }
+
+    /// Whether the certificate of target servers must be issued for the host
+    /// connected to. Disabling it still checks the certificate chain and its
+    /// validity dates, only the name is not compared. Enabled by default.
+    #[allow(dead_code)]
+    pub fn verify_hostname(mut self, verify_hostname: bool) -> Self {
+        self.verify_hostname = verify_hostname;
+        self
+    }
+
+    /// Sign a certificate for the requested host alone when the certificate of
+    /// the target cannot be spoofed, e.g. for alternative names openssl does
+    /// not let us copy. The client then sees none of the other fields of the
+    /// target certificate. Enabled by default; when disabled the connection
+    /// is closed instead.
+    #[allow(dead_code)]
+    pub fn certificate_fallback(mut self, certificate_fallback: bool) -> Self {
+        self.certificate_fallback = certificate_fallback;
+        self
+    }
+
+    /// Record the request headers of HAR entries in the order the client sent
+    /// them. A `HeaderMap` keeps the headers sharing a name together, so
+    /// without it repeated headers lose their place among the others.
+    /// Disabled by default as it parses the request heads a second time.
+    #[allow(dead_code)]
+    pub fn preserve_header_order(mut self, preserve_header_order: bool) -> Self {
+        self.preserve_header_order = preserve_header_order;
+        self
+    }
+
+    /// Forward the header names of HTTP/1.1 requests in the case the client
+    /// sent them instead of lowercasing them, for targets which are picky
+    /// about it. Disabled by default as it keeps a map of the original names
+    /// for every request.
+    #[allow(dead_code)]
+    pub fn preserve_header_case(mut self, preserve_header_case: bool) -> Self {
+        self.preserve_header_case = preserve_header_case;
+        self
+    }
+
+    /// Number of spoofed certificates kept to be presented again to the
+    /// clients connecting to the same host, instead of signing a new one for
+    /// every tunnel. The least recently used is dropped when the cache is
+    /// full, and 0 disables it. Defaults to `DEFAULT_CERT_CACHE_SIZE`.
+    #[allow(dead_code)]
+    pub fn cert_cache_size(mut self, cert_cache_size: usize) -> Self {
+        self.cert_cache_size = cert_cache_size;
+        self
+    }
+
+    /// Limit how fast certificates are signed, so clients tunnelling to many
+    /// distinct hosts cannot keep the proxy busy signing. The certificates
+    /// found in the cache are not limited. Tunnels beyond the rate wait for
+    /// their turn up to `SigningRate::max_wait`, after which their `CONNECT`
+    /// is answered with a `503 Service Unavailable`, and they are counted in
+    /// `ProxyMetrics::throttled_signings`. Unlimited by default.
+    #[allow(dead_code)]
+    pub fn certificate_signing_rate(mut self, signing_rate: SigningRate) -> Self {
+        self.signing_rate = Some(signing_rate);
+        self
+    }
+
+    /// Limit each client IP address to `per_ip_requests` requests within any
+    /// `window`, so a proxy reachable from a network cannot be flooded by one
+    /// client. Requests beyond the limit are answered with
+    /// `429 Too Many Requests` and a `Retry-After` without reaching the mitm
+    /// layer, and counted in `ProxyMetrics::rate_limited_requests`. Unlimited
+    /// by default.
+    #[allow(dead_code)]
+    pub fn rate_limit(mut self, per_ip_requests: u32, window: Duration) -> Self {
+        self.rate_limit = Some((per_ip_requests, window));
+        self
+    }
+
+    /// Tune the socket the proxy listens on, see `ListenerOptions`
+    #[allow(dead_code)]
+    pub fn listener_options(mut self, listener_options: ListenerOptions) -> Self {
+        self.listener_options = listener_options;
+        self
+    }
+
+    /// Connect to the targets through the SOCKS5 proxy at `addr`, e.g. Tor or
+    /// a jump host, authenticating with `auth` if the proxy asks for it. The
+    /// proxy resolves the host names. Replaces an `upstream_proxy`. Targets
+    /// mapped to Unix domain sockets are still connected to directly.
+    #[allow(dead_code)]
+    pub fn upstream_socks5(mut self, addr: SocketAddr, auth: Option<Socks5Auth>) -> Self {
+        self.upstream_proxy = Some(UpstreamProxy::Socks5(Socks5Proxy { addr, auth }));
+        self
+    }
+
+    /// Connect to the targets through tunnels opened with `CONNECT` by the
+    /// HTTP proxy at `uri`, e.g. a corporate proxy, sending the credentials of
+    /// the URI as basic `Proxy-Authorization` if it has some. Replaces an
+    /// `upstream_socks5` proxy. Targets mapped to Unix domain sockets are
+    /// still connected to directly.
+    #[allow(dead_code)]
+    pub fn upstream_proxy(mut self, uri: Uri) -> Self {
+        self.upstream_proxy = Some(UpstreamProxy::HttpConnect(HttpConnectProxy { uri }));
+        self
+    }
+
+    /// TLS settings for connecting to the hosts matching `host`, which can be
+    /// a glob pattern as for `additional_host_mappings`. When several
+    /// profiles match a host the most specific one is used, and hosts
+    /// matching none use the proxy-wide settings.
+    #[allow(dead_code)]
+    pub fn host_tls_profile(mut self, host: &str, profile: TlsProfile) -> Self {
+        self.tls_profiles.insert(host.to_string(), profile);
+        self
+    }
+
+    /// Capture every exchange forwarded to a target as a HAR entry, for
+    /// embedders to consume instead of writing a file:
+    /// ```ignore
+    /// let (builder, mut entries) = MitmProxy::builder(mitm, ca).capture_stream();
+    /// tokio::spawn(builder.build().bind(addr).1);
+    /// while let Some(entry) = entries.next().await { /* ... */ }
+    /// ```
+    /// Requests answered by the mitm layer without calling `ThirdWheel` are
+    /// not captured. Calling it again replaces the previous stream.
+    #[allow(dead_code)]
+    pub fn capture_stream(mut self) -> (Self, CaptureStream) {
+        let (sender, capture_stream) = CaptureStream::new();
+        self.capture = Some(sender);
+        (self, capture_stream)
+    }
+
+    /// Record the decrypted bodies in the entries of the capture stream. Off
+    /// by default as they may hold personal data, the entries only hold the
+    /// metadata of the exchanges and the sizes of their bodies then.
+    #[allow(dead_code)]
+    pub fn record_bodies(mut self, record_bodies: bool) -> Self {
+        self.record_bodies = record_bodies;
+        self
+    }
+
+    /// Only read the first `limit` bytes of each body for the entries of the
+    /// capture stream, streaming the rest on without buffering it. The
+    /// recorded bodies are marked with a `"preview"` comment when there was
+    /// more to them, whose whole size is then unknown.
+    #[allow(dead_code)]
+    pub fn body_preview(mut self, limit: usize) -> Self {
+        self.body_preview = Some(limit);
+        self
+    }
+
+    /// Replace the values of the `names` headers in the entries of the
+    /// capture stream by `[REDACTED]`, keeping their names. `Authorization`,
+    /// `Cookie`, `Set-Cookie` and `Proxy-Authorization` are redacted by
+    /// default, an empty list records every header as it is.
+    #[allow(dead_code)]
+    pub fn redact_headers(mut self, names: Vec<HeaderName>) -> Self {
+        self.redact_headers = names;
+        self
+    }
+
+    /// Follow up to `max_redirects` redirects of the targets to themselves
+    /// before answering the client, which gets the final response. Every hop
+    /// is captured as its own entry, linked to the next by its `redirect_url`.
+    /// Redirects are relayed to the client by default.
+    #[allow(dead_code)]
+    pub fn follow_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Longest time the targets may take to answer, from sending a request to
+    /// receiving the response headers, for the hosts matching each pattern as
+    /// for `additional_host_mappings`. Slower responses are logged, counted
+    /// in `ProxyMetrics::sla_violations` and carry a `SlaViolation` in their
+    /// extensions, noted in the comment of their captured HAR entry.
+    #[allow(dead_code)]
+    pub fn latency_sla(mut self, latency_sla: HashMap<String, Duration>) -> Self {
+        self.latency_sla = latency_sla;
+        self
+    }
+
+    /// Create a state for every client connection, shared by its requests and
+    /// read in the mitm closure with `ThirdWheel::get_state`:
+    /// ```ignore
+    /// struct Session { user: String }
+    ///
+    /// let mitm_proxy = MitmProxy::builder(mitm, ca)
+    ///     .connection_state_factory(|host, client_ip| {
+    ///         Arc::new(Session { user: lookup_user(client_ip) })
+    ///     })
+    ///     .build();
+    /// // in the closure
+    /// let session = third_wheel.get_state::<Session>();
+    /// ```
+    #[allow(dead_code)]
+    pub fn connection_state_factory<F>(mut self, connection_state_factory: F) -> Self
+    where
+        F: Fn(&str, SocketAddr) -> ConnectionState + Send + Sync + 'static,
+    {
+        self.connection_state_factory = Some(Arc::new(connection_state_factory));
+        self
+    }
+
+    /// Whether HTTP/1.1 clients may send several requests on a tunnel,
+    /// pipelined or one after the other, as it sets the keep-alive of the
+    /// server reading them. Pipelined requests are always answered in order.
+    /// When disabled the tunnel is closed after the first response and any
+    /// request sent after it is dropped. Enabled by default.
+    #[allow(dead_code)]
+    pub fn http1_keep_alive(mut self, http1_keep_alive: bool) -> Self {
+        self.http1_keep_alive = http1_keep_alive;
+        self
+    }
+
+    /// Gzip the body of every response sent to clients and mark it with
+    /// `Content-Encoding: gzip`, even when the client did not advertise gzip
+    /// support. Responses already encoded are left as they are. Meant for
+    /// testing how clients cope with unexpected encodings.
+    #[allow(dead_code)]
+    pub fn force_response_compression(mut self, force_response_compression: bool) -> Self {
+        self.force_response_compression = force_response_compression;
+        self
+    }
+
+    /// Hosts whose tunnels are relayed to the target as they are instead of
+    /// being intercepted, for the clients pinning certificates or the hosts
+    /// which should not be decrypted, e.g. banks. Hosts are matched ignoring
+    /// case, either exactly or as glob patterns such as `*.example.com`. No
+    /// certificate is spoofed for them and nothing of their exchanges is
+    /// captured, so they produce no HAR entries.
+    #[allow(dead_code)]
+    pub fn passthrough_hosts(mut self, passthrough_hosts: Vec<String>) -> Self {
+        self.passthrough_hosts = passthrough_hosts;
+        self
+    }
+
+    /// Hosts whose tunnels carry something else than HTTP, e.g. MQTT, matched
+    /// as for `passthrough_hosts`. Their tunnels are decrypted but not parsed,
+    /// the bytes are relayed as they are and captured in base64 as one HAR
+    /// entry for the CONNECT request once the tunnel is closed: the bytes of
+    /// the client as its request body and the ones of the target as its
+    /// response body. Bodies are left out unless `record_bodies` is set.
+    #[allow(dead_code)]
+    pub fn raw_hosts(mut self, raw_hosts: Vec<String>) -> Self {
+        self.raw_hosts = raw_hosts;
+        self
+    }
+
+    /// Copy the extended key usages of the target certificates other than
+    /// `serverAuth` to the spoofed ones, e.g. `clientAuth` for targets whose
+    /// clients check it. The spoofed certificates always have the key usages
+    /// of a server certificate, see `spoof_certificate`. Off by default.
+    #[allow(dead_code)]
+    pub fn copy_extended_key_usages(mut self, copy_extended_key_usages: bool) -> Self {
+        self.copy_extended_key_usages = copy_extended_key_usages;
+        self
+    }
+
+    /// How long the spoofed certificates are valid for, their validity
+    /// starting an hour ago to allow for clients whose clock is behind.
+    /// Defaults to 397 days, Apple platforms refusing server certificates
+    /// valid for more than 398 days.
+    #[allow(dead_code)]
+    pub fn spoofed_cert_validity(mut self, spoofed_cert_validity: Duration) -> Self {
+        self.spoofed_cert_validity = spoofed_cert_validity;
+        self
+    }
+
+    /// How the serial numbers of the spoofed certificates are chosen, see
+    /// `SerialStrategy`. Random by default.
+    #[allow(dead_code)]
+    pub fn spoofed_cert_serial_strategy(mut self, serial_strategy: SerialStrategy) -> Self {
+        self.spoofed_cert_serial_strategy = serial_strategy;
+        self
+    }
+
+    /// Headers carrying the host requests are really for, e.g.
+    /// `X-Forwarded-Host` when the proxy is reached through another one, in
+    /// the order they are looked at. The first one a request has names its
+    /// host in the URL of its HAR entry, see `LogicalHost`, the CONNECT
+    /// authority of the tunnel being used for requests with none of them. The
+    /// first one the CONNECT request has names the certificate spoofed for
+    /// clients sending no server name. None by default, the `Host` header
+    /// being used.
+    #[allow(dead_code)]
+    pub fn host_resolution_headers(mut self, host_resolution_headers: Vec<HeaderName>) -> Self {
+        self.host_resolution_headers = host_resolution_headers;
+        self
+    }
+
+    /// Ports of the tunnels carrying SMTP upgraded to TLS with `STARTTLS`,
+    /// e.g. 25 and 587. Their plaintext session is relayed as it is until the
+    /// target accepts the `STARTTLS` of the client, the TLS session following
+    /// it being intercepted with a spoofed certificate. Its decrypted bytes
+    /// are relayed and captured as for `raw_hosts`. None by default.
+    #[allow(dead_code)]
+    pub fn starttls_smtp_ports(mut self, starttls_smtp_ports: Vec<u16>) -> Self {
+        self.starttls_smtp_ports = starttls_smtp_ports;
+        self
+    }
+
+    /// Stop intercepting tunnels, only passing `log_connection` what is known
+    /// of each one before relaying its bytes untouched to the target. Nothing
+    /// is decrypted and no certificate is spoofed.
+    #[allow(dead_code)]
+    pub fn log_connections_only<F>(mut self, log_connection: F) -> Self
+    where
+        F: Fn(ConnectionInfo) + Send + Sync + 'static,
+    {
+        self.connection_logger = Some(Arc::new(log_connection));
+        self
+    }
 }
 
 // impl MitmProxy
@@ -185,18 +1029,231 @@ where
             ca,
             additional_root_certificates: Vec::new(),
             additional_host_mappings: HashMap::new(),
+            upstream_client_identity: None,
+            upstream_client_identities: HashMap::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            plaintext_fallback: false,
+            client_http_config: None,
+            max_requests_per_connection: None,
+            metrics: Arc::new(ProxyMetrics::default()),
+            connection_logger: None,
+            http1_keep_alive: true,
+            force_response_compression: false,
+            verify_hostname: true,
+            tls_profiles: HashMap::new(),
+            capture: None,
+            certificate_fallback: true,
+            latency_sla: HashMap::new(),
+            connection_state_factory: None,
+            preserve_header_order: false,
+            preserve_header_case: false,
+            listener_options: ListenerOptions::default(),
+            upstream_proxy: None,
+            record_bodies: false,
+            body_preview: None,
+            redact_headers: default_redacted_headers(),
+            cert_cache_size: DEFAULT_CERT_CACHE_SIZE,
+            signing_rate: None,
+            rate_limit: None,
+            passthrough_hosts: Vec::new(),
+            max_redirects: 0,
+            raw_hosts: Vec::new(),
+            copy_extended_key_usages: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            tls_handshake_timeout: DEFAULT_TLS_HANDSHAKE_TIMEOUT,
+            signing_timeout: DEFAULT_SIGNING_TIMEOUT,
+            spoofed_cert_validity: DEFAULT_SPOOFED_CERT_VALIDITY,
+            host_resolution_headers: Vec::new(),
+            starttls_smtp_ports: Vec::new(),
+            spoofed_cert_serial_strategy: SerialStrategy::default(),
         }
     }
 
+    /// The counters of the proxy, shared with the running server
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> Arc<ProxyMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Sign the spoofed certificates of `hosts` before the proxy is bound, so
+    /// their first tunnels do not wait for it. Each host, as `host` or
+    /// `host:port` with 443 as the default port, is connected to for the
+    /// certificate it presents, a few hosts at a time.
+    ///
+    /// # Returns
+    /// The hosts whose certificate could not be spoofed, with the reason.
+    #[allow(dead_code)]
+    pub async fn prewarm_certs(&self, hosts: &[&str]) -> Vec<(String, Error)> {
+        futures::stream::iter(hosts)
+            .map(|host| async move {
+                self.prewarm_cert(host)
+                    .await
+                    .err()
+                    .map(|e| (host.to_string(), e))
+            })
+            .buffer_unordered(PREWARM_CONCURRENCY)
+            .filter_map(futures::future::ready)
+            .collect()
+            .await
+    }
+
+    /// Spoof the certificate `host` presents into the certificate cache
+    async fn prewarm_cert(&self, host: &str) -> Result<(), Error> {
+        let authority: Authority = host.parse()?;
+        let port = authority.port_u16().unwrap_or(DEFAULT_TLS_PORT).to_string();
+        let host = authority.host();
+        let (target_stream, target_certificate, _) = connect_to_target_with_tls(
+            host,
+            &port,
+            host,
+            &self.additional_host_mappings,
+            &self.additional_root_certificates,
+            target_tls_profile(self, host),
+            HTTP_ALPN_PROTOCOLS,
+            self.upstream_proxy.as_ref(),
+            self.upstream_timeouts,
+        )
+        .await?;
+        let alpn_protocol = target_stream.alpn_protocol();
+        client_acceptor(
+            self,
+            host,
+            host,
+            target_certificate.as_ref(),
+            alpn_protocol.as_deref(),
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Reserve the signing of a certificate for a tunnel to `host` before it
+    /// is accepted, so a tunnel beyond the signing rate is refused with a
+    /// `503 Service Unavailable` rather than closed during its handshake.
+    /// Nothing is reserved for the hosts passed through or already holding a
+    /// certificate in the cache, their tunnels reserving one later if the
+    /// cached one does not fit their target.
+    fn reserve_signing(&self, host: &str) -> Result<Option<SigningPermit>, Error> {
+        if self.signing_limiter.is_none() {
+            return Ok(None);
+        }
+        let passthrough_host = self.connection_logger.is_some()
+            || self
+                .passthrough_hosts
+                .iter()
+                .any(|pattern| host_mapping::glob_matches(pattern, host));
+        if passthrough_host || self.certificate_cache.lock().unwrap().holds_domain(host) {
+            return Ok(None);
+        }
+        self.throttle_signing(host)
+    }
+
+    /// Reserve the signing of a certificate for `domain` within the signing
+    /// rate, failing with `Error::SigningRateExceeded` beyond it
+    fn throttle_signing(&self, domain: &str) -> Result<Option<SigningPermit>, Error> {
+        let Some(signing_limiter) = &self.signing_limiter else {
+            return Ok(None);
+        };
+        match signing_limiter.reserve() {
+            Some(signing_permit) => Ok(Some(signing_permit)),
+            None => {
+                self.metrics.record_throttled_signing();
+                Err(Error::SigningRateExceeded(domain.to_string()))
+            }
+        }
+    }
+
+    /// The service handling the connections accepted by the server, one
+    /// `ProxyService` per client
+    fn make_service(
+        &self,
+    ) -> impl for<'a> Service<
+        &'a AddrStream,
+        Response = ProxyService<T, U>,
+        Error = Error,
+        Future = futures::future::Ready<Result<ProxyService<T, U>, Error>>,
+    > {
+        let mitm_proxy = self.clone();
+        make_service_fn(move |conn: &AddrStream| {
+            futures::future::ready(Ok(ProxyService::new(
+                mitm_proxy.clone(),
+                conn.remote_addr(),
+            )))
+        })
+    }
+
+    /// Listen on `addr` with the listener options, panicking if it cannot be
+    /// bound as `Server::bind` does
+    fn incoming(&self, addr: SocketAddr) -> AddrIncoming {
+        self.listener_options
+            .listen(addr)
+            .unwrap_or_else(|e| panic!("error binding to {}: {}", addr, e))
+    }
+
     /// Bind to a socket address. Returns the address actually bound to, and the
     /// future to be executed that will run the server.
+    #[allow(dead_code)]
     pub fn bind(self, addr: SocketAddr) -> (SocketAddr, impl Future<Output = Result<(), Error>>) {
-        let server = Server::bind(&addr).serve(make_service!(self));
+        let server = Server::builder(self.incoming(addr)).serve(self.make_service());
         (
             server.local_addr(),
             server.map(|result| result.map_err(|e| e.into())),
         )
     }
+
+    /// Bind to a socket address like `bind`, stopping the server once `signal`
+    /// completes. New connections are refused after the signal and open ones,
+    /// the `CONNECT` tunnels included, are given the shutdown timeout to drain,
+    /// after which the returned future resolves anyway and the connections
+    /// still open are dropped with the runtime.
+    pub fn bind_with_graceful_shutdown<F>(
+        self,
+        addr: SocketAddr,
+        signal: F,
+    ) -> (SocketAddr, impl Future<Output = Result<(), Error>>)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown_timeout = self.shutdown_timeout;
+        let tunnels = self.tunnels.clone();
+        let (signalled_sender, signalled_receiver) = oneshot::channel();
+
+        let server = Server::builder(self.incoming(addr)).serve(self.make_service());
+        let local_addr = server.local_addr();
+        let server = server.with_graceful_shutdown(async move {
+            signal.await;
+            let _ = signalled_sender.send(());
+        });
+
+        // Only starts counting down once the signal was received
+        let drain_timeout = async move {
+            match signalled_receiver.await {
+                Ok(()) => tokio::time::sleep(shutdown_timeout).await,
+                Err(_) => futures::future::pending().await,
+            }
+        };
+
+        // The upgraded tunnels outlive the connections the server waits for
+        let drained = async move {
+            server.await?;
+            tunnels.drained().await;
+            Ok::<_, hyper::Error>(())
+        };
+
+        let server = async move {
+            tokio::select! {
+                result = drained => result.map_err(|e| e.into()),
+                _ = drain_timeout => {
+                    warn!(
+                        "Connections still open {:?} after shutdown, closing them",
+                        shutdown_timeout
+                    );
+                    Ok(())
+                }
+            }
+        };
+        (local_addr, server)
+    }
 }
 
 async fn run_mitm_on_connection<S, T, U>(
@@ -205,6 +1262,8 @@ async fn run_mitm_on_connection<S, T, U>(
     host: &str,
     port: &str,
     client_ip: SocketAddr, // Accept the client IP here
+    logical_host: Option<&str>,
+    signing_permit: Option<SigningPermit>,
 ) -> Result<(), Error>
 where
     T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
@@ -217,21 +1276,172 @@ where
     U::Error: std::error::Error + Send + Sync + 'static,
     <U as Service<Request<Body>>>::Future: Send,
 {
-    let (target_stream, target_certificate) = connect_to_target_with_tls(
+    let passthrough_host = mitm_proxy
+        .passthrough_hosts
+        .iter()
+        .any(|pattern| host_mapping::glob_matches(pattern, host));
+    if passthrough_host {
+        return passthrough(
+            upgraded,
+            host,
+            port,
+            &mitm_proxy.additional_host_mappings,
+            mitm_proxy.upstream_proxy.as_ref(),
+        )
+        .await;
+    }
+
+    // SMTP clients wait for the greeting of the target, nothing can be peeked
+    // before the plaintext session is relayed
+    let starttls_smtp = port
+        .parse()
+        .is_ok_and(|port| mitm_proxy.starttls_smtp_ports.contains(&port));
+    if starttls_smtp {
+        return intercept_smtp_starttls(
+            upgraded,
+            &mitm_proxy,
+            host,
+            port,
+            client_ip,
+            signing_permit,
+        )
+        .await;
+    }
+
+    // Peek at the first bytes to give a clear error if the client is not
+    // speaking TLS, rather than failing somewhere in the handshake
+    let (upgraded, is_tls) = match Rewind::peek_is_tls(upgraded).await? {
+        Some(peeked) => peeked,
+        None => return Ok(()),
+    };
+
+    // The name the client expects a certificate for, which can differ from
+    // the CONNECT target when several virtual hosts share an address
+    let server_name = is_tls
+        .then(|| sni::server_name(upgraded.prefix()))
+        .flatten();
+
+    if let Some(log_connection) = &mitm_proxy.connection_logger {
+        log_connection(ConnectionInfo {
+            host: host.to_string(),
+            port: port.to_string(),
+            client_ip,
+            server_name,
+            timestamp: SystemTime::now(),
+        });
+        return passthrough(
+            upgraded,
+            host,
+            port,
+            &mitm_proxy.additional_host_mappings,
+            mitm_proxy.upstream_proxy.as_ref(),
+        )
+        .await;
+    }
+    if !is_tls && !mitm_proxy.plaintext_fallback {
+        reject_plaintext(upgraded).await;
+        return Err(Error::PlaintextInTunnel);
+    }
+
+    // Tunnels of the hosts not speaking HTTP are relayed without parsing them,
+    // so the target must not be offered HTTP/2
+    let raw_host = mitm_proxy
+        .raw_hosts
+        .iter()
+        .any(|pattern| host_mapping::glob_matches(pattern, host));
+    let alpn_protocols = if raw_host { &[] } else { HTTP_ALPN_PROTOCOLS };
+
+    let tls_profile = target_tls_profile(&mitm_proxy, host);
+    // Ask the target for the certificate of the server name the client sent,
+    // so the spoofed certificate matches what the client checks
+    let connected = connect_to_target_with_tls(
         host,
         port,
-        mitm_proxy.additional_host_mappings,
-        mitm_proxy.additional_root_certificates,
+        server_name.as_deref().unwrap_or(host),
+        &mitm_proxy.additional_host_mappings,
+        &mitm_proxy.additional_root_certificates,
+        tls_profile,
+        alpn_protocols,
+        mitm_proxy.upstream_proxy.as_ref(),
+        mitm_proxy.upstream_timeouts,
     )
-    .await?;
-    let certificate = spoof_certificate(&target_certificate, &mitm_proxy.ca)?;
-    let identity = native_identity(&certificate, &mitm_proxy.ca.key)?;
-    let client = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
-    let client_stream = client.accept(upgraded).await?;
+    .await;
+    // The name of the spoofed certificate, the host of the CONNECT request
+    // being the last resort
+    let domain = server_name.as_deref().or(logical_host).unwrap_or(host);
+    let (target_stream, target_certificate, server_ip) = match connected {
+        Ok(connected) => connected,
+        Err(err) => {
+            if let Err(e) = answer_upstream_failure(
+                upgraded,
+                is_tls,
+                &mitm_proxy,
+                host,
+                domain,
+                &err,
+                signing_permit,
+            )
+            .await
+            {
+                error!("Failed to answer the client of {}: {}", host, e);
+            }
+            return Err(err);
+        }
+    };
 
-    // Build a connection in TLS with the proxy server
+    // Relay the tunnels of the hosts not speaking HTTP without parsing them
+    if raw_host {
+        let connection = ConnectionInfo {
+            host: host.to_string(),
+            port: port.to_string(),
+            client_ip,
+            server_name: server_name.clone(),
+            timestamp: SystemTime::now(),
+        };
+        let capture = mitm_proxy.capture.as_ref();
+        let record_bodies = mitm_proxy.record_bodies;
+        if !is_tls {
+            return relay_raw(
+                upgraded,
+                target_stream,
+                capture,
+                record_bodies,
+                connection,
+                server_ip,
+            )
+            .await;
+        }
+        let client = client_acceptor(
+            &mitm_proxy,
+            host,
+            domain,
+            target_certificate.as_ref(),
+            None,
+            signing_permit,
+        )
+        .await?;
+        let client_stream = client.accept(upgraded).await?;
+        return relay_raw(
+            client_stream,
+            target_stream,
+            capture,
+            record_bodies,
+            connection,
+            server_ip,
+        )
+        .await;
+    }
+
+    // Build a connection in TLS with the proxy server, keeping the header case
+    // of the requests if asked to, in HTTP/2 if the target chose it. hyper
+    // disables server push in the settings it sends, so targets cannot push
+    // streams the client never asked for past the proxy.
+    let alpn_protocol = target_stream.alpn_protocol();
+    let http2 = target_stream.is_http2();
     let (request_sender, connection) = Builder::new()
-        .handshake::<TlsStream<TcpStream>, Body>(target_stream)
+        .http1_preserve_header_case(mitm_proxy.preserve_header_case)
+        .http2_only(http2)
+        .handshake::<TargetStream, TimedBody>(target_stream)
         .await?;
 
     // Setup the TLS connection between client and proxy
@@ -241,43 +1451,830 @@ where
     let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
 
     // Use request_sender and receiver to use the channel
-    tokio::spawn(async move {
-        RequestSendingSynchronizer::new(request_sender, receiver)
-            .run()
-            .await
-    });
+    let metrics = mitm_proxy.metrics.clone();
+    let latency_sla = host_mapping::most_specific(&mitm_proxy.latency_sla, host).copied();
+    let mut synchronizer = RequestSendingSynchronizer::new(
+        request_sender,
+        receiver,
+        metrics,
+        host,
+        latency_sla,
+        http2,
+    );
+    tokio::spawn(async move { synchronizer.run().await });
 
     // Create the service proxy with the sender defined from the previous opened channel
-    let third_wheel = ThirdWheel::new(sender, client_ip);
+    let state = mitm_proxy
+        .connection_state_factory
+        .as_ref()
+        .map(|connection_state_factory| connection_state_factory(host, client_ip));
+    let target_port = port
+        .parse()
+        .map_err(|_| Error::RequestError(format!("Invalid port {} to connect to", port)))?;
+    let third_wheel = ThirdWheel::new(
+        sender,
+        client_ip,
+        server_ip,
+        host,
+        target_port,
+        mitm_proxy.capture.clone(),
+        mitm_proxy.record_bodies,
+        mitm_proxy.body_preview,
+        mitm_proxy.redact_headers.clone(),
+        state,
+        mitm_proxy.max_redirects,
+    );
+
+    let header_orders = mitm_proxy.preserve_header_order.then(HeaderOrders::default);
+    let capped = Arc::new(Notify::new());
+    let authority = logical_host
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{}:{}", host, port));
+    let mitm_layer = RecordHeaderOrder::new(
+        ResolveHost::new(
+            ForceCompression::new(
+                CappedService::new(
+                    RateLimited::new(
+                        mitm_proxy.mitm_layer.layer(third_wheel),
+                        mitm_proxy.rate_limiter.clone(),
+                        client_ip.ip(),
+                        mitm_proxy.metrics.clone(),
+                    ),
+                    mitm_proxy.max_requests_per_connection,
+                    mitm_proxy.metrics.clone(),
+                    capped.clone(),
+                ),
+                mitm_proxy.force_response_compression,
+            ),
+            mitm_proxy.host_resolution_headers.clone(),
+            authority,
+        ),
+        header_orders.clone(),
+    );
+
+    let mut http = Http::new();
+    http.http1_keep_alive(mitm_proxy.http1_keep_alive)
+        .http1_preserve_header_case(mitm_proxy.preserve_header_case);
+    if let Some(client_http_config) = &mitm_proxy.client_http_config {
+        client_http_config(&mut http);
+    }
+
+    if !is_tls {
+        // Fall back to reading plain HTTP from the client, still forwarding
+        // it over TLS to the target
+        let connection =
+            http.serve_connection(HeaderOrderTap::new(upgraded, header_orders), mitm_layer);
+        tokio::pin!(connection);
+        tokio::select! {
+            result = &mut connection => return result.map_err(|err| err.into()),
+            _ = capped.notified() => connection.as_mut().graceful_shutdown(),
+        }
+        return connection.await.map_err(|err| err.into());
+    }
+
+    let client = client_acceptor(
+        &mitm_proxy,
+        host,
+        domain,
+        target_certificate.as_ref(),
+        alpn_protocol.as_deref(),
+        signing_permit,
+    )
+    .await?;
+    let client_stream = client.accept(upgraded).await?;
+    let tls_parameters = TlsParameters::new(&client_stream, alpn_protocol.as_deref());
+
+    // Shut the connection down once it served its last allowed request
+    let connection = http.serve_connection(
+        HeaderOrderTap::new(client_stream, header_orders),
+        RecordTlsParameters::new(mitm_layer, tls_parameters),
+    );
+    tokio::pin!(connection);
+    tokio::select! {
+        result = &mut connection => return result.map_err(|err| err.into()),
+        _ = capped.notified() => connection.as_mut().graceful_shutdown(),
+    }
+    connection.await.map_err(|err| err.into())
+}
+
+/// How long the connection to the target of plain HTTP requests is kept
+/// for the next requests of the client without being used
+const PLAIN_HTTP_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// A connection to the target of plain HTTP requests, kept for the next
+/// requests of the same client to that target
+struct PlainHttpTarget {
+    sender: PlainHttpSender,
+    connection: tokio::task::JoinHandle<Result<(), hyper::Error>>,
+    used: Instant,
+}
+
+/// What sends the plain HTTP requests to their target
+enum PlainHttpSender {
+    /// through the mitm layer
+    Intercepted(ThirdWheel),
+    /// as they are, for the hosts which are not intercepted
+    Relayed(SendRequest<Body>),
+}
+
+impl PlainHttpTarget {
+    /// Whether the connection may carry another request
+    fn is_open(&self) -> bool {
+        !self.connection.is_finished() && self.used.elapsed() < PLAIN_HTTP_IDLE_TIMEOUT
+    }
+}
+
+/// Send a plain HTTP request in absolute form, such as
+/// `GET http://example.com/`, through the mitm layer to its target. The
+/// target is reached through the host mappings and the upstream proxy as the
+/// targets of tunnels are, without TLS, and its connection is reused by the
+/// next requests of the client to it until it is closed or left idle for
+/// `PLAIN_HTTP_IDLE_TIMEOUT`. The headers meant for the proxy are not
+/// forwarded.
+///
+/// As their tunnels are, the requests to the passthrough hosts, and all of
+/// them when only logging connections, are relayed to the target without
+/// going through the mitm layer nor being captured.
+async fn intercept_plain_http<T, U>(
+    mut req: Request<Body>,
+    mitm_proxy: &MitmProxy<T, U>,
+    client_ip: SocketAddr,
+    targets: &Mutex<HashMap<Authority, PlainHttpTarget>>,
+) -> Result<Response<Body>, Error>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let authority = req
+        .uri()
+        .authority()
+        .cloned()
+        .ok_or_else(|| Error::RequestError(format!("no host in {}", req.uri())))?;
+    // The mitm layer reads the target from the Host header, which HTTP/1.1
+    // only makes optional for requests in absolute form
+    if !req.headers().contains_key(HOST) {
+        let value = HeaderValue::from_str(authority.as_str())
+            .map_err(|e| Error::RequestError(e.to_string()))?;
+        req.headers_mut().insert(HOST, value);
+    }
+    let proxy_connection =
+        HeaderName::from_lowercase(b"proxy-connection").expect("Infallible: hardcoded header name");
+    req.headers_mut().remove(&proxy_connection);
+    req.headers_mut().remove(PROXY_AUTHORIZATION);
+
+    // The connection is taken for the request, the closed and idle ones
+    // being dropped
+    let target = {
+        let mut targets = targets.lock().unwrap();
+        targets.retain(|_, target| target.is_open());
+        targets.remove(&authority)
+    };
+    let mut target = match target {
+        Some(target) => target,
+        None => connect_plain_http_target(&authority, mitm_proxy, client_ip).await?,
+    };
+    target.used = Instant::now();
+
+    match &mut target.sender {
+        PlainHttpSender::Intercepted(third_wheel) => {
+            // The requests are sent one at a time by the connection, the next
+            // one can already be queued
+            let third_wheel = third_wheel.clone();
+            targets.lock().unwrap().insert(authority, target);
+            let mut service = ForceCompression::new(
+                RateLimited::new(
+                    mitm_proxy.mitm_layer.layer(third_wheel),
+                    mitm_proxy.rate_limiter.clone(),
+                    client_ip.ip(),
+                    mitm_proxy.metrics.clone(),
+                ),
+                mitm_proxy.force_response_compression,
+            );
+            service
+                .call(req)
+                .await
+                .map_err(|e| Error::ServerError(e.to_string()))
+        }
+        PlainHttpSender::Relayed(request_sender) => {
+            futures::future::poll_fn(|cx| request_sender.poll_ready(cx)).await?;
+            let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+            *req.uri_mut() = path
+                .parse()
+                .map_err(|_| Error::RequestError("Given URI was invalid".to_string()))?;
+            let response = request_sender.send_request(req);
+            targets.lock().unwrap().insert(authority, target);
+            Ok(response.await?)
+        }
+    }
+}
+
+/// Open a connection to the target of the plain HTTP requests to `authority`,
+/// relaying them when the host is not intercepted
+async fn connect_plain_http_target<T, U>(
+    authority: &Authority,
+    mitm_proxy: &MitmProxy<T, U>,
+    client_ip: SocketAddr,
+) -> Result<PlainHttpTarget, Error>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let host = authority.host();
+    let port = authority.port_u16().unwrap_or(80);
+    let passthrough_host = mitm_proxy
+        .passthrough_hosts
+        .iter()
+        .any(|pattern| host_mapping::glob_matches(pattern, host));
+    let logged_connection = match &mitm_proxy.connection_logger {
+        Some(log_connection) if !passthrough_host => {
+            log_connection(ConnectionInfo {
+                host: host.to_string(),
+                port: port.to_string(),
+                client_ip,
+                server_name: None,
+                timestamp: SystemTime::now(),
+            });
+            true
+        }
+        _ => false,
+    };
+    let relayed = passthrough_host || logged_connection;
+
+    let target = host_mapping::target(
+        &mitm_proxy.additional_host_mappings,
+        host,
+        &port.to_string(),
+    );
+    let target_stream = tokio::time::timeout(
+        mitm_proxy.upstream_timeouts.connect,
+        UpstreamStream::connect(&target, mitm_proxy.upstream_proxy.as_ref()),
+    )
+    .await
+    .map_err(|_| Error::Timeout(format!("connecting to {}", authority)))??;
+    let server_ip = target_stream.peer_addr();
+    let mut builder = Builder::new();
+    builder.http1_preserve_header_case(mitm_proxy.preserve_header_case);
+    if relayed {
+        let (request_sender, connection) = builder
+            .handshake::<TargetStream, Body>(TargetStream::Plain(target_stream))
+            .await?;
+        return Ok(PlainHttpTarget {
+            sender: PlainHttpSender::Relayed(request_sender),
+            connection: tokio::spawn(connection),
+            used: Instant::now(),
+        });
+    }
+    let (request_sender, connection) = builder
+        .handshake::<TargetStream, TimedBody>(TargetStream::Plain(target_stream))
+        .await?;
+    let connection = tokio::spawn(connection);
+
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let latency_sla = host_mapping::most_specific(&mitm_proxy.latency_sla, host).copied();
+    let mut synchronizer = RequestSendingSynchronizer::new(
+        request_sender,
+        receiver,
+        mitm_proxy.metrics.clone(),
+        host,
+        latency_sla,
+        false,
+    );
+    tokio::spawn(async move { synchronizer.run().await });
+
+    let state = mitm_proxy
+        .connection_state_factory
+        .as_ref()
+        .map(|connection_state_factory| connection_state_factory(host, client_ip));
+    let third_wheel = ThirdWheel::new(
+        sender,
+        client_ip,
+        server_ip,
+        host,
+        port,
+        mitm_proxy.capture.clone(),
+        mitm_proxy.record_bodies,
+        mitm_proxy.body_preview,
+        mitm_proxy.redact_headers.clone(),
+        state,
+        mitm_proxy.max_redirects,
+    );
+    Ok(PlainHttpTarget {
+        sender: PlainHttpSender::Intercepted(third_wheel),
+        connection,
+        used: Instant::now(),
+    })
+}
+
+/// How to connect to `host` over TLS, its own profile completed with the
+/// client identity and hostname verification of the proxy
+fn target_tls_profile<T, U>(mitm_proxy: &MitmProxy<T, U>, host: &str) -> TlsProfile
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let client_identity = mitm_proxy
+        .upstream_client_identities
+        .get(host)
+        .or(mitm_proxy.upstream_client_identity.as_ref())
+        .cloned();
+    host_mapping::most_specific(&mitm_proxy.tls_profiles, host)
+        .cloned()
+        .unwrap_or_default()
+        .or_defaults(client_identity, mitm_proxy.verify_hostname)
+}
+
+/// Relay the plaintext SMTP session of a tunnel until the client and the
+/// target agree to `STARTTLS`, then intercept the TLS session that follows,
+/// relaying and capturing its decrypted bytes. The plaintext part is not
+/// captured.
+async fn intercept_smtp_starttls<S, T, U>(
+    mut client: S,
+    mitm_proxy: &MitmProxy<T, U>,
+    host: &str,
+    port: &str,
+    client_ip: SocketAddr,
+    signing_permit: Option<SigningPermit>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + std::marker::Unpin + 'static,
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let timeouts = mitm_proxy.upstream_timeouts;
+    let target = host_mapping::target(&mitm_proxy.additional_host_mappings, host, port);
+    let mut target_stream = tokio::time::timeout(
+        timeouts.connect,
+        UpstreamStream::connect(&target, mitm_proxy.upstream_proxy.as_ref()),
+    )
+    .await
+    .map_err(|_| Error::Timeout(format!("connecting to {}:{}", host, port)))??;
+    let server_ip = target_stream.peer_addr();
+    if !relay_smtp_until_starttls(&mut client, &mut target_stream).await? {
+        return Ok(());
+    }
+
+    let (target_stream, target_certificate) = tls_handshake_with_target(
+        target_stream,
+        host,
+        &mitm_proxy.additional_root_certificates,
+        target_tls_profile(mitm_proxy, host),
+        &[],
+        timeouts.tls_handshake,
+    )
+    .await?;
+    let acceptor = client_acceptor(
+        mitm_proxy,
+        host,
+        host,
+        Some(&target_certificate),
+        None,
+        signing_permit,
+    )
+    .await?;
+    let client_stream = acceptor.accept(client).await?;
+    let connection = ConnectionInfo {
+        host: host.to_string(),
+        port: port.to_string(),
+        client_ip,
+        server_name: None,
+        timestamp: SystemTime::now(),
+    };
+    relay_raw(
+        client_stream,
+        TargetStream::Tls(target_stream),
+        mitm_proxy.capture.as_ref(),
+        mitm_proxy.record_bodies,
+        connection,
+        server_ip,
+    )
+    .await
+}
+
+/// The acceptor presenting the client a certificate for `domain`, reused from
+/// the certificate cache when one was already signed for it and the same
+/// target certificate. Signing a new one waits for `signing_permit`, reserved
+/// when the tunnel was accepted, or for the signing rate without one, and
+/// fails with `Error::Timeout` if it takes longer than the signing timeout.
+///
+/// With the `alpn-mirroring` feature, the acceptor offers the client
+/// `alpn_protocol`, the protocol the target chose, so a client speaking
+/// HTTP/2 to the target does so to the proxy too. Without it no protocol is
+/// offered and such a client falls back to HTTP/1.1, as native-tls only
+/// accepts ALPN with its `alpn-accept` feature.
+async fn client_acceptor<T, U>(
+    mitm_proxy: &MitmProxy<T, U>,
+    host: &str,
+    domain: &str,
+    target_certificate: Option<&X509>,
+    alpn_protocol: Option<&[u8]>,
+    signing_permit: Option<SigningPermit>,
+) -> Result<TlsAcceptor, Error>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let fingerprint = match target_certificate {
+        Some(target_certificate) => {
+            Some(target_certificate.digest(MessageDigest::sha256())?.to_vec())
+        }
+        None => None,
+    };
+    let key = (
+        domain.to_string(),
+        fingerprint,
+        alpn_protocol.map(<[u8]>::to_vec),
+    );
+    let cached = mitm_proxy.certificate_cache.lock().unwrap().get(&key);
+    if let Some(client) = cached {
+        return Ok(client);
+    }
+    let signing_permit = match signing_permit {
+        Some(signing_permit) => Some(signing_permit),
+        None => mitm_proxy.throttle_signing(domain)?,
+    };
+    if let Some(signing_permit) = signing_permit {
+        signing_permit.wait().await;
+    }
+
+    // Sign away from the threads serving the connections, giving up on a
+    // signing slower than the signing timeout
+    let signing_started = Instant::now();
+    let mut signing = tokio::task::spawn_blocking({
+        let ca = mitm_proxy.ca.clone();
+        let target_certificate = target_certificate.cloned();
+        let host = host.to_string();
+        let domain = domain.to_string();
+        let certificate_fallback = mitm_proxy.certificate_fallback;
+        let copy_extended_key_usages = mitm_proxy.copy_extended_key_usages;
+        let validity = mitm_proxy.spoofed_cert_validity;
+        let serial_strategy = mitm_proxy.spoofed_cert_serial_strategy;
+        let alpn_protocol = alpn_protocol.map(<[u8]>::to_vec);
+        move || {
+            sign_acceptor(
+                &ca,
+                target_certificate.as_ref(),
+                &host,
+                &domain,
+                certificate_fallback,
+                copy_extended_key_usages,
+                validity,
+                serial_strategy,
+                alpn_protocol.as_deref(),
+            )
+        }
+    });
+    let client = match tokio::time::timeout(mitm_proxy.signing_timeout, &mut signing).await {
+        Ok(signed) => signed.map_err(|e| Error::CertificateError(e.to_string()))??,
+        Err(_) => {
+            // A signing still waiting for a blocking thread never starts,
+            // one already running cannot be stopped and is discarded
+            signing.abort();
+            mitm_proxy.metrics.record_signing_timeout();
+            return Err(Error::Timeout(format!(
+                "signing a certificate for {}",
+                domain
+            )));
+        }
+    };
+    mitm_proxy
+        .metrics
+        .record_signed_certificate(signing_started.elapsed());
+    mitm_proxy
+        .certificate_cache
+        .lock()
+        .unwrap()
+        .insert(key, client.clone());
+    Ok(client)
+}
+
+/// Sign a certificate for `domain`, spoofing the one of the target if there is
+/// one, and make the acceptor presenting it, offering `alpn_protocol` with the
+/// `alpn-mirroring` feature
+#[allow(clippy::too_many_arguments)]
+fn sign_acceptor(
+    ca: &CertificateAuthority,
+    target_certificate: Option<&X509>,
+    host: &str,
+    domain: &str,
+    certificate_fallback: bool,
+    copy_extended_key_usages: bool,
+    validity: Duration,
+    serial_strategy: SerialStrategy,
+    alpn_protocol: Option<&[u8]>,
+) -> Result<TlsAcceptor, Error> {
+    // A plaintext target has no certificate to spoof, one is signed for the
+    // host the client asked for
+    let spoofed = match target_certificate {
+        Some(target_certificate) => {
+            let options = SpoofOptions {
+                copy_extended_key_usages,
+                host: Some(domain),
+                validity,
+                serial_strategy,
+            };
+            spoof_certificate_with_options(target_certificate, ca, &options)
+        }
+        None => create_signed_certificate_for_domain(domain, ca),
+    };
+    let certificate = match spoofed {
+        Ok(certificate) => certificate,
+        Err(err) if certificate_fallback => {
+            warn!(
+                "Could not spoof the certificate of {}, signing one for {} only: {}",
+                host, domain, err
+            );
+            create_signed_certificate_for_domain(domain, ca)?
+        }
+        Err(err) => return Err(err),
+    };
+    let identity = native_identity(&certificate, &ca.key)?;
+    #[allow(unused_mut)]
+    let mut acceptor = native_tls::TlsAcceptor::builder(identity);
+    #[cfg(feature = "alpn-mirroring")]
+    if let Some(alpn_protocol) = alpn_protocol.and_then(|p| std::str::from_utf8(p).ok()) {
+        acceptor.accept_alpn(&[alpn_protocol]);
+    }
+    #[cfg(not(feature = "alpn-mirroring"))]
+    let _ = alpn_protocol;
+    Ok(TlsAcceptor::from(acceptor.build()?))
+}
+
+/// Answer the client of a tunnel whose target could not be connected to with
+/// a `502 Bad Gateway`, or a `504 Gateway Timeout` when connecting timed out,
+/// rather than closing the tunnel without a word. A TLS client is first given
+/// a certificate signed for the name it asked for.
+async fn answer_upstream_failure<S, T, U>(
+    upgraded: S,
+    is_tls: bool,
+    mitm_proxy: &MitmProxy<T, U>,
+    host: &str,
+    domain: &str,
+    error: &Error,
+    signing_permit: Option<SigningPermit>,
+) -> Result<(), Error>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    S: AsyncRead + AsyncWrite + std::marker::Unpin + 'static,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let status = upstream_failure_status(error);
+    let message = error.to_string();
+    if !is_tls {
+        return answer_with_status(upgraded, status, message).await;
+    }
+    let client = client_acceptor(mitm_proxy, host, domain, None, None, signing_permit).await?;
+    let client_stream = client.accept(upgraded).await?;
+    answer_with_status(client_stream, status, message).await
+}
 
-    let mitm_layer = mitm_proxy.mitm_layer.layer(third_wheel);
+/// The status telling a client its target could not be reached:
+/// `504 Gateway Timeout` when it timed out, `502 Bad Gateway` otherwise
+fn upstream_failure_status(error: &Error) -> StatusCode {
+    match error {
+        Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        Error::IOError(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            StatusCode::GATEWAY_TIMEOUT
+        }
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
 
+/// Answer the request the client sends with `status`, then close the connection
+async fn answer_with_status<S: AsyncRead + AsyncWrite + std::marker::Unpin + 'static>(
+    client: S,
+    status: StatusCode,
+    message: String,
+) -> Result<(), Error> {
+    let service = service_fn(move |_: Request<Body>| {
+        let response = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from(message.clone()))
+            .expect("Infallible: valid status and header");
+        futures::future::ready(Ok::<_, Infallible>(response))
+    });
     Http::new()
-        .serve_connection(client_stream, mitm_layer)
+        .http1_keep_alive(false)
+        .serve_connection(client, service)
         .await
         .map_err(|err| err.into())
 }
 
+/// Relay the bytes of the tunnel to the target as they are
+async fn passthrough<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
+    mut client: S,
+    host: &str,
+    port: &str,
+    additional_host_mapping: &HashMap<String, String>,
+    upstream_proxy: Option<&UpstreamProxy>,
+) -> Result<(), Error> {
+    let target = host_mapping::target(additional_host_mapping, host, port);
+    let mut target_stream = UpstreamStream::connect(&target, upstream_proxy).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut target_stream).await?;
+    Ok(())
+}
+
+/// Relay the decrypted bytes of a tunnel between the client and the target
+/// until both are done, capturing them as one HAR entry for the tunnel.
+async fn relay_raw<C: AsyncRead + AsyncWrite>(
+    client: C,
+    target: TargetStream,
+    capture: Option<&CaptureSender>,
+    record_bodies: bool,
+    connection: ConnectionInfo,
+    server_ip: Option<SocketAddr>,
+) -> Result<(), Error> {
+    let record = capture.is_some() && record_bodies;
+    let (client_reader, client_writer) = tokio::io::split(client);
+    let (target_reader, target_writer) = tokio::io::split(target);
+    let mut sent = Vec::new();
+    let mut received = Vec::new();
+    let (mut sent_size, mut received_size) = (0, 0);
+    let (to_target, to_client) = tokio::join!(
+        copy_recording(
+            client_reader,
+            target_writer,
+            record.then_some(&mut sent),
+            &mut sent_size
+        ),
+        copy_recording(
+            target_reader,
+            client_writer,
+            record.then_some(&mut received),
+            &mut received_size
+        ),
+    );
+
+    if let Some(capture) = capture {
+        let mut entry = raw_tunnel_entry(
+            &connection,
+            server_ip,
+            &sent,
+            sent_size,
+            &received,
+            received_size,
+        );
+        if !record_bodies {
+            strip_bodies(&mut entry);
+        }
+        capture.send(entry);
+    }
+    to_target.and(to_client).map_err(Error::from)
+}
+
+/// Copy the bytes of `reader` to `writer` until the end, counting them in
+/// `copied` and keeping a copy of the first `STREAM_CAPTURE_LIMIT` of them in
+/// `recorded` if given, then shut the writer down
+async fn copy_recording<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut reader: R,
+    mut writer: W,
+    mut recorded: Option<&mut Vec<u8>>,
+    copied: &mut u64,
+) -> std::io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return writer.shutdown().await;
+        }
+        *copied += read as u64;
+        if let Some(recorded) = recorded.as_mut() {
+            let room = STREAM_CAPTURE_LIMIT.saturating_sub(recorded.len());
+            recorded.extend_from_slice(&buffer[..read.min(room)]);
+        }
+        writer.write_all(&buffer[..read]).await?;
+    }
+}
+
+/// Answer a client which sent plaintext on the tunnel with an error it can read
+async fn reject_plaintext<S: AsyncWrite + std::marker::Unpin>(mut client: S) {
+    let message = Error::PlaintextInTunnel.to_string();
+    let response = format!(
+        "HTTP/1.1 400 Bad Request\r\ncontent-type: text/plain\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        message.len(),
+        message
+    );
+    if let Err(e) = client.write_all(response.as_bytes()).await {
+        error!("Failed to answer plaintext client: {}", e);
+    }
+    let _ = client.shutdown().await;
+}
+
+/// Connect to the target of a tunnel, over TLS unless it is mapped to a Unix
+/// domain socket in plaintext. Returns the connection, the certificate the
+/// target presented, `None` for a plaintext target, and the address
+/// connected to, `None` for a Unix domain socket.
+#[allow(clippy::too_many_arguments)]
 async fn connect_to_target_with_tls(
     host: &str,
     port: &str,
-    additional_host_mapping: HashMap<String, String>,
-    additional_root_certificates: Vec<Certificate>,
-) -> Result<(TlsStream<TcpStream>, X509), Error> {
-    let host_address = additional_host_mapping
-        .get(host)
-        .map(|s| s.as_str())
-        .unwrap_or(host);
-    let target_stream = TcpStream::connect(format!("{}:{}", host_address, port)).await?;
+    server_name: &str,
+    additional_host_mapping: &HashMap<String, String>,
+    additional_root_certificates: &[Certificate],
+    tls_profile: TlsProfile,
+    alpn_protocols: &[&str],
+    upstream_proxy: Option<&UpstreamProxy>,
+    timeouts: UpstreamTimeouts,
+) -> Result<(TargetStream, Option<X509>, Option<SocketAddr>), Error> {
+    let target = host_mapping::target(additional_host_mapping, host, port);
+    let target_stream = tokio::time::timeout(
+        timeouts.connect,
+        UpstreamStream::connect(&target, upstream_proxy),
+    )
+    .await
+    .map_err(|_| Error::Timeout(format!("connecting to {}:{}", host, port)))??;
+    let server_ip = target_stream.peer_addr();
+    if let host_mapping::Target::Unix { tls: false, .. } = target {
+        return Ok((TargetStream::Plain(target_stream), None, server_ip));
+    }
 
+    let (target_stream, certificate) = tls_handshake_with_target(
+        target_stream,
+        server_name,
+        additional_root_certificates,
+        tls_profile,
+        alpn_protocols,
+        timeouts.tls_handshake,
+    )
+    .await?;
+    Ok((
+        TargetStream::Tls(target_stream),
+        Some(certificate),
+        server_ip,
+    ))
+}
+
+/// Perform the TLS handshake with a target already connected to, offering it
+/// `alpn_protocols` if any, or instead the ones of its TLS profile when it
+/// sets some, returning the TLS stream and the certificate the target
+/// presented
+async fn tls_handshake_with_target(
+    target_stream: UpstreamStream,
+    server_name: &str,
+    additional_root_certificates: &[Certificate],
+    tls_profile: TlsProfile,
+    alpn_protocols: &[&str],
+    tls_handshake_timeout: Duration,
+) -> Result<(TlsStream<UpstreamStream>, X509), Error> {
     let mut connector = native_tls::TlsConnector::builder();
     for root_certificate in additional_root_certificates {
-        connector.add_root_certificate(root_certificate);
+        connector.add_root_certificate(root_certificate.clone());
+    }
+    // Hosts offered no protocol, such as the raw ones, stay so
+    let alpn_protocols: Vec<String> = match &tls_profile.alpn_protocols {
+        Some(profile_protocols) if !alpn_protocols.is_empty() => profile_protocols.clone(),
+        _ => alpn_protocols.iter().map(|p| p.to_string()).collect(),
+    };
+    tls_profile.configure(&mut connector);
+    if !alpn_protocols.is_empty() {
+        let alpn_protocols: Vec<&str> = alpn_protocols.iter().map(String::as_str).collect();
+        connector.request_alpns(&alpn_protocols);
     }
     let connector = connector.build()?;
 
     let tokio_connector = tokio_native_tls::TlsConnector::from(connector);
-    let target_stream = tokio_connector.connect(host, target_stream).await?;
+    let target_stream = tokio::time::timeout(
+        tls_handshake_timeout,
+        tokio_connector.connect(server_name, target_stream),
+    )
+    .await
+    .map_err(|_| Error::Timeout(format!("waiting for the TLS handshake of {}", server_name)))??;
     //TODO: Currently to copy the certificate we do a round trip from one library -> der -> other library. This is inefficient, it should be possible to do it better some how.
     let certificate = &target_stream.get_ref().peer_certificate()?;
 
@@ -294,6 +2291,8 @@ async fn connect_to_target_with_tls(
     Ok((target_stream, certificate))
 }
 
+/// The host and port of the target of a `CONNECT` request, the port being
+/// 443 when the request names none, as some clients send `CONNECT example.com`
 fn target_host_port_from_connect(request: &Request<Body>) -> Result<(String, String), Error> {
     let host = request
         .uri()
@@ -304,10 +2303,8 @@ fn target_host_port_from_connect(request: &Request<Body>) -> Result<(String, Str
         ))?;
     let port = request
         .uri()
-        .port()
-        .map(|x| x.to_string())
-        .ok_or(Error::RequestError(
-            "No port found on CONNECT request".to_string(),
-        ))?;
+        .port_u16()
+        .unwrap_or(DEFAULT_TLS_PORT)
+        .to_string();
     Ok((host, port))
 }
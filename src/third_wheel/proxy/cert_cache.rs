@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use tokio_native_tls::TlsAcceptor;
+
+/// What a spoofed certificate was made from: the name the client asked for
+/// and the SHA-256 fingerprint of the certificate the target presented, if
+/// any, so a rotated target certificate is spoofed again, and the protocol
+/// the target chose with ALPN, offered to the client along with it
+pub(crate) type CertificateKey = (String, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// The TLS acceptors presenting the spoofed certificates, kept to sign a
+/// certificate only once per target. When full, the least recently used
+/// acceptor is evicted.
+pub(crate) struct CertificateCache {
+    capacity: usize,
+    entries: HashMap<CertificateKey, (TlsAcceptor, u64)>,
+    /// incremented on every use, to know which entry was used last
+    clock: u64,
+}
+
+impl CertificateCache {
+    /// A cache holding at most `capacity` acceptors, none if it is 0
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &CertificateKey) -> Option<TlsAcceptor> {
+        self.clock += 1;
+        let (acceptor, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.clock;
+        Some(acceptor.clone())
+    }
+
+    /// Whether an acceptor was signed for `domain`, whatever its target
+    /// certificate and protocol
+    pub(crate) fn holds_domain(&self, domain: &str) -> bool {
+        self.entries.keys().any(|(name, _, _)| name == domain)
+    }
+
+    pub(crate) fn insert(&mut self, key: CertificateKey, acceptor: TlsAcceptor) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                self.entries.remove(&least_recently_used);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (acceptor, self.clock));
+    }
+}
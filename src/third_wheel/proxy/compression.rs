@@ -0,0 +1,99 @@
+use futures::Future;
+use futures_util::stream;
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::service::Service;
+use hyper::{Body, Request, Response, StatusCode};
+use std::pin::Pin;
+
+/// Header of a gzip member: magic number, deflate method, no flags, no
+/// modification time, no extra flags and an unknown operating system
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+/// Compress `data` into a gzip member
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut compressed = GZIP_HEADER.to_vec();
+    compressed.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+    compressed.extend(crc32(data).to_le_bytes());
+    compressed.extend((data.len() as u32).to_le_bytes());
+    compressed
+}
+
+/// CRC-32 checksum as used by gzip
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Wraps the service handling a client connection to gzip every response
+/// body it sends back, whatever the client accepts. Used to test how clients
+/// handle encodings they did not ask for.
+pub(crate) struct ForceCompression<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<S> ForceCompression<S> {
+    pub(crate) fn new(inner: S, enabled: bool) -> Self {
+        Self { inner, enabled }
+    }
+}
+
+impl<S> Service<Request<Body>> for ForceCompression<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let enabled = self.enabled;
+        let fut = self.inner.call(request);
+        Box::pin(async move {
+            let response = fut.await?;
+            if !enabled || !can_compress(&response) {
+                return Ok(response);
+            }
+
+            let (mut parts, body) = response.into_parts();
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.remove(TRANSFER_ENCODING);
+
+            // The body is compressed as a whole once the target sent all of it
+            let body = Body::wrap_stream(stream::once(async move {
+                hyper::body::to_bytes(body).await.map(|bytes| gzip(&bytes))
+            }));
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+/// Responses without a body or already encoded are left as they are
+fn can_compress(response: &Response<Body>) -> bool {
+    let status = response.status();
+    !(status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || response.headers().contains_key(CONTENT_ENCODING))
+}
@@ -0,0 +1,253 @@
+use hyper::header::{HeaderName, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::service::Service;
+use hyper::{Body, Request};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::third_wheel::proxy::mitm::HeaderOrder;
+
+/// Most headers a request head can have, as hyper accepts
+const MAX_HEADERS: usize = 100;
+
+/// Largest request head buffered before giving up on following the stream
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Header names of the requests read on a connection, in the order their heads
+/// were read, waiting for hyper to hand the requests to the service
+pub(crate) type HeaderOrders = Arc<Mutex<VecDeque<Vec<HeaderName>>>>;
+
+/// Where the stream of requests is at
+enum Framing {
+    /// reading a request head
+    Head,
+    /// skipping a body of the given remaining length
+    Body(u64),
+    /// reading the size line of a chunk
+    ChunkSize,
+    /// skipping the data of a chunk of the given remaining length
+    ChunkData(u64),
+    /// reading the line ending a chunk
+    ChunkEnd,
+    /// reading the trailers after the last chunk
+    Trailers,
+    /// the stream could not be followed, e.g. after an upgrade
+    Lost,
+}
+
+/// Follows the requests sent by a client to record the order of the header
+/// names of each, which hyper does not expose
+struct RequestHeads {
+    buffer: Vec<u8>,
+    framing: Framing,
+    orders: HeaderOrders,
+}
+
+impl RequestHeads {
+    fn feed(&mut self, bytes: &[u8]) {
+        if matches!(self.framing, Framing::Lost) {
+            return;
+        }
+        self.buffer.extend_from_slice(bytes);
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let mut start = 0;
+        while let Some(consumed) = self.step(&buffer[start..]) {
+            start += consumed;
+        }
+        buffer.drain(..start);
+        if matches!(self.framing, Framing::Head) && buffer.len() > MAX_HEAD_SIZE {
+            self.framing = Framing::Lost;
+        }
+        if !matches!(self.framing, Framing::Lost) {
+            self.buffer = buffer;
+        }
+    }
+
+    /// Consume what can be read from `bytes` in the current framing, `None`
+    /// if more bytes are needed
+    fn step(&mut self, bytes: &[u8]) -> Option<usize> {
+        if bytes.is_empty() {
+            return None;
+        }
+        let (consumed, framing) = match self.framing {
+            Framing::Head => {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut request = httparse::Request::new(&mut headers);
+                match request.parse(bytes) {
+                    Ok(httparse::Status::Complete(len)) => match header_names(request.headers) {
+                        Some(names) => {
+                            let framing = body_framing(request.headers);
+                            self.orders.lock().unwrap().push_back(names);
+                            (len, framing)
+                        }
+                        None => (0, Framing::Lost),
+                    },
+                    Ok(httparse::Status::Partial) => return None,
+                    Err(_) => (0, Framing::Lost),
+                }
+            }
+            Framing::Body(remaining) => {
+                let len = remaining.min(bytes.len() as u64);
+                match remaining - len {
+                    0 => (len as usize, Framing::Head),
+                    remaining => (len as usize, Framing::Body(remaining)),
+                }
+            }
+            Framing::ChunkSize => match httparse::parse_chunk_size(bytes) {
+                Ok(httparse::Status::Complete((len, 0))) => (len, Framing::Trailers),
+                Ok(httparse::Status::Complete((len, size))) => (len, Framing::ChunkData(size)),
+                Ok(httparse::Status::Partial) => return None,
+                Err(_) => (0, Framing::Lost),
+            },
+            Framing::ChunkData(remaining) => {
+                let len = remaining.min(bytes.len() as u64);
+                match remaining - len {
+                    0 => (len as usize, Framing::ChunkEnd),
+                    remaining => (len as usize, Framing::ChunkData(remaining)),
+                }
+            }
+            Framing::ChunkEnd => match bytes {
+                [b'\r', b'\n', ..] => (2, Framing::ChunkSize),
+                [b'\r'] => return None,
+                _ => (0, Framing::Lost),
+            },
+            Framing::Trailers => match bytes.windows(2).position(|end| end == b"\r\n") {
+                Some(0) => (2, Framing::Head),
+                Some(position) => (position + 2, Framing::Trailers),
+                None => return None,
+            },
+            Framing::Lost => return None,
+        };
+        self.framing = framing;
+        match self.framing {
+            Framing::Lost => None,
+            _ => Some(consumed),
+        }
+    }
+}
+
+/// The names of `headers` as hyper reads them
+fn header_names(headers: &[httparse::Header<'_>]) -> Option<Vec<HeaderName>> {
+    headers
+        .iter()
+        .map(|header| HeaderName::from_bytes(header.name.as_bytes()).ok())
+        .collect()
+}
+
+/// How the body following a request head with `headers` is delimited
+fn body_framing(headers: &[httparse::Header<'_>]) -> Framing {
+    let header = |name: HeaderName| {
+        headers
+            .iter()
+            .filter(move |header| header.name.eq_ignore_ascii_case(name.as_str()))
+            .filter_map(|header| std::str::from_utf8(header.value).ok())
+    };
+    let chunked = header(TRANSFER_ENCODING)
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+    if chunked {
+        return Framing::ChunkSize;
+    }
+    match header(CONTENT_LENGTH).find_map(|value| value.trim().parse::<u64>().ok()) {
+        Some(length) if length > 0 => Framing::Body(length),
+        _ => Framing::Head,
+    }
+}
+
+/// A client stream reading the heads of the requests passing through it to
+/// record the order of their headers, see `MitmProxyBuilder::preserve_header_order`
+pub(crate) struct HeaderOrderTap<S> {
+    inner: S,
+    heads: Option<RequestHeads>,
+}
+
+impl<S> HeaderOrderTap<S> {
+    /// Tap `inner` when given where to record the orders, otherwise only pass
+    /// the bytes through
+    pub(crate) fn new(inner: S, orders: Option<HeaderOrders>) -> Self {
+        Self {
+            inner,
+            heads: orders.map(|orders| RequestHeads {
+                buffer: Vec::new(),
+                framing: Framing::Head,
+                orders,
+            }),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HeaderOrderTap<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(heads)) = (&poll, &mut self.heads) {
+            heads.feed(&buf.filled()[filled..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HeaderOrderTap<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Wraps the service handling a client connection to store the order of the
+/// headers recorded by its `HeaderOrderTap` in the extensions of each request
+pub(crate) struct RecordHeaderOrder<S> {
+    inner: S,
+    orders: Option<HeaderOrders>,
+}
+
+impl<S> RecordHeaderOrder<S> {
+    pub(crate) fn new(inner: S, orders: Option<HeaderOrders>) -> Self {
+        Self { inner, orders }
+    }
+}
+
+impl<S> Service<Request<Body>> for RecordHeaderOrder<S>
+where
+    S: Service<Request<Body>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    /// Requests are handed to the service in the order their heads were read,
+    /// so each takes the oldest order. One which does not match the headers of
+    /// the request, e.g. when the tap lost track of the stream, is dropped.
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        if let Some(orders) = &self.orders {
+            let names = orders.lock().unwrap().pop_front();
+            if let Some(names) = names.filter(|names| names.len() == request.headers().len()) {
+                request.extensions_mut().insert(HeaderOrder(names));
+            }
+        }
+        self.inner.call(request)
+    }
+}
@@ -0,0 +1,62 @@
+use hyper::header::{HeaderMap, HeaderName};
+use hyper::service::Service;
+use hyper::{Body, Request};
+use std::task::{Context, Poll};
+
+use crate::third_wheel::proxy::mitm::LogicalHost;
+
+/// The value of the first of `names` found in `headers`, empty values and
+/// values which are not text being skipped
+pub(crate) fn resolve_host(headers: &HeaderMap, names: &[HeaderName]) -> Option<String> {
+    names.iter().find_map(|name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(str::trim)
+            .find(|value| !value.is_empty())
+            .map(str::to_string)
+    })
+}
+
+/// Wraps the service handling a client connection to store in each request
+/// the host it is for, read from the first of the resolution headers it has,
+/// or else the authority of the tunnel. Requests are left as they are when
+/// there are no resolution headers.
+pub(crate) struct ResolveHost<S> {
+    inner: S,
+    headers: Vec<HeaderName>,
+    authority: String,
+}
+
+impl<S> ResolveHost<S> {
+    pub(crate) fn new(inner: S, headers: Vec<HeaderName>, authority: String) -> Self {
+        Self {
+            inner,
+            headers,
+            authority,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for ResolveHost<S>
+where
+    S: Service<Request<Body>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        if !self.headers.is_empty() {
+            let host = resolve_host(request.headers(), &self.headers)
+                .unwrap_or_else(|| self.authority.clone());
+            request.extensions_mut().insert(LogicalHost(host));
+        }
+        self.inner.call(request)
+    }
+}
@@ -0,0 +1,332 @@
+use hyper::body::{Buf, Bytes};
+use hyper::client::conn::Builder;
+use hyper::header::{CONNECTION, HOST, TRANSFER_ENCODING, UPGRADE};
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use log::{error, warn};
+use quinn::crypto::rustls::{HandshakeData, QuicServerConfig};
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tower::Layer;
+
+use super::mitm::{RequestSendingSynchronizer, ThirdWheel, TimedBody};
+use super::upstream::TargetStream;
+use super::{
+    connect_to_target_with_tls, target_tls_profile, MitmProxy, DEFAULT_TLS_PORT,
+    HTTP_ALPN_PROTOCOLS,
+};
+use crate::third_wheel::certificates::{
+    create_signed_certificate_for_domain, CertificateAuthority,
+};
+use crate::third_wheel::{error::Error, host_mapping};
+
+/// The protocol QUIC clients ask for with ALPN to speak HTTP/3
+const H3_ALPN: &[u8] = b"h3";
+
+/// Headers only meaningful to the connection they were sent on, which HTTP/3
+/// forbids
+const CONNECTION_HEADERS: &[&str] = &["keep-alive", "proxy-connection"];
+
+/// Signs a certificate for the server name each QUIC client sends with the
+/// authority of the proxy, keeping it for the next connections. The target is
+/// not asked for its own certificate, the handshake resolving it synchronously.
+struct SignedCertificates {
+    ca: CertificateAuthority,
+    key: Arc<dyn SigningKey>,
+    certificates: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl fmt::Debug for SignedCertificates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignedCertificates").finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for SignedCertificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name()?.to_string();
+        let mut certificates = self.certificates.lock().unwrap();
+        if let Some(certified_key) = certificates.get(&server_name) {
+            return Some(certified_key.clone());
+        }
+        let certificate = create_signed_certificate_for_domain(&server_name, &self.ca)
+            .and_then(|certificate| Ok(certificate.to_der()?));
+        let certificate = match certificate {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                error!("Failed to sign a certificate for {}: {}", server_name, e);
+                return None;
+            }
+        };
+        let certified_key = Arc::new(CertifiedKey::new(
+            vec![CertificateDer::from(certificate)],
+            self.key.clone(),
+        ));
+        certificates.insert(server_name, certified_key.clone());
+        Some(certified_key)
+    }
+}
+
+/// The QUIC configuration of the proxy facing the clients, speaking HTTP/3
+/// with the certificates signed by `ca`
+fn quic_server_config(ca: &CertificateAuthority) -> Result<quinn::ServerConfig, Error> {
+    // The signed certificates hold the key of the authority
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(ca.key.private_key_to_pkcs8()?));
+    let key = any_supported_type(&key).map_err(http3_error)?;
+    let certificates = SignedCertificates {
+        ca: ca.clone(),
+        key,
+        certificates: Mutex::default(),
+    };
+    let mut tls_config = rustls::ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(http3_error)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(certificates));
+    tls_config.alpn_protocols = vec![H3_ALPN.to_vec()];
+    let quic_config = QuicServerConfig::try_from(tls_config).map_err(http3_error)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(quic_config)))
+}
+
+fn http3_error(error: impl fmt::Display) -> Error {
+    Error::Http3Error(error.to_string())
+}
+
+impl<T, U> MitmProxy<T, U>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    /// Experimental, with the `http3` feature: intercept HTTP/3 over QUIC on
+    /// the UDP socket address `addr`, for clients whose QUIC traffic is
+    /// redirected to the proxy. QUIC has no `CONNECT`, the target is the
+    /// server name the client sent, on port 443 unless the host mappings say
+    /// otherwise, and the requests are forwarded to it over TCP through the
+    /// mitm layer, as the ones of tunnels are. Must be called within a tokio
+    /// runtime. Returns the address actually bound to, and the future to be
+    /// executed that will run the listener.
+    ///
+    /// The clients of the passthrough hosts, and all of them when only
+    /// logging connections, are refused so they fall back to TCP, where
+    /// their tunnels are not intercepted. Certificates are signed for the
+    /// server name rather than spoofed from the target, bodies are buffered
+    /// and the other options of the tunnels do not apply yet.
+    #[allow(dead_code)]
+    pub fn bind_http3(
+        self,
+        addr: SocketAddr,
+    ) -> Result<(SocketAddr, impl Future<Output = Result<(), Error>>), Error> {
+        let endpoint = quinn::Endpoint::server(quic_server_config(&self.ca)?, addr)?;
+        let local_addr = endpoint.local_addr()?;
+        let server = async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let mitm_proxy = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = intercept_http3(incoming, mitm_proxy).await {
+                        error!("HTTP/3 proxy failed: {}", e)
+                    }
+                });
+            }
+            Ok(())
+        };
+        Ok((local_addr, server))
+    }
+}
+
+/// Answer the HTTP/3 requests of a QUIC client with the responses of its
+/// target
+async fn intercept_http3<T, U>(
+    incoming: quinn::Incoming,
+    mitm_proxy: MitmProxy<T, U>,
+) -> Result<(), Error>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let connection = incoming.await.map_err(http3_error)?;
+    let client_ip = connection.remote_address();
+    let host = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok())
+        .and_then(|data| data.server_name)
+        .ok_or_else(|| Error::Http3Error("the client sent no server name".to_string()))?;
+
+    let passthrough_host = mitm_proxy
+        .passthrough_hosts
+        .iter()
+        .any(|pattern| host_mapping::glob_matches(pattern, &host));
+    if passthrough_host || mitm_proxy.connection_logger.is_some() {
+        connection.close(0u32.into(), b"not intercepted");
+        return Ok(());
+    }
+
+    let service = connect_http3_target(&mitm_proxy, &host, client_ip).await?;
+    let mut h3_connection: h3::server::Connection<_, Bytes> =
+        h3::server::Connection::new(h3_quinn::Connection::new(connection))
+            .await
+            .map_err(http3_error)?;
+    while let Some((request, stream)) = h3_connection.accept().await.map_err(http3_error)? {
+        let service = service.clone();
+        let host = host.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer_http3_request(request, stream, service).await {
+                warn!("HTTP/3 request to {} failed: {}", host, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Connect to the target `host` of a QUIC client, returning the mitm layer
+/// sending its requests there
+async fn connect_http3_target<T, U>(
+    mitm_proxy: &MitmProxy<T, U>,
+    host: &str,
+    client_ip: SocketAddr,
+) -> Result<U, Error>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error + Send + Sync + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let (target_stream, _, server_ip) = connect_to_target_with_tls(
+        host,
+        &DEFAULT_TLS_PORT.to_string(),
+        host,
+        &mitm_proxy.additional_host_mappings,
+        &mitm_proxy.additional_root_certificates,
+        target_tls_profile(mitm_proxy, host),
+        HTTP_ALPN_PROTOCOLS,
+        mitm_proxy.upstream_proxy.as_ref(),
+        mitm_proxy.upstream_timeouts,
+    )
+    .await?;
+    let http2 = target_stream.is_http2();
+    let (request_sender, connection) = Builder::new()
+        .http2_only(http2)
+        .handshake::<TargetStream, TimedBody>(target_stream)
+        .await?;
+    tokio::spawn(connection);
+
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let latency_sla = host_mapping::most_specific(&mitm_proxy.latency_sla, host).copied();
+    let mut synchronizer = RequestSendingSynchronizer::new(
+        request_sender,
+        receiver,
+        mitm_proxy.metrics.clone(),
+        host,
+        latency_sla,
+        http2,
+    );
+    tokio::spawn(async move { synchronizer.run().await });
+
+    let state = mitm_proxy
+        .connection_state_factory
+        .as_ref()
+        .map(|connection_state_factory| connection_state_factory(host, client_ip));
+    let third_wheel = ThirdWheel::new(
+        sender,
+        client_ip,
+        server_ip,
+        host,
+        DEFAULT_TLS_PORT,
+        mitm_proxy.capture.clone(),
+        mitm_proxy.record_bodies,
+        mitm_proxy.body_preview,
+        mitm_proxy.redact_headers.clone(),
+        state,
+        mitm_proxy.max_redirects,
+    );
+    Ok(mitm_proxy.mitm_layer.layer(third_wheel))
+}
+
+/// Send an HTTP/3 request through `service` and answer it with the response,
+/// both bodies being buffered
+async fn answer_http3_request<S>(
+    request: http::Request<()>,
+    mut stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    mut service: S,
+) -> Result<(), Error>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await.map_err(http3_error)? {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    let response = service
+        .call(hyper_request(request, body)?)
+        .await
+        .map_err(|e| Error::ServerError(e.to_string()))?;
+
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    stream
+        .send_response(h3_response(&parts)?)
+        .await
+        .map_err(http3_error)?;
+    if !body.is_empty() {
+        stream.send_data(body).await.map_err(http3_error)?;
+    }
+    stream.finish().await.map_err(http3_error)
+}
+
+/// The request of a QUIC client as the mitm layer takes it, with the `Host`
+/// header it reads the target from, which HTTP/3 sends as `:authority`
+fn hyper_request(request: http::Request<()>, body: Vec<u8>) -> Result<Request<Body>, Error> {
+    let mut builder = Request::builder()
+        .method(request.method().as_str())
+        .uri(request.uri().to_string());
+    if let Some(authority) = request.uri().authority() {
+        if !request.headers().contains_key(http::header::HOST) {
+            builder = builder.header(HOST, authority.as_str());
+        }
+    }
+    for (name, value) in request.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    builder
+        .body(Body::from(body))
+        .map_err(|e| Error::RequestError(e.to_string()))
+}
+
+/// The head of a response to send to a QUIC client, without the headers
+/// HTTP/3 forbids
+fn h3_response(parts: &hyper::http::response::Parts) -> Result<http::Response<()>, Error> {
+    let mut builder = http::Response::builder().status(parts.status.as_u16());
+    for (name, value) in &parts.headers {
+        let connection_header = [CONNECTION, TRANSFER_ENCODING, UPGRADE].contains(name)
+            || CONNECTION_HEADERS.contains(&name.as_str());
+        if !connection_header {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+    }
+    builder
+        .body(())
+        .map_err(|e| Error::ServerError(e.to_string()))
+}
@@ -1,93 +1,585 @@
-use futures::Future;
+use futures::{Future, Stream};
+use har::v1_2::{self, Entries};
+use hyper::body::{Bytes, HttpBody, SizeHint};
 use hyper::{client::conn::SendRequest, service::Service, Body};
-use hyper::{header::HeaderName, Request, Response};
-use log::error;
+use hyper::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST,
+        LOCATION, STRICT_TRANSPORT_SECURITY, TRANSFER_ENCODING,
+    },
+    Method, Request, Response, StatusCode, Uri, Version,
+};
+use log::{error, warn};
+use std::any::Any;
+use std::fmt;
 use std::net::SocketAddr;
 use std::pin::Pin;
-use tokio::sync::{mpsc, oneshot};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Notify};
 use tower::Layer;
+use uuid::Uuid;
 
-use crate::third_wheel::error::Error;
+use crate::third_wheel::{error::Error, metrics::ProxyMetrics};
+use crate::utilities::{
+    copy_from_http_request_to_har, copy_from_http_response_to_har, failed_har_entry, har_entry,
+    preview_response, read_body_preview, record_response, record_timing, record_tls_parameters,
+    record_transport_security, record_when_read, redact_headers, request_url, strip_bodies,
+    STREAM_CAPTURE_LIMIT,
+};
 
 type RequestResponsePair = (
     oneshot::Sender<Result<Response<Body>, Error>>,
     Request<Body>,
 );
 
+/// Name of the header carrying the request id to the target server
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// Header asking clients to enforce certificate transparency for the host
+const EXPECT_CT: &str = "expect-ct";
+
+/// A unique identifier for each request passing through the proxy, used to
+/// correlate the forwarded request with its recorded HAR entry.
+///
+/// The id is stored in the request extensions, so the mitm closure can read it
+/// with `req.extensions().get::<RequestId>()`, and is sent to the target in the
+/// `X-Request-Id` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Get the id of a request, creating it the first time the request is seen.
+    /// An `X-Request-Id` header sent by the client is reused as the id.
+    pub fn get_or_insert(request: &mut Request<Body>) -> RequestId {
+        if let Some(request_id) = request.extensions().get::<RequestId>() {
+            return request_id.clone();
+        }
+        let request_id = RequestId(
+            request
+                .headers()
+                .get(X_REQUEST_ID)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| Uuid::new_v4().to_string()),
+        );
+        request.extensions_mut().insert(request_id.clone());
+        request_id
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The names of the headers of a request in the order the client sent them,
+/// repeated for each value, stored in its extensions when
+/// `MitmProxyBuilder::preserve_header_order` is set. The HAR entry of the
+/// request lists its headers in this order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderOrder(pub Vec<HeaderName>);
+
+/// The host a request is for, read from the headers given to
+/// `MitmProxyBuilder::host_resolution_headers` or else the CONNECT authority
+/// of its tunnel, stored in its extensions when such headers are set. The URL
+/// of the HAR entry of the request is built with it rather than its `Host`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogicalHost(pub String);
+
+/// What the proxy knows of the TLS sessions of a connection, the one with the
+/// client and the one with the target, stored in the extensions of its
+/// requests and of the responses returned by `ThirdWheel`. native-tls only
+/// exposes the protocol agreed on with ALPN, neither the TLS version nor the
+/// cipher suite of a session. Clients are only offered ALPN with the
+/// `alpn-mirroring` feature.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsParameters {
+    /// the protocol the client agreed on with ALPN, if any
+    pub client_alpn: Option<String>,
+    /// the protocol the target chose with ALPN, if any
+    pub upstream_alpn: Option<String>,
+}
+
+impl fmt::Display for TlsParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client ALPN {}, upstream ALPN {}, TLS version and cipher suite unknown",
+            self.client_alpn.as_deref().unwrap_or("none"),
+            self.upstream_alpn.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+/// When a request arrived at the mitm service, stored in its extensions
+#[derive(Clone, Copy, Debug)]
+struct RequestArrival(Instant);
+
+impl RequestArrival {
+    fn get_or_insert(request: &mut Request<Body>) -> Instant {
+        if let Some(arrival) = request.extensions().get::<RequestArrival>() {
+            return arrival.0;
+        }
+        let arrived = Instant::now();
+        request.extensions_mut().insert(RequestArrival(arrived));
+        arrived
+    }
+}
+
+/// When a request went through the proxy and its target, stored in the
+/// extensions of the response returned by `ThirdWheel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyTiming {
+    /// when the request arrived at the mitm service
+    pub arrived: Instant,
+    /// when the request was handed to the connection to the target
+    pub forwarded: Instant,
+    /// when the connection to the target took the last of the request body,
+    /// or when the response arrived if the target answered before that
+    pub sent: Instant,
+    /// when the headers of the response of the target arrived
+    pub responded: Instant,
+}
+
+impl ProxyTiming {
+    /// Time spent by the request in the proxy before being forwarded, as the
+    /// `blocked` timing of HAR
+    pub fn processing_time(&self) -> Duration {
+        self.forwarded.duration_since(self.arrived)
+    }
+
+    /// Time taken to send the request to the target, as the `send` timing
+    /// of HAR
+    pub fn send(&self) -> Duration {
+        self.sent.duration_since(self.forwarded)
+    }
+
+    /// Time waited for the target to answer, from sending the request to
+    /// receiving the response headers, as the `wait` timing of HAR
+    pub fn wait(&self) -> Duration {
+        self.responded.duration_since(self.sent)
+    }
+}
+
+/// A response that took longer than the latency SLA of its host to arrive,
+/// see `MitmProxyBuilder::latency_sla`. Stored in the extensions of the
+/// response returned by `ThirdWheel` alongside its `ProxyTiming`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlaViolation {
+    /// the SLA of the host
+    pub sla: Duration,
+    /// how long the target took to answer
+    pub wait: Duration,
+}
+
+impl fmt::Display for SlaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SLA violation: waited {} ms for a {} ms SLA",
+            self.wait.as_millis(),
+            self.sla.as_millis()
+        )
+    }
+}
+
+/// The transport security policies a target announced in a response, stored
+/// in the extensions of the response returned by `ThirdWheel` when it sent
+/// any. Clients honouring them may refuse to be intercepted on later visits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransportSecurity {
+    /// the response had a `Strict-Transport-Security` header
+    pub strict_transport_security: bool,
+    /// the response had an `Expect-CT` header
+    pub expect_ct: bool,
+}
+
+impl TransportSecurity {
+    /// The policies announced in the headers of a response, if any
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let transport_security = Self {
+            strict_transport_security: headers.contains_key(STRICT_TRANSPORT_SECURITY),
+            expect_ct: headers.contains_key(EXPECT_CT),
+        };
+        (transport_security.strict_transport_security || transport_security.expect_ct)
+            .then_some(transport_security)
+    }
+}
+
+impl fmt::Display for TransportSecurity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: Vec<&str> = [
+            (self.strict_transport_security, "Strict-Transport-Security"),
+            (self.expect_ct, "Expect-CT"),
+        ]
+        .into_iter()
+        .filter_map(|(sent, header)| sent.then_some(header))
+        .collect();
+        write!(f, "upstream sent {}", headers.join(" and "))
+    }
+}
+
+/// The body of a request forwarded to the target, noting when the connection
+/// took the last of it to time sending the request
+pub(crate) struct TimedBody {
+    inner: Body,
+    sent: Arc<OnceLock<Instant>>,
+}
+
+impl TimedBody {
+    fn new(inner: Body) -> (Self, Arc<OnceLock<Instant>>) {
+        let sent = Arc::new(OnceLock::new());
+        (
+            Self {
+                inner,
+                sent: sent.clone(),
+            },
+            sent,
+        )
+    }
+
+    fn mark_sent(&self) {
+        let _ = self.sent.set(Instant::now());
+    }
+}
+
+impl HttpBody for TimedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(None) = poll {
+            self.mark_sent();
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    /// The connection asks before reading the body, so an empty body is sent
+    /// along with the head
+    fn is_end_stream(&self) -> bool {
+        let end = HttpBody::is_end_stream(&self.inner);
+        if end {
+            self.mark_sent();
+        }
+        end
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        HttpBody::size_hint(&self.inner)
+    }
+}
+
 pub(crate) struct RequestSendingSynchronizer {
-    request_sender: SendRequest<Body>,
+    request_sender: SendRequest<TimedBody>,
     receiver: mpsc::UnboundedReceiver<RequestResponsePair>,
+    metrics: Arc<ProxyMetrics>,
+    host: String,
+    latency_sla: Option<Duration>,
+    /// whether the target speaks HTTP/2, the requests being sent without
+    /// waiting for the responses to the previous ones
+    multiplexed: bool,
 }
 
 impl RequestSendingSynchronizer {
     pub(crate) fn new(
-        request_sender: SendRequest<Body>,
+        request_sender: SendRequest<TimedBody>,
         receiver: mpsc::UnboundedReceiver<RequestResponsePair>,
+        metrics: Arc<ProxyMetrics>,
+        host: &str,
+        latency_sla: Option<Duration>,
+        multiplexed: bool,
     ) -> Self {
         Self {
             request_sender,
             receiver,
+            metrics,
+            host: host.to_string(),
+            latency_sla,
+            multiplexed,
         }
     }
 
+    /// Send the requests received to the target, answering each with the
+    /// final response of the target. Over HTTP/1.1 they are sent one at a
+    /// time, over HTTP/2 each is sent as soon as it is received.
+    ///
+    /// Interim `1xx` responses, such as `103 Early Hints`, are not relayed:
+    /// the hyper 0.14 client consumes them before yielding the final response,
+    /// and its server has no way to send them to the client. `100 Continue`
+    /// is still answered to clients by the server facing them, as soon as the
+    /// request body is read to be forwarded.
     pub(crate) async fn run(&mut self) {
         while let Some((sender, mut request)) = self.receiver.recv().await {
-            // Modified the URI to verify if it contains valid path
-            let relativized_uri = request
-                .uri()
-                .path_and_query()
-                .ok_or_else(|| Error::RequestError("URI did not contain a path".to_string()))
-                .and_then(|path| {
-                    path.as_str()
-                        .parse()
-                        .map_err(|_| Error::RequestError("Given URI was invalid".to_string()))
-                });
-
-            // If the path is valid, then send the request to the target by removing proxy-connection from the header
+            let target_uri = self.target_uri(&request);
+
+            // Wait for the target connection to be done with the previous
+            // exchange over HTTP/1.1, requests are sent one at a time in the
+            // order received so responses come back in that same order
+            let ready = futures::future::poll_fn(|cx| self.request_sender.poll_ready(cx))
+                .await
+                .map_err(Error::from);
+
+            // If the URI is valid, then send the request to the target by removing proxy-connection from the header
             // and catch the response future of the request
-            let response_fut = relativized_uri.map(|path| {
-                *request.uri_mut() = path;
+            let mut sent = None;
+            let response_fut = ready.and(target_uri).map(|uri| {
+                *request.uri_mut() = uri;
                 let proxy_connection: HeaderName = HeaderName::from_lowercase(b"proxy-connection")
                     .expect("Infallible: hardcoded header name");
                 request.headers_mut().remove(&proxy_connection);
-                self.request_sender.send_request(request)
+                let arrived = RequestArrival::get_or_insert(&mut request);
+                let (parts, body) = request.into_parts();
+                let (body, body_sent) = TimedBody::new(body);
+                sent = Some((arrived, Instant::now(), body_sent));
+                self.request_sender
+                    .send_request(Request::from_parts(parts, body))
             });
 
-            // Get the response from response future
-            let response_to_send = match response_fut {
-                Ok(response) => response.await.map_err(|e| e.into()),
-                Err(e) => Err(e),
-            };
+            // Get the response from response future, noting how long the
+            // proxy held the request and how long the target took to answer
+            let metrics = self.metrics.clone();
+            let host = self.host.clone();
+            let latency_sla = self.latency_sla;
+            let answer = async move {
+                let response_to_send = match response_fut {
+                    Ok(response) => response.await.map_err(|e| e.into()).map(|mut response| {
+                        if let Some((arrived, forwarded, body_sent)) = sent {
+                            let responded = Instant::now();
+                            let timing = ProxyTiming {
+                                arrived,
+                                forwarded,
+                                sent: body_sent.get().copied().unwrap_or(responded),
+                                responded,
+                            };
+                            metrics.record_forwarded_request(timing.processing_time());
+                            if let Some(sla) = latency_sla.filter(|sla| timing.wait() > *sla) {
+                                let violation = SlaViolation {
+                                    sla,
+                                    wait: timing.wait(),
+                                };
+                                warn!("Response of {}: {}", host, violation);
+                                metrics.record_sla_violation();
+                                response.extensions_mut().insert(violation);
+                            }
+                            response.extensions_mut().insert(timing);
+                        }
+                        if let Some(transport_security) =
+                            TransportSecurity::from_headers(response.headers())
+                        {
+                            if transport_security.strict_transport_security {
+                                metrics.record_hsts_host(&host);
+                            }
+                            response.extensions_mut().insert(transport_security);
+                        }
+                        response
+                    }),
+                    Err(e) => Err(e),
+                };
 
-            // Send the reponse to the client and that is no error after sending
-            if let Err(e) = sender.send(response_to_send) {
-                error!("Requester not available to receive request {:?}", e);
+                // Send the reponse to the client and that is no error after sending
+                if let Err(e) = sender.send(response_to_send) {
+                    error!("Requester not available to receive request {:?}", e);
+                }
+            };
+            if self.multiplexed {
+                tokio::spawn(answer);
+            } else {
+                answer.await;
             }
         }
     }
+
+    /// The URI a request is sent with: its path over HTTP/1.1, and over
+    /// HTTP/2 its absolute URI, whose authority is the `Host` of the requests
+    /// read from HTTP/1.1 clients
+    fn target_uri(&self, request: &Request<Body>) -> Result<Uri, Error> {
+        let path = request
+            .uri()
+            .path_and_query()
+            .ok_or_else(|| Error::RequestError("URI did not contain a path".to_string()))?;
+        let uri = if self.multiplexed {
+            let authority = request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+                .or_else(|| request.headers().get(HOST)?.to_str().ok())
+                .unwrap_or(&self.host);
+            format!("https://{}{}", authority, path)
+        } else {
+            path.to_string()
+        };
+        uri.parse()
+            .map_err(|_| Error::RequestError("Given URI was invalid".to_string()))
+    }
 }
 
+/// Most entries waiting in a `CaptureStream` before the next ones are dropped
+pub const CAPTURE_STREAM_CAPACITY: usize = 1024;
+
+/// The HAR entries of the exchanges forwarded by the proxy, returned by
+/// `MitmProxyBuilder::capture_stream`. It ends once the proxy and all its
+/// connections are gone. The entries a slow consumer leaves waiting are
+/// bounded by `CAPTURE_STREAM_CAPACITY`, the ones arriving past it are
+/// dropped and counted in `CaptureStream::dropped`.
+pub struct CaptureStream {
+    receiver: mpsc::Receiver<Entries>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl CaptureStream {
+    pub(crate) fn new() -> (CaptureSender, Self) {
+        let (sender, receiver) = mpsc::channel(CAPTURE_STREAM_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let sender = CaptureSender {
+            sender,
+            dropped: dropped.clone(),
+        };
+        (sender, Self { receiver, dropped })
+    }
+
+    /// Wait for the next captured exchange
+    #[allow(dead_code)]
+    pub async fn recv(&mut self) -> Option<Entries> {
+        self.receiver.recv().await
+    }
+
+    /// The number of entries dropped as the stream was full
+    #[allow(dead_code)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// The sending side of a `CaptureStream`, never waiting for its consumer
+#[derive(Clone)]
+pub(crate) struct CaptureSender {
+    sender: mpsc::Sender<Entries>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl CaptureSender {
+    /// Send an entry to the stream, dropping it if the stream is full.
+    /// Nobody listening to the capture is not an error.
+    pub(crate) fn send(&self, entry: Entries) {
+        if let Err(mpsc::error::TrySendError::Full(entry)) = self.sender.try_send(entry) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Capture stream full, dropped the entry of {}",
+                entry.request.url
+            );
+        }
+    }
+}
+
+impl Stream for CaptureStream {
+    type Item = Entries;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// State shared by the requests of a client connection, see
+/// `MitmProxyBuilder::connection_state_factory`
+pub type ConnectionState = Arc<dyn Any + Send + Sync>;
+
 /// A service that will proxy traffic to a target server and return unmodified responses
 #[derive(Clone)]
 pub struct ThirdWheel {
     sender: mpsc::UnboundedSender<RequestResponsePair>,
     client_ip: SocketAddr,
+    server_ip: Option<SocketAddr>,
+    target_host: String,
+    target_port: u16,
+    capture: Option<CaptureSender>,
+    record_bodies: bool,
+    body_preview: Option<usize>,
+    redact_headers: Arc<[HeaderName]>,
+    state: Option<ConnectionState>,
+    max_redirects: usize,
 }
 
 impl ThirdWheel {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         sender: mpsc::UnboundedSender<RequestResponsePair>,
         client_ip: SocketAddr,
+        server_ip: Option<SocketAddr>,
+        target_host: &str,
+        target_port: u16,
+        capture: Option<CaptureSender>,
+        record_bodies: bool,
+        body_preview: Option<usize>,
+        redact_headers: Arc<[HeaderName]>,
+        state: Option<ConnectionState>,
+        max_redirects: usize,
     ) -> Self {
         Self {
             sender,
             client_ip, // Store the client IP
+            server_ip,
+            target_host: target_host.to_string(),
+            target_port,
+            capture,
+            record_bodies,
+            body_preview,
+            redact_headers,
+            state,
+            max_redirects,
         }
     }
 
     pub fn get_client_ip(&self) -> SocketAddr {
         self.client_ip
     }
+
+    /// The address the proxy connected to upstream, the one of the SOCKS5
+    /// proxy when there is one. `None` for a Unix domain socket.
+    pub fn get_server_ip(&self) -> Option<SocketAddr> {
+        self.server_ip
+    }
+
+    /// The host of the `CONNECT` request of the tunnel, which the `Host`
+    /// header of its requests may not match
+    #[allow(dead_code)]
+    pub fn get_target_host(&self) -> &str {
+        &self.target_host
+    }
+
+    /// The port of the `CONNECT` request of the tunnel
+    #[allow(dead_code)]
+    pub fn get_target_port(&self) -> u16 {
+        self.target_port
+    }
+
+    /// The headers whose values are redacted in the recorded exchanges
+    pub fn get_redacted_headers(&self) -> &[HeaderName] {
+        &self.redact_headers
+    }
+
+    /// The state of the client connection, if one was created for it and it
+    /// is a `T`
+    #[allow(dead_code)]
+    pub fn get_state<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.state.as_ref()?.downcast_ref::<T>()
+    }
 }
 
 impl Service<Request<Body>> for ThirdWheel {
@@ -106,23 +598,361 @@ impl Service<Request<Body>> for ThirdWheel {
 
     /// ThirdWheel performs very little modification of the request before
     /// transmitting it, but it does remove the proxy-connection header to
-    /// ensure this is not passed to the target
-    fn call(&mut self, request: Request<Body>) -> Self::Future {
-        let (response_sender, response_receiver) = oneshot::channel();
+    /// ensure this is not passed to the target, and adds an `X-Request-Id`
+    /// header if the client did not send one. The response carries the
+    /// `ProxyTiming` of the request in its extensions, and the
+    /// `TlsParameters` of its connection when it came over TLS. When the
+    /// exchanges are
+    /// captured, the HAR entry is sent once the response body was recorded,
+    /// or with an empty response of status 0 if the request failed. Its bodies
+    /// are left out unless `MitmProxyBuilder::record_bodies` is set, only
+    /// read up to the `MitmProxyBuilder::body_preview` when there is one, and
+    /// the values of the `MitmProxyBuilder::redact_headers` are redacted.
+    ///
+    /// With `MitmProxyBuilder::follow_redirects`, redirects of the target to
+    /// itself are followed and only the final response is returned. Each hop
+    /// is captured as its own entry, whose `redirect_url` is the URL of the
+    /// entry following it.
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        RequestArrival::get_or_insert(&mut request);
+        let request_id = RequestId::get_or_insert(&mut request);
+        if !request.headers().contains_key(X_REQUEST_ID) {
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                request
+                    .headers_mut()
+                    .insert(HeaderName::from_static(X_REQUEST_ID), value);
+            }
+        }
+
+        let tls_parameters = request.extensions().get::<TlsParameters>().cloned();
         let sender = self.sender.clone();
+        let capture = self.capture.clone();
+        let client_ip = self.client_ip;
+        let server_ip = self.server_ip;
+        let record_bodies = self.record_bodies;
+        let body_preview = self.body_preview;
+        let redacted_headers = self.redact_headers.clone();
+        let max_redirects = self.max_redirects;
         let fut = async move {
-            //TODO: clarify what errors are possible here
-            sender.send((response_sender, request)).map_err(|_| {
-                Error::ServerError("Failed to connect to server correctly".to_string())
-            })?;
-            response_receiver
-                .await
-                .map_err(|_| Error::ServerError("Failed to get response from server".to_string()))?
+            // Buffer the request to send it again to where the target
+            // redirects it, otherwise only its start to record it, streaming
+            // the rest on
+            let mut body_bytes = None;
+            let mut truncated = false;
+            let capture_limit = body_preview.unwrap_or(STREAM_CAPTURE_LIMIT);
+            let incomplete = match body_preview {
+                Some(_) => "preview",
+                None => "truncated",
+            };
+            if max_redirects > 0 {
+                let (parts, body) = request.into_parts();
+                let bytes = hyper::body::to_bytes(body).await?;
+                request = Request::from_parts(parts, Body::from(bytes.clone()));
+                body_bytes = Some(bytes);
+            } else if capture.is_some() {
+                let (parts, body) = request.into_parts();
+                let (mut preview, body) = read_body_preview(body, capture_limit + 1).await?;
+                truncated = preview.len() > capture_limit;
+                preview.truncate(capture_limit);
+                request = Request::from_parts(parts, body);
+                body_bytes = Some(Bytes::from(preview));
+            }
+
+            let mut redirects = 0;
+            loop {
+                let (parts, body) = request.into_parts();
+                let har_request = match (&capture, &body_bytes) {
+                    (Some(_), Some(body_bytes)) => {
+                        let mut har_request =
+                            copy_from_http_request_to_har(&parts, body_bytes.to_vec()).await;
+                        if truncated {
+                            mark_incomplete(&mut har_request, incomplete);
+                        }
+                        Some(har_request)
+                    }
+                    _ => None,
+                };
+                let head = (max_redirects > 0).then(|| request_head(&parts));
+                request = Request::from_parts(parts, body);
+
+                let (response_sender, response_receiver) = oneshot::channel();
+                //TODO: clarify what errors are possible here
+                sender.send((response_sender, request)).map_err(|_| {
+                    Error::ServerError("Failed to connect to server correctly".to_string())
+                })?;
+                let response = response_receiver
+                    .await
+                    .map_err(|_| {
+                        Error::ServerError("Failed to get response from server".to_string())
+                    })
+                    .and_then(|response| response);
+                let response = match response {
+                    Ok(mut response) => {
+                        if let Some(tls_parameters) = &tls_parameters {
+                            response.extensions_mut().insert(tls_parameters.clone());
+                        }
+                        response
+                    }
+                    Err(err) => {
+                        // A request that could not be forwarded is still captured
+                        if let (Some(capture), Some(har_request)) = (&capture, har_request) {
+                            let mut entry = failed_har_entry(
+                                har_request,
+                                client_ip,
+                                server_ip,
+                                Some(&request_id),
+                                &err,
+                            );
+                            if !record_bodies {
+                                strip_bodies(&mut entry);
+                            }
+                            redact_headers(&mut entry, &redacted_headers);
+                            capture.send(entry);
+                        }
+                        return Err(err);
+                    }
+                };
+
+                let next = match (&head, &body_bytes) {
+                    (Some(head), Some(body_bytes)) if redirects < max_redirects => {
+                        redirected_request(head, body_bytes, &response)
+                    }
+                    _ => None,
+                };
+
+                let response = match (&capture, har_request) {
+                    (Some(capture), Some(har_request)) => {
+                        let (parts, body) = response.into_parts();
+                        let receiving = Instant::now();
+                        let (har_response, body) = match body_preview {
+                            Some(limit) => preview_response(&parts, body, limit),
+                            None => record_response(&parts, body).await?,
+                        };
+                        // The response is recorded as it is sent on, the entry
+                        // gets it once the body was read
+                        let mut entry = har_entry(
+                            har_request,
+                            copy_from_http_response_to_har(&parts, Vec::new()).await,
+                            client_ip,
+                            server_ip,
+                            Some(&request_id),
+                        );
+                        record_timing(&mut entry, &parts.extensions);
+                        record_transport_security(&mut entry, &parts.extensions);
+                        record_tls_parameters(&mut entry, &parts.extensions);
+                        // Link the entry to the one of the request following it
+                        let redirect_url =
+                            next.as_ref().map(|(next_parts, _)| request_url(next_parts));
+                        let capture = capture.clone();
+                        let redacted_headers = redacted_headers.clone();
+                        record_when_read(async move {
+                            entry.response = har_response.await;
+                            entry.timings.receive = receiving.elapsed().as_secs_f64() * 1000.0;
+                            if entry.timings.blocked.is_some() {
+                                entry.time += entry.timings.receive;
+                            }
+                            if redirect_url.is_some() {
+                                entry.response.redirect_url = redirect_url;
+                            }
+                            if !record_bodies {
+                                strip_bodies(&mut entry);
+                            }
+                            redact_headers(&mut entry, &redacted_headers);
+                            capture.send(entry);
+                        });
+                        Response::from_parts(parts, body)
+                    }
+                    _ => response,
+                };
+
+                match next {
+                    Some((next_parts, next_body)) => {
+                        // Read the redirect through so the connection to the
+                        // target can carry the next request
+                        hyper::body::to_bytes(response.into_body()).await?;
+                        body_bytes = Some(next_body.clone());
+                        request = Request::from_parts(next_parts, Body::from(next_body));
+                        redirects += 1;
+                    }
+                    None => return Ok(response),
+                }
+            }
         };
         Box::pin(fut)
     }
 }
 
+/// Mark the body of a recorded request as only its start, `"truncated"` or
+/// `"preview"`, its whole size being unknown
+fn mark_incomplete(har_request: &mut v1_2::Request, incomplete: &str) {
+    har_request.body_size = -1;
+    if let Some(post_data) = har_request.post_data.as_mut() {
+        // Keep the note of a base64 body
+        post_data.comment = Some(match post_data.comment.take() {
+            Some(note) => format!("{}, {}", incomplete, note),
+            None => incomplete.to_string(),
+        });
+    }
+}
+
+/// The head of a request, kept to build the request following a redirect
+fn request_head(parts: &hyper::http::request::Parts) -> Request<()> {
+    let mut head = Request::new(());
+    *head.method_mut() = parts.method.clone();
+    *head.uri_mut() = parts.uri.clone();
+    *head.version_mut() = parts.version;
+    *head.headers_mut() = parts.headers.clone();
+    if let Some(request_id) = parts.extensions.get::<RequestId>() {
+        head.extensions_mut().insert(request_id.clone());
+    }
+    if let Some(header_order) = parts.extensions.get::<HeaderOrder>() {
+        head.extensions_mut().insert(header_order.clone());
+    }
+    if let Some(logical_host) = parts.extensions.get::<LogicalHost>() {
+        head.extensions_mut().insert(logical_host.clone());
+    }
+    head
+}
+
+/// The request to send after a response redirecting it, with its body.
+/// `None` if the response is no redirect or sends it away from the target,
+/// only absolute paths and `https` URLs of the same authority are followed.
+/// As browsers do, `303` and a `301` or `302` answering a `POST` are followed
+/// with a `GET` without body, other redirects repeat the method and body.
+fn redirected_request(
+    head: &Request<()>,
+    body: &Bytes,
+    response: &Response<Body>,
+) -> Option<(hyper::http::request::Parts, Bytes)> {
+    let status = response.status();
+    if !matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    ) {
+        return None;
+    }
+    let location: Uri = response
+        .headers()
+        .get(LOCATION)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    match (location.scheme_str(), location.authority()) {
+        (None, None) if location.path().starts_with('/') => {}
+        (Some("https"), Some(authority)) => {
+            let host = head.headers().get(HOST)?.to_str().ok()?;
+            if !authority.as_str().eq_ignore_ascii_case(host) {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+    let path = location.path_and_query()?.as_str().parse().ok()?;
+
+    let mut request = Request::new(());
+    *request.uri_mut() = path;
+    *request.version_mut() = head.version();
+    *request.headers_mut() = head.headers().clone();
+    if let Some(request_id) = head.extensions().get::<RequestId>() {
+        request.extensions_mut().insert(request_id.clone());
+    }
+    if let Some(header_order) = head.extensions().get::<HeaderOrder>() {
+        request.extensions_mut().insert(header_order.clone());
+    }
+    if let Some(logical_host) = head.extensions().get::<LogicalHost>() {
+        request.extensions_mut().insert(logical_host.clone());
+    }
+    let to_get = status == StatusCode::SEE_OTHER
+        || (head.method() == Method::POST
+            && matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND));
+    let body = if to_get {
+        *request.method_mut() = Method::GET;
+        for header in [CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING] {
+            request.headers_mut().remove(header);
+        }
+        Bytes::new()
+    } else {
+        *request.method_mut() = head.method().clone();
+        body.clone()
+    };
+    let (parts, ()) = request.into_parts();
+    Some((parts, body))
+}
+
+/// Wraps the service handling a client connection to close the connection
+/// once it served a maximum number of requests. `capped` is notified when the
+/// last request is received, for the connection to be shut down gracefully:
+/// HTTP/2 clients are only told to stop opening streams by a `GOAWAY`.
+pub(crate) struct CappedService<S> {
+    inner: S,
+    served: usize,
+    max_requests: Option<usize>,
+    metrics: Arc<ProxyMetrics>,
+    capped: Arc<Notify>,
+}
+
+impl<S> CappedService<S> {
+    pub(crate) fn new(
+        inner: S,
+        max_requests: Option<usize>,
+        metrics: Arc<ProxyMetrics>,
+        capped: Arc<Notify>,
+    ) -> Self {
+        Self {
+            inner,
+            served: 0,
+            max_requests,
+            metrics,
+            capped,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for CappedService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    /// Once the last allowed request is reached the connection is shut down,
+    /// its HTTP/1.1 response also asking for the connection to be closed
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        self.served += 1;
+        let last_request = self.max_requests == Some(self.served);
+        if last_request {
+            self.metrics.record_capped_connection();
+            self.capped.notify_one();
+        }
+        let last_request = last_request && request.version() != Version::HTTP_2;
+
+        let fut = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = fut.await?;
+            if last_request {
+                response
+                    .headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            Ok(response)
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct MitmService<F: Clone, S: Clone> {
     // MitmLayer
@@ -153,8 +983,11 @@ where
         self.inner.poll_ready(cx)
     }
 
-    // Call of the thirdwheel service
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
+    // Call of the thirdwheel service, giving the request its id and arrival
+    // time first so the closure can see them
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        RequestArrival::get_or_insert(&mut req);
+        RequestId::get_or_insert(&mut req);
         (self.f)(req, self.inner.clone())
     }
 }
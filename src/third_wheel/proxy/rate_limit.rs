@@ -0,0 +1,127 @@
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{service::Service, Body, Request, Response, StatusCode};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::third_wheel::metrics::ProxyMetrics;
+
+/// A sliding window limiting the requests of each client IP address. The
+/// times of the requests within the window are kept per address, and the
+/// addresses which sent none within it are evicted once per window.
+pub(crate) struct RateLimiter {
+    per_ip_requests: u32,
+    window: Duration,
+    /// the times of the requests of each address within the window, oldest
+    /// first, and when the idle addresses were last evicted
+    state: Mutex<(HashMap<IpAddr, VecDeque<Instant>>, Instant)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_ip_requests: u32, window: Duration) -> Self {
+        Self {
+            per_ip_requests,
+            window,
+            state: Mutex::new((HashMap::new(), Instant::now())),
+        }
+    }
+
+    /// Count a request of `ip`, returning how long until it may send another
+    /// if it already sent as many as allowed within the window
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (buckets, last_eviction) = &mut *state;
+        let now = Instant::now();
+        if now.duration_since(*last_eviction) >= self.window {
+            buckets.retain(|_, requests| {
+                requests
+                    .back()
+                    .is_some_and(|last| now.duration_since(*last) < self.window)
+            });
+            *last_eviction = now;
+        }
+
+        let requests = buckets.entry(ip).or_default();
+        while requests
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= self.window)
+        {
+            requests.pop_front();
+        }
+        if requests.len() >= self.per_ip_requests as usize {
+            let retry_after = requests
+                .front()
+                .map(|first| self.window.saturating_sub(now.duration_since(*first)))
+                .unwrap_or(self.window);
+            return Err(retry_after);
+        }
+        requests.push_back(now);
+        Ok(())
+    }
+}
+
+/// Wraps the service handling a client connection to answer the requests
+/// beyond the rate limit of the client with `429 Too Many Requests`, without
+/// passing them on
+pub(crate) struct RateLimited<S> {
+    inner: S,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    client_ip: IpAddr,
+    metrics: Arc<ProxyMetrics>,
+}
+
+impl<S> RateLimited<S> {
+    pub(crate) fn new(
+        inner: S,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        client_ip: IpAddr,
+        metrics: Arc<ProxyMetrics>,
+    ) -> Self {
+        Self {
+            inner,
+            rate_limiter,
+            client_ip,
+            metrics,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for RateLimited<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let limited = self
+            .rate_limiter
+            .as_ref()
+            .and_then(|rate_limiter| rate_limiter.check(self.client_ip).err());
+        let Some(retry_after) = limited else {
+            return Box::pin(self.inner.call(request));
+        };
+        self.metrics.record_rate_limited_request();
+        // Retry-After is in whole seconds, rounded up so the client does not
+        // come back too early
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        let mut response = Response::new(Body::from("Too many requests"));
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        Box::pin(futures::future::ready(Ok(response)))
+    }
+}
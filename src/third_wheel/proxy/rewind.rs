@@ -0,0 +1,102 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// First byte of a TLS handshake record, which a ClientHello always starts with
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Length of the TLS record starting `bytes` including its 5 bytes header, if
+/// the header was read
+fn record_len(bytes: &[u8]) -> Option<usize> {
+    (bytes.len() >= 5).then(|| 5 + u16::from_be_bytes([bytes[3], bytes[4]]) as usize)
+}
+
+/// A stream whose first bytes were already read, giving them back before
+/// reading from the underlying stream again. This lets us peek at what a client
+/// sends on a tunnel before handing the stream to TLS or HTTP.
+pub(crate) struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> Rewind<S> {
+    /// Read the first bytes sent on the stream and check whether they start a
+    /// TLS handshake, reading the whole first record if they do. Returns `None` if the stream was closed before sending
+    /// anything.
+    pub(crate) async fn peek_is_tls(mut inner: S) -> io::Result<Option<(Self, bool)>> {
+        let mut prefix = vec![0u8; 512];
+        let read = inner.read(&mut prefix).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        prefix.truncate(read);
+        let is_tls = prefix[0] == TLS_HANDSHAKE_RECORD;
+
+        // Keep reading until the whole first record is buffered so the
+        // ClientHello can be inspected, clients send it in one go
+        if is_tls {
+            while let Some(record_len) = record_len(&prefix) {
+                if prefix.len() >= record_len {
+                    break;
+                }
+                let mut rest = vec![0u8; record_len - prefix.len()];
+                let read = inner.read(&mut rest).await?;
+                if read == 0 {
+                    break;
+                }
+                prefix.extend_from_slice(&rest[..read]);
+            }
+        }
+        Ok(Some((
+            Self {
+                prefix,
+                position: 0,
+                inner,
+            },
+            is_tls,
+        )))
+    }
+}
+
+impl<S> Rewind<S> {
+    /// The bytes read when peeking
+    pub(crate) fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let end = self.prefix.len().min(self.position + buf.remaining());
+            buf.put_slice(&self.prefix[self.position..end]);
+            self.position = end;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::third_wheel::proxy::SigningRate;
+
+/// A token bucket limiting how fast certificates are signed. Signings beyond
+/// the burst wait for their token in turn, unless they would wait longer than
+/// allowed.
+pub(crate) struct SigningLimiter {
+    rate: SigningRate,
+    /// tokens left, negative when signings are waiting, and when they were
+    /// last counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl SigningLimiter {
+    pub(crate) fn new(rate: SigningRate) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((f64::from(rate.certificates), Instant::now())),
+        }
+    }
+
+    /// Reserve the right to sign a certificate, or `None` if it would have to
+    /// wait longer than `max_wait`
+    pub(crate) fn reserve(&self) -> Option<SigningPermit> {
+        if self.rate.certificates == 0 {
+            return None;
+        }
+        let capacity = f64::from(self.rate.certificates);
+        let per_token = self.rate.per / self.rate.certificates;
+
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last_counted) = &mut *state;
+        let now = Instant::now();
+        let refilled = now.duration_since(*last_counted).as_secs_f64() / per_token.as_secs_f64();
+        *tokens = (*tokens + refilled).min(capacity);
+        *last_counted = now;
+
+        let wait = if *tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            per_token.mul_f64(1.0 - *tokens)
+        };
+        if wait > self.rate.max_wait {
+            return None;
+        }
+        *tokens -= 1.0;
+        Some(SigningPermit { ready: now + wait })
+    }
+}
+
+/// The right to sign a certificate once the rate allows it, reserved before
+/// the tunnel needing it is accepted
+pub(crate) struct SigningPermit {
+    ready: Instant,
+}
+
+impl SigningPermit {
+    /// Wait until the certificate may be signed
+    pub(crate) async fn wait(self) {
+        tokio::time::sleep_until(self.ready.into()).await;
+    }
+}
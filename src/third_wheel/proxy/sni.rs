@@ -0,0 +1,76 @@
+/// Handshake message type of a ClientHello
+const CLIENT_HELLO: u8 = 1;
+
+/// Extension carrying the server name indication
+const SERVER_NAME_EXTENSION: u16 = 0;
+
+/// Reads a TLS record containing a ClientHello without validating it
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// Take a block prefixed by its length on `len_bytes` bytes
+    fn block(&mut self, len_bytes: usize) -> Option<Reader<'a>> {
+        let len = self
+            .take(len_bytes)?
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        self.take(len).map(|bytes| Reader { bytes })
+    }
+}
+
+/// Find the server name a client asked for in the first bytes it sent, if
+/// they hold a ClientHello with a server name indication. Returns `None` if
+/// the hello is cut before the extension.
+pub(crate) fn server_name(client_hello: &[u8]) -> Option<String> {
+    let mut record = Reader {
+        bytes: client_hello,
+    };
+    // Record header: content type, version and length, the hello may be
+    // longer than what was read so the length is not checked
+    record.take(5)?;
+    if record.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // Handshake length, client version and random
+    record.take(3 + 2 + 32)?;
+    // Session id, cipher suites and compression methods
+    record.block(1)?;
+    record.block(2)?;
+    record.block(1)?;
+
+    let mut extensions = record.block(2)?;
+    while let Some(extension_type) = extensions.u16() {
+        let mut extension = extensions.block(2)?;
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let mut names = extension.block(2)?;
+        while let Some(name_type) = names.u8() {
+            let name = names.block(2)?;
+            // Only host names are defined
+            if name_type == 0 {
+                return String::from_utf8(name.bytes.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
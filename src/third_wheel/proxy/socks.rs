@@ -0,0 +1,44 @@
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::third_wheel::{error::Error, proxy::Socks5Auth};
+
+/// A SOCKS5 proxy the connections to the targets go through
+#[derive(Clone, Debug)]
+pub(crate) struct Socks5Proxy {
+    pub(crate) addr: SocketAddr,
+    pub(crate) auth: Option<Socks5Auth>,
+}
+
+impl Socks5Proxy {
+    /// Open a tunnel to `address`, a `host:port` as built by the host
+    /// mappings, through the proxy
+    pub(crate) async fn connect(&self, address: &str) -> Result<TcpStream, Error> {
+        let (host, port) = address
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| Error::ConfigError(format!("invalid target address {}", address)))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let stream = match &self.auth {
+            Some(auth) => {
+                Socks5Stream::connect_with_password(
+                    self.addr,
+                    (host, port),
+                    &auth.username,
+                    &auth.password,
+                )
+                .await
+            }
+            None => Socks5Stream::connect(self.addr, (host, port)).await,
+        }
+        .map_err(|e| {
+            Error::ServerError(format!(
+                "SOCKS5 proxy: connecting to {} failed: {}",
+                address, e
+            ))
+        })?;
+        Ok(stream.into_inner())
+    }
+}
@@ -0,0 +1,134 @@
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+
+use crate::third_wheel::error::Error;
+
+/// Longest line relayed, well above the 1000 bytes SMTP allows for a command
+/// or a line of a message
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+/// Reply of an SMTP server accepting the message to follow `DATA`
+const START_MAIL_INPUT: u16 = 354;
+
+/// Reply of an SMTP server ready to start the TLS handshake
+const SERVICE_READY: u16 = 220;
+
+/// Relay the plaintext SMTP session between `client` and `target`, command by
+/// command, until the target accepts a `STARTTLS` of the client.
+///
+/// # Returns
+/// `true` once the target answered `STARTTLS` with `220`, both sides being
+/// about to start the TLS handshake, `false` if either side closed the
+/// session before.
+pub(crate) async fn relay_smtp_until_starttls<C, T>(
+    client: &mut C,
+    target: &mut T,
+) -> Result<bool, Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client = BufReader::new(client);
+    let mut target = BufReader::new(target);
+
+    // The target greets the client first
+    if relay_reply(&mut target, &mut client).await?.is_none() {
+        return Ok(false);
+    }
+    loop {
+        let Some(command) = read_line(&mut client).await? else {
+            return Ok(false);
+        };
+        target.write_all(&command).await?;
+        target.flush().await?;
+        let Some(code) = relay_reply(&mut target, &mut client).await? else {
+            return Ok(false);
+        };
+
+        if is_starttls(&command) && code == SERVICE_READY {
+            // Bytes sent along with STARTTLS would not be part of the
+            // handshake, RFC 3207 has them discarded
+            if !client.buffer().is_empty() || !target.buffer().is_empty() {
+                return Err(Error::RequestError(
+                    "data was sent before the TLS handshake of STARTTLS".to_string(),
+                ));
+            }
+            return Ok(true);
+        }
+        if code == START_MAIL_INPUT {
+            // The message follows, up to a line holding a single dot
+            loop {
+                let Some(line) = read_line(&mut client).await? else {
+                    return Ok(false);
+                };
+                target.write_all(&line).await?;
+                if line == b".\r\n" || line == b".\n" {
+                    break;
+                }
+            }
+            target.flush().await?;
+            if relay_reply(&mut target, &mut client).await?.is_none() {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Whether a command of the client is `STARTTLS`
+fn is_starttls(command: &[u8]) -> bool {
+    String::from_utf8_lossy(command)
+        .trim()
+        .eq_ignore_ascii_case("STARTTLS")
+}
+
+/// Read a reply of the target, of one or more lines, and send it on to the
+/// client. Returns its code, `None` if the target closed the session.
+async fn relay_reply<R, W>(target: &mut R, client: &mut W) -> Result<Option<u16>, Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let Some(line) = read_line(target).await? else {
+            return Ok(None);
+        };
+        client.write_all(&line).await?;
+        // The code of the last line is followed by a space, the others by a dash
+        if line.get(3) == Some(&b'-') {
+            continue;
+        }
+        client.flush().await?;
+        let code = line
+            .get(..3)
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| {
+                Error::ServerError(format!(
+                    "invalid SMTP reply: {}",
+                    String::from_utf8_lossy(&line).trim_end()
+                ))
+            })?;
+        return Ok(Some(code));
+    }
+}
+
+/// Read a line with its line ending, `None` at the end of the stream
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LENGTH)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.ends_with(b"\n") {
+        Ok(Some(line))
+    } else if line.len() as u64 == MAX_LINE_LENGTH {
+        Err(Error::RequestError(format!(
+            "SMTP line longer than {} bytes",
+            MAX_LINE_LENGTH
+        )))
+    } else {
+        // A line cut by the end of the stream is not relayed
+        Ok(None)
+    }
+}
@@ -0,0 +1,66 @@
+use hyper::service::Service;
+use hyper::{Body, Request};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_native_tls::TlsStream;
+
+use crate::third_wheel::proxy::mitm::TlsParameters;
+
+/// The protocol agreed on with ALPN during the handshake of a TLS session
+fn negotiated_alpn<S: AsyncRead + AsyncWrite + Unpin>(stream: &TlsStream<S>) -> Option<String> {
+    stream
+        .get_ref()
+        .negotiated_alpn()
+        .ok()
+        .flatten()
+        .map(|protocol| String::from_utf8_lossy(&protocol).into_owned())
+}
+
+impl TlsParameters {
+    /// The parameters of the session with the client over `client_stream`,
+    /// the target having chosen `upstream_alpn`
+    pub(crate) fn new<S: AsyncRead + AsyncWrite + Unpin>(
+        client_stream: &TlsStream<S>,
+        upstream_alpn: Option<&[u8]>,
+    ) -> Self {
+        Self {
+            client_alpn: negotiated_alpn(client_stream),
+            upstream_alpn: upstream_alpn
+                .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        }
+    }
+}
+
+/// Wraps the service handling a client connection over TLS to store in each
+/// request the `TlsParameters` of the connection
+pub(crate) struct RecordTlsParameters<S> {
+    inner: S,
+    tls_parameters: TlsParameters,
+}
+
+impl<S> RecordTlsParameters<S> {
+    pub(crate) fn new(inner: S, tls_parameters: TlsParameters) -> Self {
+        Self {
+            inner,
+            tls_parameters,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for RecordTlsParameters<S>
+where
+    S: Service<Request<Body>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        request.extensions_mut().insert(self.tls_parameters.clone());
+        self.inner.call(request)
+    }
+}
@@ -0,0 +1,175 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_native_tls::TlsStream;
+
+use crate::third_wheel::{
+    error::Error,
+    host_mapping::Target,
+    proxy::{http_connect::HttpConnectProxy, socks::Socks5Proxy},
+};
+
+/// A proxy the connections to the targets are tunneled through
+#[derive(Clone, Debug)]
+pub(crate) enum UpstreamProxy {
+    Socks5(Socks5Proxy),
+    HttpConnect(HttpConnectProxy),
+}
+
+impl UpstreamProxy {
+    /// Open a tunnel to `address`, a `host:port` as built by the host
+    /// mappings, through the proxy
+    async fn connect(&self, address: &str) -> Result<TcpStream, Error> {
+        match self {
+            Self::Socks5(proxy) => proxy.connect(address).await,
+            Self::HttpConnect(proxy) => proxy.connect(address).await,
+        }
+    }
+}
+
+/// The transport of a connection to a target, before any TLS
+pub(crate) enum UpstreamStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    /// Connect to `target`, tunneling TCP targets through `upstream_proxy` if
+    /// given. Unix domain sockets are local and always connected to directly.
+    pub(crate) async fn connect(
+        target: &Target,
+        upstream_proxy: Option<&UpstreamProxy>,
+    ) -> Result<Self, Error> {
+        match target {
+            Target::Tcp(address) => match upstream_proxy {
+                Some(upstream_proxy) => Ok(Self::Tcp(upstream_proxy.connect(address).await?)),
+                None => Ok(Self::Tcp(TcpStream::connect(address).await?)),
+            },
+            #[cfg(unix)]
+            Target::Unix { path, .. } => Ok(Self::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Target::Unix { path, .. } => Err(Error::ConfigError(format!(
+                "cannot connect to {}: Unix domain sockets are not supported on this platform",
+                path.display()
+            ))),
+        }
+    }
+}
+
+impl UpstreamStream {
+    /// The address connected to, `None` for a Unix domain socket
+    pub(crate) fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+/// The connection to a target requests are sent on, over TLS unless the
+/// target is a Unix domain socket mapped as plaintext
+pub(crate) enum TargetStream {
+    Tls(TlsStream<UpstreamStream>),
+    Plain(UpstreamStream),
+}
+
+impl TargetStream {
+    /// The protocol the target chose with ALPN during the TLS handshake, if
+    /// any
+    pub(crate) fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Tls(stream) => stream.get_ref().negotiated_alpn().ok().flatten(),
+            Self::Plain(_) => None,
+        }
+    }
+
+    /// Whether the target chose HTTP/2 during the TLS handshake
+    pub(crate) fn is_http2(&self) -> bool {
+        self.alpn_protocol().as_deref() == Some(&b"h2"[..])
+    }
+}
+
+// Every stream is Unpin, so the variants are polled through `Pin::new`
+macro_rules! delegate {
+    ($self:ident, $stream:ident => $call:expr) => {
+        match $self.get_mut() {
+            UpstreamStream::Tcp($stream) => $call,
+            #[cfg(unix)]
+            UpstreamStream::Unix($stream) => $call,
+        }
+    };
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_shutdown(cx))
+    }
+}
+
+impl AsyncRead for TargetStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TargetStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
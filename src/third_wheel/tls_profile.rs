@@ -0,0 +1,53 @@
+use native_tls::{Identity, Protocol, TlsConnectorBuilder};
+
+/// TLS settings for the connections to some target servers, set for a host
+/// pattern with `MitmProxyBuilder::host_tls_profile`. Settings left unset
+/// keep the proxy-wide value.
+///
+/// ```ignore
+/// let lenient = TlsProfile {
+///     accept_invalid_certs: true,
+///     ..TlsProfile::default()
+/// };
+/// let mitm_proxy = MitmProxy::builder(mitm, ca)
+///     .host_tls_profile("*.internal.example.com", lenient)
+///     .build();
+/// ```
+#[derive(Clone, Default)]
+pub struct TlsProfile {
+    /// lowest TLS version accepted from the target
+    pub min_protocol_version: Option<Protocol>,
+    /// client certificate to present to the target
+    pub client_identity: Option<Identity>,
+    /// accept certificates not signed by a trusted authority or expired.
+    /// Meant for internal servers with self-signed certificates only.
+    pub accept_invalid_certs: bool,
+    /// whether the certificate must be issued for the host connected to
+    pub verify_hostname: Option<bool>,
+    /// protocols offered to the target with ALPN instead of HTTP/2 and
+    /// HTTP/1.1, `http/1.1` alone keeping the target on HTTP/1.1
+    pub alpn_protocols: Option<Vec<String>>,
+}
+
+impl TlsProfile {
+    /// Fill the settings left unset with the proxy-wide ones
+    pub(crate) fn or_defaults(
+        mut self,
+        client_identity: Option<Identity>,
+        verify_hostname: bool,
+    ) -> Self {
+        self.client_identity = self.client_identity.or(client_identity);
+        self.verify_hostname = Some(self.verify_hostname.unwrap_or(verify_hostname));
+        self
+    }
+
+    /// Apply the profile to the connector used to reach the target
+    pub(crate) fn configure(self, connector: &mut TlsConnectorBuilder) {
+        if let Some(identity) = self.client_identity {
+            connector.identity(identity);
+        }
+        connector.min_protocol_version(self.min_protocol_version);
+        connector.danger_accept_invalid_certs(self.accept_invalid_certs);
+        connector.danger_accept_invalid_hostnames(!self.verify_hostname.unwrap_or(true));
+    }
+}
@@ -0,0 +1,278 @@
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use hyper::{Body, Method, Request, Response, Uri};
+use native_tls::Certificate;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::TcpStream;
+
+use crate::third_wheel::{error::Error, host_mapping};
+
+/// Extension of the transcript files
+pub const TRANSCRIPT_EXTENSION: &str = "http";
+
+/// Start of the line naming the target of a transcript
+const TARGET_PREFIX: &str = "# target: ";
+
+/// Most headers a transcript can hold
+const MAX_HEADERS: usize = 100;
+
+/// A decrypted client request, as raw HTTP/1.1 bytes, with the host and port
+/// it was sent to.
+///
+/// Saved as a first `# target: host:port` line followed by the raw request,
+/// so `tail -n +2` gives bytes a fuzzer, `curl` or `openssl s_client` can send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transcript {
+    pub host: String,
+    pub port: u16,
+    pub request: Vec<u8>,
+}
+
+impl Transcript {
+    /// The transcript of a decrypted request, sent to the host of its `Host`
+    /// header and to the port of the tunnel it was read from, whatever the
+    /// header says. The body is written as it was read, so a chunked body is
+    /// given a `Content-Length` instead. `None` for a request naming no host.
+    ///
+    /// # Arguments
+    /// * `parts` - The parts of the HTTP request.
+    /// * `body` - The body of the HTTP request.
+    /// * `port` - The port of the target the request was sent to.
+    ///
+    /// # Returns
+    /// The transcript of the request, if it names its host.
+    pub fn from_request(
+        parts: &hyper::http::request::Parts,
+        body: &[u8],
+        port: u16,
+    ) -> Option<Self> {
+        let authority = parts
+            .headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| parts.uri.authority().map(|authority| authority.to_string()))?;
+        let authority: hyper::http::uri::Authority = authority.parse().ok()?;
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+
+        let mut request = format!("{} {} HTTP/1.1\r\n", parts.method, path).into_bytes();
+        let chunked = parts.headers.contains_key(TRANSFER_ENCODING);
+        for (name, value) in &parts.headers {
+            if *name == TRANSFER_ENCODING || (chunked && *name == CONTENT_LENGTH) {
+                continue;
+            }
+            request.extend(name.as_str().as_bytes());
+            request.extend(b": ");
+            request.extend(value.as_bytes());
+            request.extend(b"\r\n");
+        }
+        if chunked {
+            request.extend(format!("content-length: {}\r\n", body.len()).into_bytes());
+        }
+        request.extend(b"\r\n");
+        request.extend(body);
+
+        Some(Self {
+            host: authority.host().to_string(),
+            port,
+            request,
+        })
+    }
+
+    /// The content of the transcript file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{}{}:{}\n", TARGET_PREFIX, self.host, self.port).into_bytes();
+        bytes.extend(&self.request);
+        bytes
+    }
+
+    /// Read a transcript from the content of its file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::ConfigError(format!("invalid transcript: {}", reason));
+        let end_of_line = bytes
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .ok_or_else(|| invalid("no target line"))?;
+        let target = std::str::from_utf8(&bytes[..end_of_line])
+            .ok()
+            .and_then(|line| line.trim_end().strip_prefix(TARGET_PREFIX))
+            .ok_or_else(|| invalid("no target line"))?;
+        let (host, port) = target
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("no port in the target"))?;
+        let port = port.parse().map_err(|_| invalid("invalid port"))?;
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            request: bytes[end_of_line + 1..].to_vec(),
+        })
+    }
+
+    /// Read a transcript from its file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// The request of the transcript, to be sent again. The bytes after the
+    /// head are the body, whatever its headers say.
+    pub fn to_request(&self) -> Result<Request<Body>, Error> {
+        let invalid =
+            |reason: String| Error::RequestError(format!("invalid transcript: {}", reason));
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let head_length = match parsed.parse(&self.request) {
+            Ok(httparse::Status::Complete(head_length)) => head_length,
+            Ok(httparse::Status::Partial) => return Err(invalid("incomplete head".to_string())),
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+
+        let method = Method::from_bytes(parsed.method.unwrap_or_default().as_bytes())
+            .map_err(|e| invalid(e.to_string()))?;
+        let uri: Uri = parsed.path.unwrap_or("/").parse()?;
+        let mut request = Request::new(Body::from(self.request[head_length..].to_vec()));
+        *request.method_mut() = method;
+        *request.uri_mut() = uri;
+        for header in parsed.headers.iter() {
+            let name = HeaderName::from_bytes(header.name.as_bytes())
+                .map_err(|e| invalid(e.to_string()))?;
+            let value =
+                HeaderValue::from_bytes(header.value).map_err(|e| invalid(e.to_string()))?;
+            request.headers_mut().append(name, value);
+        }
+        Ok(request)
+    }
+}
+
+/// Writes the transcript of every request to its own file of a directory,
+/// named after its order and host, e.g. `000042-example.com.http`. The
+/// numbering carries on after the highest number of the transcripts already
+/// in the directory, and a file which exists is never overwritten, its
+/// number being skipped.
+#[derive(Debug)]
+pub struct TranscriptDump {
+    dir: PathBuf,
+    next: AtomicU64,
+}
+
+impl TranscriptDump {
+    /// Write the transcripts to `dir`, created if it does not exist
+    pub fn create<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let next = transcript_paths(&dir)?
+            .iter()
+            .filter_map(|path| transcript_number(path))
+            .max()
+            .map_or(0, |number| number + 1);
+        Ok(Self {
+            dir,
+            next: AtomicU64::new(next),
+        })
+    }
+
+    /// Write a transcript to its file, returning its path
+    pub fn record(&self, transcript: &Transcript) -> Result<PathBuf, Error> {
+        // Keep the host from escaping the directory
+        let host: String = transcript
+            .host
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        loop {
+            let number = self.next.fetch_add(1, Ordering::Relaxed);
+            let path = self
+                .dir
+                .join(format!("{:06}-{}.{}", number, host, TRANSCRIPT_EXTENSION));
+            // Another dump may be writing to the directory too
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(&transcript.to_bytes())?;
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// The number a transcript file is named after, if it is named as
+/// `TranscriptDump` names them
+fn transcript_number(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    let (number, _) = name.split_once('-')?;
+    number.parse().ok()
+}
+
+/// The transcript files of a directory, in the order they were written
+fn transcript_paths(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) == Some(TRANSCRIPT_EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Load the transcripts of a directory, in the order they were written
+pub fn load_transcripts<P: AsRef<Path>>(dir: P) -> Result<Vec<(PathBuf, Transcript)>, Error> {
+    transcript_paths(dir.as_ref())?
+        .into_iter()
+        .map(|path| Transcript::load(&path).map(|transcript| (path, transcript)))
+        .collect()
+}
+
+/// Sends transcripts to their targets over TLS
+#[derive(Clone, Default)]
+pub struct TranscriptReplayer {
+    /// hosts to connect to at another address, as for
+    /// `MitmProxyBuilder::additional_host_mappings`. Unix domain sockets are
+    /// not supported.
+    pub host_mappings: HashMap<String, String>,
+    /// certificates trusted in addition to the system ones
+    pub root_certificates: Vec<Certificate>,
+}
+
+impl TranscriptReplayer {
+    /// Send the request of a transcript to its target, returning the response
+    pub async fn replay(&self, transcript: &Transcript) -> Result<Response<Body>, Error> {
+        let request = transcript.to_request()?;
+        let port = transcript.port.to_string();
+        let address = match host_mapping::target(&self.host_mappings, &transcript.host, &port) {
+            host_mapping::Target::Tcp(address) => address,
+            host_mapping::Target::Unix { .. } => {
+                return Err(Error::ConfigError(
+                    "transcripts cannot be replayed to Unix domain sockets".to_string(),
+                ))
+            }
+        };
+        let stream = TcpStream::connect(address).await?;
+
+        let mut connector = native_tls::TlsConnector::builder();
+        for root_certificate in &self.root_certificates {
+            connector.add_root_certificate(root_certificate.clone());
+        }
+        let connector = tokio_native_tls::TlsConnector::from(connector.build()?);
+        let stream = connector.connect(&transcript.host, stream).await?;
+
+        let (mut request_sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(connection);
+        Ok(request_sender.send_request(request).await?)
+    }
+}
@@ -1,18 +1,308 @@
-use chrono::Local;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Local, SecondsFormat};
 use cookie::Cookie;
 use core::net::SocketAddr;
-use futures_util::stream;
+use flate2::read::MultiGzDecoder;
+use futures_util::{stream, Future, FutureExt, StreamExt};
 use har::v1_2::{self, Entries, Headers};
 use hyper::{
-    header::{CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE},
-    Body, Response, StatusCode,
+    body::HttpBody,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_ENCODING,
+        CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION, PROXY_AUTHORIZATION, SET_COOKIE,
+    },
+    Body, Response, StatusCode, Version,
 };
+use log::{debug, warn};
 use serde_json::Value::Null;
 use serde_json::{json, Value};
-use time::format_description;
-use tokio::sync::mpsc;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use tokio::sync::{mpsc, oneshot};
 use uuid::Uuid;
 
+use crate::rules::block_page;
+use crate::third_wheel::error::Error;
+use crate::third_wheel::proxy::{
+    compression::gzip,
+    mitm::{
+        HeaderOrder, LogicalHost, ProxyTiming, RequestId, SlaViolation, TlsParameters,
+        TransportSecurity,
+    },
+    ConnectionInfo,
+};
+
+/// Most bytes of a streamed or large body recorded
+pub const STREAM_CAPTURE_LIMIT: usize = 1024 * 1024;
+
+/// How long a streamed response body is recorded for
+pub const STREAM_CAPTURE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Options controlling what is recorded in the HAR entries
+#[derive(Clone, Debug)]
+pub struct CaptureOptions {
+    /// Record the decrypted bodies. Off by default as they may hold personal
+    /// data, only the metadata of the exchanges and the sizes of their bodies
+    /// are recorded then.
+    pub record_bodies: bool,
+    /// Only record the first bytes of each body. The recorded text is marked
+    /// with a `"preview"` comment.
+    pub body_preview: Option<usize>,
+    /// Write the bodies larger than a threshold to their own file instead of
+    /// inlining them. Previews are still recorded inline.
+    pub external_bodies: Option<ExternalBodies>,
+    /// Headers whose values are replaced by `[REDACTED]`, the credentials
+    /// ones by default. See `redact_headers`.
+    pub redact_headers: Vec<HeaderName>,
+    /// Keep the prompt of the requests when their bodies are not recorded,
+    /// for the prompts format. Only the prompt is kept, as the text of the
+    /// request marked with a `"prompt only"` comment. See `keep_prompt`.
+    pub record_prompts: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            record_bodies: false,
+            body_preview: None,
+            external_bodies: None,
+            redact_headers: default_redacted_headers(),
+            record_prompts: false,
+        }
+    }
+}
+
+/// Where the bodies too large to be inlined in the HAR are written. The
+/// recorded text holds the path of the file, marked with a
+/// `"stored externally"` comment.
+#[derive(Clone, Debug)]
+pub struct ExternalBodies {
+    /// directory holding the body files
+    pub dir: PathBuf,
+    /// bodies larger than this number of bytes are stored externally
+    pub threshold: usize,
+}
+
+impl ExternalBodies {
+    /// Store the body in `<dir>/<id>.bin` if it is over the threshold.
+    ///
+    /// # Returns
+    /// The path of the file, or `None` if the body is to be inlined.
+    pub fn store(&self, id: &str, body: &[u8]) -> Option<String> {
+        if body.len() <= self.threshold {
+            return None;
+        }
+        match store_body_externally(&self.dir, id, body) {
+            Ok(path) => Some(path.display().to_string()),
+            Err(e) => {
+                warn!("Error storing body externally: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Writes a body to `<dir>/<id>.bin`, creating the directory if needed.
+///
+/// # Arguments
+/// * `dir` - The directory holding the body files.
+/// * `id` - The identifier of the body, used as the file name. Only ASCII
+///   letters, digits and `-` are accepted, so it cannot name a file outside
+///   of `dir`.
+/// * `body` - The body to write.
+///
+/// # Returns
+/// The path of the written file.
+pub fn store_body_externally(dir: &Path, id: &str, body: &[u8]) -> std::io::Result<PathBuf> {
+    if !is_safe_file_id(id) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid body id {:?}", id),
+        ));
+    }
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.bin", id));
+    std::fs::write(&path, body)?;
+    Ok(path)
+}
+
+/// Whether an identifier can be used as a file name as it is: not empty and
+/// only made of ASCII letters, digits and `-`.
+fn is_safe_file_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-')
+}
+
+/// Reconstructs the absolute URL of a request, as HAR records it.
+///
+/// HTTP/2 requests carry their target in the `:authority` pseudo-header,
+/// which becomes the authority of the URI, while HTTP/1.1 requests read from
+/// the tunnel only hold a path with the target in the `Host` header. The
+/// scheme defaults to `https`, the proxy only intercepting TLS tunnels. The
+/// `LogicalHost` of a request, when it has one, takes the place of both.
+///
+/// # Arguments
+/// * `parts` - The parts of the HTTP request.
+///
+/// # Returns
+/// The absolute URL, or the URI as it is if the target is not known.
+pub fn request_url(parts: &hyper::http::request::Parts) -> String {
+    let uri = &parts.uri;
+    let logical_host = parts.extensions.get::<LogicalHost>();
+    if uri.path_and_query().is_none() || (uri.scheme().is_some() && logical_host.is_none()) {
+        // Already absolute, or the authority form of CONNECT
+        return uri.to_string();
+    }
+    let authority = logical_host
+        .map(|logical_host| logical_host.0.as_str())
+        .or_else(|| uri.authority().map(|authority| authority.as_str()))
+        .or_else(|| {
+            parts
+                .headers
+                .get(HOST)
+                .and_then(|value| value.to_str().ok())
+        });
+    match authority {
+        Some(authority) if !authority.is_empty() => {
+            format!(
+                "https://{}{}",
+                authority,
+                uri.path_and_query().map_or("/", |path| path.as_str())
+            )
+        }
+        _ => uri.to_string(),
+    }
+}
+
+/// Encoding of the HAR bodies that are not UTF-8 text
+pub const BASE64_ENCODING: &str = "base64";
+
+/// Media type of the bytes of the tunnels which are not parsed
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// The text of a body in HAR, as is for UTF-8 text and in base64 otherwise,
+/// with the encoding used if it is not the text itself
+fn body_text(body: Vec<u8>) -> (String, Option<&'static str>) {
+    match String::from_utf8(body) {
+        Ok(text) => (text, None),
+        Err(e) => (STANDARD.encode(e.into_bytes()), Some(BASE64_ENCODING)),
+    }
+}
+
+/// Decode a HAR body text written in `encoding`, given for base64 bodies.
+/// A body that cannot be decoded is returned as it is written.
+fn body_bytes(text: &str, encoding: Option<&str>) -> Vec<u8> {
+    match encoding {
+        Some(BASE64_ENCODING) => STANDARD
+            .decode(text)
+            .unwrap_or_else(|_| text.as_bytes().to_vec()),
+        _ => text.as_bytes().to_vec(),
+    }
+}
+
+/// The bytes of a response body recorded in HAR
+pub fn har_content_bytes(content: &v1_2::Content) -> Vec<u8> {
+    body_bytes(
+        content.text.as_deref().unwrap_or(""),
+        content.encoding.as_deref(),
+    )
+}
+
+/// The bytes of a request body recorded in HAR. Base64 bodies are noted in
+/// the comment of the post data, HAR 1.2 having no encoding for them.
+pub fn har_post_data_bytes(post_data: &v1_2::PostData) -> Vec<u8> {
+    let base64 = post_data
+        .comment
+        .as_deref()
+        .is_some_and(|comment| comment.split(", ").any(|note| note == BASE64_ENCODING));
+    body_bytes(
+        post_data.text.as_deref().unwrap_or(""),
+        base64.then_some(BASE64_ENCODING),
+    )
+}
+
+/// Note in the comment of a HAR header whose value is not valid UTF-8
+pub const LOSSY_HEADER_NOTE: &str = "value lossily decoded from non UTF-8 bytes";
+
+/// The text of a header value. Values which are not UTF-8, as some servers
+/// send in `Set-Cookie` or `Content-Disposition`, have their invalid bytes
+/// replaced by U+FFFD.
+fn header_text(value: &HeaderValue) -> Cow<'_, str> {
+    String::from_utf8_lossy(value.as_bytes())
+}
+
+/// A header as recorded in HAR, noting in its comment when its value could
+/// only be decoded lossily
+fn har_header(name: &HeaderName, value: &HeaderValue) -> Headers {
+    let text = header_text(value);
+    let comment = matches!(text, Cow::Owned(_)).then(|| LOSSY_HEADER_NOTE.to_string());
+    Headers {
+        name: name.as_str().to_string(),
+        value: text.into_owned(),
+        comment,
+    }
+}
+
+/// Decode the `%XX` escapes of a URI component
+pub(crate) fn percent_decode(component: &str) -> Vec<u8> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// The name/value pairs of a query string as recorded in HAR, decoded as
+/// form data. A pair without `=` has an empty value.
+fn har_query_string(query: &str) -> Vec<v1_2::QueryString> {
+    let decode = |component: &str| {
+        String::from_utf8_lossy(&percent_decode(&component.replace('+', " "))).into_owned()
+    };
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            v1_2::QueryString {
+                name: decode(name),
+                value: decode(value),
+                comment: None,
+            }
+        })
+        .collect()
+}
+
+/// The HTTP version as written in HAR, e.g. `HTTP/2.0`
+fn har_http_version(version: Version) -> String {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2.0",
+        Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+    .to_string()
+}
+
 /// Converts an HTTP request into a HAR request format.
 ///
 /// # Arguments
@@ -26,15 +316,11 @@ pub async fn copy_from_http_request_to_har(
     body: Vec<u8>,
 ) -> v1_2::Request {
     let method = parts.method.as_str().to_string();
-    let url = format!("{}", parts.uri);
-    let http_version = "HTTP/1.1".to_string();
+    let url = request_url(parts);
+    let http_version = har_http_version(parts.version);
     let mut headers = Vec::new();
-    for (name, value) in &parts.headers {
-        headers.push(Headers {
-            name: name.as_str().to_string(),
-            value: value.to_str().unwrap().to_string(),
-            comment: None,
-        })
+    for (name, value) in request_headers(parts) {
+        headers.push(har_header(name, value))
     }
     let headers_size: i64 = headers.iter().fold(0, |sum, headers| {
         sum + (headers.name.len() as i64 + headers.value.len() as i64)
@@ -44,22 +330,16 @@ pub async fn copy_from_http_request_to_har(
         .headers
         .iter()
         .filter(|(key, _)| key == &COOKIE)
-        .map(|(_, value)| parse_cookie(value.to_str().unwrap()))
+        .filter_map(|(_, value)| parse_cookie(&header_text(value)))
         .collect();
 
-    let body = match String::from_utf8(body) {
-        Ok(valid_string) => valid_string,
-        Err(e) => {
-            eprintln!("Error converting bytes to UTF-8: {}", e);
-            String::new()
-        }
-    };
     let body_size = body.len() as i64;
+    let (body, encoding) = body_text(body);
     let mime_type = parts
         .headers
         .iter()
         .filter(|(key, _)| key == &CONTENT_TYPE)
-        .map(|(_, value)| value.to_str().unwrap().to_string())
+        .map(|(_, value)| header_text(value).into_owned())
         .next()
         .unwrap_or("".to_string());
     let post_data = if body_size > 0 {
@@ -67,7 +347,8 @@ pub async fn copy_from_http_request_to_har(
             mime_type,
             text: Some(body),
             params: None,
-            comment: None,
+            // HAR 1.2 has no encoding for request bodies, it is noted instead
+            comment: encoding.map(str::to_string),
         })
     } else {
         None
@@ -79,7 +360,7 @@ pub async fn copy_from_http_request_to_har(
         http_version,
         cookies,
         headers,
-        query_string: Vec::new(),
+        query_string: parts.uri.query().map(har_query_string).unwrap_or_default(),
         post_data,
         headers_size,
         body_size,
@@ -87,6 +368,33 @@ pub async fn copy_from_http_request_to_har(
     }
 }
 
+/// The headers of a request in the order the client sent them when its
+/// `HeaderOrder` was recorded, otherwise in the order of its `HeaderMap`.
+/// Headers added after the order was recorded, e.g. by the mitm closure, come
+/// last.
+fn request_headers(parts: &hyper::http::request::Parts) -> Vec<(&HeaderName, &HeaderValue)> {
+    let Some(HeaderOrder(names)) = parts.extensions.get::<HeaderOrder>() else {
+        return parts.headers.iter().collect();
+    };
+    let mut values: HashMap<&HeaderName, _> = parts
+        .headers
+        .keys()
+        .map(|name| (name, parts.headers.get_all(name).iter()))
+        .collect();
+    let mut headers: Vec<_> = names
+        .iter()
+        .filter_map(|name| Some((name, values.get_mut(name)?.next()?)))
+        .collect();
+    for (name, values) in parts
+        .headers
+        .keys()
+        .filter_map(|name| values.remove_entry(name))
+    {
+        headers.extend(values.map(|value| (name, value)));
+    }
+    headers
+}
+
 /// Converts an HTTP response into a HAR response format.
 ///
 /// # Arguments
@@ -101,11 +409,7 @@ pub async fn copy_from_http_response_to_har(
 ) -> v1_2::Response {
     let mut headers = Vec::new();
     for (name, value) in &parts.headers {
-        headers.push(Headers {
-            name: name.as_str().to_string(),
-            value: value.to_str().unwrap().to_string(),
-            comment: None,
-        })
+        headers.push(har_header(name, value))
     }
     let headers_size: i64 = headers.iter().fold(0, |sum, headers| {
         sum + (headers.name.len() as i64 + headers.value.len() as i64)
@@ -115,18 +419,18 @@ pub async fn copy_from_http_response_to_har(
         .headers
         .iter()
         .filter(|(key, _)| key == &SET_COOKIE)
-        .map(|(_, value)| value.to_str().unwrap().to_string())
+        .map(|(_, value)| header_text(value).into_owned())
         .collect();
     let cookies: Vec<har::v1_2::Cookies> = cookies
         .iter()
-        .map(|cookie_string| parse_cookie(cookie_string))
+        .filter_map(|cookie_string| parse_cookie(cookie_string))
         .collect();
 
     let mime_type = parts
         .headers
         .iter()
         .filter(|(key, _)| key == &CONTENT_TYPE)
-        .map(|(_, value)| value.to_str().unwrap().to_string())
+        .map(|(_, value)| header_text(value).into_owned())
         .next()
         .unwrap_or("".to_string());
 
@@ -135,7 +439,7 @@ pub async fn copy_from_http_response_to_har(
             .headers
             .iter()
             .filter(|(key, _)| key == &LOCATION)
-            .map(|(_, value)| value.to_str().unwrap_or("").to_string())
+            .map(|(_, value)| header_text(value).into_owned())
             .next();
 
         match url_option {
@@ -146,23 +450,25 @@ pub async fn copy_from_http_response_to_har(
         "".to_string() // Default case if not a redirection
     };
 
-    let http_version = "HTTP/1.1".to_string();
+    let http_version = har_http_version(parts.version);
 
-    let body = match String::from_utf8(body) {
-        Ok(valid_string) => valid_string,
-        Err(e) => {
-            eprintln!("Error converting bytes to UTF-8: {}", e);
-            String::new()
+    // The body is recorded decoded, noting how many bytes its encoding saved
+    let body_size = body.len() as i64;
+    let (body, compression) = match decode_content(&parts.headers, &body) {
+        Some(decoded) => {
+            let compression = decoded.len() as i64 - body_size;
+            (decoded, Some(compression))
         }
+        None => (body, None),
     };
-
-    let body_size = body.len() as i64;
+    let size = body.len() as i64;
+    let (body, encoding) = body_text(body);
     let content = v1_2::Content {
-        size: body_size,
-        compression: None,
+        size,
+        compression,
         mime_type: Some(mime_type),
         text: Some(body),
-        encoding: None,
+        encoding: encoding.map(str::to_string),
         comment: None,
     };
     v1_2::Response {
@@ -179,31 +485,108 @@ pub async fn copy_from_http_response_to_har(
     }
 }
 
-/// Parses a cookie string into a HAR Cookies format.
+/// The codings of the `Content-Encoding` of `headers`, in the order they were
+/// applied, `identity` left out
+pub(crate) fn content_codings(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect()
+}
+
+/// Decode a body sent with the `Content-Encoding` of `headers`, undoing the
+/// codings in the reverse order they were applied. Returns `None` if the body
+/// is not encoded, uses a coding which cannot be decoded such as `zstd`, or
+/// is not valid for its coding, e.g. a truncated preview.
+pub(crate) fn decode_content(headers: &HeaderMap, body: &[u8]) -> Option<Vec<u8>> {
+    let codings = content_codings(headers);
+    if codings.is_empty() {
+        return None;
+    }
+    codings
+        .iter()
+        .rev()
+        .try_fold(body.to_vec(), |body, coding| match coding.as_str() {
+            "gzip" | "x-gzip" => gunzip(&body),
+            // Servers send either a zlib stream, as the spec says, or a raw one
+            "deflate" => miniz_oxide::inflate::decompress_to_vec_zlib(&body)
+                .or_else(|_| miniz_oxide::inflate::decompress_to_vec(&body))
+                .ok(),
+            "br" => unbrotli(&body),
+            _ => None,
+        })
+}
+
+/// Encode a body with the `Content-Encoding` of `headers`, applying the
+/// codings in order, the reverse of `decode_content`. Returns `None` if the
+/// body is not to be encoded or a coding cannot be applied.
+pub(crate) fn encode_content(headers: &HeaderMap, body: &[u8]) -> Option<Vec<u8>> {
+    let codings = content_codings(headers);
+    if codings.is_empty() {
+        return None;
+    }
+    codings
+        .iter()
+        .try_fold(body.to_vec(), |body, coding| match coding.as_str() {
+            "gzip" | "x-gzip" => Some(gzip(&body)),
+            "deflate" => Some(miniz_oxide::deflate::compress_to_vec_zlib(&body, 6)),
+            "br" => brotli_compress(&body),
+            _ => None,
+        })
+}
+
+/// Decode a gzip stream, all of its members one after the other
+fn gunzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    MultiGzDecoder::new(data).read_to_end(&mut decoded).ok()?;
+    Some(decoded)
+}
+
+/// Decode a brotli stream
+fn unbrotli(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    brotli::Decompressor::new(data, 4096)
+        .read_to_end(&mut decoded)
+        .ok()?;
+    Some(decoded)
+}
+
+/// Encode a body with brotli, at the quality browsers get from most servers
+fn brotli_compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+    writer.write_all(data).ok()?;
+    Some(writer.into_inner())
+}
+
+/// Parses a cookie string into a HAR Cookies format. The malformed cookies
+/// are left out of the HAR cookies, the headers still recording them.
 ///
 /// # Arguments
 /// * `cookie_str` - A string representation of a cookie.
 ///
 /// # Returns
-/// A `v1_2::Cookies` object containing parsed cookie details.
-pub fn parse_cookie(cookie_str: &str) -> v1_2::Cookies {
-    let parsed = Cookie::parse(cookie_str).unwrap();
-    v1_2::Cookies {
+/// A `v1_2::Cookies` object containing parsed cookie details, or `None` if
+/// the string is not a valid cookie.
+pub fn parse_cookie(cookie_str: &str) -> Option<v1_2::Cookies> {
+    let parsed = Cookie::parse(cookie_str).ok()?;
+    Some(v1_2::Cookies {
         name: parsed.name().to_string(),
         value: parsed.value().to_string(),
         path: parsed.path().map(|p| p.to_string()),
         domain: parsed.domain().map(|d| d.to_string()),
         expires: parsed.expires().and_then(|e| match e {
-            cookie::Expiration::DateTime(datetime) => {
-                let format_description = format_description::parse("%F %r %z").unwrap();
-                datetime.format(&format_description).ok()
-            }
+            // HAR 1.2 records the expiration in ISO 8601
+            cookie::Expiration::DateTime(datetime) => datetime.format(&Rfc3339).ok(),
             cookie::Expiration::Session => Some("session".to_owned()),
         }),
         http_only: parsed.http_only(),
         secure: parsed.secure(),
         comment: None,
-    }
+    })
 }
 
 /// Converts the body of a request from bytes to a JSON value.
@@ -214,54 +597,193 @@ pub fn parse_cookie(cookie_str: &str) -> v1_2::Cookies {
 /// # Returns
 /// A `Value` representing the parsed JSON, or `Value::Null` if parsing fails.
 pub fn convert_body_to_json(body_bytes: Vec<u8>) -> Value {
-    // Convert the body bytes to a string and handle errors
-    let body_string = match String::from_utf8(body_bytes.clone()) {
-        Ok(valid_string) => valid_string,
-        Err(e) => {
-            eprintln!("Error converting bytes to UTF-8: {}", e);
-            String::new()
-        }
-    };
+    // Binary bodies are common, so failing to parse one is only worth a debug
+    // message
+    serde_json::from_slice(&body_bytes).unwrap_or_else(|e| {
+        debug!("Body of {} bytes is not JSON: {}", body_bytes.len(), e);
+        Value::Null
+    })
+}
 
-    // Parse the request body as JSON and handle errors
-    match serde_json::from_str(&body_string) {
-        Ok(json) => json,
-        Err(e) => {
-            eprintln!("Failed to parse body as JSON: {}", e);
-            Value::Null
-        }
-    }
+/// The prompts of a conversation request: the content of each of its user
+/// messages, either the first of its `parts` as ChatGPT sends it or a plain
+/// string as the chat completion APIs do. Every user message is read, not
+/// only the last one, as a client can send earlier ones it made up. The
+/// messages of other roles and those holding no such content are skipped.
+fn prompt_values(body_json: &Value) -> Vec<&Value> {
+    let Some(messages) = body_json.get("messages").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    messages
+        .iter()
+        .filter(|message| {
+            let role = message
+                .get("role")
+                .or_else(|| message.get("author")?.get("role"));
+            role.is_none_or(|role| role == "user")
+        })
+        .filter_map(|message| {
+            let content = message.get("content")?;
+            match content {
+                Value::String(_) => Some(content),
+                _ => content.get("parts")?.as_array()?.first(),
+            }
+        })
+        .collect()
 }
 
-// Extracts specific content from a JSON request body, particularly the message.
+/// Extracts specific content from a JSON request body, particularly the message.
 ///
 /// # Arguments
 /// * `body_bytes` - A byte vector containing the body of a request.
 ///
 /// # Returns
-/// A string containing the message content extracted from the JSON body.
+/// The content of the user message as JSON, a JSON array of them if there
+/// are several, or an empty string if the body holds no message.
 pub fn parse_request(body_bytes: Vec<u8>) -> String {
-    let mut body_json: Value = convert_body_to_json(body_bytes);
-
-    // Extract the message content and check for specific keywords
-    if body_json.get_mut("messages").is_some() {
-        let message = body_json.get_mut("messages").unwrap();
-        let content = message[0].get_mut("content").unwrap();
-        let parts = content.get_mut("parts").unwrap();
-        parts[0].to_string()
-    } else {
-        String::new()
+    let body_json: Value = convert_body_to_json(body_bytes);
+    match prompt_values(&body_json).as_slice() {
+        [] => String::new(),
+        [prompt] => prompt.to_string(),
+        prompts => Value::Array(prompts.iter().map(|&prompt| prompt.clone()).collect()).to_string(),
+    }
+}
+
+/// Extracts the prompt written by the user from a ChatGPT conversation
+/// request body.
+///
+/// # Arguments
+/// * `body_bytes` - The body of the request.
+///
+/// # Returns
+/// The text of the prompt, the user messages separated by new lines, or
+/// `None` if the body does not hold one.
+pub fn extract_prompt(body_bytes: &[u8]) -> Option<String> {
+    let body_json: Value = serde_json::from_slice(body_bytes).ok()?;
+    let prompts: Vec<String> = prompt_values(&body_json)
+        .into_iter()
+        .map(|part| match part.as_str() {
+            Some(text) => text.to_string(),
+            None => part.to_string(),
+        })
+        .collect();
+    match prompts.is_empty() {
+        true => None,
+        false => Some(prompts.join("\n")),
+    }
+}
+
+/// Language of the block message used when the client accepts none of the
+/// configured ones
+pub const DEFAULT_BLOCK_LANGUAGE: &str = "en";
+
+/// The message answering blocked prompts in each language, chosen from the
+/// `Accept-Language` header of the request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockMessages {
+    /// message by lowercase language tag, e.g. `en` or `fr-ch`
+    messages: HashMap<String, String>,
+}
+
+impl Default for BlockMessages {
+    fn default() -> Self {
+        Self {
+            messages: HashMap::from([
+                (
+                    DEFAULT_BLOCK_LANGUAGE.to_string(),
+                    "Unable to process your request as it contains information that could compromise your company!".to_string(),
+                ),
+                (
+                    "fr".to_string(),
+                    "Impossible d'executer votre requête car elle contient des informations compromettantes pour votre entreprise !".to_string(),
+                ),
+            ]),
+        }
+    }
+}
+
+impl BlockMessages {
+    /// The built-in messages, with `messages` added or replacing the ones of
+    /// the same language
+    pub fn new(messages: HashMap<String, String>) -> Self {
+        let mut block_messages = Self::default();
+        block_messages.messages.extend(
+            messages
+                .into_iter()
+                .map(|(language, message)| (language.to_lowercase(), message)),
+        );
+        block_messages
     }
+
+    /// The message for the most preferred language of `accept_language` which
+    /// has one, a regional tag such as `fr-CH` falling back to `fr`, or the
+    /// English one.
+    ///
+    /// # Arguments
+    /// * `accept_language` - The value of the `Accept-Language` header, if any.
+    ///
+    /// # Returns
+    /// The message to answer the blocked prompt with.
+    pub fn message(&self, accept_language: Option<&str>) -> &str {
+        accept_language
+            .map(parse_accept_language)
+            .unwrap_or_default()
+            .iter()
+            .find_map(|language| {
+                self.messages.get(language.as_str()).or_else(|| {
+                    let primary = language.split('-').next().unwrap_or(language);
+                    self.messages.get(primary)
+                })
+            })
+            .or_else(|| self.messages.get(DEFAULT_BLOCK_LANGUAGE))
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+}
+
+/// Parses an `Accept-Language` header into its language tags, the most
+/// preferred first.
+///
+/// # Arguments
+/// * `accept_language` - The value of the header, e.g. `fr-CH, fr;q=0.9, en;q=0.8`.
+///
+/// # Returns
+/// The lowercase language tags by decreasing quality, the ones of equal
+/// quality in the order they were listed. The wildcard and the tags with a
+/// quality of 0 are left out.
+pub fn parse_accept_language(accept_language: &str) -> Vec<String> {
+    let mut languages: Vec<(String, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let language = params.next()?.trim().to_lowercase();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!language.is_empty() && language != "*" && quality > 0.0)
+                .then_some((language, quality))
+        })
+        .collect();
+    // A stable sort keeps the order of the tags of equal quality
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    languages
+        .into_iter()
+        .map(|(language, _)| language)
+        .collect()
 }
 
 /// Creates an HTTP response for streaming data using Server-Sent Events (SSE).
+/// Requests without chat messages, such as the ones a JSON rule blocks, are
+/// answered with the block page instead.
 ///
 /// # Arguments
 /// * `body_bytes` - A byte vector containing the body of the request.
+/// * `message` - The message answering the blocked prompt.
 ///
 /// # Returns
 /// A `Response<Body>` object representing the HTTP response.
-pub fn create_response(body_bytes: Vec<u8>) -> Response<Body> {
+pub fn create_response(body_bytes: Vec<u8>, message: &str) -> Response<Body> {
     // Default response builder
     let mut response_builder = Response::builder().status(StatusCode::OK);
 
@@ -271,20 +793,30 @@ pub fn create_response(body_bytes: Vec<u8>) -> Response<Body> {
     // Create a channel to send data chunks
     let (tx, rx) = mpsc::channel(10);
 
-    let mut body_json = convert_body_to_json(body_bytes);
+    let body_json = convert_body_to_json(body_bytes);
+    if !body_json.get("messages").is_some_and(Value::is_array) {
+        return block_page();
+    }
+    let message = message.to_string();
+    // The message answered, a new id when it has none as with the chat
+    // completions APIs
+    let parent_id = body_json
+        .get("messages")
+        .and_then(|messages| messages.get(0))
+        .and_then(|message| message.get("id"))
+        .cloned()
+        .unwrap_or_else(|| Value::String(Uuid::new_v4().to_string()));
+    // The conversation of the message, a new one when the client names none
+    let conversation_id = body_json
+        .get("conversation_id")
+        .filter(|conversation_id| conversation_id.is_string())
+        .cloned();
+    let is_new_conversation = conversation_id.is_none();
+    let conversation_id =
+        conversation_id.unwrap_or_else(|| Value::String(Uuid::new_v4().to_string()));
 
     // Spawn an async task to send data chunks to the stream
     tokio::spawn(async move {
-        let mut body_json_copy = body_json.clone();
-        let messages = body_json.get_mut("messages").unwrap();
-        let parent_id = messages[0].get_mut("id").unwrap();
-        let is_conversation_id = body_json_copy.get_mut("conversation_id").is_none();
-        let conversation_id = if is_conversation_id {
-            // Creation of new conversation
-            &mut serde_json::Value::String(Uuid::new_v4().to_string())
-        } else {
-            body_json_copy.get_mut("conversation_id").unwrap()
-        };
         let message_id = serde_json::Value::String(Uuid::new_v4().to_string());
 
         let message1 = json!({
@@ -299,7 +831,7 @@ pub fn create_response(body_bytes: Vec<u8>) -> Response<Body> {
                 "update_time": Null,
                 "content": {
                     "content_type": "text",
-                    "parts": ["Impossible d'executer votre requête car elle contient des informations compromettantes pour votre entreprise !"]
+                    "parts": [message]
                 },
                 "status": "finished_successfully",
                 "end_turn": true,
@@ -327,7 +859,7 @@ pub fn create_response(body_bytes: Vec<u8>) -> Response<Body> {
             "error": Null
         });
 
-        let message2 = if is_conversation_id {
+        let message2 = if is_new_conversation {
             json!({
                 "type": "title_generation",
                 "title": "New chat",
@@ -371,11 +903,272 @@ pub fn create_response(body_bytes: Vec<u8>) -> Response<Body> {
     response_builder.body(body_stream).unwrap()
 }
 
-/// Logs a blocked HTTP request and returns its HAR representation.
+/// Creates the `502 Bad Gateway` response answering a request whose response
+/// could not be read from its target.
+///
+/// # Arguments
+/// * `message` - Why the response could not be read.
+pub fn bad_gateway(message: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(message.to_string()))
+        .expect("Infallible: valid status and header")
+}
+
+/// Reads at most `limit` bytes of a body for recording without buffering the
+/// rest of it.
+///
+/// # Arguments
+/// * `body` - The body to preview.
+/// * `limit` - The maximum number of bytes to keep in the preview.
+///
+/// # Returns
+/// A tuple containing the preview bytes and a body streaming the whole original
+/// content, the chunks already read followed by the rest of the body.
+pub async fn read_body_preview(
+    mut body: Body,
+    limit: usize,
+) -> Result<(Vec<u8>, Body), hyper::Error> {
+    let mut preview = Vec::new();
+    let mut read_chunks = Vec::new();
+
+    // Only pull chunks until the preview is full
+    while preview.len() < limit {
+        match body.data().await {
+            Some(chunk) => {
+                let chunk = chunk?;
+                let end = chunk.len().min(limit - preview.len());
+                preview.extend_from_slice(&chunk[..end]);
+                read_chunks.push(Ok(chunk));
+            }
+            None => break,
+        }
+    }
+
+    // Replay the chunks already read before streaming the remainder
+    let body = Body::wrap_stream(stream::iter(read_chunks).chain(body));
+    Ok((preview, body))
+}
+
+/// Whether a response body may never end, so it cannot be buffered whole:
+/// server-sent events, and binary streams sent without a length.
+///
+/// # Arguments
+/// * `parts` - The parts of the HTTP response.
+pub fn is_unbounded_response(parts: &hyper::http::response::Parts) -> bool {
+    let media_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    media_type == "text/event-stream"
+        || (media_type == "application/octet-stream" && !parts.headers.contains_key(CONTENT_LENGTH))
+}
+
+/// Sends a body on while recording at most `limit` bytes of it, within
+/// `window` if any, so large bodies and streams that never end can be
+/// recorded without holding them back. The chunks are sent on as they arrive
+/// and recorded as the client reads them.
+///
+/// # Arguments
+/// * `body` - The body to record.
+/// * `limit` - The maximum number of bytes to record.
+/// * `window` - How long to record the body for before giving up on its end,
+///   `None` to wait for it.
+///
+/// # Returns
+/// A tuple containing a body streaming the whole original content, and the
+/// receiver of the recorded bytes along with whether they are the whole body.
+/// The receiver fails if the body could not be read or the client went away
+/// before the end of the recording.
+pub fn tee_body(
+    mut body: Body,
+    limit: usize,
+    window: Option<Duration>,
+) -> (Body, oneshot::Receiver<(Vec<u8>, bool)>) {
+    let (mut sender, tee) = Body::channel();
+    let (recorded_sender, recorded) = oneshot::channel();
+    tokio::spawn(async move {
+        let deadline = window.map(|window| tokio::time::Instant::now() + window);
+        let mut recording = Some((Vec::new(), recorded_sender));
+        loop {
+            let chunk = if let (Some(deadline), Some(_)) = (deadline, &recording) {
+                match tokio::time::timeout_at(deadline, body.data()).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        // The window is over, the rest is only sent on
+                        if let Some((captured, recorded_sender)) = recording.take() {
+                            let _ = recorded_sender.send((captured, false));
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                body.data().await
+            };
+            match chunk {
+                Some(Ok(chunk)) => {
+                    if let Some((captured, _)) = recording.as_mut() {
+                        let end = chunk.len().min(limit - captured.len());
+                        captured.extend_from_slice(&chunk[..end]);
+                        if captured.len() >= limit {
+                            if let Some((captured, recorded_sender)) = recording.take() {
+                                let _ = recorded_sender.send((captured, false));
+                            }
+                        }
+                    }
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Err(_)) => {
+                    sender.abort();
+                    break;
+                }
+                None => {
+                    if let Some((captured, recorded_sender)) = recording.take() {
+                        let _ = recorded_sender.send((captured, true));
+                    }
+                    break;
+                }
+            }
+        }
+    });
+    (tee, recorded)
+}
+
+/// The HAR response of a response being sent on, ready once its body was
+/// recorded
+pub type PendingResponse = Pin<Box<dyn Future<Output = v1_2::Response> + Send>>;
+
+/// Converts an HTTP response into HAR format while it is sent on. Streams
+/// are sent on as they arrive and only recorded within the capture window,
+/// see `tee_body`, marked with a `"partial stream"` comment if they did not
+/// end in it. Bodies of at most `STREAM_CAPTURE_LIMIT` bytes are read whole
+/// first, larger ones or ones of unknown length are sent on as they arrive,
+/// only their first `STREAM_CAPTURE_LIMIT` bytes being recorded, marked with
+/// a `"truncated"` comment.
+///
+/// # Arguments
+/// * `parts` - The parts of the HTTP response.
+/// * `body` - The body of the HTTP response.
+///
+/// # Returns
+/// A tuple containing the HAR response, ready once the body was recorded, and
+/// a body streaming the whole original content.
+pub async fn record_response(
+    parts: &hyper::http::response::Parts,
+    body: Body,
+) -> Result<(PendingResponse, Body), hyper::Error> {
+    let small = body
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= STREAM_CAPTURE_LIMIT as u64);
+    if small && !is_unbounded_response(parts) {
+        let body_bytes = hyper::body::to_bytes(body).await?;
+        let har_response = copy_from_http_response_to_har(parts, body_bytes.to_vec()).await;
+        return Ok((
+            Box::pin(futures_util::future::ready(har_response)),
+            Body::from(body_bytes),
+        ));
+    }
+
+    let (window, incomplete) = if is_unbounded_response(parts) {
+        (Some(STREAM_CAPTURE_WINDOW), "partial stream")
+    } else {
+        (None, "truncated")
+    };
+    let (body, recorded) = tee_body(body, STREAM_CAPTURE_LIMIT, window);
+    let head = response_head(parts);
+    let har_response = async move {
+        let (captured, complete) = recorded.await.unwrap_or_default();
+        let mut har_response = copy_from_http_response_to_har(&head, captured).await;
+        if !complete {
+            // Only the start of the body is known
+            har_response.body_size = -1;
+            har_response.content.comment = Some(incomplete.to_string());
+        }
+        har_response
+    };
+    Ok((Box::pin(har_response), body))
+}
+
+/// Converts an HTTP response into HAR format while it is sent on, as
+/// `record_response` does, only recording the first `limit` bytes of its body
+/// without ever buffering it whole. A body with more to it is marked with a
+/// `"preview"` comment.
+///
+/// # Arguments
+/// * `parts` - The parts of the HTTP response.
+/// * `body` - The body of the HTTP response.
+/// * `limit` - The maximum number of bytes to keep in the preview.
+///
+/// # Returns
+/// A tuple containing the HAR response, ready once the preview was recorded,
+/// and a body streaming the whole original content.
+pub fn preview_response(
+    parts: &hyper::http::response::Parts,
+    body: Body,
+    limit: usize,
+) -> (PendingResponse, Body) {
+    let window = is_unbounded_response(parts).then_some(STREAM_CAPTURE_WINDOW);
+    let (body, recorded) = tee_body(body, limit, window);
+    let head = response_head(parts);
+    let har_response = async move {
+        let (captured, complete) = recorded.await.unwrap_or_default();
+        let mut har_response = copy_from_http_response_to_har(&head, captured).await;
+        if !complete {
+            // Only the start of the body is known
+            har_response.body_size = -1;
+            har_response.content.comment = Some("preview".to_string());
+        }
+        har_response
+    };
+    (Box::pin(har_response), body)
+}
+
+/// The status, version and headers of a response, all HAR records of it
+/// besides the body
+fn response_head(parts: &hyper::http::response::Parts) -> hyper::http::response::Parts {
+    let mut head = Response::new(());
+    *head.status_mut() = parts.status;
+    *head.version_mut() = parts.version;
+    *head.headers_mut() = parts.headers.clone();
+    head.into_parts().0
+}
+
+/// Runs `record`, which waits for a response body to be recorded, right away
+/// if it already was, otherwise in a task of its own so the response is not
+/// held back
+///
+/// # Arguments
+/// * `record` - What to do with the recorded response.
+pub fn record_when_read<F>(record: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut record = Box::pin(record);
+    if (&mut record).now_or_never().is_none() {
+        tokio::spawn(record);
+    }
+}
+
+/// Logs a blocked HTTP request and returns its HAR representation. The id of
+/// the request, if it has one, is recorded in the entry comment and names the
+/// files of the bodies stored externally.
 ///
 /// # Arguments
 /// * `req_parts` - The parts of the HTTP request.
 /// * `body_bytes` - The body of the HTTP request as a byte vector.
+/// * `ip_client` - The address of the client which sent the request.
+/// * `ip_server` - The address the proxy connected to upstream for the
+///   client connection, if any.
+/// * `options` - What to record in the entry.
+/// * `block_messages` - The messages to answer with, chosen from the
+///   `Accept-Language` header of the request.
 ///
 /// # Returns
 /// A tuple containing the HAR log entries and the HTTP response for the blocked request.
@@ -383,31 +1176,463 @@ pub async fn log_blocked_request(
     req_parts: &hyper::http::request::Parts,
     body_bytes: Vec<u8>,
     ip_client: SocketAddr,
+    ip_server: Option<SocketAddr>,
+    options: &CaptureOptions,
+    block_messages: &BlockMessages,
 ) -> (Entries, Response<Body>) {
     // Process the request and prepare it for logging
-    let mut copied_bytes = Vec::with_capacity(body_bytes.len());
-    copied_bytes.extend(&body_bytes); // Make a copy of the request body
-    let har_request = copy_from_http_request_to_har(req_parts, copied_bytes).await;
+    let request_id = req_parts.extensions.get::<RequestId>();
+    // The body is handed to the block response, its prompt is read first
+    let prompt = match options.record_prompts && !options.record_bodies {
+        true => extract_prompt(&body_bytes),
+        false => None,
+    };
+    // The id may come from the client, it only names the files when it is
+    // safe to
+    let body_id = request_id
+        .map(|request_id| request_id.to_string())
+        .filter(|request_id| is_safe_file_id(request_id))
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    // Bodies which are not recorded are not stored externally either
+    let external_bodies = options
+        .external_bodies
+        .as_ref()
+        .filter(|_| options.record_bodies);
+    let stored_request = match (external_bodies, options.body_preview) {
+        (Some(external), None) => external.store(&format!("{}-request", body_id), &body_bytes),
+        _ => None,
+    };
+    let mut har_request = if let Some(limit) = options.body_preview {
+        preview_request_to_har(req_parts, &body_bytes, limit).await
+    } else if let Some(path) = stored_request {
+        let mut har_request = copy_from_http_request_to_har(req_parts, path.into_bytes()).await;
+        if let Some(post_data) = har_request.post_data.as_mut() {
+            post_data.comment = Some("stored externally".to_string());
+        }
+        har_request
+    } else {
+        let mut copied_bytes = Vec::with_capacity(body_bytes.len());
+        copied_bytes.extend(&body_bytes); // Make a copy of the request body
+        copy_from_http_request_to_har(req_parts, copied_bytes).await
+    };
+    har_request.body_size = body_bytes.len() as i64;
 
-    // Creation of the response
-    let response = create_response(body_bytes);
+    // Creation of the response, in the language the client prefers
+    let accept_language = req_parts
+        .headers
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok());
+    let response = create_response(body_bytes, block_messages.message(accept_language));
     let (res_parts, res_body) = response.into_parts();
 
-    // Process the response and prepare it for logging
-    let body_bytes: Vec<u8> = hyper::body::to_bytes(res_body).await.unwrap().to_vec();
-    let mut copied_bytes = Vec::with_capacity(body_bytes.len());
-    copied_bytes.extend(&body_bytes); // Make a copy of the response body
-    let har_response = copy_from_http_response_to_har(&res_parts, copied_bytes).await;
+    // Process the response and prepare it for logging, only reading the
+    // preview of the body if one was asked for. The block response is
+    // generated here and ends, so it can be read whole.
+    let (har_response, body) = if let Some(limit) = options.body_preview {
+        let (preview, body) = read_body_preview(res_body, limit)
+            .await
+            .expect("Infallible: the block response is generated");
+        let mut har_response = copy_from_http_response_to_har(&res_parts, preview).await;
+        har_response.content.comment = Some("preview".to_string());
+        (har_response, body)
+    } else {
+        let body_bytes: Vec<u8> = hyper::body::to_bytes(res_body)
+            .await
+            .expect("Infallible: the block response is generated")
+            .to_vec();
+        let stored_response = external_bodies
+            .and_then(|external| external.store(&format!("{}-response", body_id), &body_bytes));
+        let har_response = if let Some(path) = stored_response {
+            let mut har_response =
+                copy_from_http_response_to_har(&res_parts, path.into_bytes()).await;
+            har_response.content.size = body_bytes.len() as i64;
+            har_response.body_size = body_bytes.len() as i64;
+            har_response.content.comment = Some("stored externally".to_string());
+            har_response
+        } else {
+            let mut copied_bytes = Vec::with_capacity(body_bytes.len());
+            copied_bytes.extend(&body_bytes); // Make a copy of the response body
+            copy_from_http_response_to_har(&res_parts, copied_bytes).await
+        };
+        (
+            har_response,
+            Body::from(hyper::body::Bytes::from(body_bytes)),
+        )
+    };
 
     // Create HAR log entries
-    let entries = Entries {
+    let mut entries = har_entry(har_request, har_response, ip_client, ip_server, request_id);
+    if !options.record_bodies {
+        strip_bodies(&mut entries);
+        if let Some(prompt) = prompt {
+            keep_prompt(&mut entries, prompt);
+        }
+    }
+    redact_headers(&mut entries, &options.redact_headers);
+
+    // Rebuild the response from its parts and body
+    let response = Response::<Body>::from_parts(res_parts, body);
+
+    (entries, response)
+}
+
+/// Converts an HTTP request into HAR format, only copying the first `limit`
+/// bytes of its body. The recorded text is marked with a `"preview"` comment.
+///
+/// # Arguments
+/// * `req_parts` - The parts of the HTTP request.
+/// * `body_bytes` - The body of the HTTP request.
+/// * `limit` - The maximum number of bytes to keep in the preview.
+///
+/// # Returns
+/// The HAR request, whose body size is the one of the whole body.
+async fn preview_request_to_har(
+    req_parts: &hyper::http::request::Parts,
+    body_bytes: &[u8],
+    limit: usize,
+) -> v1_2::Request {
+    let preview = body_bytes[..body_bytes.len().min(limit)].to_vec();
+    let mut har_request = copy_from_http_request_to_har(req_parts, preview).await;
+    if let Some(post_data) = har_request.post_data.as_mut() {
+        // Keep the note of a base64 body
+        post_data.comment = Some(match post_data.comment.take() {
+            Some(note) => format!("preview, {}", note),
+            None => "preview".to_string(),
+        });
+    }
+    har_request.body_size = body_bytes.len() as i64;
+    har_request
+}
+
+/// Drop the bodies recorded in a HAR entry, keeping their sizes, for when
+/// bodies are not to be recorded.
+///
+/// # Arguments
+/// * `entry` - The entry to remove the bodies from.
+pub fn strip_bodies(entry: &mut Entries) {
+    entry.request.post_data = None;
+    let content = &mut entry.response.content;
+    content.text = None;
+    content.encoding = None;
+    content.comment = None;
+}
+
+/// The comment marking a request text which only holds its prompt
+pub const PROMPT_ONLY: &str = "prompt only";
+
+/// Record only the prompt of a request whose body was stripped, so the
+/// prompts can be written without recording the bodies.
+///
+/// # Arguments
+/// * `entry` - The entry whose bodies were stripped.
+/// * `prompt` - The prompt read from the body of the request.
+pub fn keep_prompt(entry: &mut Entries, prompt: String) {
+    entry.request.post_data = Some(v1_2::PostData {
+        mime_type: "text/plain; charset=utf-8".to_string(),
+        text: Some(prompt),
+        params: None,
+        comment: Some(PROMPT_ONLY.to_string()),
+    });
+}
+
+/// What replaces the value of a redacted header in a HAR entry
+pub const REDACTED: &str = "[REDACTED]";
+
+/// The headers redacted from the HAR entries unless told otherwise, the ones
+/// carrying credentials
+pub fn default_redacted_headers() -> Vec<HeaderName> {
+    vec![AUTHORIZATION, COOKIE, SET_COOKIE, PROXY_AUTHORIZATION]
+}
+
+/// Replace the values of the `names` headers recorded in a HAR entry by
+/// `[REDACTED]`, keeping their names, so a capture can be shared without the
+/// credentials it saw. The cookies parsed from a redacted `Cookie` or
+/// `Set-Cookie` have their values redacted too.
+///
+/// # Arguments
+/// * `entry` - The entry to redact the headers of.
+/// * `names` - The headers to redact.
+pub fn redact_headers(entry: &mut Entries, names: &[HeaderName]) {
+    let redacted = |name: &str| {
+        names
+            .iter()
+            .any(|redacted| redacted.as_str().eq_ignore_ascii_case(name))
+    };
+    let headers = entry
+        .request
+        .headers
+        .iter_mut()
+        .chain(entry.response.headers.iter_mut());
+    for header in headers.filter(|header| redacted(&header.name)) {
+        header.value = REDACTED.to_string();
+        header.comment = None;
+    }
+    if redacted(COOKIE.as_str()) {
+        for cookie in &mut entry.request.cookies {
+            cookie.value = REDACTED.to_string();
+        }
+    }
+    if redacted(SET_COOKIE.as_str()) {
+        for cookie in &mut entry.response.cookies {
+            cookie.value = REDACTED.to_string();
+        }
+    }
+}
+
+/// Records a tunnel that was relayed without being decrypted, as a HAR entry
+/// for its CONNECT request. Only the target, the client and the time are
+/// known so no body is recorded.
+///
+/// # Arguments
+/// * `connection` - What is known of the tunnel.
+///
+/// # Returns
+/// The HAR log entries describing the tunnel.
+pub fn log_connection(connection: &ConnectionInfo) -> Entries {
+    let target = format!("{}:{}", connection.host, connection.port);
+    let comment = match &connection.server_name {
+        Some(server_name) => format!("connection only, server name: {}", server_name),
+        None => "connection only".to_string(),
+    };
+
+    Entries {
+        request: v1_2::Request {
+            method: "CONNECT".to_string(),
+            url: target.clone(),
+            http_version: "HTTP/1.1".to_string(),
+            cookies: Vec::new(),
+            headers: vec![Headers {
+                name: "host".to_string(),
+                value: target,
+                comment: None,
+            }],
+            query_string: Vec::new(),
+            post_data: None,
+            headers_size: -1,
+            body_size: 0,
+            comment: None,
+        },
+        response: v1_2::Response {
+            status: 200,
+            status_text: "OK".to_string(),
+            http_version: "HTTP/1.1".to_string(),
+            cookies: Vec::new(),
+            headers: Vec::new(),
+            content: v1_2::Content {
+                size: 0,
+                compression: None,
+                mime_type: None,
+                text: None,
+                encoding: None,
+                comment: None,
+            },
+            redirect_url: None,
+            headers_size: -1,
+            body_size: 0,
+            comment: None,
+        },
+        time: 0.0,
+        server_ip_address: None,
+        connection: Some(connection.client_ip.to_string()),
+        comment: Some(comment),
+        started_date_time: har_date_time(DateTime::<Local>::from(connection.timestamp)),
+        cache: v1_2::Cache {
+            before_request: None,
+            after_request: None,
+        },
+        timings: v1_2::Timings {
+            blocked: None,
+            dns: None,
+            connect: None,
+            send: 0.0,
+            wait: 0.0,
+            receive: 0.0,
+            ssl: None,
+            comment: None,
+        },
+        pageref: None,
+    }
+}
+
+/// Records a tunnel whose decrypted bytes were relayed without being parsed,
+/// as a HAR entry for its CONNECT request. The bytes sent by the client are
+/// its request body and the ones sent by the target its response body, both
+/// in base64. Bodies of which only the first bytes were recorded are marked
+/// with a `"truncated"` comment, their sizes counting all of their bytes.
+///
+/// # Arguments
+/// * `connection` - What is known of the tunnel, opened at its `timestamp`.
+/// * `server_ip` - The address the proxy connected to upstream, if any.
+/// * `sent` - The bytes sent by the client, as recorded.
+/// * `sent_size` - How many bytes the client sent.
+/// * `received` - The bytes sent by the target, as recorded.
+/// * `received_size` - How many bytes the target sent.
+///
+/// # Returns
+/// The HAR log entries describing the tunnel.
+pub fn raw_tunnel_entry(
+    connection: &ConnectionInfo,
+    server_ip: Option<SocketAddr>,
+    sent: &[u8],
+    sent_size: u64,
+    received: &[u8],
+    received_size: u64,
+) -> Entries {
+    let mut entry = log_connection(connection);
+    entry.comment = Some("raw tunnel".to_string());
+    entry.server_ip_address = server_ip.map(|server_ip| server_ip.to_string());
+    entry.time = connection
+        .timestamp
+        .elapsed()
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64() * 1000.0);
+
+    let truncated = |recorded: &[u8], size: u64| recorded.len() as u64 != size;
+    entry.request.body_size = sent_size as i64;
+    entry.request.post_data = Some(v1_2::PostData {
+        mime_type: OCTET_STREAM.to_string(),
+        text: Some(STANDARD.encode(sent)),
+        params: None,
+        // HAR 1.2 has no encoding for request bodies, it is noted instead
+        comment: Some(match truncated(sent, sent_size) {
+            true => format!("truncated, {}", BASE64_ENCODING),
+            false => BASE64_ENCODING.to_string(),
+        }),
+    });
+    entry.response.body_size = received_size as i64;
+    entry.response.content = v1_2::Content {
+        size: received_size as i64,
+        compression: None,
+        mime_type: Some(OCTET_STREAM.to_string()),
+        text: Some(STANDARD.encode(received)),
+        encoding: Some(BASE64_ENCODING.to_string()),
+        comment: truncated(received, received_size).then(|| "truncated".to_string()),
+    };
+    entry
+}
+
+/// Records a request forwarded to its target as a HAR entry, before its
+/// response is known. The response of the entry is left empty with a status
+/// of 0, as HAR does for requests without a response.
+///
+/// # Arguments
+/// * `req_parts` - The parts of the HTTP request.
+/// * `body_bytes` - The body of the HTTP request as a byte vector.
+/// * `ip_client` - The address of the client which sent the request.
+/// * `ip_server` - The address the proxy connected to upstream, if any.
+/// * `options` - What to record in the entry.
+///
+/// # Returns
+/// The HAR log entries describing the request.
+pub async fn log_forwarded_request(
+    req_parts: &hyper::http::request::Parts,
+    body_bytes: Vec<u8>,
+    ip_client: SocketAddr,
+    ip_server: Option<SocketAddr>,
+    options: &CaptureOptions,
+) -> Entries {
+    let prompt = match options.record_prompts && !options.record_bodies {
+        true => extract_prompt(&body_bytes),
+        false => None,
+    };
+    let har_request = match options.body_preview {
+        Some(limit) => preview_request_to_har(req_parts, &body_bytes, limit).await,
+        None => copy_from_http_request_to_har(req_parts, body_bytes).await,
+    };
+
+    let mut entries = har_entry(
+        har_request,
+        no_response(),
+        ip_client,
+        ip_server,
+        req_parts.extensions.get::<RequestId>(),
+    );
+    if !options.record_bodies {
+        strip_bodies(&mut entries);
+        if let Some(prompt) = prompt {
+            keep_prompt(&mut entries, prompt);
+        }
+    }
+    redact_headers(&mut entries, &options.redact_headers);
+    entries
+}
+
+/// The response of a HAR entry for a request that got none, with a status of
+/// 0 as HAR does
+fn no_response() -> v1_2::Response {
+    v1_2::Response {
+        status: 0,
+        status_text: String::new(),
+        http_version: String::new(),
+        cookies: Vec::new(),
+        headers: Vec::new(),
+        content: v1_2::Content {
+            size: 0,
+            compression: None,
+            mime_type: None,
+            text: None,
+            encoding: None,
+            comment: None,
+        },
+        redirect_url: None,
+        headers_size: -1,
+        body_size: -1,
+        comment: None,
+    }
+}
+
+/// Builds the HAR entry of a request that could not be forwarded, with an
+/// empty response of status 0 and the error in the entry comment.
+///
+/// # Arguments
+/// * `har_request` - The request in HAR format.
+/// * `ip_client` - The address of the client which sent the request.
+/// * `ip_server` - The address the proxy connected to upstream, if any.
+/// * `request_id` - The id of the request.
+/// * `error` - Why forwarding the request failed.
+///
+/// # Returns
+/// The HAR log entries describing the failed request.
+pub fn failed_har_entry(
+    har_request: v1_2::Request,
+    ip_client: SocketAddr,
+    ip_server: Option<SocketAddr>,
+    request_id: Option<&RequestId>,
+    error: &Error,
+) -> Entries {
+    let mut entry = har_entry(har_request, no_response(), ip_client, ip_server, request_id);
+    append_comment(&mut entry, &format!("forwarding failed: {}", error));
+    entry
+}
+
+/// Builds the HAR entry of an exchange, started now. The id of the request,
+/// if it has one, is recorded in the entry comment. The address of the
+/// client is recorded as the `connection` of the entry, which identifies the
+/// client connection the request came on.
+///
+/// # Arguments
+/// * `har_request` - The request in HAR format.
+/// * `har_response` - The response in HAR format.
+/// * `ip_client` - The address of the client which sent the request.
+/// * `ip_server` - The address the proxy connected to upstream, if any.
+/// * `request_id` - The id of the request.
+///
+/// # Returns
+/// The HAR log entries describing the exchange.
+pub fn har_entry(
+    har_request: v1_2::Request,
+    har_response: v1_2::Response,
+    ip_client: SocketAddr,
+    ip_server: Option<SocketAddr>,
+    request_id: Option<&RequestId>,
+) -> Entries {
+    Entries {
         request: har_request,
         response: har_response,
         time: 0.0,
-        server_ip_address: Some(ip_client.to_string()),
-        connection: None,
-        comment: None,
-        started_date_time: Local::now().format("%d/%m/%Y %H:%M:%S").to_string(),
+        server_ip_address: ip_server.map(|ip_server| ip_server.to_string()),
+        connection: Some(ip_client.to_string()),
+        comment: request_id.map(|request_id| format!("request id: {}", request_id)),
+        started_date_time: har_date_time(Local::now()),
         cache: v1_2::Cache {
             before_request: None,
             after_request: None,
@@ -423,11 +1648,72 @@ pub async fn log_blocked_request(
             comment: None,
         },
         pageref: None,
-    };
+    }
+}
 
-    // Rebuild the response from its parts and body
-    let body: Body = Body::from(hyper::body::Bytes::from(body_bytes));
-    let response = Response::<Body>::from_parts(res_parts, body);
+/// Format a date as the `startedDateTime` of a HAR entry, an ISO 8601 date
+/// with milliseconds and the offset of the local timezone, e.g.
+/// `2024-05-01T13:45:30.123+02:00`
+pub fn har_date_time(date: DateTime<Local>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Millis, false)
+}
 
-    (entries, response)
-}
\ No newline at end of file
+/// Records in a HAR entry the timings of the exchange as measured by the proxy
+/// and stored in the extensions of the response: `blocked` is the time spent
+/// in the proxy, `send` and `wait` the time taken to send the request and to
+/// get the response headers back. The `receive` timing, measured by the
+/// caller while reading the body, is kept and `time` is set to the sum of all.
+/// A response slower than the latency SLA of its host is flagged in the entry
+/// comment.
+///
+/// # Arguments
+/// * `entry` - The HAR entry of the exchange.
+/// * `extensions` - The extensions of the response returned by `ThirdWheel`.
+pub fn record_timing(entry: &mut Entries, extensions: &hyper::http::Extensions) {
+    if let Some(timing) = extensions.get::<ProxyTiming>() {
+        let millis = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+        let blocked = millis(timing.processing_time());
+        entry.timings.blocked = Some(blocked);
+        entry.timings.send = millis(timing.send());
+        entry.timings.wait = millis(timing.wait());
+        entry.time = blocked + entry.timings.send + entry.timings.wait + entry.timings.receive;
+    }
+    if let Some(violation) = extensions.get::<SlaViolation>() {
+        append_comment(entry, &violation.to_string());
+    }
+}
+
+/// Flags in the comment of a HAR entry a response announcing transport
+/// security policies, `Strict-Transport-Security` or `Expect-CT`, as found
+/// by the proxy in the extensions of the response.
+///
+/// # Arguments
+/// * `entry` - The HAR entry of the exchange.
+/// * `extensions` - The extensions of the response returned by `ThirdWheel`.
+pub fn record_transport_security(entry: &mut Entries, extensions: &hyper::http::Extensions) {
+    if let Some(transport_security) = extensions.get::<TransportSecurity>() {
+        append_comment(entry, &transport_security.to_string());
+    }
+}
+
+/// Notes in the comment of a HAR entry what the proxy knows of the TLS
+/// sessions its exchange went through, as found in the extensions of the
+/// response. native-tls does not expose their TLS version nor cipher suite,
+/// only the protocols agreed on with ALPN are known.
+///
+/// # Arguments
+/// * `entry` - The HAR entry of the exchange.
+/// * `extensions` - The extensions of the response returned by `ThirdWheel`.
+pub fn record_tls_parameters(entry: &mut Entries, extensions: &hyper::http::Extensions) {
+    if let Some(tls_parameters) = extensions.get::<TlsParameters>() {
+        append_comment(entry, &tls_parameters.to_string());
+    }
+}
+
+/// Add a note to the comment of a HAR entry, after the ones it already has
+fn append_comment(entry: &mut Entries, note: &str) {
+    entry.comment = Some(match entry.comment.take() {
+        Some(comment) => format!("{}, {}", comment, note),
+        None => note.to_string(),
+    });
+}
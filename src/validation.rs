@@ -0,0 +1,195 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::DateTime;
+use har::v1_2::{self, Entries};
+use std::fmt;
+use std::path::Path;
+
+use crate::third_wheel::error::Error;
+use crate::utilities::BASE64_ENCODING;
+
+/// Largest difference, in milliseconds, allowed between the time of an entry
+/// and the sum of its timings, for the rounding of the sum
+const TIME_TOLERANCE_MS: f64 = 0.001;
+
+/// A field of a HAR archive breaking the HAR 1.2 specification
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    /// where the field is in the archive, e.g. `log.entries[2].request.url`
+    pub path: String,
+    /// what is wrong with it
+    pub message: String,
+}
+
+impl ValidationError {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Check a HAR archive against the HAR 1.2 specification: the fields it
+/// requires are filled, the start dates are ISO 8601 dates, the time of each
+/// entry is the sum of its timings and the base64 bodies decode.
+///
+/// # Arguments
+/// * `har` - The archive to check.
+///
+/// # Returns
+/// Every error found, or `Ok` if the archive is valid.
+pub fn validate_har(har: &har::Har) -> Result<(), Vec<ValidationError>> {
+    let log = match &har.log {
+        har::Spec::V1_2(log) => log,
+        har::Spec::V1_3(_) => {
+            return Err(vec![ValidationError::new("log", "not a HAR 1.2 archive")]);
+        }
+    };
+
+    let mut errors = Vec::new();
+    if log.creator.name.is_empty() {
+        errors.push(ValidationError::new("log.creator.name", "is empty"));
+    }
+    if log.creator.version.is_empty() {
+        errors.push(ValidationError::new("log.creator.version", "is empty"));
+    }
+    for (index, entry) in log.entries.iter().enumerate() {
+        errors.extend(validate_entry(&format!("log.entries[{}]", index), entry));
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+/// Check the HAR file written at `path`, see `validate_har`
+pub fn validate_har_file<P: AsRef<Path>>(path: P) -> Result<Vec<ValidationError>, Error> {
+    let har = har::from_path(path).map_err(|e| Error::ConfigError(e.to_string()))?;
+    Ok(validate_har(&har).err().unwrap_or_default())
+}
+
+/// Check a single entry of an archive, found at `path`
+pub fn validate_entry(path: &str, entry: &Entries) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let mut error = |field: &str, message: &str| {
+        errors.push(ValidationError::new(format!("{}.{}", path, field), message))
+    };
+
+    if DateTime::parse_from_rfc3339(&entry.started_date_time).is_err() {
+        error(
+            "startedDateTime",
+            &format!("{:?} is not an ISO 8601 date", entry.started_date_time),
+        );
+    }
+
+    let request = &entry.request;
+    if request.method.is_empty() {
+        error("request.method", "is empty");
+    }
+    // Tunnels are recorded with their target, as they are requested
+    let connect = request.method == "CONNECT";
+    match request.url.parse::<hyper::Uri>() {
+        Ok(url) if url.authority().is_some() && (connect || url.scheme().is_some()) => {}
+        _ => error(
+            "request.url",
+            &format!("{:?} is not an absolute URL", request.url),
+        ),
+    }
+    if request.http_version.is_empty() {
+        error("request.httpVersion", "is empty");
+    }
+    if let Some(post_data) = &request.post_data {
+        let base64 = post_data
+            .comment
+            .as_deref()
+            .is_some_and(|comment| comment.split(", ").any(|note| note == BASE64_ENCODING));
+        if base64 && !is_base64(post_data.text.as_deref()) {
+            error("request.postData.text", "is not valid base64");
+        }
+    }
+
+    // Requests which got no response have a status of 0 and nothing else
+    let response = &entry.response;
+    if response.status != 0 {
+        if !(100..=599).contains(&response.status) {
+            error(
+                "response.status",
+                &format!("{} is not an HTTP status", response.status),
+            );
+        }
+        if response.http_version.is_empty() {
+            error("response.httpVersion", "is empty");
+        }
+    }
+    if response.content.size < 0 {
+        error("response.content.size", "is negative");
+    }
+    match response.content.encoding.as_deref() {
+        None => {}
+        Some(BASE64_ENCODING) if is_base64(response.content.text.as_deref()) => {}
+        Some(BASE64_ENCODING) => error("response.content.text", "is not valid base64"),
+        Some(encoding) => error(
+            "response.content.encoding",
+            &format!("unknown encoding {:?}", encoding),
+        ),
+    }
+
+    errors.extend(validate_timings(path, entry));
+    errors
+}
+
+/// Check the timings of an entry add up to its time. The optional ones are
+/// -1 when they do not apply, and `ssl` is already counted in `connect`.
+fn validate_timings(path: &str, entry: &Entries) -> Vec<ValidationError> {
+    let timings: &v1_2::Timings = &entry.timings;
+    let mut errors = Vec::new();
+    let mut total = 0.0;
+    let optional = [
+        ("blocked", timings.blocked),
+        ("dns", timings.dns),
+        ("connect", timings.connect),
+        ("ssl", timings.ssl),
+    ];
+    for (name, timing) in optional {
+        match timing {
+            Some(timing) if timing < 0.0 && timing != -1.0 => errors.push(ValidationError::new(
+                format!("{}.timings.{}", path, name),
+                format!("{} is neither a duration nor -1", timing),
+            )),
+            Some(timing) if timing > 0.0 && name != "ssl" => total += timing,
+            _ => {}
+        }
+    }
+    let required = [
+        ("send", timings.send),
+        ("wait", timings.wait),
+        ("receive", timings.receive),
+    ];
+    for (name, timing) in required {
+        if timing < 0.0 {
+            errors.push(ValidationError::new(
+                format!("{}.timings.{}", path, name),
+                format!("{} is negative", timing),
+            ));
+        }
+        total += timing;
+    }
+    if (entry.time - total).abs() > TIME_TOLERANCE_MS {
+        errors.push(ValidationError::new(
+            format!("{}.time", path),
+            format!("{} is not the sum of the timings, {}", entry.time, total),
+        ));
+    }
+    errors
+}
+
+fn is_base64(text: Option<&str>) -> bool {
+    STANDARD.decode(text.unwrap_or("")).is_ok()
+}
@@ -0,0 +1,148 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::*;
+    use hyper::header::{AGE, CACHE_CONTROL, ETAG, IF_NONE_MATCH};
+    use hyper::{Body, Request, Response, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tls_interceptor_proxy::cache::{serve_cached, ResponseCache};
+    use tls_interceptor_proxy::third_wheel::certificates::CertificateAuthority;
+    use tls_interceptor_proxy::third_wheel::proxy::mitm::{mitm_layer, ThirdWheel};
+
+    /// A target counting the requests it answers, with the `Cache-Control`
+    /// given by the path and the `ETag` `"v1"`, answering a request holding
+    /// that `ETag` in `If-None-Match` with `304 Not Modified`
+    async fn spawn_counting_upstream(ca: &CertificateAuthority) -> (u16, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = spawn_upstream(ca, "localhost", move |req: Request<Body>| {
+            let hit = upstream_hits.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                let cache_control = match req.uri().path() {
+                    "/fresh" => "max-age=60",
+                    "/stale" => "no-cache",
+                    _ => "no-store",
+                };
+                let response = Response::builder()
+                    .header(CACHE_CONTROL, cache_control)
+                    .header(ETAG, "\"v1\"");
+                if req
+                    .headers()
+                    .get(IF_NONE_MATCH)
+                    .is_some_and(|tag| tag == "\"v1\"")
+                {
+                    return response
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(Body::empty())
+                        .unwrap();
+                }
+                response
+                    .body(Body::from(format!("answer {}", hit)))
+                    .unwrap()
+            }
+        })
+        .await;
+        (upstream.port(), hits)
+    }
+
+    /// Send the requests through a proxy answering from a cache,
+    /// returning the status and body of each response and whether it had an
+    /// `Age` header
+    async fn send_through_cache(
+        ca: &CertificateAuthority,
+        port: u16,
+        requests: Vec<Request<Body>>,
+    ) -> Vec<(StatusCode, String, bool)> {
+        let cache = Arc::new(Mutex::new(ResponseCache::default()));
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let cache = cache.clone();
+            Box::pin(async move { serve_cached(&cache, req, &mut third_wheel).await })
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, ca).build());
+
+        let mut client = client_through_proxy(proxy, "localhost", port, ca).await;
+        let mut responses = Vec::new();
+        for request in requests {
+            let response = client.send_request(request).await.unwrap();
+            let status = response.status();
+            let aged = response.headers().contains_key(AGE);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            responses.push((status, String::from_utf8(body.to_vec()).unwrap(), aged));
+        }
+        responses
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path)
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cacheable_response_served_from_cache() {
+        let ca = test_ca();
+        let (port, hits) = spawn_counting_upstream(&ca).await;
+
+        // Call the function
+        let responses = send_through_cache(&ca, port, vec![get("/fresh"), get("/fresh")]).await;
+
+        // Verify the second response came from the cache
+        assert_eq!(
+            responses,
+            vec![
+                (StatusCode::OK, "answer 1".to_string(), false),
+                (StatusCode::OK, "answer 1".to_string(), true),
+            ]
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_store_response_not_cached() {
+        let ca = test_ca();
+        let (port, hits) = spawn_counting_upstream(&ca).await;
+
+        // Call the function
+        let responses =
+            send_through_cache(&ca, port, vec![get("/no-store"), get("/no-store")]).await;
+
+        // Verify both requests reached the target
+        assert_eq!(responses[0].1, "answer 1");
+        assert_eq!(responses[1].1, "answer 2");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_request_answered_from_cache() {
+        let ca = test_ca();
+        let (port, hits) = spawn_counting_upstream(&ca).await;
+        let mut conditional = get("/fresh");
+        conditional
+            .headers_mut()
+            .insert(IF_NONE_MATCH, "\"v1\"".parse().unwrap());
+
+        // Call the function
+        let responses = send_through_cache(&ca, port, vec![get("/fresh"), conditional]).await;
+
+        // Verify the client was told its copy still holds without asking the target
+        assert_eq!(responses[1].0, StatusCode::NOT_MODIFIED);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_response_revalidated() {
+        let ca = test_ca();
+        let (port, hits) = spawn_counting_upstream(&ca).await;
+
+        // Call the function
+        let responses = send_through_cache(&ca, port, vec![get("/stale"), get("/stale")]).await;
+
+        // Verify the target confirmed the cached response, which was served
+        assert_eq!(responses[1], (StatusCode::OK, "answer 1".to_string(), true));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+}
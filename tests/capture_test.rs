@@ -0,0 +1,302 @@
+#[cfg(test)]
+mod tests {
+
+    use har::v1_2::{self, Entries};
+    use hyper::header::{CONTENT_TYPE, HOST};
+    use hyper::{Request, Response};
+    use tls_interceptor_proxy::capture::*;
+    use tls_interceptor_proxy::utilities::*;
+
+    /// A HAR entry for a small JSON exchange with example.com
+    async fn sample_entry() -> Entries {
+        let (req_parts, _) = Request::builder()
+            .method("POST")
+            .uri("/api?x=1")
+            .header(HOST, "example.com")
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (res_parts, _) = Response::builder()
+            .status(201)
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        Entries {
+            request: copy_from_http_request_to_har(&req_parts, b"{\"a\":1}".to_vec()).await,
+            response: copy_from_http_response_to_har(&res_parts, b"created".to_vec()).await,
+            time: 0.0,
+            server_ip_address: Some("127.0.0.1:1234".to_string()),
+            connection: None,
+            comment: None,
+            started_date_time: "2024-02-01T10:00:00.000+01:00".to_string(),
+            cache: v1_2::Cache {
+                before_request: None,
+                after_request: None,
+            },
+            timings: v1_2::Timings {
+                blocked: None,
+                dns: None,
+                connect: None,
+                send: 0.0,
+                wait: 0.0,
+                receive: 0.0,
+                ssl: None,
+                comment: None,
+            },
+            pageref: None,
+        }
+    }
+
+    #[test]
+    fn test_capture_format_from_str() {
+        // Call the function
+        let har = "har".parse::<CaptureFormat>();
+        let mitmproxy = "mitmproxy".parse::<CaptureFormat>();
+        let prompts = "prompts".parse::<CaptureFormat>();
+        let unknown = "pcap".parse::<CaptureFormat>();
+
+        // Verify the parsed formats
+        assert_eq!(har.unwrap(), CaptureFormat::Har);
+        assert_eq!(mitmproxy.unwrap(), CaptureFormat::Mitmproxy);
+        assert_eq!(prompts.unwrap(), CaptureFormat::Prompts);
+        assert!(unknown.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_har_file_sink() {
+        let path = std::env::temp_dir().join(format!("capture_test_{}.har", std::process::id()));
+        let entry = sample_entry().await;
+
+        // Call the function
+        let mut sink = CaptureFormat::Har.create_sink(&path).unwrap();
+        sink.record(&entry).unwrap();
+        sink.record(&entry).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Verify the file is a single archive holding both entries
+        let har: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 2);
+        assert!(contents.starts_with("{\n  \"log\": {"));
+    }
+
+    #[tokio::test]
+    async fn test_streaming_har_sink() {
+        let path =
+            std::env::temp_dir().join(format!("capture_test_stream_{}.har", std::process::id()));
+        let entry = sample_entry().await;
+
+        // Call the function, reading the file back after each entry
+        let mut sink = CaptureFormat::Har.create_sink(&path).unwrap();
+        let mut entry_counts = Vec::new();
+        for _ in 0..3 {
+            let contents = std::fs::read_to_string(&path).unwrap();
+            let har: serde_json::Value = serde_json::from_str(&contents).unwrap();
+            entry_counts.push(har["log"]["entries"].as_array().unwrap().len());
+            sink.record(&entry).unwrap();
+        }
+        drop(sink);
+        let har = har::from_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Verify the file was a valid archive at every point
+        assert_eq!(entry_counts, vec![0, 1, 2]);
+        let har::Spec::V1_2(log) = har.log else {
+            panic!("unexpected HAR version");
+        };
+        assert_eq!(log.entries.len(), 3);
+        assert_eq!(log.entries[2].request.url, entry.request.url);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_entries_file_is_valid_har() {
+        let path =
+            std::env::temp_dir().join(format!("capture_test_blocked_{}.har", std::process::id()));
+        let (parts, _) = Request::post("https://chatgpt.com/backend-api/conversation")
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let mut sink = CaptureFormat::Har.create_sink(&path).unwrap();
+        let options = CaptureOptions {
+            record_bodies: true,
+            ..CaptureOptions::default()
+        };
+        for body in [
+            r#"{"messages":[{"id":"one"}]}"#,
+            r#"{"messages":[{"id":"two"}]}"#,
+        ] {
+            let (entry, _) = log_blocked_request(
+                &parts,
+                body.as_bytes().to_vec(),
+                "127.0.0.1:1234".parse().unwrap(),
+                None,
+                &options,
+                &BlockMessages::default(),
+            )
+            .await;
+            sink.record(&entry).unwrap();
+        }
+        drop(sink);
+        let file = std::fs::File::open(&path).unwrap();
+        let har = har::from_reader(std::io::BufReader::new(file)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Verify the file is one HAR document holding both blocked entries
+        let har::Spec::V1_2(log) = har.log else {
+            panic!("unexpected HAR version");
+        };
+        assert_eq!(log.entries.len(), 2);
+        let prompt = log.entries[1].request.post_data.as_ref().unwrap();
+        assert_eq!(
+            prompt.text.as_deref(),
+            Some(r#"{"messages":[{"id":"two"}]}"#)
+        );
+    }
+
+    /// An in-memory file counting the bytes written to it
+    struct CountingWriter {
+        file: std::io::Cursor<Vec<u8>>,
+        written: usize,
+    }
+
+    impl std::io::Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written += buf.len();
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for CountingWriter {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_har_sink_cost_per_entry_is_constant() {
+        let entry = sample_entry().await;
+        let mut sink = StreamingHarSink::new(CountingWriter {
+            file: std::io::Cursor::new(Vec::new()),
+            written: 0,
+        })
+        .unwrap();
+        sink.record(&entry).unwrap();
+
+        // Call the function, counting what each entry writes
+        let mut written = Vec::new();
+        for _ in 0..1000 {
+            let before = sink.get_ref().written;
+            sink.record(&entry).unwrap();
+            written.push(sink.get_ref().written - before);
+        }
+
+        // Verify the last entry cost as much as the first, the archive holding all
+        assert!(written.iter().all(|bytes| *bytes == written[0]));
+        let file = sink.into_inner().file.into_inner();
+        let har: serde_json::Value = serde_json::from_slice(&file).unwrap();
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 1001);
+    }
+
+    #[tokio::test]
+    async fn test_mitmproxy_flow_sink() {
+        let entry = sample_entry().await;
+
+        // Call the function
+        let mut sink = MitmproxyFlowSink::new(Vec::new());
+        sink.record(&entry).unwrap();
+        let bytes = sink.into_inner();
+
+        // Verify the flow is a single tnetstring dictionary
+        let separator = bytes.iter().position(|byte| *byte == b':').unwrap();
+        let length: usize = std::str::from_utf8(&bytes[..separator])
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(bytes.len(), separator + 1 + length + 1);
+        assert_eq!(*bytes.last().unwrap(), b'}');
+
+        // Verify the exchange is stored the way mitmproxy expects it
+        let flow = String::from_utf8_lossy(&bytes);
+        let expected = [
+            "4:type;4:http;".to_string(),
+            format!("7:version;2:{}#", MITMPROXY_FLOW_FORMAT_VERSION),
+            "6:method;4:POST,".to_string(),
+            "4:host;11:example.com;".to_string(),
+            "4:port;3:443#".to_string(),
+            "4:path;8:/api?x=1,".to_string(),
+            "7:content;7:{\"a\":1},".to_string(),
+            "11:status_code;3:201#".to_string(),
+            "7:content;7:created,".to_string(),
+            "8:peername;19:9:127.0.0.1;4:1234#]".to_string(),
+        ];
+        for fragment in expected {
+            assert!(flow.contains(&fragment), "missing {}", fragment);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_summary() {
+        let path = std::env::temp_dir().join(format!("capture_summary_{}.txt", std::process::id()));
+        let mut fast = sample_entry().await;
+        fast.time = 10.0;
+        let mut slow = sample_entry().await;
+        slow.time = 30.0;
+        let mut failed = sample_entry().await;
+        failed.time = 5.0;
+        failed.response.status = 502;
+        for header in failed.request.headers.iter_mut() {
+            if header.name.eq_ignore_ascii_case("host") {
+                header.value = "api.example.org:8443".to_string();
+            }
+        }
+
+        // Call the function on a short session
+        let mut summary = SessionSummary::new();
+        for entry in [&fast, &failed, &slow] {
+            summary.record(entry).unwrap();
+        }
+        summary.write_to_file(&path).unwrap();
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Verify the hosts, without their port, and what each one exchanged
+        let hosts: Vec<&String> = summary.hosts().keys().collect();
+        assert_eq!(hosts, vec!["api.example.org", "example.com"]);
+        let sent = (fast.request.headers_size + fast.request.body_size) as u64;
+        let received = (fast.response.headers_size + fast.response.body_size) as u64;
+        let example = &summary.hosts()["example.com"];
+        assert_eq!(example.requests, 2);
+        assert_eq!(example.bytes_sent, 2 * sent);
+        assert_eq!(example.bytes_received, 2 * received);
+        assert_eq!(example.errors, 0);
+        assert_eq!(
+            example.average_latency(),
+            Some(std::time::Duration::from_millis(20))
+        );
+        let api = &summary.hosts()["api.example.org"];
+        assert_eq!(api.requests, 1);
+        assert_eq!(api.errors, 1);
+
+        // Verify the report written has a line per host
+        assert_eq!(
+            report,
+            format!(
+                "api.example.org: 1 requests, {} bytes sent, {} bytes received, 1 errors, 5.0 ms average latency\n\
+                 example.com: 2 requests, {} bytes sent, {} bytes received, 0 errors, 20.0 ms average latency\n",
+                api.bytes_sent,
+                api.bytes_received,
+                2 * sent,
+                2 * received
+            )
+        );
+    }
+}
@@ -0,0 +1,550 @@
+#[cfg(test)]
+mod tests {
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::{
+        extension::{ExtendedKeyUsage, SubjectAlternativeName},
+        X509Name, X509,
+    };
+    use std::time::Duration;
+    use tls_interceptor_proxy::third_wheel::certificates::{
+        create_signed_certificate_for_domain, spoof_certificate_with_options, CertificateAuthority,
+        KeyType, SerialStrategy, SpoofOptions, DEFAULT_SPOOFED_CERT_VALIDITY,
+    };
+    use tls_interceptor_proxy::third_wheel::error::Error;
+
+    /// Spoof `target` as the proxy does by default
+    fn spoof_with_defaults(target: &X509, ca: &CertificateAuthority) -> Result<X509, Error> {
+        spoof_certificate_with_options(target, ca, &SpoofOptions::default())
+    }
+
+    /// The certificate authority shipped in the repository
+    fn fixture_ca() -> CertificateAuthority {
+        CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+            "ca/ca_certs/cert.pem",
+            "ca/ca_certs/key.pem",
+            "third-wheel",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_fingerprint_sha256() {
+        // Call the function
+        let fingerprint = fixture_ca().fingerprint_sha256().unwrap();
+
+        // Verify it matches `openssl x509 -in ca/ca_certs/cert.pem -noout -fingerprint -sha256`
+        assert_eq!(
+            fingerprint,
+            "F2:4F:B1:1D:A2:B4:BC:45:2B:AE:C4:BF:F3:AD:3B:B0:73:DF:2C:BE:5B:A5:78:9A:2E:25:89:5F:47:44:BD:E1"
+        );
+    }
+
+    #[test]
+    fn test_subject_and_not_after() {
+        let ca = fixture_ca();
+
+        // Call the function
+        let subject = ca.subject();
+        let not_after = ca.not_after();
+
+        // Verify they match `openssl x509 -in ca/ca_certs/cert.pem -noout -subject -enddate`
+        assert_eq!(
+            subject,
+            "C=US, ST=private, L=province, O=city, CN=hostname.example.com"
+        );
+        assert_eq!(not_after, "Oct 26 21:59:25 2025 GMT");
+    }
+
+    #[test]
+    fn test_spoof_certificate_copies_full_subject() {
+        let ca = fixture_ca();
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("C", "FR").unwrap();
+        subject.append_entry_by_text("O", "Example Corp").unwrap();
+        subject.append_entry_by_text("OU", "Web Services").unwrap();
+        subject.append_entry_by_text("CN", "example.com").unwrap();
+        let subject = subject.build();
+        let mut target = X509::builder().unwrap();
+        target.set_version(2).unwrap();
+        target.set_subject_name(&subject).unwrap();
+        target.set_issuer_name(&subject).unwrap();
+        target
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        target
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        target.set_pubkey(&ca.key).unwrap();
+        target.sign(&ca.key, MessageDigest::sha256()).unwrap();
+        let target = target.build();
+
+        // Call the function
+        let spoofed = spoof_with_defaults(&target, &ca).unwrap();
+
+        // Verify every entry of the subject was reproduced as is
+        assert_eq!(
+            spoofed.subject_name().to_der().unwrap(),
+            target.subject_name().to_der().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_spoof_certificate_copies_ip_addresses() {
+        let ca = fixture_ca();
+        let mut target = X509::builder().unwrap();
+        target.set_version(2).unwrap();
+        target
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        target
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        target.set_pubkey(&ca.key).unwrap();
+        let alt_names = SubjectAlternativeName::new()
+            .ip("127.0.0.1")
+            .ip("::1")
+            .build(&target.x509v3_context(None, None))
+            .unwrap();
+        target.append_extension(alt_names).unwrap();
+        target.sign(&ca.key, MessageDigest::sha256()).unwrap();
+        let target = target.build();
+
+        // Call the function
+        let spoofed = spoof_with_defaults(&target, &ca).unwrap();
+
+        // Verify the addresses were copied as addresses
+        let addresses: Vec<Vec<u8>> = spoofed
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|name| name.ipaddress().map(<[u8]>::to_vec))
+            .collect();
+        let mut loopback_v6 = vec![0; 16];
+        loopback_v6[15] = 1;
+        assert_eq!(addresses, vec![vec![127, 0, 0, 1], loopback_v6]);
+    }
+
+    /// The DNS names of the alternative names of a certificate
+    fn dns_names(certificate: &X509) -> Vec<String> {
+        certificate
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.dnsname().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_spoof_certificate_copies_all_dns_names() {
+        let ca = fixture_ca();
+        let mut target = X509::builder().unwrap();
+        target.set_version(2).unwrap();
+        target
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        target
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        target.set_pubkey(&ca.key).unwrap();
+        let alt_names = SubjectAlternativeName::new()
+            .dns("example.com")
+            .dns("www.example.com")
+            .dns("*.cdn.example.com")
+            .build(&target.x509v3_context(None, None))
+            .unwrap();
+        target.append_extension(alt_names).unwrap();
+        target.sign(&ca.key, MessageDigest::sha256()).unwrap();
+        let target = target.build();
+
+        // Call the function
+        let options = SpoofOptions {
+            host: Some("www.example.com"),
+            ..SpoofOptions::default()
+        };
+        let spoofed = spoof_certificate_with_options(&target, &ca, &options).unwrap();
+
+        // Verify every name of the target was kept, in its order
+        assert_eq!(
+            dns_names(&spoofed),
+            vec!["example.com", "www.example.com", "*.cdn.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_spoof_certificate_names_host_without_alt_names() {
+        let ca = fixture_ca();
+        let target = target_with_client_auth(&ca);
+
+        // Call the function
+        let named = spoof_certificate_with_options(
+            &target,
+            &ca,
+            &SpoofOptions {
+                host: Some("legacy.example.com"),
+                ..SpoofOptions::default()
+            },
+        )
+        .unwrap();
+        let address = spoof_certificate_with_options(
+            &target,
+            &ca,
+            &SpoofOptions {
+                host: Some("10.0.0.1"),
+                ..SpoofOptions::default()
+            },
+        )
+        .unwrap();
+
+        // Verify the host is the alternative name, as an address for an IP
+        assert_eq!(dns_names(&named), vec!["legacy.example.com"]);
+        assert!(dns_names(&address).is_empty());
+        let addresses: Vec<Vec<u8>> = address
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|name| name.ipaddress().map(<[u8]>::to_vec))
+            .collect();
+        assert_eq!(addresses, vec![vec![10, 0, 0, 1]]);
+        assert!(spoof_with_defaults(&target, &ca)
+            .unwrap()
+            .subject_alt_names()
+            .is_none());
+    }
+
+    #[test]
+    fn test_spoofed_certificates_have_random_serial_numbers() {
+        let ca = fixture_ca();
+        let target = target_with_client_auth(&ca);
+
+        // Call the function twice for the same target
+        let first = spoof_with_defaults(&target, &ca).unwrap();
+        let second = spoof_with_defaults(&target, &ca).unwrap();
+
+        // Verify the serial numbers differ, from the target's too, and are
+        // positive numbers of 128 bits
+        let serial = |certificate: &X509| certificate.serial_number().to_bn().unwrap();
+        assert_ne!(serial(&first), serial(&second));
+        assert_ne!(serial(&first), serial(&target));
+        for certificate in [&first, &second] {
+            assert!(!serial(certificate).is_negative());
+            assert_eq!(serial(certificate).num_bits(), 128);
+        }
+    }
+
+    #[test]
+    fn test_spoofed_certificate_serial_from_host() {
+        let ca = fixture_ca();
+        let target = target_with_client_auth(&ca);
+        let spoof = |host| {
+            let options = SpoofOptions {
+                host: Some(host),
+                serial_strategy: SerialStrategy::FromHost,
+                ..SpoofOptions::default()
+            };
+            spoof_certificate_with_options(&target, &ca, &options).unwrap()
+        };
+
+        // Call the function twice for a host and once for another
+        let first = spoof("www.example.com");
+        let second = spoof("www.example.com");
+        let other = spoof("api.example.com");
+
+        // Verify a host keeps its serial number, the one derived from its
+        // digest in any run, and another host is given another one
+        let serial = |certificate: &X509| certificate.serial_number().to_bn().unwrap();
+        assert_eq!(
+            serial(&first).to_hex_str().unwrap().to_string(),
+            "80FC0FB9266DB7B83F85850FA0E6548B"
+        );
+        assert_eq!(serial(&first), serial(&second));
+        assert_ne!(serial(&first), serial(&other));
+        assert_eq!(serial(&other).num_bits(), 128);
+    }
+
+    #[test]
+    fn test_spoofed_certificate_validity() {
+        let ca = fixture_ca();
+        let target = target_with_client_auth(&ca);
+
+        // Call the function, with the default validity and a shorter one
+        let default = spoof_with_defaults(&target, &ca).unwrap();
+        let short = spoof_certificate_with_options(
+            &target,
+            &ca,
+            &SpoofOptions {
+                validity: Duration::from_secs(30 * 24 * 60 * 60),
+                ..SpoofOptions::default()
+            },
+        )
+        .unwrap();
+
+        // Verify each window spans its validity, starting about an hour ago
+        let now = Asn1Time::days_from_now(0).unwrap();
+        for (certificate, validity) in [
+            (&default, DEFAULT_SPOOFED_CERT_VALIDITY),
+            (&short, Duration::from_secs(30 * 24 * 60 * 60)),
+        ] {
+            let window = certificate
+                .not_before()
+                .diff(certificate.not_after())
+                .unwrap();
+            assert_eq!(
+                window.days as u64 * 24 * 60 * 60 + window.secs as u64,
+                validity.as_secs()
+            );
+            let backdated = certificate.not_before().diff(&now).unwrap();
+            assert_eq!(backdated.days, 0);
+            assert!((3600..3660).contains(&backdated.secs), "{}", backdated.secs);
+        }
+    }
+
+    #[test]
+    fn test_generate_ca() {
+        for key_type in [KeyType::Rsa2048, KeyType::EcdsaP256] {
+            // Call the function
+            let ca = CertificateAuthority::generate("Test CA", 30, key_type).unwrap();
+
+            // Verify the certificate is self-signed with the given name and
+            // validity, and signs certificates it verifies
+            assert_eq!(ca.subject(), "CN=Test CA");
+            assert!(ca.cert.verify(&ca.cert.public_key().unwrap()).unwrap());
+            let in_30_days = Asn1Time::days_from_now(30).unwrap();
+            let diff = ca.cert.not_after().diff(&in_30_days).unwrap();
+            assert_eq!(diff.days, 0);
+            let leaf = create_signed_certificate_for_domain("example.com", &ca).unwrap();
+            assert!(leaf.verify(&ca.cert.public_key().unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_generated_ca_saved_with_passphrase() {
+        let dir = std::env::temp_dir().join(format!("generated_ca_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+        let ca = CertificateAuthority::generate("Test CA", 1, KeyType::EcdsaP256).unwrap();
+
+        // Call the function
+        ca.save_to_pem_files(&cert_file, &key_file, Some("secret"))
+            .unwrap();
+
+        // Verify the files load back, the key only with its passphrase
+        let loaded = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+            &cert_file, &key_file, "secret",
+        )
+        .unwrap();
+        let wrong_passphrase = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+            &cert_file, &key_file, "wrong",
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            loaded.fingerprint_sha256().unwrap(),
+            ca.fingerprint_sha256().unwrap()
+        );
+        assert!(loaded.key.public_eq(&ca.key));
+        assert!(wrong_passphrase.is_err());
+    }
+
+    #[test]
+    fn test_parse_key_type() {
+        // Call the function
+        let key_type: Result<KeyType, _> = "ecdsa-p256".parse();
+        let unknown: Result<KeyType, _> = "dsa".parse();
+
+        // Verify the known types are parsed and the others refused
+        assert_eq!(key_type.unwrap(), KeyType::EcdsaP256);
+        assert!(unknown.is_err());
+    }
+
+    /// The value of an extension of a certificate, as OpenSSL prints it
+    fn extension_text(certificate: &X509, extension: &str) -> Option<String> {
+        let text = String::from_utf8(certificate.to_text().unwrap()).unwrap();
+        let mut lines = text.lines();
+        lines.find(|line| line.trim_start().starts_with(extension))?;
+        lines.next().map(|line| line.trim().to_string())
+    }
+
+    /// A target certificate with the `serverAuth` and `clientAuth` usages
+    fn target_with_client_auth(ca: &CertificateAuthority) -> X509 {
+        let mut usages = ExtendedKeyUsage::new();
+        usages.server_auth().client_auth();
+        target_with_extended_key_usages(ca, usages)
+    }
+
+    /// A target certificate with the given extended key usages
+    fn target_with_extended_key_usages(
+        ca: &CertificateAuthority,
+        mut usages: ExtendedKeyUsage,
+    ) -> X509 {
+        let mut target = X509::builder().unwrap();
+        target.set_version(2).unwrap();
+        target
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        target
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        target.set_pubkey(&ca.key).unwrap();
+        target.append_extension(usages.build().unwrap()).unwrap();
+        target.sign(&ca.key, MessageDigest::sha256()).unwrap();
+        target.build()
+    }
+
+    #[test]
+    fn test_spoofed_certificate_key_usages() {
+        let ca = fixture_ca();
+        let target = target_with_client_auth(&ca);
+
+        // Call the function
+        let spoofed = spoof_with_defaults(&target, &ca).unwrap();
+        let domain = create_signed_certificate_for_domain("example.com", &ca).unwrap();
+
+        // Verify both have the usages of a server certificate only
+        for certificate in [spoofed, domain] {
+            assert_eq!(
+                extension_text(&certificate, "X509v3 Key Usage").unwrap(),
+                "Digital Signature, Key Encipherment"
+            );
+            assert_eq!(
+                extension_text(&certificate, "X509v3 Extended Key Usage").unwrap(),
+                "TLS Web Server Authentication"
+            );
+        }
+    }
+
+    #[test]
+    fn test_spoofed_certificate_copies_extended_key_usages() {
+        let ca = fixture_ca();
+        let target = target_with_client_auth(&ca);
+
+        // Call the function
+        let options = SpoofOptions {
+            copy_extended_key_usages: true,
+            ..SpoofOptions::default()
+        };
+        let spoofed = spoof_certificate_with_options(&target, &ca, &options).unwrap();
+
+        // Verify the other usages of the target follow serverAuth
+        assert_eq!(
+            extension_text(&spoofed, "X509v3 Extended Key Usage").unwrap(),
+            "TLS Web Server Authentication, TLS Web Client Authentication"
+        );
+    }
+
+    #[test]
+    fn test_spoofed_certificate_copies_unnamed_extended_key_usages() {
+        let ca = fixture_ca();
+        let mut usages = ExtendedKeyUsage::new();
+        usages.server_auth().other("1.3.6.1.4.1.55555.1");
+        let target = target_with_extended_key_usages(&ca, usages);
+
+        // Call the function
+        let options = SpoofOptions {
+            copy_extended_key_usages: true,
+            ..SpoofOptions::default()
+        };
+        let spoofed = spoof_certificate_with_options(&target, &ca, &options).unwrap();
+
+        // Verify a usage OpenSSL has no name for is copied by its OID
+        assert_eq!(
+            extension_text(&spoofed, "X509v3 Extended Key Usage").unwrap(),
+            "TLS Web Server Authentication, 1.3.6.1.4.1.55555.1"
+        );
+    }
+
+    #[test]
+    fn test_ecdsa_certificate_key_usage() {
+        let ca = CertificateAuthority::generate("Test CA", 1, KeyType::EcdsaP256).unwrap();
+
+        // Call the function
+        let certificate = create_signed_certificate_for_domain("example.com", &ca).unwrap();
+
+        // Verify no key encipherment is claimed for an ECDSA key
+        assert_eq!(
+            extension_text(&certificate, "X509v3 Key Usage").unwrap(),
+            "Digital Signature"
+        );
+    }
+
+    #[test]
+    fn test_encrypted_key_without_passphrase() {
+        // Call the function on the shipped key, which is encrypted
+        let result = CertificateAuthority::load_from_pem_files(
+            "ca/ca_certs/cert.pem",
+            "ca/ca_certs/key.pem",
+        );
+
+        // Verify the error says a passphrase is needed
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("a passphrase is needed"), "{}", message);
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        // Call the function
+        let result = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+            "ca/ca_certs/cert.pem",
+            "ca/ca_certs/key.pem",
+            "not-the-passphrase",
+        );
+
+        // Verify the error says the passphrase is wrong
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("does not decrypt"), "{}", message);
+    }
+
+    #[test]
+    fn test_unencrypted_key() {
+        let dir = std::env::temp_dir().join(format!("unencrypted_ca_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+        let ca = CertificateAuthority::generate("Test CA", 1, KeyType::Rsa2048).unwrap();
+        ca.save_to_pem_files(&cert_file, &key_file, None).unwrap();
+
+        // Call the function
+        let loaded = CertificateAuthority::load_from_pem_files(&cert_file, &key_file);
+        let with_passphrase = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+            &cert_file,
+            &key_file,
+            "third-wheel",
+        );
+        let encrypted = CertificateAuthority::is_key_file_encrypted(&key_file).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Verify the key loads without a passphrase only
+        assert!(loaded.unwrap().key.public_eq(&ca.key));
+        let message = with_passphrase.err().unwrap().to_string();
+        assert!(message.contains("not encrypted"), "{}", message);
+        assert!(!encrypted);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_saved_key_readable_by_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("key_mode_ca_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+        // A key file left readable by everyone, as when overwriting with --force
+        std::fs::write(&key_file, "old key").unwrap();
+        std::fs::set_permissions(&key_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let ca = CertificateAuthority::generate("Test CA", 1, KeyType::Rsa2048).unwrap();
+
+        // Call the function
+        ca.save_to_pem_files(&cert_file, &key_file, None).unwrap();
+        let mode = std::fs::metadata(&key_file).unwrap().permissions().mode();
+        let loaded = CertificateAuthority::load_from_pem_files(&cert_file, &key_file);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Verify the overwritten key is only readable by its owner
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(loaded.unwrap().key.public_eq(&ca.key));
+    }
+}
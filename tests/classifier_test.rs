@@ -0,0 +1,66 @@
+#[cfg(test)]
+mod tests {
+
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tls_interceptor_proxy::classifier::*;
+    use tls_interceptor_proxy::rules::{BlockRule, BlockRules, RuleAction};
+    use tls_interceptor_proxy::third_wheel::error::Error;
+
+    /// A classifier counting the prompts it is asked about, failing on the
+    /// ones containing "fail"
+    #[derive(Default)]
+    struct CountingClassifier {
+        calls: AtomicUsize,
+    }
+
+    impl PromptClassifier for CountingClassifier {
+        fn is_sensitive<'a>(&'a self, prompt: &'a str) -> BoxFuture<'a, Result<bool, Error>> {
+            Box::pin(async move {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                match prompt.contains("fail") {
+                    true => Err(Error::ServerError("classifier unavailable".to_string())),
+                    false => Ok(prompt.len() > 10),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keyword_classifier() {
+        let classifier = KeywordClassifier::default();
+
+        // Call the function
+        let confidential = classifier.is_sensitive("a confidential plan").await;
+        let public = classifier.is_sensitive("a Confidential plan").await;
+
+        // Verify the default keywords are matched as written
+        assert!(confidential.unwrap());
+        assert!(!public.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rules_ask_injected_classifier() {
+        let catch_all = || BlockRule {
+            host: Some("llm.example.com".to_string()),
+            ..BlockRule::default()
+        };
+        let rules = BlockRules::new(vec![catch_all(), catch_all()]);
+        let classifier = CountingClassifier::default();
+        let check = |prompt: &'static str| {
+            rules.check("llm.example.com", "POST", "/chat", None, prompt, &classifier)
+        };
+
+        // Call the function
+        let long = check("a long enough prompt").await.unwrap();
+        let short = check("short").await.unwrap();
+        let failed = check("fail").await;
+
+        // Verify the classifier decides, is asked once per prompt, and its
+        // errors are returned
+        assert_eq!(long, RuleAction::Block);
+        assert_eq!(short, RuleAction::Allow);
+        assert!(failed.is_err());
+        assert_eq!(classifier.calls.load(Ordering::SeqCst), 3);
+    }
+}
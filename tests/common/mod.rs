@@ -0,0 +1,315 @@
+//! Helpers shared by the integration tests: a throwaway certificate authority,
+//! a local TLS server standing in for the target and a client tunnelling
+//! through the proxy with CONNECT.
+#![allow(dead_code)]
+
+use hyper::client::conn::SendRequest;
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use hyper::{Body, Request, Response};
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    hash::MessageDigest,
+    pkcs12::Pkcs12,
+    pkey::PKey,
+    rsa::Rsa,
+    x509::{
+        extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier},
+        X509Name, X509,
+    },
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use tls_interceptor_proxy::third_wheel::{
+    certificates::{create_signed_certificate_for_domain, CertificateAuthority},
+    proxy::{mitm::ThirdWheel, MitmProxy, MitmProxyBuilder},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_native_tls::{TlsAcceptor, TlsStream};
+use tower::Layer;
+
+/// Create a fresh self-signed certificate authority for a test
+pub fn test_ca() -> CertificateAuthority {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_text("CN", "tls_interceptor_proxy test CA")
+        .unwrap();
+    let name = name.build();
+
+    let mut cert_builder = X509::builder().unwrap();
+    cert_builder.set_version(2).unwrap();
+    let serial_number = {
+        let mut serial_number = BigNum::new().unwrap();
+        serial_number
+            .rand(159, MsbOption::MAYBE_ZERO, false)
+            .unwrap();
+        serial_number.to_asn1_integer().unwrap()
+    };
+    cert_builder.set_serial_number(&serial_number).unwrap();
+    cert_builder.set_subject_name(&name).unwrap();
+    cert_builder.set_issuer_name(&name).unwrap();
+    cert_builder.set_pubkey(&key).unwrap();
+    cert_builder
+        .set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+        .unwrap();
+    cert_builder
+        .set_not_after(Asn1Time::days_from_now(1).unwrap().as_ref())
+        .unwrap();
+    cert_builder
+        .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    cert_builder
+        .append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    let subject_key_identifier = SubjectKeyIdentifier::new()
+        .build(&cert_builder.x509v3_context(None, None))
+        .unwrap();
+    cert_builder
+        .append_extension(subject_key_identifier)
+        .unwrap();
+    cert_builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    CertificateAuthority {
+        cert: cert_builder.build(),
+        key,
+    }
+}
+
+/// The certificate of the authority, to be trusted by clients of the proxy and
+/// by the proxy itself when connecting to test servers
+pub fn trusted_certificate(ca: &CertificateAuthority) -> native_tls::Certificate {
+    native_tls::Certificate::from_der(&ca.cert.to_der().unwrap()).unwrap()
+}
+
+/// A TLS identity for `domain` signed by the test authority
+pub fn identity_for_domain(ca: &CertificateAuthority, domain: &str) -> native_tls::Identity {
+    let certificate = create_signed_certificate_for_domain(domain, ca).unwrap();
+    identity_for_certificate(ca, &certificate)
+}
+
+/// A TLS identity for a certificate using the key of the test authority
+pub fn identity_for_certificate(
+    ca: &CertificateAuthority,
+    certificate: &X509,
+) -> native_tls::Identity {
+    let pkcs = Pkcs12::builder()
+        .name("test")
+        .pkey(&ca.key)
+        .cert(certificate)
+        .build2("test")
+        .unwrap()
+        .to_der()
+        .unwrap();
+    native_tls::Identity::from_pkcs12(&pkcs, "test").unwrap()
+}
+
+/// Start a TLS server for `domain` answering every request with `handler`.
+/// Returns the address it listens on.
+pub async fn spawn_upstream<F, Fut>(
+    ca: &CertificateAuthority,
+    domain: &str,
+    handler: F,
+) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response<Body>> + Send + 'static,
+{
+    let certificate = create_signed_certificate_for_domain(domain, ca).unwrap();
+    spawn_upstream_with_certificate(ca, &certificate, handler).await
+}
+
+/// Start a TLS server presenting `certificate`, whose key must be the one of
+/// the test authority, answering every request with `handler`
+pub async fn spawn_upstream_with_certificate<F, Fut>(
+    ca: &CertificateAuthority,
+    certificate: &X509,
+    handler: F,
+) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response<Body>> + Send + 'static,
+{
+    let acceptor = TlsAcceptor::from(
+        native_tls::TlsAcceptor::new(identity_for_certificate(ca, certificate)).unwrap(),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    let service = service_fn(move |req| {
+                        let handler = handler.clone();
+                        async move { Ok::<_, Infallible>(handler(req).await) }
+                    });
+                    let _ = Http::new().serve_connection(stream, service).await;
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+/// Start a TLS server for `domain` that reads one raw request head per
+/// connection, hands it to `inspect` and answers with an empty 200 response
+pub async fn spawn_raw_upstream<F>(
+    ca: &CertificateAuthority,
+    domain: &str,
+    inspect: F,
+) -> SocketAddr
+where
+    F: Fn(String) + Clone + Send + Sync + 'static,
+{
+    let acceptor =
+        TlsAcceptor::from(native_tls::TlsAcceptor::new(identity_for_domain(ca, domain)).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let inspect = inspect.clone();
+            tokio::spawn(async move {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let head = read_head(&mut stream).await;
+                    inspect(String::from_utf8_lossy(&head).to_string());
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await;
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+/// A proxy builder that trusts the test authority and resolves `localhost`
+/// to the IPv4 loopback where the test servers listen
+pub fn proxy_builder<T, U>(mitm_layer: T, ca: &CertificateAuthority) -> MitmProxyBuilder<T, U>
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + Clone
+        + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+    <U as Service<Request<Body>>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    MitmProxy::builder(mitm_layer, ca.clone())
+        .additional_root_certificates(vec![trusted_certificate(ca)])
+        .additional_host_mappings(HashMap::from([(
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+        )]))
+}
+
+/// Run the proxy on an ephemeral port, returning the address it is bound to
+pub fn spawn_proxy<T, U>(mitm_proxy: MitmProxy<T, U>) -> SocketAddr
+where
+    T: Layer<ThirdWheel, Service = U> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + std::marker::Sync
+        + std::marker::Send
+        + Clone
+        + 'static,
+    <U as Service<Request<Body>>>::Future: Send,
+    <U as Service<Request<Body>>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    let (addr, server) = mitm_proxy.bind("127.0.0.1:0".parse().unwrap());
+    tokio::spawn(server);
+    addr
+}
+
+/// Read from the stream until the end of an HTTP head
+pub async fn read_head<S: tokio::io::AsyncRead + Unpin>(stream: &mut S) -> Vec<u8> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte).await {
+            Ok(1) => head.push(byte[0]),
+            _ => break,
+        }
+    }
+    head
+}
+
+/// Send a CONNECT request for `host:port` to the proxy, returning the stream
+/// and the head of the answer of the proxy
+pub async fn send_connect(proxy: SocketAddr, host: &str, port: u16) -> (TcpStream, Vec<u8>) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n",
+                host = host,
+                port = port
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = read_head(&mut stream).await;
+    (stream, head)
+}
+
+/// Open a CONNECT tunnel to `host:port` through the proxy, returning the raw
+/// stream once the proxy accepted the tunnel
+pub async fn open_tunnel(proxy: SocketAddr, host: &str, port: u16) -> TcpStream {
+    let (stream, head) = send_connect(proxy, host, port).await;
+    assert!(
+        head.starts_with(b"HTTP/1.1 200"),
+        "CONNECT was refused: {}",
+        String::from_utf8_lossy(&head)
+    );
+    stream
+}
+
+/// Open a tunnel and perform the TLS handshake with the proxy, trusting the
+/// test authority
+pub async fn tls_through_proxy(
+    proxy: SocketAddr,
+    host: &str,
+    port: u16,
+    ca: &CertificateAuthority,
+) -> TlsStream<TcpStream> {
+    let stream = open_tunnel(proxy, host, port).await;
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(trusted_certificate(ca))
+        .build()
+        .unwrap();
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, stream)
+        .await
+        .unwrap()
+}
+
+/// Open a tunnel through the proxy and return an HTTP client speaking over it
+pub async fn client_through_proxy(
+    proxy: SocketAddr,
+    host: &str,
+    port: u16,
+    ca: &CertificateAuthority,
+) -> SendRequest<Body> {
+    let stream = tls_through_proxy(proxy, host, port, ca).await;
+    let (request_sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    request_sender
+}
@@ -0,0 +1,218 @@
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use tls_interceptor_proxy::config::*;
+    use tls_interceptor_proxy::third_wheel::certificates::{CertificateAuthority, KeyType};
+    use tls_interceptor_proxy::third_wheel::error::Error;
+    use tls_interceptor_proxy::third_wheel::proxy::ListenerOptions;
+
+    const SAMPLE_CONFIG: &str = r#"
+        port = 9000
+        outfile = "session.har"
+        cert_file = "certs/ca.pem"
+        key_file = "certs/ca.key"
+        passphrase = "secret"
+        listen_backlog = 1024
+        tcp_nodelay = true
+
+        [host_mappings]
+        "example.com" = "127.0.0.1"
+        "api.example.com" = "10.0.0.2"
+
+        [latency_sla]
+        "*.example.com" = 250
+    "#;
+
+    #[test]
+    fn test_load_config() {
+        // Write the sample configuration to a file
+        let path = std::env::temp_dir().join(format!("config_test_{}.toml", std::process::id()));
+        std::fs::write(&path, SAMPLE_CONFIG).unwrap();
+
+        // Call the function
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Verify the loaded values
+        assert_eq!(config.port(), 9000);
+        assert_eq!(config.outfile(), "session.har");
+        assert_eq!(config.cert_file(), "certs/ca.pem");
+        assert_eq!(config.key_file(), "certs/ca.key");
+        assert_eq!(config.passphrase().unwrap(), "secret");
+        assert_eq!(config.host_mappings["example.com"], "127.0.0.1");
+        assert_eq!(config.host_mappings["api.example.com"], "10.0.0.2");
+        assert_eq!(
+            config.latency_sla()["*.example.com"],
+            std::time::Duration::from_millis(250)
+        );
+        let listener_options = config.listener_options();
+        assert_eq!(listener_options.backlog, 1024);
+        assert!(listener_options.nodelay);
+        assert!(!listener_options.reuse_port);
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        // An empty file uses the same defaults as the command line
+        let config = Config::from_toml_str("").unwrap();
+
+        // Verify the default values
+        assert_eq!(config.port(), DEFAULT_PORT);
+        assert_eq!(config.outfile(), DEFAULT_OUTFILE);
+        assert_eq!(config.cert_file(), DEFAULT_CERT_FILE);
+        assert_eq!(config.key_file(), DEFAULT_KEY_FILE);
+        assert_eq!(config.passphrase().unwrap(), DEFAULT_PASSPHRASE);
+        assert!(config.host_mappings.is_empty());
+        assert_eq!(config.listener_options(), ListenerOptions::default());
+        assert!(!config.capture_options().record_bodies);
+        assert!(!config.validate());
+    }
+
+    #[test]
+    fn test_merge_config_overrides() {
+        // Simulate the options given on the command line
+        let file_config = Config::from_toml_str(SAMPLE_CONFIG).unwrap();
+        let cli_config = Config {
+            port: Some(8443),
+            host_mappings: HashMap::from([("example.com".to_string(), "10.0.0.1".to_string())]),
+            ..Config::default()
+        };
+
+        // Call the function
+        let config = file_config.merge(cli_config);
+
+        // Verify that command line values win and others come from the file
+        assert_eq!(config.port(), 8443);
+        assert_eq!(config.outfile(), "session.har");
+        assert_eq!(config.host_mappings["example.com"], "10.0.0.1");
+        assert_eq!(config.host_mappings["api.example.com"], "10.0.0.2");
+    }
+
+    #[test]
+    fn test_bind_address() {
+        // Call the function, with the default address, a port and an address
+        let default = Config::default();
+        let port = Config::from_toml_str("port = 9000").unwrap();
+        let bind = Config::from_toml_str(
+            r#"
+            port = 9000
+            bind = "[::1]:8443"
+            "#,
+        )
+        .unwrap();
+
+        // Verify the address wins over the port, which is on the loopback
+        assert_eq!(default.bind_address(), "127.0.0.1:8081".parse().unwrap());
+        assert_eq!(port.bind_address(), "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(bind.bind_address(), "[::1]:8443".parse().unwrap());
+    }
+
+    #[test]
+    fn test_parse_bind_address() {
+        // Call the function
+        let any = parse_bind_address("0.0.0.0:8081");
+        let ipv6 = parse_bind_address("[::1]:8081");
+        let no_port = parse_bind_address("0.0.0.0");
+
+        // Verify a missing port is reported with what is expected
+        assert_eq!(any, Ok("0.0.0.0:8081".parse().unwrap()));
+        assert_eq!(ipv6, Ok("[::1]:8081".parse().unwrap()));
+        assert!(no_port.unwrap_err().contains("\"0.0.0.0\""));
+    }
+
+    #[test]
+    fn test_passphrase_from_env() {
+        // Point the passphrase at an environment variable
+        std::env::set_var("CONFIG_TEST_PASSPHRASE", "from-env");
+        let config = Config::from_toml_str(r#"passphrase_env = "CONFIG_TEST_PASSPHRASE""#).unwrap();
+
+        // Verify the passphrase is read from the environment
+        assert_eq!(config.passphrase().unwrap(), "from-env");
+    }
+
+    #[test]
+    fn test_load_ca_without_passphrase() {
+        // Save an authority with an unencrypted key
+        let dir = std::env::temp_dir().join(format!("config_ca_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_file = dir.join("cert.pem").display().to_string();
+        let key_file = dir.join("key.pem").display().to_string();
+        let ca = CertificateAuthority::generate("Test CA", 1, KeyType::EcdsaP256).unwrap();
+        ca.save_to_pem_files(&cert_file, &key_file, None).unwrap();
+        let config = Config {
+            cert_file: Some(cert_file),
+            key_file: Some(key_file),
+            ..Config::default()
+        };
+        let with_passphrase = Config {
+            passphrase: Some("secret".to_string()),
+            ..config.clone()
+        };
+
+        // Call the function
+        let loaded = config.load_ca();
+        let mismatch = with_passphrase.load_ca();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Verify the key is loaded as it is, and refused with a passphrase
+        assert_eq!(
+            loaded.unwrap().cert.to_der().unwrap(),
+            ca.cert.to_der().unwrap()
+        );
+        assert!(
+            matches!(mismatch, Err(Error::ConfigError(message)) if message.contains("not encrypted"))
+        );
+    }
+
+    #[test]
+    fn test_load_shipped_ca_with_default_passphrase() {
+        // Call the function, the shipped key being encrypted with the default passphrase
+        let ca = Config::default().load_ca();
+
+        // Verify it was decrypted
+        assert!(ca.is_ok());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        // Unknown keys are rejected
+        let result = Config::from_toml_str("unknown_option = true");
+
+        // Verify an error is returned
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_json_rewrites_config() {
+        // Rewrite rules are listed as an array of tables
+        let config = Config::from_toml_str(
+            r#"
+            [[json_rewrites]]
+            path = "$.user.role"
+            value = "admin"
+
+            [[json_rewrites]]
+            path = "$.limits.max"
+            value = 10
+        "#,
+        )
+        .unwrap();
+
+        // Verify the rules were read in order
+        assert_eq!(config.json_rewrites.len(), 2);
+        assert_eq!(config.json_rewrites[0].path.to_string(), "$.user.role");
+        assert_eq!(config.json_rewrites[0].value, "admin");
+        assert_eq!(config.json_rewrites[1].value, 10);
+
+        // Verify an invalid path is rejected when loading
+        let result = Config::from_toml_str(
+            r#"
+            [[json_rewrites]]
+            path = "user.role"
+            value = "admin"
+        "#,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,142 @@
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use tls_interceptor_proxy::third_wheel::host_mapping::{lookup, HostMappingEntry};
+
+    fn mappings() -> HashMap<String, String> {
+        HashMap::from([
+            ("example.com".to_string(), "10.0.0.1".to_string()),
+            ("*.example.com".to_string(), "10.0.0.2".to_string()),
+            ("*.staging.example.com".to_string(), "10.0.0.3".to_string()),
+            ("db-?.internal".to_string(), "10.0.0.4".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_lookup_exact_host() {
+        let mappings = mappings();
+
+        // Call the function
+        let address = lookup(&mappings, "example.com");
+
+        // Verify the exact entry is used
+        assert_eq!(address, Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_lookup_glob_patterns() {
+        let mappings = mappings();
+
+        // Call the function and verify the most specific pattern wins
+        assert_eq!(lookup(&mappings, "api.example.com"), Some("10.0.0.2"));
+        assert_eq!(lookup(&mappings, "API.Example.com"), Some("10.0.0.2"));
+        assert_eq!(lookup(&mappings, "a.b.example.com"), Some("10.0.0.2"));
+        assert_eq!(
+            lookup(&mappings, "api.staging.example.com"),
+            Some("10.0.0.3")
+        );
+        assert_eq!(lookup(&mappings, "db-1.internal"), Some("10.0.0.4"));
+    }
+
+    #[test]
+    fn test_lookup_regex_patterns() {
+        let mut mappings = mappings();
+        mappings.insert(
+            r"~^api-[0-9]+\.example\.com$".to_string(),
+            "10.0.0.5".to_string(),
+        );
+
+        // Call the function and verify the expression is matched ignoring case
+        assert_eq!(lookup(&mappings, "api-12.example.com"), Some("10.0.0.5"));
+        assert_eq!(lookup(&mappings, "API-3.Example.com"), Some("10.0.0.5"));
+        assert_eq!(lookup(&mappings, "api-x.example.com"), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_parse_invalid_regex_entry() {
+        // Call the function
+        let entry = "~api-(.example.com=10.0.0.5".parse::<HostMappingEntry>();
+
+        // Verify the expression was refused
+        assert!(entry.is_err());
+    }
+
+    #[test]
+    fn test_lookup_unmapped_host() {
+        let mappings = mappings();
+
+        // Call the function and verify nothing is returned
+        assert_eq!(lookup(&mappings, "example.org"), None);
+        assert_eq!(lookup(&mappings, "notexample.com"), None);
+        assert_eq!(lookup(&mappings, "db-10.internal"), None);
+    }
+
+    #[test]
+    fn test_parse_entry_with_port() {
+        // Call the function
+        let entry: HostMappingEntry = "example.com=127.0.0.1:8443".parse().unwrap();
+
+        // Verify every part was read
+        assert_eq!(entry.host, "example.com");
+        assert_eq!(entry.address, "127.0.0.1");
+        assert_eq!(entry.port, Some(8443));
+        assert_eq!(entry.target(), "127.0.0.1:8443");
+        assert_eq!(entry.to_string(), "example.com=127.0.0.1:8443");
+    }
+
+    #[test]
+    fn test_parse_entry_without_port() {
+        // Call the function
+        let entry: HostMappingEntry = "*.example.com=backend.local".parse().unwrap();
+        let ipv6: HostMappingEntry = "example.com=::1".parse().unwrap();
+        let ipv6_with_port: HostMappingEntry = "example.com=[::1]:8443".parse().unwrap();
+
+        // Verify the port is left to the tunnel's
+        assert_eq!(entry.host, "*.example.com");
+        assert_eq!(entry.address, "backend.local");
+        assert_eq!(entry.port, None);
+        assert_eq!(ipv6.address, "::1");
+        assert_eq!(ipv6.port, None);
+        assert_eq!(ipv6_with_port.address, "::1");
+        assert_eq!(ipv6_with_port.port, Some(8443));
+        assert_eq!(ipv6_with_port.target(), "[::1]:8443");
+    }
+
+    #[test]
+    fn test_parse_unix_socket_entries() {
+        // Call the function
+        let tls: HostMappingEntry = "example.com=unix:/run/sidecar.sock".parse().unwrap();
+        let plain: HostMappingEntry = "example.com=unix+plain:/run/sidecar.sock".parse().unwrap();
+
+        // Verify the socket path is kept whole, without a port
+        assert_eq!(tls.address, "unix:/run/sidecar.sock");
+        assert_eq!(tls.port, None);
+        assert_eq!(tls.target(), "unix:/run/sidecar.sock");
+        assert_eq!(plain.target(), "unix+plain:/run/sidecar.sock");
+    }
+
+    #[test]
+    fn test_parse_invalid_entries() {
+        // Call the function and verify each malformed entry is rejected
+        for entry in [
+            "example.com",
+            "=127.0.0.1",
+            "example.com=",
+            "example.com=127.0.0.1:",
+            "example.com=127.0.0.1:99999",
+            "example.com=127.0.0.1:https",
+            "example.com=[::1",
+            "example.com=unix:",
+            "bad host=127.0.0.1",
+        ] {
+            let error = entry.parse::<HostMappingEntry>().unwrap_err();
+            assert!(
+                error.to_string().contains(entry),
+                "unexpected error for {}: {}",
+                entry,
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,106 @@
+mod common;
+
+#[cfg(all(test, feature = "http3"))]
+mod tests {
+
+    use crate::common::*;
+    use hyper::body::{Buf, Bytes};
+    use hyper::{service::Service, Body, Request, Response};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::pki_types::CertificateDer;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tls_interceptor_proxy::third_wheel::certificates::CertificateAuthority;
+    use tls_interceptor_proxy::third_wheel::proxy::mitm::{mitm_layer, ThirdWheel};
+
+    /// Open an HTTP/3 connection to the proxy listening on `proxy`, trusting
+    /// the test authority
+    async fn h3_client(
+        proxy: SocketAddr,
+        ca: &CertificateAuthority,
+    ) -> h3::client::SendRequest<h3_quinn::OpenStreams, Bytes> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(ca.cert.to_der().unwrap()))
+            .unwrap();
+        let mut tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        tls_config.alpn_protocols = vec![b"h3".to_vec()];
+        let client_config =
+            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config).unwrap()));
+
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(client_config);
+        let connection = endpoint.connect(proxy, "localhost").unwrap().await.unwrap();
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        tokio::spawn(async move { futures::future::poll_fn(|cx| driver.poll_close(cx)).await });
+        send_request
+    }
+
+    #[tokio::test]
+    async fn test_http3_smoke() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            let method = req.method().to_string();
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            Response::new(Body::from(format!(
+                "{} {}",
+                method,
+                String::from_utf8_lossy(&body)
+            )))
+        })
+        .await;
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_layer = intercepted.clone();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_layer.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .additional_host_mappings(HashMap::from([(
+                "localhost".to_string(),
+                format!("127.0.0.1:{}", upstream.port()),
+            )]))
+            .build();
+        let (proxy, server) = mitm_proxy
+            .bind_http3("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        tokio::spawn(server);
+
+        // Call the function, with a GET and a POST over one QUIC connection
+        let mut client = h3_client(proxy, &ca).await;
+        let mut bodies = Vec::new();
+        for (method, body) in [("GET", ""), ("POST", "hello")] {
+            let request = http::Request::builder()
+                .method(method)
+                .uri("https://localhost/")
+                .body(())
+                .unwrap();
+            let mut stream = client.send_request(request).await.unwrap();
+            if !body.is_empty() {
+                stream.send_data(Bytes::from(body)).await.unwrap();
+            }
+            stream.finish().await.unwrap();
+            let response = stream.recv_response().await.unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK);
+            let mut received = Vec::new();
+            while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+                received.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+            }
+            bodies.push(String::from_utf8(received).unwrap());
+        }
+
+        // Verify both requests reached the target through the mitm layer
+        assert_eq!(bodies, ["GET ", "POST hello"]);
+        assert_eq!(intercepted.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,88 @@
+#[cfg(all(test, feature = "wasm-plugins"))]
+mod tests {
+
+    use tls_interceptor_proxy::plugin::{Plugin, PluginVerdict};
+
+    /// A plugin blocking the requests holding the marker `BLOCKME`
+    const MARKER_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "BLOCKME")
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1024))
+          (func (export "inspect") (param $ptr i32) (param $len i32) (result i32)
+            (local $i i32)
+            (local $last i32)
+            (local.set $i (local.get $ptr))
+            (local.set $last
+              (i32.add (local.get $ptr) (i32.sub (local.get $len) (i32.const 7))))
+            (block $done
+              (loop $next
+                (br_if $done (i32.gt_s (local.get $i) (local.get $last)))
+                ;; Compare the 7 bytes at $i with the marker
+                (if (i64.eq
+                      (i64.and (i64.load (local.get $i)) (i64.const 0x00ffffffffffffff))
+                      (i64.load (i32.const 0)))
+                  (then (return (i32.const 1))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i32.const 0)))
+    "#;
+
+    /// A plugin sending every request to `/rewritten`
+    const REWRITING_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 2048) "GET /rewritten HTTP/1.1\0d\0ahost: example.com\0d\0a\0d\0a")
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 4096))
+          (func (export "inspect") (param $ptr i32) (param $len i32) (result i32)
+            (i32.const 2))
+          (func (export "modified") (result i64)
+            ;; 46 bytes at 2048
+            (i64.const 8796093022254)))
+    "#;
+
+    #[test]
+    fn test_plugin_blocks_on_marker() {
+        let plugin = Plugin::from_bytes(MARKER_PLUGIN.as_bytes()).unwrap();
+
+        // Call the function, for a request with the marker and one without
+        let blocked = plugin
+            .inspect(b"POST / HTTP/1.1\r\nhost: example.com\r\n\r\nplease BLOCKME")
+            .unwrap();
+        let allowed = plugin
+            .inspect(b"POST / HTTP/1.1\r\nhost: example.com\r\n\r\nhello")
+            .unwrap();
+
+        // Verify only the request with the marker was blocked
+        assert_eq!(blocked, PluginVerdict::Block);
+        assert_eq!(allowed, PluginVerdict::Allow);
+    }
+
+    #[test]
+    fn test_plugin_modifies_request() {
+        let plugin = Plugin::from_bytes(REWRITING_PLUGIN.as_bytes()).unwrap();
+
+        // Call the function
+        let verdict = plugin
+            .inspect(b"GET / HTTP/1.1\r\nhost: example.com\r\n\r\n")
+            .unwrap();
+
+        // Verify the request of the plugin is to be forwarded instead
+        assert_eq!(
+            verdict,
+            PluginVerdict::Modify(b"GET /rewritten HTTP/1.1\r\nhost: example.com\r\n\r\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_invalid_plugin() {
+        // Call the function on a module missing the exports
+        let plugin = Plugin::from_bytes(b"(module)").unwrap();
+        let result = plugin.inspect(b"GET / HTTP/1.1\r\n\r\n");
+
+        // Verify the request could not be inspected
+        assert!(result.is_err());
+    }
+}
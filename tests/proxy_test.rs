@@ -0,0 +1,3481 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use futures::StreamExt;
+    use hyper::header::HeaderName;
+    use hyper::{service::Service, Body, Request, Response, StatusCode};
+    use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+    use openssl::hash::MessageDigest;
+    use openssl::ssl::{
+        AlpnError, NameType, Ssl, SslAcceptor, SslMethod, SslVerifyMode, SslVersion,
+    };
+    use openssl::x509::{extension::SubjectAlternativeName, X509Extension, X509Name, X509};
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tls_interceptor_proxy::capture::{CaptureSink, PromptSink};
+    use tls_interceptor_proxy::classifier::KeywordClassifier;
+    use tls_interceptor_proxy::replay::{load_har, play_cassette, Cassette};
+    use tls_interceptor_proxy::rewrite::{rewrite_json_request_body, JsonRewriteRule};
+    use tls_interceptor_proxy::rules::{BlockRule, BlockRules, RuleAction};
+    use tls_interceptor_proxy::third_wheel::certificates::{
+        create_signed_certificate_for_domain, CertificateAuthority,
+    };
+    use tls_interceptor_proxy::third_wheel::host_mapping::HostMappingEntry;
+    use tls_interceptor_proxy::third_wheel::metrics::SIGNING_TIME_BUCKETS;
+    use tls_interceptor_proxy::third_wheel::proxy::mitm::{
+        mitm_layer, ProxyTiming, RequestId, ThirdWheel, CAPTURE_STREAM_CAPACITY, X_REQUEST_ID,
+    };
+    use tls_interceptor_proxy::third_wheel::proxy::{
+        ListenerOptions, MitmProxy, ProxyService, SigningRate, Socks5Auth,
+    };
+    use tls_interceptor_proxy::third_wheel::tls_profile::TlsProfile;
+    use tls_interceptor_proxy::utilities::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::{mpsc, oneshot};
+
+    /// An upstream handler answering with the request id it received
+    async fn echo_request_id(req: Request<Body>) -> Response<Body> {
+        let request_id = req
+            .headers()
+            .get(X_REQUEST_ID)
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        Response::new(Body::from(request_id))
+    }
+
+    #[tokio::test]
+    async fn test_request_id_forwarded_and_recorded() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", echo_request_id).await;
+
+        // Record a HAR entry for every request before forwarding it
+        let (entry_sender, mut entry_receiver) = mpsc::unbounded_channel();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let entry_sender = entry_sender.clone();
+            let fut = async move {
+                let request_id = req.extensions().get::<RequestId>().cloned().unwrap();
+                let (parts, body) = req.into_parts();
+                let body_bytes = hyper::body::to_bytes(body).await.unwrap().to_vec();
+                let (entries, _) = log_blocked_request(
+                    &parts,
+                    body_bytes.clone(),
+                    third_wheel.get_client_ip(),
+                    third_wheel.get_server_ip(),
+                    &CaptureOptions::default(),
+                    &BlockMessages::default(),
+                )
+                .await;
+                entry_sender.send((request_id, entries)).unwrap();
+
+                let req = Request::from_parts(parts, Body::from(body_bytes));
+                third_wheel.call(req).await
+            };
+            Box::pin(fut)
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Send a request through the proxy
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::builder()
+            .uri("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let forwarded_id = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the same id reached the target and the HAR entry
+        let (request_id, entries) = entry_receiver.recv().await.unwrap();
+        assert_eq!(forwarded_id, request_id.0.as_bytes());
+        assert_eq!(
+            entries.comment.unwrap(),
+            format!("request id: {}", request_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_request_id_is_kept() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", echo_request_id).await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Send a request which already has an id
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::builder()
+            .uri("/")
+            .header("host", "localhost")
+            .header(X_REQUEST_ID, "client-id")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the id is not replaced
+        let forwarded_id = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(forwarded_id, "client-id");
+    }
+
+    /// Start a server for `domain` which only accepts clients presenting a
+    /// certificate signed by the test authority. It speaks TLS 1.2 at most, so
+    /// a client without a certificate fails the handshake itself, where TLS
+    /// 1.3 would only refuse it once the handshake completed on its side.
+    fn spawn_mtls_upstream(ca: &CertificateAuthority, domain: &str) -> SocketAddr {
+        let certificate = create_signed_certificate_for_domain(domain, ca).unwrap();
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_max_proto_version(Some(SslVersion::TLS1_2))
+            .unwrap();
+        acceptor.set_private_key(&ca.key).unwrap();
+        acceptor.set_certificate(&certificate).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        acceptor.cert_store_mut().add_cert(ca.cert.clone()).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let Ok(mut stream) = acceptor.accept(stream) else {
+                    continue;
+                };
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                    head.push(byte[0]);
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_upstream_client_identity() {
+        let ca = test_ca();
+        let upstream = spawn_mtls_upstream(&ca, "localhost");
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .upstream_client_identity(identity_for_domain(&ca, "proxy-client"))
+                .build(),
+        );
+
+        // Send a request to the server requiring a client certificate
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::builder()
+            .uri("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the server accepted the proxy's certificate
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_client_identity_for_host() {
+        let ca = test_ca();
+        let upstream = spawn_mtls_upstream(&ca, "localhost");
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .upstream_client_identity_for_host(
+                    "localhost",
+                    identity_for_domain(&ca, "proxy-client"),
+                )
+                .build(),
+        );
+
+        // Send a request to the server requiring a client certificate
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::builder()
+            .uri("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the certificate for this host was used
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_missing_upstream_client_identity() {
+        let ca = test_ca();
+        let upstream = spawn_mtls_upstream(&ca, "localhost");
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Send a request to the server without a client certificate
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::builder()
+            .uri("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the proxy answered the failed handshake with the server
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_target_answered_with_bad_gateway() {
+        let ca = test_ca();
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Find a port nothing listens on
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", port, &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5), client.send_request(request))
+            .await
+            .expect("the client was left waiting")
+            .unwrap();
+
+        // Verify the client was told the target could not be reached
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_timeout() {
+        let ca = test_ca();
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .tls_handshake_timeout(Duration::from_millis(200))
+                .build(),
+        );
+
+        // A target accepting connections but never answering the handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", port, &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5), client.send_request(request))
+            .await
+            .expect("the client was left waiting")
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the client was told the target timed out
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(String::from_utf8_lossy(&body).starts_with("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let ca = test_ca();
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .connect_timeout(Duration::from_millis(200))
+                .build(),
+        );
+
+        // A target never accepting connections, whose backlog is filled so
+        // the next connections are left waiting instead of being refused
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let target = listener.local_addr().unwrap();
+        let mut backlog = Vec::new();
+        while let Ok(Ok(stream)) = tokio::time::timeout(
+            Duration::from_millis(100),
+            tokio::net::TcpStream::connect(target),
+        )
+        .await
+        {
+            backlog.push(stream);
+        }
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", target.port(), &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5), client.send_request(request))
+            .await
+            .expect("the client was left waiting")
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the client was told connecting to the target timed out
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(String::from_utf8_lossy(&body).starts_with("timed out connecting to"));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout_with_hung_connection() {
+        let ca = test_ca();
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .shutdown_timeout(Duration::from_millis(200))
+            .build();
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let (addr, server) =
+            mitm_proxy.bind_with_graceful_shutdown("127.0.0.1:0".parse().unwrap(), async {
+                let _ = shutdown_receiver.await;
+            });
+        let server = tokio::spawn(server);
+
+        // Open a connection that never finishes sending its request
+        let mut hung = tokio::net::TcpStream::connect(addr).await.unwrap();
+        hung.write_all(b"CONNECT localhost:443 HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Signal the shutdown
+        let start = Instant::now();
+        shutdown_sender.send(()).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), server).await;
+
+        // Verify the proxy stopped once the timeout expired
+        assert!(result.unwrap().unwrap().is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_open_tunnel() {
+        let ca = test_ca();
+        let upstream =
+            spawn_upstream(&ca, "localhost", |_| async { Response::new(Body::empty()) }).await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .shutdown_timeout(Duration::from_secs(5))
+            .build();
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let (addr, server) =
+            mitm_proxy.bind_with_graceful_shutdown("127.0.0.1:0".parse().unwrap(), async {
+                let _ = shutdown_receiver.await;
+            });
+        let mut server = tokio::spawn(server);
+        let mut client = client_through_proxy(addr, "localhost", upstream.port(), &ca).await;
+        let request = Request::get(format!("https://localhost:{}/", upstream.port()))
+            .body(Body::empty())
+            .unwrap();
+        client.send_request(request).await.unwrap();
+
+        // Signal the shutdown while the tunnel is open
+        shutdown_sender.send(()).unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(200), &mut server).await;
+
+        // Verify the proxy waits for the tunnel, then stops once it is closed
+        assert!(waiting.is_err());
+        let start = Instant::now();
+        drop(client);
+        let result = tokio::time::timeout(Duration::from_secs(2), server).await;
+        assert!(result.unwrap().unwrap().is_ok());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_in_tunnel_is_rejected() {
+        let ca = test_ca();
+        let upstream =
+            spawn_upstream(&ca, "localhost", |_| async { Response::new(Body::empty()) }).await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Send plain HTTP over the tunnel instead of a TLS handshake
+        let mut stream = open_tunnel(proxy, "localhost", upstream.port()).await;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        // Verify the client gets a readable error
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(response.ends_with(
+            "client sent plaintext over the CONNECT tunnel instead of starting a TLS handshake"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_fallback() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("hello"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).plaintext_fallback(true).build());
+
+        // Send plain HTTP over the tunnel
+        let stream = open_tunnel(proxy, "localhost", upstream.port()).await;
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::builder()
+            .uri("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the request was forwarded to the target
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    /// Send a raw request with a mixed case header through the proxy and
+    /// return the request head received by the target
+    async fn forwarded_head(preserve_header_case: bool) -> String {
+        let ca = test_ca();
+        let (head_sender, mut head_receiver) = mpsc::unbounded_channel();
+        let upstream = spawn_raw_upstream(&ca, "localhost", move |head| {
+            head_sender.send(head).unwrap();
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .preserve_header_case(preserve_header_case)
+                .build(),
+        );
+
+        let mut stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Custom-Header: 1\r\n\r\n")
+            .await
+            .unwrap();
+        read_head(&mut stream).await;
+        head_receiver.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preserve_header_case() {
+        // Call the function
+        let head = forwarded_head(true).await;
+
+        // Verify the header kept its case
+        assert!(head.contains("X-Custom-Header: 1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_default_header_case() {
+        // Call the function
+        let head = forwarded_head(false).await;
+
+        // Verify the header was lowercased
+        assert!(head.contains("x-custom-header: 1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_client_http_config() {
+        let ca = test_ca();
+        let upstream =
+            spawn_upstream(&ca, "localhost", |_| async { Response::new(Body::empty()) }).await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .client_http_config(|http| {
+                    http.max_buf_size(8192);
+                })
+                .build(),
+        );
+
+        // Send a request head larger than the buffer of the server
+        let mut stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Large: {}\r\n\r\n",
+            "a".repeat(16 * 1024)
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut stream).await;
+
+        // Verify the configured server refused it
+        assert!(
+            head.starts_with(b"HTTP/1.1 431"),
+            "unexpected answer: {}",
+            String::from_utf8_lossy(&head)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_requests_per_connection() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .max_requests_per_connection(2)
+            .build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let mut responses = Vec::new();
+        for _ in 0..3 {
+            let request = Request::get("/").body(Body::empty()).unwrap();
+            responses.push(client.send_request(request).await);
+        }
+
+        // Verify the last allowed request closed the tunnel
+        assert!(responses[0].is_ok());
+        let second = responses[1].as_ref().unwrap();
+        assert_eq!(second.headers().get("connection").unwrap(), "close");
+        assert!(responses[2].is_err());
+        assert_eq!(metrics.capped_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_requests_per_http2_connection() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .max_requests_per_connection(2)
+            .build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function, speaking HTTP/2 to the proxy
+        let stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let (mut client, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake::<_, Body>(stream)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let mut responses = Vec::new();
+        for _ in 0..3 {
+            let request = Request::get("https://localhost/")
+                .body(Body::empty())
+                .unwrap();
+            responses.push(client.send_request(request).await);
+        }
+
+        // Verify the last allowed request made the proxy close the connection
+        assert!(responses[0].is_ok());
+        assert!(responses[1].is_ok());
+        assert!(responses[2].is_err());
+        assert_eq!(metrics.capped_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_json_rewrite_rule() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            Response::new(Body::from(body))
+        })
+        .await;
+
+        // Rewrite the body of every request before forwarding it
+        let rules = vec![JsonRewriteRule {
+            path: "$.user.role".parse().unwrap(),
+            value: serde_json::json!("admin"),
+        }];
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let rules = rules.clone();
+            let fut = async move {
+                let (mut parts, body) = req.into_parts();
+                let body_bytes = hyper::body::to_bytes(body).await.unwrap().to_vec();
+                let body_bytes = rewrite_json_request_body(&mut parts, body_bytes, &rules);
+                third_wheel
+                    .call(Request::from_parts(parts, Body::from(body_bytes)))
+                    .await
+            };
+            Box::pin(fut)
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"user":{"name":"bob","role":"guest"}}"#))
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the upstream received the rewritten body
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let forwarded: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(forwarded["user"]["role"], "admin");
+        assert_eq!(forwarded["user"]["name"], "bob");
+    }
+
+    #[tokio::test]
+    async fn test_json_rule_blocks_body_without_messages() {
+        let ca = test_ca();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = spawn_upstream(&ca, "localhost", move |_| {
+            upstream_hits.fetch_add(1, Ordering::SeqCst);
+            async { Response::new(Body::from("from upstream")) }
+        })
+        .await;
+
+        // Block the requests for gpt-4 as the proxy binary does
+        let rule: BlockRule =
+            toml::from_str(r#"json = [{ path = "$.model", op = "==", value = "gpt-4" }]"#).unwrap();
+        let rules = BlockRules::new(vec![rule]);
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let rules = rules.clone();
+            let fut = async move {
+                let (parts, body) = req.into_parts();
+                let body_bytes = hyper::body::to_bytes(body).await.unwrap().to_vec();
+                let body_json = convert_body_to_json(body_bytes.clone());
+                let action = rules
+                    .check(
+                        "localhost",
+                        parts.method.as_str(),
+                        parts.uri.path(),
+                        Some(&body_json),
+                        &parse_request(body_bytes.clone()),
+                        &KeywordClassifier::default(),
+                    )
+                    .await
+                    .unwrap();
+                if action == RuleAction::Block {
+                    let client = third_wheel.get_client_ip();
+                    let (_, response) = log_blocked_request(
+                        &parts,
+                        body_bytes,
+                        client,
+                        None,
+                        &Default::default(),
+                        &BlockMessages::default(),
+                    )
+                    .await;
+                    return Ok(response);
+                }
+                third_wheel
+                    .call(Request::from_parts(parts, Body::from(body_bytes)))
+                    .await
+            };
+            Box::pin(fut)
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function with a body holding no chat messages
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/v1/embeddings")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"model":"gpt-4","input":"confidential"}"#))
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the client got the block page and the target nothing
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("blocked by the proxy"));
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_log_connections_only() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("from upstream"))
+        })
+        .await;
+
+        // Count the requests seen decrypted by the proxy
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_layer = intercepted.clone();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_layer.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let (connection_sender, mut connection_receiver) = mpsc::unbounded_channel();
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .log_connections_only(move |connection| {
+                    connection_sender.send(log_connection(&connection)).unwrap();
+                })
+                .build(),
+        );
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/").body(Body::from("secret body")).unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the exchange went through untouched
+        assert_eq!(&body[..], b"from upstream");
+        assert_eq!(intercepted.load(Ordering::SeqCst), 0);
+
+        // Verify only the connection metadata was recorded
+        let entry = connection_receiver.recv().await.unwrap();
+        assert_eq!(entry.request.method, "CONNECT");
+        assert_eq!(entry.request.url, format!("localhost:{}", upstream.port()));
+        assert_eq!(
+            entry.comment.unwrap(),
+            "connection only, server name: localhost"
+        );
+        assert!(entry.request.post_data.is_none());
+        assert!(entry.response.content.text.is_none());
+    }
+
+    /// Start a TLS server for `domain` sending back every byte it reads
+    async fn spawn_tls_echo(ca: &CertificateAuthority, domain: &str) -> SocketAddr {
+        let acceptor = tokio_native_tls::TlsAcceptor::from(
+            native_tls::TlsAcceptor::new(identity_for_domain(ca, domain)).unwrap(),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let (mut reader, mut writer) = tokio::io::split(stream);
+                        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_raw_hosts() {
+        let ca = test_ca();
+        let upstream = spawn_tls_echo(&ca, "localhost").await;
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_layer = intercepted.clone();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_layer.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .raw_hosts(vec!["localhost".to_string()])
+            .record_bodies(true)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, sending bytes which are not HTTP over the
+        // intercepted tunnel
+        let mut stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let sent: Vec<u8> = (0..=255).collect();
+        stream.write_all(&sent).await.unwrap();
+        let mut received = vec![0; sent.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        // Verify the bytes were relayed unmodified without going through the
+        // mitm layer, and captured both ways once the tunnel closed
+        assert_eq!(received, sent);
+        let entry = entries.next().await.unwrap();
+        assert_eq!(intercepted.load(Ordering::SeqCst), 0);
+        assert_eq!(entry.request.method, "CONNECT");
+        assert_eq!(entry.request.url, format!("localhost:{}", upstream.port()));
+        let post_data = entry.request.post_data.unwrap();
+        assert_eq!(post_data.comment.unwrap(), "base64");
+        assert_eq!(STANDARD.decode(post_data.text.unwrap()).unwrap(), sent);
+        assert_eq!(entry.response.content.encoding.unwrap(), "base64");
+        assert_eq!(
+            STANDARD
+                .decode(entry.response.content.text.unwrap())
+                .unwrap(),
+            sent
+        );
+        assert_eq!(entry.server_ip_address.unwrap(), upstream.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_raw_hosts_capture_limit() {
+        let ca = test_ca();
+        let upstream = spawn_tls_echo(&ca, "localhost").await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .raw_hosts(vec!["localhost".to_string()])
+            .record_bodies(true)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, relaying more bytes than are recorded
+        let stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let size = STREAM_CAPTURE_LIMIT + 1000;
+        let sent = vec![7u8; size];
+        let mut received = vec![0; size];
+        let (written, read) = tokio::join!(
+            async {
+                writer.write_all(&sent).await?;
+                writer.shutdown().await
+            },
+            reader.read_exact(&mut received)
+        );
+        written.unwrap();
+        read.unwrap();
+
+        // Verify every byte was relayed, only the first ones recorded, and
+        // the sizes count them all
+        assert_eq!(received, sent);
+        let entry = entries.next().await.unwrap();
+        assert_eq!(entry.request.body_size, size as i64);
+        let post_data = entry.request.post_data.unwrap();
+        assert_eq!(post_data.comment.unwrap(), "truncated, base64");
+        assert_eq!(
+            STANDARD.decode(post_data.text.unwrap()).unwrap().len(),
+            STREAM_CAPTURE_LIMIT
+        );
+        assert_eq!(entry.response.content.size, size as i64);
+        assert_eq!(entry.response.content.comment.unwrap(), "truncated");
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_hosts() {
+        // The target is signed by an authority the proxy does not use, so the
+        // client only trusts it when the tunnel is not intercepted
+        let ca = test_ca();
+        let target_ca = test_ca();
+        let upstream = spawn_tls_echo(&target_ca, "localhost").await;
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_layer = intercepted.clone();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_layer.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .passthrough_hosts(vec!["*.example.com".to_string(), "LOCALHOST".to_string()])
+                .build(),
+        );
+
+        // Call the function, sending bytes which are not HTTP
+        let stream = open_tunnel(proxy, "localhost", upstream.port()).await;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(trusted_certificate(&target_ca))
+            .build()
+            .unwrap();
+        let mut stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", stream)
+            .await
+            .unwrap();
+        let sent: Vec<u8> = (0..=255).collect();
+        stream.write_all(&sent).await.unwrap();
+        let mut received = vec![0; sent.len()];
+        stream.read_exact(&mut received).await.unwrap();
+
+        // Verify the target's own certificate was presented and the bytes
+        // came back unmodified, without going through the mitm layer
+        let presented = stream.get_ref().peer_certificate().unwrap().unwrap();
+        let presented = X509::from_der(&presented.to_der().unwrap()).unwrap();
+        assert!(presented.verify(&target_ca.key).unwrap());
+        assert_eq!(received, sent);
+        assert_eq!(intercepted.load(Ordering::SeqCst), 0);
+    }
+
+    /// Start an SMTP server for `localhost` offering `STARTTLS`, answering the
+    /// commands it receives over TLS until `QUIT`
+    async fn spawn_smtp_server(ca: &CertificateAuthority) -> SocketAddr {
+        let acceptor = tokio_native_tls::TlsAcceptor::from(
+            native_tls::TlsAcceptor::new(identity_for_domain(ca, "localhost")).unwrap(),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut line = [0u8; 10];
+            stream.write_all(b"220 mail.test ESMTP\r\n").await.unwrap();
+            stream.read_exact(&mut line).await.unwrap();
+            stream
+                .write_all(b"250-mail.test\r\n250 STARTTLS\r\n")
+                .await
+                .unwrap();
+            stream.read_exact(&mut line).await.unwrap();
+            stream.write_all(b"220 Ready\r\n").await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.read_exact(&mut line).await.unwrap();
+            stream.write_all(b"250 mail.test\r\n").await.unwrap();
+            stream.read_exact(&mut line[..6]).await.unwrap();
+            stream.write_all(b"221 Bye\r\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_starttls_smtp() {
+        // The target is signed by an authority the client does not trust, so
+        // the handshake only succeeds with the certificate spoofed by the proxy
+        let ca = test_ca();
+        let target_ca = test_ca();
+        let smtp = spawn_smtp_server(&target_ca).await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .additional_root_certificates(vec![
+                trusted_certificate(&ca),
+                trusted_certificate(&target_ca),
+            ])
+            .starttls_smtp_ports(vec![smtp.port()])
+            .record_bodies(true)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, upgrading the plaintext session with STARTTLS
+        let mut stream = open_tunnel(proxy, "localhost", smtp.port()).await;
+        let mut reply = vec![0; 21];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"220 mail.test ESMTP\r\n");
+        stream.write_all(b"EHLO a.b\r\n").await.unwrap();
+        let mut reply = vec![0; 29];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"250-mail.test\r\n250 STARTTLS\r\n");
+        stream.write_all(b"STARTTLS\r\n").await.unwrap();
+        let mut reply = vec![0; 11];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"220 Ready\r\n");
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(trusted_certificate(&ca))
+            .build()
+            .unwrap();
+        let mut stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", stream)
+            .await
+            .unwrap();
+        stream.write_all(b"EHLO a.b\r\nQUIT\r\n").await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+
+        // Verify the session went on over TLS and its decrypted bytes were
+        // captured both ways
+        assert_eq!(received, b"250 mail.test\r\n221 Bye\r\n");
+        let entry = entries.next().await.unwrap();
+        assert_eq!(entry.request.method, "CONNECT");
+        assert_eq!(entry.request.url, format!("localhost:{}", smtp.port()));
+        assert_eq!(
+            STANDARD
+                .decode(entry.request.post_data.unwrap().text.unwrap())
+                .unwrap(),
+            b"EHLO a.b\r\nQUIT\r\n"
+        );
+        assert_eq!(
+            STANDARD
+                .decode(entry.response.content.text.unwrap())
+                .unwrap(),
+            received
+        );
+    }
+
+    /// Send two pipelined requests through the proxy, the first one answered
+    /// slowly by the target, and return the raw bytes received until the
+    /// tunnel is closed
+    async fn pipelined_exchange(http1_keep_alive: bool) -> String {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            if req.uri().path() == "/slow" {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            Response::new(Body::from(req.uri().path().to_string()))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .http1_keep_alive(http1_keep_alive)
+                .build(),
+        );
+
+        let mut stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        stream
+            .write_all(
+                b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /fast HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .unwrap();
+        String::from_utf8_lossy(&received).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_in_order() {
+        // Call the function
+        let received = pipelined_exchange(true).await;
+
+        // Verify both responses arrived in the order of the requests
+        let slow = received.find("/slow").unwrap();
+        let fast = received.find("/fast").unwrap();
+        assert!(slow < fast);
+        assert_eq!(received.matches("HTTP/1.1 200 OK").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_disabled() {
+        // Call the function
+        let received = pipelined_exchange(false).await;
+
+        // Verify only the first request was answered before closing
+        assert_eq!(received.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(received.contains("/slow"));
+        assert!(!received.contains("/fast"));
+    }
+
+    #[tokio::test]
+    async fn test_force_response_compression() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("plain text from upstream"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .force_response_compression(true)
+                .build(),
+        );
+
+        // Call the function, without advertising gzip support
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the body was gzipped and marked as such
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..2], &[0x1f, 0x8b]);
+        let deflated = &body[10..body.len() - 8];
+        let decompressed = miniz_oxide::inflate::decompress_to_vec(deflated).unwrap();
+        assert_eq!(decompressed, b"plain text from upstream");
+        let original_len = u32::from_le_bytes(body[body.len() - 4..].try_into().unwrap());
+        assert_eq!(original_len as usize, decompressed.len());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_processing_time() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+
+        // Keep the timing of every forwarded request
+        let (timing_sender, mut timing_receiver) = mpsc::unbounded_channel();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let timing_sender = timing_sender.clone();
+            let fut = async move {
+                let response = third_wheel.call(req).await?;
+                let timing = response.extensions().get::<ProxyTiming>().copied();
+                timing_sender.send(timing).unwrap();
+                Ok(response)
+            };
+            Box::pin(fut)
+        });
+        let mitm_proxy = proxy_builder(mitm, &ca).build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        client.send_request(request).await.unwrap();
+
+        // Verify the time spent in the proxy was recorded and is small
+        let processing_time = timing_receiver
+            .recv()
+            .await
+            .unwrap()
+            .unwrap()
+            .processing_time();
+        assert!(processing_time > Duration::ZERO);
+        assert!(processing_time < Duration::from_millis(500));
+        assert_eq!(metrics.forwarded_requests(), 1);
+        assert!(metrics.average_processing_time().unwrap() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_host_mapping() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "api.example.com", |_| async {
+            Response::new(Body::from("from backend"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .additional_host_mappings(HashMap::from([(
+                    "*.example.com".to_string(),
+                    "127.0.0.1".to_string(),
+                )]))
+                .build(),
+        );
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "api.example.com", upstream.port(), &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the request reached the mapped backend
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"from backend");
+    }
+
+    #[tokio::test]
+    async fn test_host_mapping_with_port() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "api.example.com", |_| async {
+            Response::new(Body::from("from backend"))
+        })
+        .await;
+        let entry: HostMappingEntry = format!("api.example.com=127.0.0.1:{}", upstream.port())
+            .parse()
+            .unwrap();
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .additional_host_mappings(HashMap::from([(entry.host.clone(), entry.target())]))
+                .build(),
+        );
+
+        // Call the function, tunnelling to the default port
+        let mut client = client_through_proxy(proxy, "api.example.com", 443, &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the request reached the backend on the mapped port
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"from backend");
+    }
+
+    /// Start a server on a Unix domain socket at `path`, speaking TLS for
+    /// `domain` if one is given, answering every request with "from socket"
+    #[cfg(unix)]
+    fn spawn_unix_upstream(
+        ca: &CertificateAuthority,
+        domain: Option<&str>,
+        path: &std::path::Path,
+    ) {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        let acceptor = domain.map(|domain| {
+            tokio_native_tls::TlsAcceptor::from(
+                native_tls::TlsAcceptor::new(identity_for_domain(ca, domain)).unwrap(),
+            )
+        });
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(|_| async {
+                        Ok::<_, std::convert::Infallible>(Response::new(Body::from("from socket")))
+                    });
+                    let http = hyper::server::conn::Http::new();
+                    let _ = match acceptor {
+                        Some(acceptor) => match acceptor.accept(stream).await {
+                            Ok(stream) => http.serve_connection(stream, service).await,
+                            Err(_) => return,
+                        },
+                        None => http.serve_connection(stream, service).await,
+                    };
+                });
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_host_mapping_to_unix_socket() {
+        let ca = test_ca();
+        let dir = tempfile::TempDir::new().unwrap();
+        let tls_socket = dir.path().join("tls.sock");
+        let plain_socket = dir.path().join("plain.sock");
+        spawn_unix_upstream(&ca, Some("tls.sidecar"), &tls_socket);
+        spawn_unix_upstream(&ca, None, &plain_socket);
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .additional_host_mappings(HashMap::from([
+                    (
+                        "tls.sidecar".to_string(),
+                        format!("unix:{}", tls_socket.display()),
+                    ),
+                    (
+                        "plain.sidecar".to_string(),
+                        format!("unix+plain:{}", plain_socket.display()),
+                    ),
+                ]))
+                .build(),
+        );
+
+        for host in ["tls.sidecar", "plain.sidecar"] {
+            // Call the function
+            let mut client = client_through_proxy(proxy, host, 443, &ca).await;
+            let request = Request::get("/").body(Body::empty()).unwrap();
+            let response = client.send_request(request).await.unwrap();
+
+            // Verify the request reached the server on the socket
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"from socket", "through {}", host);
+        }
+    }
+
+    /// Start a TLS server presenting a certificate for whichever of `domains`
+    /// the client asked for with SNI, answering with the name it was asked for
+    fn spawn_virtual_hosts_upstream(ca: &CertificateAuthority, domains: &[&str]) -> SocketAddr {
+        let mut contexts = HashMap::new();
+        for domain in domains {
+            let certificate = create_signed_certificate_for_domain(domain, ca).unwrap();
+            let mut context = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+            context.set_private_key(&ca.key).unwrap();
+            context.set_certificate(&certificate).unwrap();
+            contexts.insert(domain.to_string(), context.build().into_context());
+        }
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&ca.key).unwrap();
+        acceptor
+            .set_certificate(&create_signed_certificate_for_domain(domains[0], ca).unwrap())
+            .unwrap();
+        acceptor.set_servername_callback(move |ssl, _| {
+            let context = ssl
+                .servername(NameType::HOST_NAME)
+                .and_then(|name| contexts.get(name));
+            if let Some(context) = context {
+                ssl.set_ssl_context(context).unwrap();
+            }
+            Ok(())
+        });
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let acceptor = acceptor.clone();
+                std::thread::spawn(move || {
+                    let Ok(mut stream) = acceptor.accept(stream) else {
+                        return;
+                    };
+                    let mut head = Vec::new();
+                    let mut byte = [0u8; 1];
+                    while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                        head.push(byte[0]);
+                    }
+                    let name = stream
+                        .ssl()
+                        .servername(NameType::HOST_NAME)
+                        .unwrap_or("")
+                        .to_string();
+                    let _ = stream.write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
+                            name.len(),
+                            name
+                        )
+                        .as_bytes(),
+                    );
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_certificate_follows_client_sni() {
+        let ca = test_ca();
+        let upstream = spawn_virtual_hosts_upstream(&ca, &["a.test", "b.test"]);
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        for server_name in ["a.test", "b.test"] {
+            // Call the function, tunnelling to the same target with another SNI
+            let stream = open_tunnel(proxy, "localhost", upstream.port()).await;
+            let connector = native_tls::TlsConnector::builder()
+                .add_root_certificate(trusted_certificate(&ca))
+                .build()
+                .unwrap();
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(server_name, stream)
+                .await
+                .unwrap();
+            let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+            tokio::spawn(connection);
+            let request = Request::get("/").body(Body::empty()).unwrap();
+            let response = client.send_request(request).await.unwrap();
+
+            // Verify the certificate matched the SNI and the target saw it too
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], server_name.as_bytes());
+        }
+    }
+
+    /// Open a TLS connection through the proxy asking for `server_name`,
+    /// returning whether the handshake succeeded
+    async fn handshake_with_sni(
+        proxy: SocketAddr,
+        upstream: SocketAddr,
+        server_name: &str,
+        ca: &CertificateAuthority,
+    ) -> bool {
+        let stream = open_tunnel(proxy, "localhost", upstream.port()).await;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(trusted_certificate(ca))
+            .build()
+            .unwrap();
+        tokio_native_tls::TlsConnector::from(connector)
+            .connect(server_name, stream)
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_certificate_signing_rate_exceeded() {
+        let ca = test_ca();
+        let upstream = spawn_virtual_hosts_upstream(&ca, &["a.test", "b.test"]);
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .additional_host_mappings(HashMap::from([
+                ("a.test".to_string(), "127.0.0.1".to_string()),
+                ("b.test".to_string(), "127.0.0.1".to_string()),
+            ]))
+            .certificate_signing_rate(SigningRate {
+                certificates: 1,
+                per: Duration::from_secs(3600),
+                max_wait: Duration::from_millis(100),
+            })
+            .build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function, the second host needing a certificate beyond the rate
+        let _first = tls_through_proxy(proxy, "a.test", upstream.port(), &ca).await;
+        let (_, refused) = send_connect(proxy, "b.test", upstream.port()).await;
+        let _cached = tls_through_proxy(proxy, "a.test", upstream.port(), &ca).await;
+
+        // Verify only the new host was throttled, its CONNECT being answered
+        // before the tunnel was accepted
+        assert!(
+            refused.starts_with(b"HTTP/1.1 503"),
+            "{}",
+            String::from_utf8_lossy(&refused)
+        );
+        assert_eq!(metrics.signed_certificates(), 1);
+        assert_eq!(metrics.throttled_signings(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_certificate_signing_rate_queues_new_hosts() {
+        let ca = test_ca();
+        let upstream = spawn_virtual_hosts_upstream(&ca, &["a.test", "b.test"]);
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .certificate_signing_rate(SigningRate {
+                certificates: 1,
+                per: Duration::from_millis(300),
+                max_wait: Duration::from_secs(5),
+            })
+            .build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function
+        assert!(handshake_with_sni(proxy, upstream, "a.test", &ca).await);
+        let start = Instant::now();
+        let queued = handshake_with_sni(proxy, upstream, "b.test", &ca).await;
+
+        // Verify the second host waited for its turn instead of being refused
+        assert!(queued);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(metrics.signed_certificates(), 2);
+        assert_eq!(metrics.throttled_signings(), 0);
+    }
+
+    /// Send a request through the proxy to a target whose certificate is for
+    /// another name than the host connected to, returning whether it succeeded
+    async fn request_to_mismatched_host(verify_hostname: bool) -> bool {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "other.test", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .verify_hostname(verify_hostname)
+                .build(),
+        );
+
+        // The spoofed certificate keeps the name of the target's one
+        let stream = open_tunnel(proxy, "localhost", upstream.port()).await;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(trusted_certificate(&ca))
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .unwrap();
+        let Ok(stream) = tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", stream)
+            .await
+        else {
+            return false;
+        };
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        matches!(
+            client.send_request(request).await,
+            Ok(response) if response.status().is_success()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_hostname_mismatch_rejected() {
+        // Call the function
+        let succeeded = request_to_mismatched_host(true).await;
+
+        // Verify the proxy refused the target's certificate
+        assert!(!succeeded);
+    }
+
+    #[tokio::test]
+    async fn test_hostname_verification_disabled() {
+        // Call the function
+        let succeeded = request_to_mismatched_host(false).await;
+
+        // Verify the proxy accepted the target's certificate
+        assert!(succeeded);
+    }
+
+    /// A certificate for `localhost` whose alternative names also hold an IP
+    /// address of 5 bytes, which cannot be copied into a spoofed certificate
+    fn certificate_with_malformed_ip(ca: &CertificateAuthority) -> X509 {
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("CN", "localhost").unwrap();
+        let subject = subject.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&subject).unwrap();
+        certificate.set_issuer_name(ca.cert.subject_name()).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        certificate.set_pubkey(&ca.key).unwrap();
+        // SEQUENCE { [2] "localhost", [7] 127.0.0.1.0 }
+        let mut alt_names = vec![0x30, 0x12, 0x82, 0x09];
+        alt_names.extend_from_slice(b"localhost");
+        alt_names.extend_from_slice(&[0x87, 0x05, 127, 0, 0, 1, 0]);
+        let alt_names = X509Extension::new_from_der(
+            &Asn1Object::from_str("2.5.29.17").unwrap(),
+            false,
+            &Asn1OctetString::new_from_bytes(&alt_names).unwrap(),
+        )
+        .unwrap();
+        certificate.append_extension(alt_names).unwrap();
+        certificate.sign(&ca.key, MessageDigest::sha256()).unwrap();
+        certificate.build()
+    }
+
+    /// A certificate whose alternative names are `names`
+    fn certificate_with_names(ca: &CertificateAuthority, names: &[&str]) -> X509 {
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("CN", names[0]).unwrap();
+        let subject = subject.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&subject).unwrap();
+        certificate.set_issuer_name(ca.cert.subject_name()).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        certificate.set_pubkey(&ca.key).unwrap();
+        let mut alt_names = SubjectAlternativeName::new();
+        for name in names {
+            alt_names.dns(name);
+        }
+        let alt_names = alt_names
+            .build(&certificate.x509v3_context(Some(&ca.cert), None))
+            .unwrap();
+        certificate.append_extension(alt_names).unwrap();
+        certificate.sign(&ca.key, MessageDigest::sha256()).unwrap();
+        certificate.build()
+    }
+
+    #[tokio::test]
+    async fn test_rotated_upstream_certificate_is_spoofed_again() {
+        let ca = test_ca();
+        let before = certificate_with_names(&ca, &["localhost", "before.test"]);
+        let after = certificate_with_names(&ca, &["localhost", "after.test"]);
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function, the host presenting another certificate on the
+        // second connection
+        let mut spoofed_names = Vec::new();
+        for certificate in [&before, &after] {
+            let upstream = spawn_upstream_with_certificate(&ca, certificate, |_| async {
+                Response::new(Body::from("ok"))
+            })
+            .await;
+            let stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+            let spoofed = stream.get_ref().peer_certificate().unwrap().unwrap();
+            let spoofed = X509::from_der(&spoofed.to_der().unwrap()).unwrap();
+            let names: Vec<String> = spoofed
+                .subject_alt_names()
+                .unwrap()
+                .iter()
+                .filter_map(|name| name.dnsname().map(str::to_string))
+                .collect();
+            spoofed_names.push(names);
+        }
+
+        // Verify each spoofed certificate follows the one presented upstream
+        assert_eq!(spoofed_names[0], vec!["localhost", "before.test"]);
+        assert_eq!(spoofed_names[1], vec!["localhost", "after.test"]);
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_certificate_is_cached() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca).build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function, opening two tunnels to the same host
+        let mut spoofed = Vec::new();
+        for _ in 0..2 {
+            let stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+            let certificate = stream.get_ref().peer_certificate().unwrap().unwrap();
+            spoofed.push(certificate.to_der().unwrap());
+        }
+
+        // Verify the second tunnel was given the certificate signed for the first
+        assert_eq!(metrics.signed_certificates(), 1);
+        assert_eq!(spoofed[0], spoofed[1]);
+    }
+
+    #[tokio::test]
+    async fn test_prewarm_certs() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca).build();
+        let metrics = mitm_proxy.metrics();
+
+        // Call the function, with a host nothing listens on
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = format!("localhost:{}", listener.local_addr().unwrap().port());
+        drop(listener);
+        let prewarmed = format!("localhost:{}", upstream.port());
+        let failed = mitm_proxy
+            .prewarm_certs(&[prewarmed.as_str(), closed.as_str()])
+            .await;
+        let signed_before_tunnel = metrics.signed_certificates();
+        let proxy = spawn_proxy(mitm_proxy);
+        tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+
+        // Verify the certificate was signed ahead and presented from the cache
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, closed);
+        assert_eq!(signed_before_tunnel, 1);
+        assert_eq!(metrics.signed_certificates(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_certificate_cache_disabled() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca).cert_cache_size(0).build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function
+        for _ in 0..2 {
+            tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        }
+
+        // Verify a certificate was signed for every tunnel
+        assert_eq!(metrics.signed_certificates(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_signing_time_recorded() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca).build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+        assert_eq!(metrics.average_signing_time(), None);
+
+        // Call the function
+        tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+
+        // Verify how long the signing took was recorded
+        let average = metrics.average_signing_time().unwrap();
+        assert!(average > Duration::ZERO);
+        assert_eq!(metrics.longest_signing_time(), Some(average));
+        assert_eq!(metrics.signing_timeouts(), 0);
+        let histogram = metrics.signing_time_histogram();
+        assert_eq!(histogram.len(), SIGNING_TIME_BUCKETS.len() + 1);
+        assert_eq!(histogram.iter().map(|(_, count)| count).sum::<u64>(), 1);
+        let (bound, _) = histogram.iter().find(|(_, count)| *count == 1).unwrap();
+        assert!(bound.is_none_or(|bound| average <= bound));
+    }
+
+    #[test]
+    fn test_signing_timeout() {
+        // A single blocking thread, kept busy so the signing cannot finish
+        // in time, as when the proxy is swamped with signings
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let ca = test_ca();
+            let upstream = spawn_upstream(&ca, "localhost", |_| async {
+                Response::new(Body::from("ok"))
+            })
+            .await;
+            let mitm =
+                mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+            let mitm_proxy = proxy_builder(mitm, &ca)
+                .signing_timeout(Duration::from_millis(200))
+                .build();
+            let metrics = mitm_proxy.metrics();
+            let proxy = spawn_proxy(mitm_proxy);
+            let (release, busy) = std::sync::mpsc::channel::<()>();
+            let occupied = tokio::task::spawn_blocking(move || busy.recv());
+
+            // Call the function
+            let handshake = handshake_with_sni(proxy, upstream, "localhost", &ca).await;
+            release.send(()).unwrap();
+            occupied.await.unwrap().unwrap();
+            let retried = handshake_with_sni(proxy, upstream, "localhost", &ca).await;
+
+            // Verify the tunnel was closed without a certificate, the next
+            // one getting its own once the thread was free
+            assert!(!handshake);
+            assert!(retried);
+            assert_eq!(metrics.signing_timeouts(), 1);
+            assert_eq!(metrics.signed_certificates(), 1);
+        });
+    }
+
+    /// Send a request through the proxy to a target whose certificate cannot
+    /// be spoofed, returning the body of the response if it succeeded
+    async fn request_to_unspoofable_target(certificate_fallback: bool) -> Option<String> {
+        let ca = test_ca();
+        let upstream =
+            spawn_upstream_with_certificate(&ca, &certificate_with_malformed_ip(&ca), |_| async {
+                Response::new(Body::from("ok"))
+            })
+            .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .certificate_fallback(certificate_fallback)
+                .build(),
+        );
+
+        let stream = open_tunnel(proxy, "localhost", upstream.port()).await;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(trusted_certificate(&ca))
+            .build()
+            .unwrap();
+        let stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", stream)
+            .await
+            .ok()?;
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.ok()?;
+        let body = hyper::body::to_bytes(response.into_body()).await.ok()?;
+        Some(String::from_utf8_lossy(&body).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_certificate_fallback() {
+        // Call the function
+        let body = request_to_unspoofable_target(true).await;
+
+        // Verify the client accepted the certificate signed for the host
+        assert_eq!(body.as_deref(), Some("ok"));
+    }
+
+    #[tokio::test]
+    async fn test_certificate_fallback_disabled() {
+        // Call the function
+        let body = request_to_unspoofable_target(false).await;
+
+        // Verify the proxy closed the connection instead
+        assert!(body.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_host_tls_profile_applies_to_its_host_only() {
+        // The targets' authority is not trusted by the proxy
+        let ca = test_ca();
+        let internal = spawn_upstream(&ca, "app.internal.test", |_| async {
+            Response::new(Body::from("internal"))
+        })
+        .await;
+        let public = spawn_upstream(&ca, "public.test", |_| async {
+            Response::new(Body::from("public"))
+        })
+        .await;
+        let lenient = TlsProfile {
+            accept_invalid_certs: true,
+            ..TlsProfile::default()
+        };
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            MitmProxy::builder(mitm, ca.clone())
+                .additional_host_mappings(HashMap::from([(
+                    "*.test".to_string(),
+                    "127.0.0.1".to_string(),
+                )]))
+                .host_tls_profile("*.internal.test", lenient)
+                .build(),
+        );
+
+        // Call the function for the host with the profile
+        let mut client =
+            client_through_proxy(proxy, "app.internal.test", internal.port(), &ca).await;
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the untrusted certificate was accepted for it
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"internal");
+
+        // Verify other hosts still require a trusted certificate
+        let stream = open_tunnel(proxy, "public.test", public.port()).await;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(trusted_certificate(&ca))
+            .build()
+            .unwrap();
+        let stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect("public.test", stream)
+            .await
+            .unwrap();
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            Response::new(Body::from(format!("answer to {}", req.uri().path())))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .record_bodies(true)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Send two requests through the proxy
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        for (path, body) in [("/first", ""), ("/second", "payload")] {
+            let request = Request::post(path)
+                .header("host", "localhost")
+                .body(Body::from(body))
+                .unwrap();
+            let response = client.send_request(request).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        // Call the function
+        let first = entries.next().await.unwrap();
+        let second = entries.next().await.unwrap();
+
+        // Verify both exchanges were captured in order
+        assert_eq!(first.request.url, "https://localhost/first");
+        assert_eq!(first.response.content.text.unwrap(), "answer to /first");
+        assert_eq!(second.request.url, "https://localhost/second");
+        assert_eq!(second.request.post_data.unwrap().text.unwrap(), "payload");
+        assert_eq!(second.response.content.text.unwrap(), "answer to /second");
+        assert!(first.comment.unwrap().starts_with("request id: "));
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream_drops_overflow() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, once more than the stream holds, without
+        // consuming the stream
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        for _ in 0..=CAPTURE_STREAM_CAPACITY {
+            let request = Request::get("/")
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.send_request(request).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        // Verify the exchanges were still answered, the last entry dropped
+        assert_eq!(entries.dropped(), 1);
+        for _ in 0..CAPTURE_STREAM_CAPACITY {
+            entries.next().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream_truncates_large_bodies() {
+        let ca = test_ca();
+        let size = STREAM_CAPTURE_LIMIT * 2;
+        let upstream = spawn_upstream(&ca, "localhost", move |req: Request<Body>| async move {
+            let received = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            assert_eq!(received.len(), size);
+            // Sent without a length
+            let chunks = futures::stream::iter(
+                (0..size / 1024).map(|_| Ok::<_, std::io::Error>(vec![b'b'; 1024])),
+            );
+            Response::new(Body::wrap_stream(chunks))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .record_bodies(true)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/upload")
+            .header("host", "localhost")
+            .body(Body::from(vec![b'a'; size]))
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let entry = entries.next().await.unwrap();
+
+        // Verify both bodies went through whole, only their start recorded
+        assert_eq!(body.len(), size);
+        let post_data = entry.request.post_data.unwrap();
+        assert_eq!(post_data.text.unwrap().len(), STREAM_CAPTURE_LIMIT);
+        assert_eq!(post_data.comment.unwrap(), "truncated");
+        assert_eq!(entry.request.body_size, -1);
+        assert_eq!(
+            entry.response.content.text.unwrap().len(),
+            STREAM_CAPTURE_LIMIT
+        );
+        assert_eq!(entry.response.content.comment.unwrap(), "truncated");
+        assert_eq!(entry.response.body_size, -1);
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream_body_preview() {
+        let ca = test_ca();
+        // Tell when a request reached the target, before reading its body
+        let (arrived_sender, mut arrived) = mpsc::unbounded_channel();
+        let upstream = spawn_upstream(&ca, "localhost", move |_| {
+            arrived_sender.send(()).unwrap();
+            async { Response::new(Body::from(vec![b'b'; 100])) }
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .record_bodies(true)
+            .body_preview(16)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, only sending the start of the request body
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let (mut body_sender, body) = Body::channel();
+        body_sender.send_data(vec![b'a'; 32].into()).await.unwrap();
+        let request = Request::post("/upload")
+            .header("host", "localhost")
+            .body(body)
+            .unwrap();
+        let response = tokio::spawn(client.send_request(request));
+
+        // Verify the request was forwarded once its preview was read, before
+        // the rest of its body was sent
+        tokio::time::timeout(Duration::from_secs(5), arrived.recv())
+            .await
+            .expect("the request was held back until its body ended")
+            .unwrap();
+        body_sender.send_data(vec![b'a'; 32].into()).await.unwrap();
+        drop(body_sender);
+        let response = response.await.unwrap().unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let entry = entries.next().await.unwrap();
+
+        // Verify only the previews were recorded, the response going through whole
+        assert_eq!(body.len(), 100);
+        let post_data = entry.request.post_data.unwrap();
+        assert_eq!(post_data.text.unwrap(), "a".repeat(16));
+        assert_eq!(post_data.comment.unwrap(), "preview");
+        assert_eq!(entry.request.body_size, -1);
+        assert_eq!(entry.response.content.text.unwrap(), "b".repeat(16));
+        assert_eq!(entry.response.content.comment.unwrap(), "preview");
+        assert_eq!(entry.response.body_size, -1);
+    }
+
+    #[tokio::test]
+    async fn test_host_resolution_headers() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .host_resolution_headers(vec![
+                HeaderName::from_static("x-original-host"),
+                HeaderName::from_static("x-forwarded-host"),
+            ])
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, with the host in a custom header and without it
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let requests = [
+            Request::get("/forwarded")
+                .header("host", "localhost")
+                .header("x-forwarded-host", "api.internal.example")
+                .body(Body::empty())
+                .unwrap(),
+            Request::get("/plain")
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap(),
+        ];
+        for request in requests {
+            let response = client.send_request(request).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+        let forwarded = entries.next().await.unwrap();
+        let plain = entries.next().await.unwrap();
+
+        // Verify the custom header overrides the Host in the URL, the CONNECT
+        // authority being used without it
+        assert_eq!(
+            forwarded.request.url,
+            "https://api.internal.example/forwarded"
+        );
+        assert_eq!(
+            plain.request.url,
+            format!("https://localhost:{}/plain", upstream.port())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_follow_redirects() {
+        // The target redirects /a to /b and /b to /c
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            let redirect = |status: u16, location: &str| {
+                Response::builder()
+                    .status(status)
+                    .header("location", location)
+                    .body(Body::from("moved"))
+                    .unwrap()
+            };
+            match req.uri().path() {
+                "/a" => redirect(302, "/b"),
+                "/b" => redirect(307, "https://localhost/c"),
+                _ => Response::new(Body::from(format!("final {}", req.method()))),
+            }
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .follow_redirects(5)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/a")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the client got the final response and each hop was captured,
+        // linked to the next
+        assert_eq!(status, 200);
+        assert_eq!(&body[..], b"final GET");
+        let first = entries.next().await.unwrap();
+        let second = entries.next().await.unwrap();
+        let third = entries.next().await.unwrap();
+        assert_eq!(first.request.url, "https://localhost/a");
+        assert_eq!(first.response.status, 302);
+        assert_eq!(first.response.redirect_url.unwrap(), second.request.url);
+        assert_eq!(second.request.url, "https://localhost/b");
+        assert_eq!(second.response.status, 307);
+        assert_eq!(second.response.redirect_url.unwrap(), third.request.url);
+        assert_eq!(third.request.url, "https://localhost/c");
+        assert_eq!(third.response.status, 200);
+        assert_eq!(third.response.redirect_url.unwrap(), "");
+        assert_eq!(first.comment, third.comment);
+    }
+
+    #[tokio::test]
+    async fn test_redirects_not_followed_by_default() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::builder()
+                .status(302)
+                .header("location", "/elsewhere")
+                .body(Body::empty())
+                .unwrap()
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the redirect was relayed to the client
+        assert_eq!(response.status(), 302);
+        assert_eq!(response.headers()["location"], "/elsewhere");
+    }
+
+    #[tokio::test]
+    async fn test_captured_server_ip() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm = mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| {
+            assert_eq!(
+                third_wheel.get_server_ip().unwrap().ip().to_string(),
+                "127.0.0.1"
+            );
+            third_wheel.call(req)
+        });
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let response = client
+            .send_request(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the entry holds the upstream address, and the client one apart
+        let entry = entries.next().await.unwrap();
+        assert_eq!(entry.server_ip_address.unwrap(), upstream.to_string());
+        let client_ip: SocketAddr = entry.connection.unwrap().parse().unwrap();
+        assert_ne!(client_ip, upstream);
+    }
+
+    #[tokio::test]
+    async fn test_target_host_and_port() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let (target_sender, mut target_receiver) = mpsc::unbounded_channel();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            target_sender
+                .send((
+                    third_wheel.get_target_host().to_string(),
+                    third_wheel.get_target_port(),
+                ))
+                .unwrap();
+            third_wheel.call(req)
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function, with a Host header naming another host
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/")
+            .header("host", "example.com")
+            .body(Body::empty())
+            .unwrap();
+        let _ = client.send_request(request).await;
+
+        // Verify the closure saw the target of the CONNECT request
+        let (host, port) = target_receiver.recv().await.unwrap();
+        assert_eq!(host, "localhost");
+        assert_eq!(port, upstream.port());
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream_without_bodies() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("secret answer"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/")
+            .header("host", "localhost")
+            .body(Body::from("secret question"))
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the client got the body but the entry only holds its size
+        assert_eq!(&body[..], b"secret answer");
+        let entry = entries.next().await.unwrap();
+        assert!(entry.request.post_data.is_none());
+        assert_eq!(entry.request.body_size, 15);
+        assert!(entry.response.content.text.is_none());
+        assert_eq!(entry.response.content.size, 13);
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream_redacts_headers() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::builder()
+                .header("set-cookie", "session=secret")
+                .body(Body::from("ok"))
+                .unwrap()
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/")
+            .header("host", "localhost")
+            .header("authorization", "Bearer xyz")
+            .header("accept", "text/plain")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the credentials were redacted and the other headers kept
+        let entry = entries.next().await.unwrap();
+        let header = |headers: &[har::v1_2::Headers], name: &str| {
+            headers
+                .iter()
+                .find(|header| header.name == name)
+                .map(|header| header.value.clone())
+        };
+        assert_eq!(
+            header(&entry.request.headers, "authorization").as_deref(),
+            Some("[REDACTED]")
+        );
+        assert_eq!(
+            header(&entry.request.headers, "accept").as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(
+            header(&entry.response.headers, "set-cookie").as_deref(),
+            Some("[REDACTED]")
+        );
+        assert_eq!(entry.response.cookies[0].name, "session");
+        assert_eq!(entry.response.cookies[0].value, "[REDACTED]");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_sink_records_forwarded_prompt() {
+        let ca = test_ca();
+        let upstream =
+            spawn_upstream(&ca, "localhost", |_| async { Response::new(Body::empty()) }).await;
+        let (entry_sender, mut entry_receiver) = mpsc::unbounded_channel();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let entry_sender = entry_sender.clone();
+            let fut = async move {
+                let (parts, body) = req.into_parts();
+                let body_bytes = hyper::body::to_bytes(body).await.unwrap().to_vec();
+                let options = CaptureOptions {
+                    record_prompts: true,
+                    ..CaptureOptions::default()
+                };
+                let entry = log_forwarded_request(
+                    &parts,
+                    body_bytes.clone(),
+                    third_wheel.get_client_ip(),
+                    third_wheel.get_server_ip(),
+                    &options,
+                )
+                .await;
+                entry_sender.send(entry).unwrap();
+                third_wheel
+                    .call(Request::from_parts(parts, Body::from(body_bytes)))
+                    .await
+            };
+            Box::pin(fut)
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/backend-api/conversation")
+            .header("host", "localhost")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"messages":[{"content":{"parts":["Summarize this report"]}}]}"#,
+            ))
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let entry = entry_receiver.recv().await.unwrap();
+        let recorded = entry.request.post_data.as_ref().unwrap();
+        let mut sink = PromptSink::new(Vec::new());
+        sink.record(&entry).unwrap();
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+
+        // Verify only the prompt of the request was kept, not its body, and
+        // it was written as a JSON line
+        assert_eq!(recorded.text.as_deref(), Some("Summarize this report"));
+        assert_eq!(recorded.comment.as_deref(), Some("prompt only"));
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["prompt"], "Summarize this report");
+        assert_eq!(line["host"], "localhost");
+        assert!(line["client_ip"]
+            .as_str()
+            .unwrap()
+            .starts_with("127.0.0.1:"));
+        assert_eq!(line["timestamp"], entry.started_date_time);
+    }
+
+    #[tokio::test]
+    async fn test_failed_request_is_captured() {
+        // A target closing the connection without answering
+        let ca = test_ca();
+        let acceptor = tokio_native_tls::TlsAcceptor::from(
+            native_tls::TlsAcceptor::new(identity_for_domain(&ca, "localhost")).unwrap(),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    read_head(&mut stream).await;
+                }
+            }
+        });
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .record_bodies(true)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/lost")
+            .header("host", "localhost")
+            .body(Body::from("payload"))
+            .unwrap();
+        let _ = client.send_request(request).await;
+
+        // Verify the request was captured with the failure
+        let entry = entries.next().await.unwrap();
+        assert_eq!(entry.request.url, "https://localhost/lost");
+        assert_eq!(entry.request.post_data.unwrap().text.unwrap(), "payload");
+        assert_eq!(entry.response.status, 0);
+        assert!(entry.comment.unwrap().contains(", forwarding failed: "));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_service_embedded() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            Response::new(Body::from(format!("embedded {}", req.uri().path())))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca).build();
+        let client_ip = "127.0.0.1:1234".parse().unwrap();
+
+        // Call the service directly, without a connection
+        let mut service = ProxyService::new(mitm_proxy.clone(), client_ip);
+        let connect = Request::connect("localhost:443")
+            .body(Body::empty())
+            .unwrap();
+        let accepted = service.call(connect).await.unwrap();
+        let get = Request::get("/").body(Body::empty()).unwrap();
+        let refused = service.call(get).await.unwrap();
+
+        // Verify tunnels are accepted and other requests refused
+        assert_eq!(accepted.status(), StatusCode::OK);
+        assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+
+        // Serve it from a server of our own instead of `bind`
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, client_ip)) = listener.accept().await {
+                let service = ProxyService::new(mitm_proxy.clone(), client_ip);
+                tokio::spawn(
+                    hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .with_upgrades(),
+                );
+            }
+        });
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/inside")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the embedded service intercepted the tunnel
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "embedded /inside");
+    }
+
+    #[tokio::test]
+    async fn test_latency_sla_violation() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            if req.uri().path() == "/slow" {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .latency_sla(HashMap::from([(
+                "localhost".to_string(),
+                Duration::from_millis(100),
+            )]))
+            .capture_stream();
+        let mitm_proxy = builder.build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        for path in ["/fast", "/slow"] {
+            let request = Request::get(path)
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.send_request(request).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        // Verify only the slow response was flagged, with its measured wait
+        let fast = entries.next().await.unwrap();
+        let slow = entries.next().await.unwrap();
+        assert!(!fast.comment.unwrap().contains("SLA violation"));
+        assert!(fast.timings.wait < slow.timings.wait);
+        assert!(slow.comment.unwrap().contains("SLA violation"));
+        assert!(slow.timings.wait >= 300.0);
+        assert!(slow.time >= slow.timings.wait);
+        assert_eq!(metrics.sla_violations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_measured_timings() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_req: Request<Body>| async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data("first ".into()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                sender.send_data("last".into()).await.unwrap();
+            });
+            Response::new(body)
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/timed")
+            .header("host", "localhost")
+            .body(Body::from("payload"))
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "first last");
+
+        // Verify each phase was measured and the total is their sum
+        let entry = entries.next().await.unwrap();
+        let timings = &entry.timings;
+        let blocked = timings.blocked.unwrap();
+        assert!(blocked >= 0.0);
+        assert!(timings.send >= 0.0);
+        assert!(timings.wait >= 200.0);
+        assert!(timings.receive >= 100.0);
+        let total = blocked + timings.send + timings.wait + timings.receive;
+        assert!((entry.time - total).abs() < 1e-6);
+        for phase in [blocked, timings.send, timings.wait, timings.receive] {
+            assert!(entry.time >= phase);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hsts_response_flagged() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |req: Request<Body>| async move {
+            let mut response = Response::builder();
+            if req.uri().path() == "/hsts" {
+                response = response
+                    .header("strict-transport-security", "max-age=31536000")
+                    .header("expect-ct", "max-age=86400, enforce");
+            }
+            response.body(Body::from("ok")).unwrap()
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let mitm_proxy = builder.build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        for path in ["/plain", "/hsts", "/hsts"] {
+            let request = Request::get(path)
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.send_request(request).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        // Verify only the HSTS responses were flagged, and the host counted once
+        let plain = entries.next().await.unwrap();
+        let hsts = entries.next().await.unwrap();
+        assert!(!plain.comment.unwrap().contains("upstream sent"));
+        assert!(hsts
+            .comment
+            .unwrap()
+            .ends_with(", upstream sent Strict-Transport-Security and Expect-CT"));
+        assert_eq!(metrics.hsts_hosts(), 1);
+    }
+
+    /// The state of a test connection
+    struct Session {
+        host: String,
+        client_ip: SocketAddr,
+        requests: AtomicUsize,
+    }
+
+    #[tokio::test]
+    async fn test_connection_state() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+
+        // Answer with what the state of the connection holds
+        let mitm = mitm_layer(|_: Request<Body>, third_wheel: ThirdWheel| {
+            let session = third_wheel.get_state::<Session>().unwrap();
+            let requests = session.requests.fetch_add(1, Ordering::SeqCst) + 1;
+            let body = format!(
+                "{} {} {} {}",
+                session.host,
+                session.client_ip.ip(),
+                requests,
+                third_wheel.get_state::<String>().is_none()
+            );
+            Box::pin(async move { Ok(Response::new(Body::from(body))) })
+        });
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .connection_state_factory(|host: &str, client_ip: SocketAddr| {
+                    Arc::new(Session {
+                        host: host.to_string(),
+                        client_ip,
+                        requests: AtomicUsize::new(0),
+                    })
+                })
+                .build(),
+        );
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let mut bodies = Vec::new();
+        for _ in 0..2 {
+            let request = Request::get("/").body(Body::empty()).unwrap();
+            let response = client.send_request(request).await.unwrap();
+            bodies.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+        }
+
+        // Verify the requests of the connection shared its state
+        assert_eq!(&bodies[0][..], b"localhost 127.0.0.1 1 true");
+        assert_eq!(&bodies[1][..], b"localhost 127.0.0.1 2 true");
+    }
+
+    /// A SOCKS5 proxy accepting the username `user` with the password
+    /// `secret`, sending the `host:port` of each tunnel it opens
+    async fn spawn_socks5_stub() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                // Greeting, choosing username/password authentication
+                let mut greeting = [0u8; 2];
+                client.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0u8; greeting[1] as usize];
+                client.read_exact(&mut methods).await.unwrap();
+                assert!(methods.contains(&2));
+                client.write_all(&[5, 2]).await.unwrap();
+
+                let mut credentials = vec![0u8; 2];
+                client.read_exact(&mut credentials).await.unwrap();
+                let mut username = vec![0u8; credentials[1] as usize];
+                client.read_exact(&mut username).await.unwrap();
+                let mut password = vec![0u8; client.read_u8().await.unwrap() as usize];
+                client.read_exact(&mut password).await.unwrap();
+                let accepted = username == b"user" && password == b"secret";
+                client.write_all(&[1, !accepted as u8]).await.unwrap();
+                if !accepted {
+                    continue;
+                }
+
+                // Connect request to an IPv4 address or a domain name
+                let mut request = [0u8; 4];
+                client.read_exact(&mut request).await.unwrap();
+                assert_eq!(request[..3], [5, 1, 0]);
+                let host = match request[3] {
+                    1 => {
+                        let mut ip = [0u8; 4];
+                        client.read_exact(&mut ip).await.unwrap();
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    }
+                    3 => {
+                        let mut host = vec![0u8; client.read_u8().await.unwrap() as usize];
+                        client.read_exact(&mut host).await.unwrap();
+                        String::from_utf8(host).unwrap()
+                    }
+                    address_type => panic!("unexpected address type {}", address_type),
+                };
+                let port = client.read_u16().await.unwrap();
+                let target = format!("{}:{}", host, port);
+                let mut upstream = tokio::net::TcpStream::connect(&target).await.unwrap();
+                sender.send(target).unwrap();
+                client
+                    .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                    .await
+                    .unwrap();
+                tokio::spawn(async move {
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                });
+            }
+        });
+        (addr, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_upstream_socks5() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let (socks5, mut tunnels) = spawn_socks5_stub().await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                // Leave resolving the target name to the SOCKS5 proxy
+                .additional_host_mappings(HashMap::new())
+                .upstream_socks5(
+                    socks5,
+                    Some(Socks5Auth {
+                        username: "user".to_string(),
+                        password: "secret".to_string(),
+                    }),
+                )
+                .build(),
+        );
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let response = client
+            .send_request(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // Verify the request went through a tunnel opened by the SOCKS5 proxy
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+        assert_eq!(
+            tunnels.recv().await.unwrap(),
+            format!("localhost:{}", upstream.port())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_parent = intercepted.clone();
+        let parent_mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_parent.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let parent = spawn_proxy(proxy_builder(parent_mitm, &ca).build());
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                // Leave resolving the target name to the parent proxy
+                .additional_host_mappings(HashMap::new())
+                .upstream_proxy(format!("http://{}", parent).parse().unwrap())
+                .build(),
+        );
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let response = client
+            .send_request(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // Verify the request went through the parent proxy
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+        assert_eq!(intercepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy_refused() {
+        let ca = test_ca();
+
+        // A parent proxy asking for other credentials
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let parent = listener.local_addr().unwrap();
+        let (head_sender, head_receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            head_sender
+                .send(String::from_utf8_lossy(&head).to_string())
+                .unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 407 Proxy Authentication Required\r\ncontent-length: 0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .additional_host_mappings(HashMap::new())
+                .upstream_proxy(format!("http://user:p%40ss@{}", parent).parse().unwrap())
+                .build(),
+        );
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", 8443, &ca).await;
+        let response = client
+            .send_request(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // Verify the tunnel was asked for with the credentials of the URI,
+        // and the client told the target could not be reached
+        let head = head_receiver.await.unwrap();
+        assert!(head.starts_with("CONNECT localhost:8443 HTTP/1.1\r\n"));
+        assert!(head.contains(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            STANDARD.encode("user:p@ss")
+        )));
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    /// Start a server for `domain` choosing HTTP/2 when the client offers it,
+    /// answering every request with the HTTP version it was sent in
+    async fn spawn_h2_upstream(ca: &CertificateAuthority, domain: &str) -> SocketAddr {
+        let certificate = create_signed_certificate_for_domain(domain, ca).unwrap();
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&ca.key).unwrap();
+        acceptor.set_certificate(&certificate).unwrap();
+        acceptor.set_alpn_select_callback(|_, offered| {
+            openssl::ssl::select_next_proto(b"\x02h2", offered).ok_or(AlpnError::NOACK)
+        });
+        let acceptor = acceptor.build();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let mut stream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+                tokio::spawn(async move {
+                    if std::pin::Pin::new(&mut stream).accept().await.is_err() {
+                        return;
+                    }
+                    let http2 = stream.ssl().selected_alpn_protocol() == Some(b"h2");
+                    let service = hyper::service::service_fn(|req: Request<Body>| async move {
+                        Ok::<_, std::convert::Infallible>(Response::new(Body::from(format!(
+                            "{:?}",
+                            req.version()
+                        ))))
+                    });
+                    let _ = hyper::server::conn::Http::new()
+                        .http2_only(http2)
+                        .serve_connection(stream, service)
+                        .await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_http2_target() {
+        let ca = test_ca();
+        let upstream = spawn_h2_upstream(&ca, "localhost").await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function, twice on the same connection
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let mut versions = Vec::new();
+        for _ in 0..2 {
+            let request = Request::get("/")
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.send_request(request).await.unwrap();
+            versions.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+        }
+
+        // Verify the target was spoken to in HTTP/2
+        assert_eq!(versions, vec!["HTTP/2.0", "HTTP/2.0"]);
+    }
+
+    #[tokio::test]
+    async fn test_tls_parameters_recorded() {
+        let ca = test_ca();
+        let upstream = spawn_h2_upstream(&ca, "localhost").await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, the client offering no protocol with ALPN
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the entry notes the protocols of both sessions, and that
+        // their version and cipher suite are not known
+        let entry = entries.next().await.unwrap();
+        assert!(entry.comment.unwrap().ends_with(
+            ", client ALPN none, upstream ALPN h2, TLS version and cipher suite unknown"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_host_tls_profile_alpn_protocols() {
+        let ca = test_ca();
+        let upstream = spawn_h2_upstream(&ca, "localhost").await;
+        let http1_only = TlsProfile {
+            alpn_protocols: Some(vec!["http/1.1".to_string()]),
+            ..TlsProfile::default()
+        };
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .host_tls_profile("localhost", http1_only)
+                .build(),
+        );
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+
+        // Verify the target was only offered HTTP/1.1
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"HTTP/1.1");
+    }
+
+    /// Perform the TLS handshake with the proxy in front of the target
+    /// listening on `port`, offering HTTP/2 and HTTP/1.1 with ALPN
+    #[cfg(feature = "alpn-mirroring")]
+    async fn tls_offering_h2(
+        ca: &CertificateAuthority,
+        port: u16,
+    ) -> tokio_native_tls::TlsStream<tokio::net::TcpStream> {
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, ca).build());
+        let stream = open_tunnel(proxy, "localhost", port).await;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(trusted_certificate(ca))
+            .request_alpns(&["h2", "http/1.1"])
+            .build()
+            .unwrap();
+        tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", stream)
+            .await
+            .unwrap()
+    }
+
+    #[cfg(feature = "alpn-mirroring")]
+    #[tokio::test]
+    async fn test_alpn_mirrored_to_client() {
+        let ca = test_ca();
+        let h2_upstream = spawn_h2_upstream(&ca, "localhost").await;
+        let http1_upstream =
+            spawn_upstream(&ca, "localhost", |_| async { Response::new(Body::empty()) }).await;
+
+        // Call the function, for a target choosing HTTP/2 and one choosing
+        // no protocol
+        let h2_stream = tls_offering_h2(&ca, h2_upstream.port()).await;
+        let http1_stream = tls_offering_h2(&ca, http1_upstream.port()).await;
+
+        // Verify the client agreed on what the target chose, and speaks
+        // HTTP/2 to the proxy
+        assert_eq!(
+            h2_stream.get_ref().negotiated_alpn().unwrap().as_deref(),
+            Some(&b"h2"[..])
+        );
+        assert_eq!(http1_stream.get_ref().negotiated_alpn().unwrap(), None);
+        let (mut client, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(h2_stream)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let response = client
+            .send_request(
+                Request::get("https://localhost/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"HTTP/2.0");
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_request() {
+        // A target without TLS
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let server =
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(hyper::service::make_service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                        |req: Request<Body>| async move {
+                            Ok::<_, std::convert::Infallible>(Response::new(Body::from(format!(
+                                "plain {}",
+                                req.uri()
+                            ))))
+                        },
+                    ))
+                }));
+        tokio::spawn(server);
+        let ca = test_ca();
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_layer = intercepted.clone();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_layer.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let (builder, mut entries) = proxy_builder(mitm, &ca).capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function, sending a request in absolute form to the proxy
+        let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let url = format!("http://localhost:{}/hello?x=1", upstream.port());
+        let response = client
+            .send_request(Request::get(&url).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // Verify the request went through the mitm layer to the target, which
+        // was sent its path, and was captured
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"plain /hello?x=1");
+        assert_eq!(intercepted.load(Ordering::SeqCst), 1);
+        let entry = entries.next().await.unwrap();
+        assert_eq!(entry.request.url, url);
+        assert_eq!(entry.response.status, 200);
+        assert_eq!(entry.server_ip_address.unwrap(), upstream.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_connection_reused() {
+        // A target without TLS counting its connections, and answering with
+        // the proxy headers it was sent
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        let server =
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(hyper::service::make_service_fn(move |_| {
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    async {
+                        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                            |req: Request<Body>| async move {
+                                let proxy_headers = ["proxy-authorization", "proxy-connection"]
+                                    .iter()
+                                    .filter(|name| req.headers().contains_key(**name))
+                                    .count();
+                                Ok::<_, std::convert::Infallible>(Response::new(Body::from(
+                                    proxy_headers.to_string(),
+                                )))
+                            },
+                        ))
+                    }
+                }));
+        tokio::spawn(server);
+        let ca = test_ca();
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Call the function, sending two requests in absolute form over one
+        // connection to the proxy
+        let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let url = format!("http://localhost:{}/", upstream.port());
+        let mut bodies = Vec::new();
+        for _ in 0..2 {
+            let request = Request::get(&url)
+                .header("proxy-authorization", "Basic dXNlcjpwYXNz")
+                .header("proxy-connection", "keep-alive")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.send_request(request).await.unwrap();
+            bodies.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+        }
+
+        // Verify both requests were sent over one connection to the target,
+        // without the headers meant for the proxy
+        assert_eq!(bodies, ["0", "0"]);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    /// Start a target without TLS answering every request with its URI
+    fn spawn_plain_upstream() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let server =
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(hyper::service::make_service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                        |req: Request<Body>| async move {
+                            Ok::<_, std::convert::Infallible>(Response::new(Body::from(
+                                req.uri().to_string(),
+                            )))
+                        },
+                    ))
+                }));
+        tokio::spawn(server);
+        upstream
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_passthrough_hosts() {
+        let upstream = spawn_plain_upstream();
+        let ca = test_ca();
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_layer = intercepted.clone();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_layer.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .passthrough_hosts(vec!["localhost".to_string()])
+                .build(),
+        );
+
+        // Call the function
+        let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let url = format!("http://localhost:{}/path?query", upstream.port());
+        let request = Request::get(&url).body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Verify the target got the request in origin form, without it going
+        // through the mitm layer
+        assert_eq!(&body[..], b"/path?query");
+        assert_eq!(intercepted.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_log_connections_only() {
+        let upstream = spawn_plain_upstream();
+        let ca = test_ca();
+        let intercepted = Arc::new(AtomicUsize::new(0));
+        let intercepted_by_layer = intercepted.clone();
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            intercepted_by_layer.fetch_add(1, Ordering::SeqCst);
+            third_wheel.call(req)
+        });
+        let (connection_sender, mut connection_receiver) = mpsc::unbounded_channel();
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .log_connections_only(move |connection| {
+                    connection_sender
+                        .send((connection.host, connection.port, connection.server_name))
+                        .unwrap();
+                })
+                .build(),
+        );
+
+        // Call the function, sending two requests over one connection
+        let stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        let (mut client, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let url = format!("http://localhost:{}/", upstream.port());
+        for _ in 0..2 {
+            let request = Request::get(&url).body(Body::empty()).unwrap();
+            let response = client.send_request(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"/");
+        }
+
+        // Verify the connection to the target was logged once and the
+        // requests did not go through the mitm layer
+        let logged = connection_receiver.recv().await.unwrap();
+        assert_eq!(
+            logged,
+            ("localhost".to_string(), upstream.port().to_string(), None)
+        );
+        assert!(connection_receiver.try_recv().is_err());
+        assert_eq!(intercepted.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connect_target_default_port() {
+        let ca = test_ca();
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (connection_sender, mut connection_receiver) = mpsc::unbounded_channel();
+        let proxy = spawn_proxy(
+            proxy_builder(mitm, &ca)
+                .log_connections_only(move |connection| {
+                    connection_sender
+                        .send((connection.host, connection.port))
+                        .unwrap();
+                })
+                .build(),
+        );
+
+        // Call the function, with a CONNECT target naming no port
+        let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        stream
+            .write_all(b"CONNECT example.com HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut stream).await;
+        stream.write_all(b"hello").await.unwrap();
+
+        // Verify the tunnel was opened to the port of HTTPS
+        assert!(head.starts_with(b"HTTP/1.1 200"));
+        let logged = connection_receiver.recv().await.unwrap();
+        assert_eq!(logged, ("example.com".to_string(), "443".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let ca = test_ca();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = spawn_upstream(&ca, "localhost", move |_| {
+            upstream_hits.fetch_add(1, Ordering::SeqCst);
+            async { Response::new(Body::from("ok")) }
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let mitm_proxy = proxy_builder(mitm, &ca)
+            .rate_limit(3, Duration::from_secs(60))
+            .build();
+        let metrics = mitm_proxy.metrics();
+        let proxy = spawn_proxy(mitm_proxy);
+
+        // Call the function, one request more than allowed on a connection,
+        // then another on a new connection from the same address
+        let mut statuses = Vec::new();
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        for _ in 0..4 {
+            let request = Request::get("/")
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            statuses.push(client.send_request(request).await.unwrap().status());
+        }
+        let mut other_client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::get("/")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let other = other_client.send_request(request).await.unwrap();
+
+        // Verify the requests beyond the limit were refused without reaching
+        // the target
+        assert_eq!(
+            statuses,
+            vec![
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+        assert_eq!(other.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(other.headers().contains_key("retry-after"));
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(metrics.rate_limited_requests(), 2);
+    }
+
+    /// Whether `TCP_NODELAY` is set on a connection accepted by a listener
+    /// with `listener_options`
+    async fn accepted_nodelay(listener_options: ListenerOptions) -> bool {
+        use hyper::server::accept::Accept;
+        let mut incoming = listener_options
+            .listen("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let _client = tokio::net::TcpStream::connect(incoming.local_addr())
+            .await
+            .unwrap();
+        let accepted =
+            futures::future::poll_fn(|cx| std::pin::Pin::new(&mut incoming).poll_accept(cx))
+                .await
+                .unwrap()
+                .unwrap();
+        accepted.into_inner().nodelay().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_listener_tcp_nodelay() {
+        // Call the function
+        let nodelay = accepted_nodelay(ListenerOptions {
+            nodelay: true,
+            ..ListenerOptions::default()
+        })
+        .await;
+        let default = accepted_nodelay(ListenerOptions::default()).await;
+
+        // Verify only the tuned listener set it on the accepted connection
+        assert!(nodelay);
+        assert!(!default);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_with_listener_options() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (proxy, server) = proxy_builder(mitm, &ca)
+            .listener_options(ListenerOptions {
+                backlog: 1024,
+                nodelay: true,
+                ..ListenerOptions::default()
+            })
+            .build()
+            .bind("127.0.0.1:0".parse().unwrap());
+        tokio::spawn(server);
+
+        // Call the function
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let response = client
+            .send_request(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // Verify the tuned proxy serves the connections
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+
+    /// The names of the request headers recorded in a HAR entry
+    fn recorded_header_names(entry: &har::v1_2::Entries) -> Vec<&str> {
+        entry
+            .request
+            .headers
+            .iter()
+            .map(|header| header.name.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_preserve_header_order() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::new(Body::from("ok"))
+        })
+        .await;
+        let mitm =
+            mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req));
+        let (builder, mut entries) = proxy_builder(mitm, &ca)
+            .preserve_header_order(true)
+            .capture_stream();
+        let proxy = spawn_proxy(builder.build());
+
+        // Call the function with a chunked request followed by another on the
+        // same connection, both repeating a header among the others
+        let mut stream = tls_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        stream
+            .write_all(
+                b"POST /first HTTP/1.1\r\nX-B: 1\r\nHost: localhost\r\nX-A: 2\r\n\
+                  Transfer-Encoding: chunked\r\nX-B: 3\r\n\r\n\
+                  4\r\nbody\r\n0\r\n\r\n\
+                  GET /second HTTP/1.1\r\nAccept: a\r\nHost: localhost\r\n\
+                  Accept: b\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        // Verify the headers were recorded in the order they were sent
+        let first = entries.next().await.unwrap();
+        let second = entries.next().await.unwrap();
+        assert_eq!(
+            recorded_header_names(&first),
+            [
+                "x-b",
+                "host",
+                "x-a",
+                "transfer-encoding",
+                "x-b",
+                "x-request-id"
+            ]
+        );
+        assert_eq!(first.request.headers[4].value, "3");
+        assert_eq!(
+            recorded_header_names(&second),
+            ["accept", "host", "accept", "x-request-id"]
+        );
+    }
+
+    /// Send a request through a proxy playing the cassette at `path`,
+    /// returning the body of the response
+    async fn request_with_cassette(
+        ca: &CertificateAuthority,
+        upstream: SocketAddr,
+        path: &std::path::Path,
+    ) -> String {
+        let cassette = Arc::new(std::sync::Mutex::new(Cassette::open(path).unwrap()));
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let cassette = cassette.clone();
+            Box::pin(async move { play_cassette(&cassette, req, &mut third_wheel).await })
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, ca).build());
+
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), ca).await;
+        let request = Request::get("/weather?city=paris")
+            .header("host", "localhost")
+            .header("authorization", "Bearer xyz")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cassette_records_then_replays() {
+        let ca = test_ca();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = spawn_upstream(&ca, "localhost", move |_| {
+            let hit = upstream_hits.fetch_add(1, Ordering::SeqCst) + 1;
+            async move { Response::new(Body::from(format!("sunny, answer {}", hit))) }
+        })
+        .await;
+        let path = std::env::temp_dir().join(format!("cassette_test_{}.har", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // Call the function on an empty cassette, then on the one it recorded
+        let first = request_with_cassette(&ca, upstream, &path).await;
+        let second = request_with_cassette(&ca, upstream, &path).await;
+        std::fs::remove_file(&path).unwrap();
+
+        // Verify the second run was answered from the cassette
+        assert_eq!(first, "sunny, answer 1");
+        assert_eq!(second, "sunny, answer 1");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cassette_redacts_headers() {
+        let ca = test_ca();
+        let upstream = spawn_upstream(&ca, "localhost", |_| async {
+            Response::builder()
+                .header("set-cookie", "session=secret")
+                .body(Body::from("sunny"))
+                .unwrap()
+        })
+        .await;
+        let path =
+            std::env::temp_dir().join(format!("cassette_redacted_test_{}.har", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // Call the function, recording an exchange carrying credentials
+        request_with_cassette(&ca, upstream, &path).await;
+        let entries = load_har(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Verify the cassette holds the credentials redacted
+        let header = |headers: &[har::v1_2::Headers], name: &str| {
+            headers
+                .iter()
+                .find(|header| header.name == name)
+                .map(|header| header.value.clone())
+        };
+        assert_eq!(
+            header(&entries[0].request.headers, "authorization").as_deref(),
+            Some("[REDACTED]")
+        );
+        assert_eq!(
+            header(&entries[0].response.headers, "set-cookie").as_deref(),
+            Some("[REDACTED]")
+        );
+    }
+}
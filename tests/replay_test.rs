@@ -0,0 +1,145 @@
+#[cfg(test)]
+mod tests {
+
+    use har::v1_2;
+    use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE, DATE, HOST};
+    use hyper::{Request, Response};
+    use tls_interceptor_proxy::replay::*;
+    use tls_interceptor_proxy::utilities::*;
+
+    async fn har_request(path: &str) -> v1_2::Request {
+        let (parts, _) = Request::get(path)
+            .header(HOST, "example.com")
+            .body(())
+            .unwrap()
+            .into_parts();
+        copy_from_http_request_to_har(&parts, Vec::new()).await
+    }
+
+    async fn har_response(date: &str, body: &str) -> v1_2::Response {
+        let (parts, _) = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .header(DATE, date)
+            .body(())
+            .unwrap()
+            .into_parts();
+        copy_from_http_response_to_har(&parts, body.as_bytes().to_vec()).await
+    }
+
+    #[tokio::test]
+    async fn test_identical_responses() {
+        let recorded = har_response("Mon, 01 Jan 2024 10:00:00 GMT", "hello").await;
+        let live = har_response("Tue, 02 Jan 2024 10:00:00 GMT", "hello").await;
+
+        // Call the function
+        let differences = diff_responses(&recorded, &live, &DiffOptions::default());
+
+        // Verify the ignored date header is not reported
+        assert!(differences.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_changed_body_is_flagged() {
+        let recorded = har_response("Mon, 01 Jan 2024 10:00:00 GMT", "hello").await;
+        let live = har_response("Mon, 01 Jan 2024 10:00:00 GMT", "hello world").await;
+
+        // Call the function
+        let differences = diff_responses(&recorded, &live, &DiffOptions::default());
+
+        // Verify only the body is reported
+        assert_eq!(
+            differences,
+            vec![Difference::Body {
+                recorded_size: 5,
+                live_size: 11
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decoded_body_replayed_without_encoding() {
+        let (parts, _) = Response::builder()
+            .header(CONTENT_ENCODING, "deflate")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let deflated = miniz_oxide::deflate::compress_to_vec_zlib(b"hello", 6);
+        let recorded = copy_from_http_response_to_har(&parts, deflated).await;
+
+        // Call the function
+        let response = response_from_har(&recorded);
+
+        // Verify the decoded body is not announced as encoded
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_header_differences() {
+        let recorded = har_response("Mon, 01 Jan 2024 10:00:00 GMT", "hello").await;
+        let live = har_response("Tue, 02 Jan 2024 10:00:00 GMT", "hello").await;
+        let options = DiffOptions {
+            ignore_headers: vec!["content-type".to_string()],
+        };
+
+        // Call the function
+        let differences = diff_responses(&recorded, &live, &options);
+
+        // Verify the date is reported once it is no longer ignored
+        assert_eq!(
+            differences,
+            vec![Difference::Header {
+                name: "date".to_string(),
+                recorded: Some("Mon, 01 Jan 2024 10:00:00 GMT".to_string()),
+                live: Some("Tue, 02 Jan 2024 10:00:00 GMT".to_string()),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recorded_responses_in_order() {
+        let entry = |request: v1_2::Request, response: v1_2::Response| v1_2::Entries {
+            request,
+            response,
+            time: 0.0,
+            server_ip_address: None,
+            connection: None,
+            comment: None,
+            started_date_time: String::new(),
+            cache: v1_2::Cache {
+                before_request: None,
+                after_request: None,
+            },
+            timings: v1_2::Timings {
+                blocked: None,
+                dns: None,
+                connect: None,
+                send: 0.0,
+                wait: 0.0,
+                receive: 0.0,
+                ssl: None,
+                comment: None,
+            },
+            pageref: None,
+        };
+        let date = "Mon, 01 Jan 2024 10:00:00 GMT";
+        let mut recorded = RecordedResponses::from_entries(vec![
+            entry(har_request("/a").await, har_response(date, "first").await),
+            entry(har_request("/a").await, har_response(date, "second").await),
+        ]);
+
+        // Call the function
+        let request = har_request("/a").await;
+        let first = recorded.take(&request).unwrap();
+        let second = recorded.take(&request).unwrap();
+        let none = recorded.take(&request);
+        let other = recorded.take(&har_request("/b").await);
+
+        // Verify the responses came back in the recorded order
+        assert_eq!(first.content.text.unwrap(), "first");
+        assert_eq!(second.content.text.unwrap(), "second");
+        assert!(none.is_none());
+        assert!(other.is_none());
+    }
+}
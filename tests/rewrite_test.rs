@@ -0,0 +1,183 @@
+#[cfg(test)]
+mod tests {
+
+    use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+    use hyper::{Request, Response};
+    use serde_json::json;
+    use tls_interceptor_proxy::rewrite::*;
+    use tls_interceptor_proxy::utilities::copy_from_http_response_to_har;
+
+    /// `{"message":"hello hello hello hello hello"}` compressed with gzip
+    const GZIPPED_JSON: [u8; 42] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0xca, 0x4d, 0x2d,
+        0x2e, 0x4e, 0x4c, 0x4f, 0x55, 0xb2, 0x52, 0xca, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc0, 0x41,
+        0x2a, 0xd5, 0x02, 0x00, 0xe4, 0x86, 0x65, 0x19, 0x2b, 0x00, 0x00, 0x00,
+    ];
+
+    fn rule(path: &str, value: serde_json::Value) -> JsonRewriteRule {
+        JsonRewriteRule {
+            path: path.parse().unwrap(),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_json_path_replace() {
+        let mut json = json!({
+            "user": {"name": "bob", "role": "guest"},
+            "items": [{"price": 1}, {"price": 2}],
+            "odd key": 1
+        });
+
+        // Call the function
+        let role = "$.user.role".parse::<JsonPath>().unwrap();
+        let prices = "$.items[*].price".parse::<JsonPath>().unwrap();
+        let first = "$['items'][0]".parse::<JsonPath>().unwrap();
+        let missing = "$.user.email".parse::<JsonPath>().unwrap();
+
+        // Verify the selected nodes were replaced
+        assert_eq!(role.replace(&mut json, &json!("admin")), 1);
+        assert_eq!(prices.replace(&mut json, &json!(0)), 2);
+        assert_eq!(json["user"]["role"], "admin");
+        assert_eq!(json["items"], json!([{"price": 0}, {"price": 0}]));
+        assert_eq!(first.replace(&mut json, &json!(null)), 1);
+        assert_eq!(json["items"][0], json!(null));
+        assert_eq!(missing.replace(&mut json, &json!("x")), 0);
+        assert!(json["user"].get("email").is_none());
+    }
+
+    #[test]
+    fn test_invalid_json_path() {
+        // Call the function and verify invalid paths are rejected
+        assert!("user.role".parse::<JsonPath>().is_err());
+        assert!("$.".parse::<JsonPath>().is_err());
+        assert!("$[abc]".parse::<JsonPath>().is_err());
+        assert!("$.items[0".parse::<JsonPath>().is_err());
+    }
+
+    #[test]
+    fn test_rewrite_json_request_body() {
+        let body = br#"{"user":{"name":"bob","role":"guest"}}"#.to_vec();
+        let (mut parts, _) = Request::post("/")
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .header(CONTENT_LENGTH, body.len())
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let rewritten =
+            rewrite_json_request_body(&mut parts, body, &[rule("$.user.role", json!("admin"))]);
+
+        // Verify the body and its length were updated
+        let rewritten_json: serde_json::Value = serde_json::from_slice(&rewritten).unwrap();
+        assert_eq!(
+            rewritten_json,
+            json!({"user": {"name": "bob", "role": "admin"}})
+        );
+        assert_eq!(
+            parts.headers[CONTENT_LENGTH],
+            rewritten.len().to_string().as_str()
+        );
+    }
+
+    #[test]
+    fn test_rewrite_ignores_non_json_body() {
+        let body = br#"{"user":{"role":"guest"}}"#.to_vec();
+        let (mut parts, _) = Request::post("/")
+            .header(CONTENT_TYPE, "text/plain")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let rewritten = rewrite_json_request_body(
+            &mut parts,
+            body.clone(),
+            &[rule("$.user.role", json!("admin"))],
+        );
+
+        // Verify the body was left untouched
+        assert_eq!(rewritten, body);
+        assert!(parts.headers.get(CONTENT_LENGTH).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_transform_gzip_response_body() {
+        let (mut parts, _) = Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .header(CONTENT_LENGTH, GZIPPED_JSON.len())
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function, replacing a word of the decoded body
+        let body = transform_body(&mut parts.headers, GZIPPED_JSON.to_vec(), |body| {
+            Some(
+                String::from_utf8_lossy(body)
+                    .replace("hello", "bye")
+                    .into_bytes(),
+            )
+        });
+
+        // Verify the forwarded body is the modified one gzipped again, with its length
+        assert_eq!(parts.headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            parts.headers[CONTENT_LENGTH],
+            body.len().to_string().as_str()
+        );
+        assert_eq!(&body[..2], &[0x1f, 0x8b]);
+        let recorded = copy_from_http_response_to_har(&parts, body).await;
+        assert_eq!(
+            recorded.content.text.as_deref(),
+            Some(r#"{"message":"bye bye bye bye bye"}"#)
+        );
+    }
+
+    #[test]
+    fn test_transform_skips_undecodable_body() {
+        let (mut parts, _) = Response::builder()
+            .header(CONTENT_ENCODING, "zstd")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let body = transform_body(&mut parts.headers, b"\x28\xb5".to_vec(), |_| {
+            Some(b"changed".to_vec())
+        });
+
+        // Verify the body the transform could not read was forwarded as it was
+        assert!(parts.headers.get(CONTENT_LENGTH).is_none());
+        assert_eq!(body, b"\x28\xb5");
+    }
+
+    #[test]
+    fn test_rewrite_gzip_json_request_body() {
+        let (mut parts, _) = Request::post("/")
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let rewritten = rewrite_json_request_body(
+            &mut parts,
+            GZIPPED_JSON.to_vec(),
+            &[rule("$.message", json!("bye"))],
+        );
+
+        // Verify the rewritten body was gzipped again
+        assert_eq!(&rewritten[..2], &[0x1f, 0x8b]);
+        assert_eq!(
+            parts.headers[CONTENT_LENGTH],
+            rewritten.len().to_string().as_str()
+        );
+        let size = u32::from_le_bytes(rewritten[rewritten.len() - 4..].try_into().unwrap());
+        let decoded =
+            miniz_oxide::inflate::decompress_to_vec(&rewritten[10..rewritten.len() - 8]).unwrap();
+        assert_eq!(decoded, br#"{"message":"bye"}"#);
+        assert_eq!(size as usize, decoded.len());
+    }
+}
@@ -0,0 +1,449 @@
+#[cfg(test)]
+mod tests {
+
+    use futures::stream;
+    use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+    use serde_json::json;
+    use std::net::IpAddr;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+    use tls_interceptor_proxy::classifier::KeywordClassifier;
+    use tls_interceptor_proxy::config::Config;
+    use tls_interceptor_proxy::rules::*;
+
+    fn zip_rule() -> Rule {
+        Rule {
+            host: Some("*.example.com".to_string()),
+            response_content_types: vec!["application/zip".to_string()],
+            ..Rule::default()
+        }
+    }
+
+    fn client(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    /// A response whose body notes whether it was ever read
+    fn response_with_body(content_type: &str, read: Arc<AtomicBool>) -> Response<Body> {
+        let body = Body::wrap_stream(stream::poll_fn(move |_| {
+            read.store(true, Ordering::SeqCst);
+            std::task::Poll::Ready(None::<Result<Vec<u8>, std::io::Error>>)
+        }));
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_banned_content_type_is_blocked() {
+        let engine = RuleEngine::new(vec![zip_rule()]);
+        let read = Arc::new(AtomicBool::new(false));
+        let response = response_with_body("application/zip", read.clone());
+
+        // Call the function
+        let (response, blocked) =
+            engine.filter_response("downloads.example.com", client("10.0.0.1"), response);
+
+        // Verify the block page replaced the response without reading its body
+        assert!(blocked);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let page = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&page).contains("Blocked"));
+        assert!(!read.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_other_responses_are_allowed() {
+        let engine = RuleEngine::new(vec![zip_rule()]);
+
+        // Call the function for another content type and another host
+        let (html, html_blocked) = engine.filter_response(
+            "downloads.example.com",
+            client("10.0.0.1"),
+            response_with_body("text/html; charset=utf-8", Arc::default()),
+        );
+        let (_, other_host_blocked) = engine.filter_response(
+            "example.org",
+            client("10.0.0.1"),
+            response_with_body("application/zip", Arc::default()),
+        );
+
+        // Verify the responses go through untouched
+        assert!(!html_blocked);
+        assert_eq!(html.status(), StatusCode::OK);
+        assert!(!other_host_blocked);
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let allow = Rule {
+            host: Some("trusted.example.com".to_string()),
+            response_content_types: vec!["Application/Zip".to_string()],
+            action: RuleAction::Allow,
+            ..Rule::default()
+        };
+        let engine = RuleEngine::new(vec![allow, zip_rule()]);
+        let (parts, _) = Response::builder()
+            .header(CONTENT_TYPE, "application/zip; name=archive.zip")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let trusted = engine.check_response("trusted.example.com", client("10.0.0.1"), &parts);
+        let other = engine.check_response("cdn.example.com", client("10.0.0.1"), &parts);
+
+        // Verify the allow rule exempts its host only
+        assert_eq!(trusted, RuleAction::Allow);
+        assert_eq!(other, RuleAction::Block);
+    }
+
+    #[test]
+    fn test_rules_config() {
+        // Call the function
+        let config = Config::from_toml_str(
+            r#"
+            [[rules]]
+            host = "*.example.com"
+            response_content_types = ["application/zip"]
+
+            [[rules]]
+            client_cidrs = ["192.168.100.0/24"]
+            action = "allow"
+            "#,
+        )
+        .unwrap();
+
+        // Verify the action defaults to block
+        assert_eq!(
+            config.rules,
+            vec![
+                zip_rule(),
+                Rule {
+                    client_cidrs: vec!["192.168.100.0/24".parse().unwrap()],
+                    action: RuleAction::Allow,
+                    ..Rule::default()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rule_applies_to_clients_in_cidr_only() {
+        let guests = Rule {
+            client_cidrs: vec!["192.168.100.0/24".parse().unwrap()],
+            host: Some("*.social.example".to_string()),
+            ..Rule::default()
+        };
+        let engine = RuleEngine::new(vec![guests]);
+
+        // Call the function for clients inside and outside the network
+        let guest = engine.check_request("www.social.example", client("192.168.100.42"));
+        let mapped_guest =
+            engine.check_request("www.social.example", client("::ffff:192.168.100.42"));
+        let staff = engine.check_request("www.social.example", client("192.168.1.42"));
+        let other_host = engine.check_request("example.org", client("192.168.100.42"));
+
+        // Verify only the guests are blocked
+        assert_eq!(guest, RuleAction::Block);
+        assert_eq!(mapped_guest, RuleAction::Block);
+        assert_eq!(staff, RuleAction::Allow);
+        assert_eq!(other_host, RuleAction::Allow);
+    }
+
+    #[test]
+    fn test_cidr_exemption() {
+        let admins = Rule {
+            client_cidrs: vec!["10.0.0.0/30".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            action: RuleAction::Allow,
+            ..Rule::default()
+        };
+        let everyone = Rule {
+            host: Some("admin.example.com".to_string()),
+            ..Rule::default()
+        };
+        let engine = RuleEngine::new(vec![admins, everyone]);
+
+        // Call the function and verify the earlier allow rule exempts admins
+        assert_eq!(
+            engine.check_request("admin.example.com", client("10.0.0.3")),
+            RuleAction::Allow
+        );
+        assert_eq!(
+            engine.check_request("admin.example.com", client("fd12::1")),
+            RuleAction::Allow
+        );
+        assert_eq!(
+            engine.check_request("admin.example.com", client("10.0.0.4")),
+            RuleAction::Block
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builtin_block_rules() {
+        let rules = BlockRules::builtin();
+        let classifier = KeywordClassifier::default();
+        let (host, path) = ("chatgpt.com", "/backend-api/conversation");
+
+        // Call the function
+        let confidential = rules
+            .check(
+                host,
+                "POST",
+                path,
+                None,
+                "this is confidential",
+                &classifier,
+            )
+            .await
+            .unwrap();
+        let hello = rules
+            .check(host, "POST", path, None, "hello", &classifier)
+            .await
+            .unwrap();
+
+        // Verify only the confidential prompts to the conversations are blocked
+        assert!(rules.watches(host, "POST", path));
+        assert!(!rules.watches(host, "GET", path));
+        assert!(!rules.watches("example.com", "POST", path));
+        assert_eq!(confidential, RuleAction::Block);
+        assert_eq!(hello, RuleAction::Allow);
+    }
+
+    #[tokio::test]
+    async fn test_block_rules_first_match_wins() {
+        let rules = BlockRules::new(vec![
+            BlockRule {
+                host: Some("*.openai.com".to_string()),
+                keywords: vec!["public".to_string()],
+                action: RuleAction::Allow,
+                ..BlockRule::default()
+            },
+            BlockRule {
+                host: Some("*.openai.com".to_string()),
+                path: Some("/v1/*".to_string()),
+                method: Some("post".to_string()),
+                ..BlockRule::default()
+            },
+        ]);
+        let classifier = KeywordClassifier::new(vec!["secret".to_string()]);
+        let check = |path: &'static str, prompt: &'static str| {
+            rules.check("api.openai.com", "POST", path, None, prompt, &classifier)
+        };
+
+        // Call the function
+        let public = check("/v1/chat", "public secret").await.unwrap();
+        let secret = check("/v1/chat", "secret").await.unwrap();
+        let hello = check("/v1/chat", "hello").await.unwrap();
+        let other_path = check("/v2/chat", "secret").await.unwrap();
+
+        // Verify the earlier allow rule wins and the classifier decides the rest
+        assert_eq!(public, RuleAction::Allow);
+        assert_eq!(secret, RuleAction::Block);
+        assert_eq!(hello, RuleAction::Allow);
+        assert_eq!(other_path, RuleAction::Allow);
+    }
+
+    #[tokio::test]
+    async fn test_json_predicate_rule() {
+        let path = std::env::temp_dir().join(format!("json_rules_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [[rules]]
+            host = "api.openai.com"
+            json = [
+                { path = "$.model", op = "==", value = "gpt-4" },
+                { path = "$.temperature", op = ">", value = 1.0 },
+            ]
+            "#,
+        )
+        .unwrap();
+        let rules = BlockRules::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let classifier = KeywordClassifier::default();
+        let check = |body: Option<serde_json::Value>| {
+            let rules = rules.clone();
+            let classifier = classifier.clone();
+            async move {
+                rules
+                    .check(
+                        "api.openai.com",
+                        "POST",
+                        "/v1/chat/completions",
+                        body.as_ref(),
+                        "confidential",
+                        &classifier,
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // Call the function with bodies above and below the threshold
+        let hot = check(Some(json!({"model": "gpt-4", "temperature": 1.5}))).await;
+        let cold = check(Some(json!({"model": "gpt-4", "temperature": 1}))).await;
+        let other_model = check(Some(json!({"model": "gpt-3.5", "temperature": 2}))).await;
+        let not_json = check(None).await;
+
+        // Verify only the requests meeting every condition are blocked, without
+        // asking the classifier
+        assert_eq!(hot, RuleAction::Block);
+        assert_eq!(cold, RuleAction::Allow);
+        assert_eq!(other_model, RuleAction::Allow);
+        assert_eq!(not_json, RuleAction::Allow);
+    }
+
+    #[test]
+    fn test_json_predicate_operators() {
+        let body = json!({"tags": ["a", "b"], "user": {"name": "alice"}, "n": 3});
+        let predicate = |path: &str, op: JsonOperator, value: serde_json::Value| JsonPredicate {
+            path: path.parse().unwrap(),
+            op,
+            value,
+        };
+
+        // Call the function and verify each operator
+        assert!(predicate("$.n", JsonOperator::Eq, json!(3.0)).matches(&body));
+        assert!(predicate("$.n", JsonOperator::Ne, json!(4)).matches(&body));
+        assert!(predicate("$.n", JsonOperator::Ge, json!(3)).matches(&body));
+        assert!(predicate("$.n", JsonOperator::Lt, json!(10)).matches(&body));
+        assert!(!predicate("$.n", JsonOperator::Le, json!(2)).matches(&body));
+        assert!(!predicate("$.user.name", JsonOperator::Gt, json!(1)).matches(&body));
+        assert!(predicate("$.user.name", JsonOperator::Contains, json!("lic")).matches(&body));
+        assert!(predicate("$.tags", JsonOperator::Contains, json!("b")).matches(&body));
+        assert!(predicate("$.tags[*]", JsonOperator::Eq, json!("a")).matches(&body));
+        assert!(predicate("$.user", JsonOperator::Exists, json!(null)).matches(&body));
+        assert!(!predicate("$.missing", JsonOperator::Exists, json!(null)).matches(&body));
+    }
+
+    #[tokio::test]
+    async fn test_load_block_rules() {
+        let dir = std::env::temp_dir();
+        let toml_path = dir.join(format!("block_rules_{}.toml", std::process::id()));
+        let json_path = dir.join(format!("block_rules_{}.json", std::process::id()));
+        std::fs::write(
+            &toml_path,
+            r#"
+            [[rules]]
+            host = "chat.example.com"
+            path = "/api/*"
+            method = "POST"
+            keywords = ["secret", "internal"]
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            &json_path,
+            r#"{"rules": [{"host": "chat.example.com", "path": "/api/*", "method": "POST",
+                "keywords": ["secret", "internal"], "action": "block"}]}"#,
+        )
+        .unwrap();
+
+        // Call the function
+        let from_toml = BlockRules::load(&toml_path).unwrap();
+        let from_json = BlockRules::load(&json_path).unwrap();
+        let config = Config {
+            rules_file: Some(toml_path.to_string_lossy().to_string()),
+            ..Config::default()
+        };
+
+        // Verify both formats give the same rules, blocking by default
+        assert_eq!(from_toml, from_json);
+        assert_eq!(config.block_rules().unwrap(), from_toml);
+        assert_eq!(
+            Config::default().block_rules().unwrap(),
+            BlockRules::builtin()
+        );
+        let action = from_toml
+            .check(
+                "chat.example.com",
+                "POST",
+                "/api/send",
+                None,
+                "an internal memo",
+                &KeywordClassifier::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(action, RuleAction::Block);
+        std::fs::remove_file(&toml_path).unwrap();
+        std::fs::remove_file(&json_path).unwrap();
+
+        // Verify a rule with an unknown field is rejected
+        std::fs::write(&json_path, r#"{"rules": [{"regex": "secret"}]}"#).unwrap();
+        assert!(BlockRules::load(&json_path).is_err());
+        std::fs::remove_file(&json_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_rule_patterns() {
+        let rules = BlockRules::new(vec![BlockRule {
+            host: Some("llm.example.com".to_string()),
+            keywords: vec!["internal".to_string()],
+            patterns: vec![PromptPattern::new(r"\bPRJ-\d{4}\b").unwrap()],
+            ..BlockRule::default()
+        }]);
+        let classifier = KeywordClassifier::new(vec!["report".to_string()]);
+        let check = |prompt: &'static str| {
+            rules.check(
+                "llm.example.com",
+                "POST",
+                "/chat",
+                None,
+                prompt,
+                &classifier,
+            )
+        };
+
+        // Call the function
+        let pattern = check("summarize the PRJ-1234 report").await.unwrap();
+        let keyword = check("an internal memo").await.unwrap();
+        let neither = check("summarize the PRJ-12 report").await.unwrap();
+
+        // Verify the prompts matching the pattern or holding a keyword are
+        // blocked, without asking the classifier about the others
+        assert_eq!(pattern, RuleAction::Block);
+        assert_eq!(keyword, RuleAction::Block);
+        assert_eq!(neither, RuleAction::Allow);
+
+        // Verify an invalid pattern is rejected when loading the rules
+        let path = std::env::temp_dir().join(format!("pattern_rules_{}.toml", std::process::id()));
+        std::fs::write(&path, "[[rules]]\npatterns = ['(unclosed']\n").unwrap();
+        assert!(BlockRules::load(&path).is_err());
+        std::fs::write(&path, "[[rules]]\npatterns = ['^secret']\n").unwrap();
+        assert_eq!(
+            BlockRules::load(&path).unwrap(),
+            BlockRules::new(vec![BlockRule {
+                patterns: vec![PromptPattern::new("^secret").unwrap()],
+                ..BlockRule::default()
+            }])
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        // Call the function
+        let network = parse_network("10.1.2.3/8").unwrap();
+        let single = parse_network("2001:db8::1").unwrap();
+        let everything = parse_network("0.0.0.0/0").unwrap();
+
+        // Verify the networks hold the expected addresses
+        assert!(network.contains(&client("10.200.0.1")));
+        assert!(!network.contains(&client("11.0.0.1")));
+        assert_eq!(single.to_string(), "2001:db8::1/128");
+        assert!(single.contains(&client("2001:db8::1")));
+        assert!(!single.contains(&client("2001:db8::2")));
+        assert!(everything.contains(&client("203.0.113.9")));
+        assert!(!everything.contains(&client("::1")));
+
+        // Verify malformed networks are rejected
+        for cidr in ["10.0.0.0/33", "10.0.0/8", "fd00::/129", "10.0.0.0/", "any"] {
+            assert!(parse_network(cidr).is_err(), "accepted {}", cidr);
+        }
+    }
+}
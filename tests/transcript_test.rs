@@ -0,0 +1,157 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::*;
+    use hyper::header::{CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+    use hyper::{Body, Request, Response};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tls_interceptor_proxy::third_wheel::proxy::mitm::{mitm_layer, ThirdWheel};
+    use tls_interceptor_proxy::transcript::*;
+    use tokio::sync::mpsc;
+
+    /// A directory of its own for the transcripts of a test
+    fn transcript_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_transcript_from_request() {
+        let (parts, _) = Request::post("/upload?draft=1")
+            .header(HOST, "example.com")
+            .header(TRANSFER_ENCODING, "chunked")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let transcript = Transcript::from_request(&parts, b"hello", 8443).unwrap();
+
+        // Verify the target, on the port of the tunnel rather than the default
+        // one, and the decoded body framed with its length
+        assert_eq!(transcript.host, "example.com");
+        assert_eq!(transcript.port, 8443);
+        assert_eq!(
+            String::from_utf8(transcript.request.clone()).unwrap(),
+            "POST /upload?draft=1 HTTP/1.1\r\nhost: example.com\r\ncontent-length: 5\r\n\r\nhello"
+        );
+        let parsed = Transcript::from_bytes(&transcript.to_bytes()).unwrap();
+        assert_eq!(parsed, transcript);
+        let request = parsed.to_request().unwrap();
+        assert_eq!(request.uri(), "/upload?draft=1");
+        assert_eq!(request.headers()[CONTENT_LENGTH], "5");
+    }
+
+    #[test]
+    fn test_transcript_dump_never_overwrites() {
+        let dir = transcript_dir("transcript_dump_numbers");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("000003-example.com.http"), "kept").unwrap();
+        let transcript = Transcript {
+            host: "example.com".to_string(),
+            port: 8443,
+            request: b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+        };
+
+        // Call the function, from two dumps sharing the directory
+        let first = TranscriptDump::create(&dir).unwrap();
+        let second = TranscriptDump::create(&dir).unwrap();
+        let first_path = first.record(&transcript).unwrap();
+        let second_path = second.record(&transcript).unwrap();
+        let kept = std::fs::read_to_string(dir.join("000003-example.com.http")).unwrap();
+        let transcripts = load_transcripts(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Verify the numbering carries on after the existing transcript and
+        // the second dump skipped the number the first one took
+        assert_eq!(kept, "kept");
+        assert!(first_path.ends_with("000004-example.com.http"));
+        assert!(second_path.ends_with("000005-example.com.http"));
+        assert_eq!(transcripts.len(), 2);
+        assert_eq!(transcripts[0].1, transcript);
+    }
+
+    #[test]
+    fn test_invalid_transcript() {
+        // Call the function on a file without its target line
+        let result = Transcript::from_bytes(b"GET / HTTP/1.1\r\n\r\n");
+
+        // Verify it is refused
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transcript_round_trip_through_proxy() {
+        let ca = test_ca();
+        let dir = transcript_dir("transcript_round_trip");
+
+        // The target reports every request it receives
+        let (request_sender, mut request_receiver) = mpsc::unbounded_channel();
+        let upstream = spawn_upstream(&ca, "localhost", move |req: Request<Body>| {
+            let request_sender = request_sender.clone();
+            async move {
+                let method = req.method().to_string();
+                let uri = req.uri().to_string();
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                request_sender.send((method, uri, body.to_vec())).unwrap();
+                Response::new(Body::from("ok"))
+            }
+        })
+        .await;
+
+        // Dump the decrypted requests before forwarding them
+        let dump = Arc::new(TranscriptDump::create(&dir).unwrap());
+        let mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+            let dump = dump.clone();
+            let fut = async move {
+                let (parts, body) = req.into_parts();
+                let body_bytes = hyper::body::to_bytes(body).await?;
+                let port = third_wheel.get_target_port();
+                let transcript = Transcript::from_request(&parts, &body_bytes, port).unwrap();
+                dump.record(&transcript).unwrap();
+                third_wheel
+                    .call(Request::from_parts(parts, Body::from(body_bytes)))
+                    .await
+            };
+            Box::pin(fut)
+        });
+        let proxy = spawn_proxy(proxy_builder(mitm, &ca).build());
+
+        // Send a request through the proxy
+        let mut client = client_through_proxy(proxy, "localhost", upstream.port(), &ca).await;
+        let request = Request::post("/prompt")
+            .header(HOST, "localhost")
+            .body(Body::from("secret prompt"))
+            .unwrap();
+        client.send_request(request).await.unwrap();
+        let forwarded = request_receiver.recv().await.unwrap();
+
+        // Call the function, replaying the dumped transcript to the target
+        let transcripts = load_transcripts(&dir).unwrap();
+        let replayer = TranscriptReplayer {
+            host_mappings: HashMap::from([("localhost".to_string(), "127.0.0.1".to_string())]),
+            root_certificates: vec![trusted_certificate(&ca)],
+        };
+        let response = replayer.replay(&transcripts[0].1).await.unwrap();
+        let replayed = request_receiver.recv().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Verify one transcript was written and the target got the same request again
+        assert_eq!(transcripts.len(), 1);
+        assert_eq!(transcripts[0].1.port, upstream.port());
+        assert!(response.status().is_success());
+        assert_eq!(replayed, forwarded);
+        assert_eq!(
+            replayed,
+            (
+                "POST".to_string(),
+                "/prompt".to_string(),
+                b"secret prompt".to_vec()
+            )
+        );
+    }
+}
@@ -1,11 +1,23 @@
 #[cfg(test)]
 mod tests {
 
+    use futures::StreamExt;
     use hyper::{
-        header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
-        Body, Request, Response, StatusCode,
+        header::{
+            HeaderValue, ACCEPT_LANGUAGE, CONTENT_ENCODING, CONTENT_TYPE, COOKIE, HOST, SET_COOKIE,
+        },
+        Body, Request, Response, StatusCode, Version,
     };
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tls_interceptor_proxy::third_wheel::proxy::{mitm::RequestId, ConnectionInfo};
     use tls_interceptor_proxy::utilities::*;
+    use tls_interceptor_proxy::validation::{validate_entry, validate_har};
 
     #[tokio::test]
     async fn test_copy_from_http_request_to_har() {
@@ -32,6 +44,95 @@ mod tests {
         assert_eq!(har_request.cookies[0].value, "value");
     }
 
+    #[tokio::test]
+    async fn test_query_string_recorded() {
+        let (parts, _) = Request::get("https://example.com/search?q=hello%20world&page=2&flag")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let har_request = copy_from_http_request_to_har(&parts, Vec::new()).await;
+
+        // Verify every pair was decoded, the one without a value included
+        let pairs: Vec<(&str, &str)> = har_request
+            .query_string
+            .iter()
+            .map(|pair| (pair.name.as_str(), pair.value.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![("q", "hello world"), ("page", "2"), ("flag", "")]
+        );
+    }
+
+    #[test]
+    fn test_request_url() {
+        let parts =
+            |request: hyper::http::request::Builder| request.body(()).unwrap().into_parts().0;
+
+        // Call the function for an HTTP/2 request, whose :authority is in the URI
+        let h2 = request_url(&parts(
+            Request::builder()
+                .version(Version::HTTP_2)
+                .uri("https://api.example.com/v1/chat?stream=true"),
+        ));
+        // For an HTTP/1.1 request read from a tunnel, with only a path
+        let h1 = request_url(&parts(
+            Request::builder()
+                .uri("/v1/chat?stream=true")
+                .header(HOST, "api.example.com:8443"),
+        ));
+        // For a request whose target is unknown
+        let unknown = request_url(&parts(Request::builder().uri("/v1/chat")));
+
+        // Verify the absolute URLs were reconstructed
+        assert_eq!(h2, "https://api.example.com/v1/chat?stream=true");
+        assert_eq!(h1, "https://api.example.com:8443/v1/chat?stream=true");
+        assert_eq!(unknown, "/v1/chat");
+    }
+
+    #[tokio::test]
+    async fn test_copy_h2_request_to_har() {
+        // Create an HTTP/2 request, without a host header
+        let request = Request::builder()
+            .version(Version::HTTP_2)
+            .method("GET")
+            .uri("https://chatgpt.com/backend-api/models")
+            .body(())
+            .unwrap();
+        let (parts, _) = request.into_parts();
+
+        // Call the function
+        let har_request = copy_from_http_request_to_har(&parts, Vec::new()).await;
+
+        // Verify the URL is absolute
+        assert_eq!(har_request.url, "https://chatgpt.com/backend-api/models");
+    }
+
+    #[tokio::test]
+    async fn test_http_version_recorded() {
+        let (request_parts, _) = Request::builder()
+            .version(Version::HTTP_10)
+            .uri("http://example.com/")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (response_parts, _) = Response::builder()
+            .version(Version::HTTP_2)
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the functions
+        let har_request = copy_from_http_request_to_har(&request_parts, Vec::new()).await;
+        let har_response = copy_from_http_response_to_har(&response_parts, Vec::new()).await;
+
+        // Verify the versions spoken were recorded
+        assert_eq!(har_request.http_version, "HTTP/1.0");
+        assert_eq!(har_response.http_version, "HTTP/2.0");
+    }
+
     #[tokio::test]
     async fn test_copy_from_http_response_to_har() {
         // Create a mock HTTP response
@@ -54,15 +155,160 @@ mod tests {
         assert_eq!(har_response.cookies[0].value, "value");
     }
 
+    #[tokio::test]
+    async fn test_non_utf8_headers_recorded_lossily() {
+        // Create a request and a response with headers holding 0xFF bytes
+        let binary = HeaderValue::from_bytes(b"attachment; filename=\"r\xffsum\xff.pdf\"").unwrap();
+        let request = Request::get("https://example.com/")
+            .header(HOST, "example.com")
+            .header("x-binary", HeaderValue::from_bytes(b"\xff\xfe").unwrap())
+            .header(COOKIE, HeaderValue::from_bytes(b"id=\xff").unwrap())
+            .body(())
+            .unwrap();
+        let response = Response::builder()
+            .header("content-disposition", binary)
+            .header(SET_COOKIE, HeaderValue::from_bytes(b"\xff").unwrap())
+            .header(SET_COOKIE, "name=value")
+            .body(())
+            .unwrap();
+        let (request_parts, _) = request.into_parts();
+        let (response_parts, _) = response.into_parts();
+
+        // Call the function
+        let har_request = copy_from_http_request_to_har(&request_parts, Vec::new()).await;
+        let har_response = copy_from_http_response_to_har(&response_parts, Vec::new()).await;
+
+        // Verify the values are recorded with replacement characters and noted
+        let binary = &har_request.headers[1];
+        assert_eq!(binary.name, "x-binary");
+        assert_eq!(binary.value, "\u{fffd}\u{fffd}");
+        assert_eq!(binary.comment.as_deref(), Some(LOSSY_HEADER_NOTE));
+        assert!(har_request.headers[0].comment.is_none());
+        assert_eq!(har_request.cookies[0].value, "\u{fffd}");
+        let disposition = &har_response.headers[0];
+        assert_eq!(
+            disposition.value,
+            "attachment; filename=\"r\u{fffd}sum\u{fffd}.pdf\""
+        );
+        assert_eq!(disposition.comment.as_deref(), Some(LOSSY_HEADER_NOTE));
+        // The malformed cookie is only kept in the headers
+        assert_eq!(har_response.cookies.len(), 1);
+        assert_eq!(har_response.cookies[0].name, "name");
+    }
+
+    /// `{"message":"hello hello hello hello hello"}` compressed with gzip
+    const GZIPPED_JSON: [u8; 42] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0xca, 0x4d, 0x2d,
+        0x2e, 0x4e, 0x4c, 0x4f, 0x55, 0xb2, 0x52, 0xca, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc0, 0x41,
+        0x2a, 0xd5, 0x02, 0x00, 0xe4, 0x86, 0x65, 0x19, 0x2b, 0x00, 0x00, 0x00,
+    ];
+
+    #[tokio::test]
+    async fn test_compressed_response_recorded_decoded() {
+        let json = r#"{"message":"hello hello hello hello hello"}"#;
+        let (gzip_parts, _) = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (deflate_parts, _) = Response::builder()
+            .header(CONTENT_ENCODING, "deflate")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (brotli_parts, _) = Response::builder()
+            .header(CONTENT_ENCODING, "br")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (zstd_parts, _) = Response::builder()
+            .header(CONTENT_ENCODING, "zstd")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let deflated = miniz_oxide::deflate::compress_to_vec_zlib(json.as_bytes(), 6);
+        let mut brotli_writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        brotli_writer.write_all(json.as_bytes()).unwrap();
+        let brotli_compressed = brotli_writer.into_inner();
+        // Two gzip members, as sent by servers compressing chunks apart
+        let two_members = [GZIPPED_JSON, GZIPPED_JSON].concat();
+
+        // Call the function
+        let gzip = copy_from_http_response_to_har(&gzip_parts, GZIPPED_JSON.to_vec()).await;
+        let deflate = copy_from_http_response_to_har(&deflate_parts, deflated.clone()).await;
+        let brotli = copy_from_http_response_to_har(&brotli_parts, brotli_compressed.clone()).await;
+        let multi_gzip = copy_from_http_response_to_har(&gzip_parts, two_members).await;
+        let zstd = copy_from_http_response_to_har(&zstd_parts, b"\x28\xb5".to_vec()).await;
+
+        // Verify the bodies were decoded with the bytes their encoding saved
+        assert_eq!(gzip.content.text.as_deref(), Some(json));
+        assert_eq!(gzip.content.size, json.len() as i64);
+        assert_eq!(gzip.content.compression, Some(json.len() as i64 - 42));
+        assert_eq!(gzip.body_size, 42);
+        assert_eq!(deflate.content.text.as_deref(), Some(json));
+        assert_eq!(
+            deflate.content.compression,
+            Some(json.len() as i64 - deflated.len() as i64)
+        );
+        assert_eq!(brotli.content.text.as_deref(), Some(json));
+        assert_eq!(
+            brotli.content.compression,
+            Some(json.len() as i64 - brotli_compressed.len() as i64)
+        );
+        assert_eq!(multi_gzip.content.text, Some(format!("{}{}", json, json)));
+        assert_eq!(multi_gzip.body_size, 84);
+        // A coding which cannot be decoded is recorded as it was sent
+        assert_eq!(zstd.content.compression, None);
+        assert_eq!(zstd.content.size, 2);
+    }
+
+    #[tokio::test]
+    async fn test_binary_bodies_in_base64() {
+        let png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0xff];
+        let (req_parts, _) = Request::post("/upload")
+            .header(HOST, "example.com")
+            .header(CONTENT_TYPE, "image/png")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (res_parts, _) = Response::builder()
+            .header(CONTENT_TYPE, "image/png")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        // Call the function
+        let har_request = copy_from_http_request_to_har(&req_parts, png.clone()).await;
+        let har_response = copy_from_http_response_to_har(&res_parts, png.clone()).await;
+        let text_response =
+            copy_from_http_response_to_har(&res_parts, b"plain text".to_vec()).await;
+
+        // Verify the binary bodies are kept in base64 with their decoded size
+        let post_data = har_request.post_data.unwrap();
+        assert_eq!(post_data.text.as_deref(), Some("iVBORw0KGgoA/w=="));
+        assert_eq!(post_data.comment.as_deref(), Some("base64"));
+        assert_eq!(har_post_data_bytes(&post_data), png);
+        assert_eq!(har_request.body_size, 10);
+        assert_eq!(har_response.content.encoding.as_deref(), Some("base64"));
+        assert_eq!(har_response.content.size, 10);
+        assert_eq!(har_response.body_size, 10);
+        assert_eq!(har_content_bytes(&har_response.content), png);
+        assert_eq!(text_response.content.text.as_deref(), Some("plain text"));
+        assert_eq!(text_response.content.encoding, None);
+    }
+
     #[test]
     fn test_parse_cookie() {
         // Create a mock cookie string
         let cookie_str = "sessionId=abc123; Path=/; HttpOnly; Secure";
 
         // Call the function
-        let parsed_cookie = parse_cookie(cookie_str);
+        let parsed_cookie = parse_cookie(cookie_str).unwrap();
+        let malformed = parse_cookie("no equals sign");
 
-        // Verify the parsed cookie fields
+        // Verify the parsed cookie fields, and a malformed cookie gives none
+        assert!(malformed.is_none());
         assert_eq!(parsed_cookie.name, "sessionId");
         assert_eq!(parsed_cookie.value, "abc123");
         assert_eq!(parsed_cookie.path.unwrap(), "/");
@@ -70,6 +316,27 @@ mod tests {
         assert_eq!(parsed_cookie.secure, Some(true));
     }
 
+    #[tokio::test]
+    async fn test_cookie_expiration() {
+        let response = Response::builder()
+            .header(
+                SET_COOKIE,
+                "id=a3fWa; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=/",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let (parts, _) = response.into_parts();
+
+        // Call the function
+        let har_response = copy_from_http_response_to_har(&parts, Vec::new()).await;
+
+        // Verify the expiration was recorded in ISO 8601
+        assert_eq!(
+            har_response.cookies[0].expires.as_deref(),
+            Some("2015-10-21T07:28:00Z")
+        );
+    }
+
     #[test]
     fn test_convert_body_to_json() {
         // Define a JSON string
@@ -82,6 +349,66 @@ mod tests {
         assert_eq!(json_value["message"], "Hello");
     }
 
+    #[test]
+    fn test_convert_binary_body_to_json() {
+        // Define a body which is not UTF-8
+        let body_bytes = vec![0xff, 0xfe, 0x00, 0x7b];
+
+        // Call the function
+        let json_value = convert_body_to_json(body_bytes);
+
+        // Verify it is given no JSON value
+        assert_eq!(json_value, serde_json::Value::Null);
+    }
+
+    /// Set for the child process of `test_binary_bodies_without_stderr`
+    const STDERR_CHILD: &str = "UTILITIES_TEST_STDERR_CHILD";
+
+    /// Converts binary bodies, only when run by `test_binary_bodies_without_stderr`
+    #[tokio::test]
+    async fn test_binary_bodies_without_stderr_child() {
+        if std::env::var_os(STDERR_CHILD).is_none() {
+            return;
+        }
+        let body_bytes = vec![0xff, 0xfe, 0x00, 0x7b];
+        let (req_parts, _) = Request::post("/")
+            .header(HOST, "example.com")
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (res_parts, _) = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        copy_from_http_request_to_har(&req_parts, body_bytes.clone()).await;
+        copy_from_http_response_to_har(&res_parts, body_bytes.clone()).await;
+        convert_body_to_json(body_bytes);
+    }
+
+    #[test]
+    fn test_binary_bodies_without_stderr() {
+        // Call the function in a child process whose stderr is captured
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "tests::test_binary_bodies_without_stderr_child",
+                "--nocapture",
+            ])
+            .env(STDERR_CHILD, "1")
+            .output()
+            .unwrap();
+
+        // Verify the conversions ran and wrote nothing to stderr
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!stderr.contains("Error converting bytes to UTF-8"));
+        assert_eq!(stderr, "");
+    }
+
     #[test]
     fn test_parse_request() {
         // Define a JSON string with a message structure
@@ -95,6 +422,76 @@ mod tests {
         assert_eq!(parsed_message, "\"Hello, world!\"");
     }
 
+    #[test]
+    fn test_parse_request_other_shapes() {
+        // Define bodies of other shapes than a single ChatGPT message
+        let conversation = br#"{ "messages": [
+            { "content": { "parts": ["first"] } },
+            { "content": { "parts": ["latest", "extra"] } }
+        ] }"#;
+        let completion = br#"{ "messages": [
+            { "role": "system", "content": "be brief" },
+            { "role": "user", "content": "Hello, world!" }
+        ] }"#;
+        let malformed = [
+            &b""[..],
+            br#"{ "messages": [] }"#,
+            br#"{ "messages": "hello" }"#,
+            br#"{ "messages": [{ "content": { "parts": [] } }] }"#,
+            br#"{ "messages": [{ "content": 42 }] }"#,
+            br#"{ "messages": [{ "role": "user" }] }"#,
+        ];
+
+        // Call the function
+        let every = parse_request(conversation.to_vec());
+        let user = parse_request(completion.to_vec());
+
+        // Verify every message is read, in either shape, and the others give
+        // no prompt instead of panicking
+        assert_eq!(every, r#"["first","latest"]"#);
+        assert_eq!(user, "\"Hello, world!\"");
+        assert_eq!(extract_prompt(completion).unwrap(), "Hello, world!");
+        for body in malformed {
+            assert_eq!(parse_request(body.to_vec()), "");
+            assert!(extract_prompt(body).is_none());
+        }
+    }
+
+    #[test]
+    fn test_extract_prompt_reads_every_user_message() {
+        // Define a conversation whose keyword is in an earlier user message
+        let body_bytes = br#"{ "messages": [
+            { "author": { "role": "user" }, "content": { "parts": ["a confidential memo"] } },
+            { "author": { "role": "assistant" }, "content": { "parts": ["Noted."] } },
+            { "author": { "role": "user" }, "content": { "parts": ["Summarize it"] } }
+        ] }"#;
+
+        // Call the function
+        let prompt = extract_prompt(body_bytes).unwrap();
+        let parsed = parse_request(body_bytes.to_vec());
+
+        // Verify the earlier user message is part of the prompt, without the
+        // answers of the assistant
+        assert_eq!(prompt, "a confidential memo\nSummarize it");
+        assert!(parsed.contains("confidential"));
+        assert!(!parsed.contains("Noted."));
+    }
+
+    #[test]
+    fn test_extract_prompt() {
+        // Define a conversation body and one without any message
+        let body_bytes = br#"{ "messages": [{ "content": { "parts": ["Hello, world!"] }}] }"#;
+        let other_bytes = br#"{ "model": "gpt-4" }"#;
+
+        // Call the function
+        let prompt = extract_prompt(body_bytes);
+        let no_prompt = extract_prompt(other_bytes);
+
+        // Verify the prompt text was extracted
+        assert_eq!(prompt.unwrap(), "Hello, world!");
+        assert!(no_prompt.is_none());
+    }
+
     #[tokio::test]
     async fn test_create_response() {
         // Define a body byte array
@@ -102,7 +499,7 @@ mod tests {
             br#"{"messages":[{"id":"aaa211a5-24d7-4868-8d8c-b657402be43b"}]}"#.to_vec();
 
         // Call the function
-        let response = create_response(body_bytes);
+        let response = create_response(body_bytes, "Blocked");
 
         // Verify the response headers and status
         assert_eq!(response.status(), StatusCode::OK);
@@ -115,4 +512,645 @@ mod tests {
         let body_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
         assert!(body_bytes.starts_with(b"data: "));
     }
+
+    #[tokio::test]
+    async fn test_create_response_conversation_id() {
+        // Define bodies continuing a conversation, and naming none
+        let continued = br#"{"conversation_id":"c-1","messages":[{"id":"m-1"}]}"#.to_vec();
+        let unnamed = br#"{"conversation_id":null,"messages":[{"id":"m-1"}]}"#.to_vec();
+
+        // Call the function
+        let continued = create_response(continued, "Blocked");
+        let unnamed = create_response(unnamed, "Blocked");
+
+        // Verify the conversation is kept, and a new one titled otherwise
+        let continued = hyper::body::to_bytes(continued.into_body()).await.unwrap();
+        let continued = String::from_utf8(continued.to_vec()).unwrap();
+        assert!(continued.contains(r#""conversation_id":"c-1""#));
+        assert!(!continued.contains("title_generation"));
+        let unnamed = hyper::body::to_bytes(unnamed.into_body()).await.unwrap();
+        let unnamed = String::from_utf8(unnamed.to_vec()).unwrap();
+        assert!(unnamed.contains("title_generation"));
+        assert!(!unnamed.contains(r#""conversation_id":null"#));
+    }
+
+    #[tokio::test]
+    async fn test_block_chat_completions_request() {
+        // Define a body of the chat completions API, whose messages have no id
+        let body_bytes = br#"{"model":"gpt-4o","messages":[{"role":"user","content":"Hi"}]}"#;
+        let (parts, _) = Request::post("https://api.openai.com/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let client = "127.0.0.1:1234".parse().unwrap();
+
+        // Call the function
+        let (_, response) = log_blocked_request(
+            &parts,
+            body_bytes.to_vec(),
+            client,
+            None,
+            &Default::default(),
+            &BlockMessages::default(),
+        )
+        .await;
+
+        // Verify the client is still sent the block message
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with("data: "));
+        assert!(body.contains(BlockMessages::default().message(None)));
+        assert!(body.ends_with("data: [DONE]\n\n"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_gateway() {
+        // Call the function
+        let response = bad_gateway("connection reset");
+
+        // Verify the client is told why the response is missing
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body_bytes, "connection reset");
+    }
+
+    #[test]
+    fn test_parse_accept_language() {
+        // Call the function with weighted, regional and wildcard ranges
+        let languages = parse_accept_language("en;q=0.8, fr-CH, *;q=0.5, de;q=0, FR;q=0.9");
+
+        // Verify the tags are ordered by quality, without the refused ones
+        assert_eq!(languages, vec!["fr-ch", "fr", "en"]);
+        assert!(parse_accept_language("").is_empty());
+    }
+
+    /// The text of the block response to a request accepting `accept_language`
+    async fn block_message(accept_language: Option<&str>) -> String {
+        let mut request = Request::post("/backend-api/conversation");
+        if let Some(accept_language) = accept_language {
+            request = request.header(ACCEPT_LANGUAGE, accept_language);
+        }
+        let (parts, _) = request.body(()).unwrap().into_parts();
+        let body_bytes =
+            br#"{"messages":[{"id":"aaa211a5-24d7-4868-8d8c-b657402be43b"}]}"#.to_vec();
+        let client = "127.0.0.1:1234".parse().unwrap();
+        let (_, response) = log_blocked_request(
+            &parts,
+            body_bytes,
+            client,
+            None,
+            &Default::default(),
+            &BlockMessages::default(),
+        )
+        .await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_block_message_follows_accept_language() {
+        // Call the function
+        let french = block_message(Some("fr")).await;
+        let english = block_message(Some("en")).await;
+        let regional = block_message(Some("fr-CA, en;q=0.5")).await;
+        let unknown = block_message(Some("ja")).await;
+        let missing = block_message(None).await;
+
+        // Verify each client gets its language, English by default
+        let messages = BlockMessages::default();
+        let french_message = messages.message(Some("fr"));
+        let english_message = messages.message(Some("en"));
+        assert!(french_message.starts_with("Impossible d'executer"));
+        assert!(english_message.starts_with("Unable to process"));
+        assert!(french.contains(french_message));
+        assert!(english.contains(english_message));
+        assert!(regional.contains(french_message));
+        assert!(unknown.contains(english_message));
+        assert!(missing.contains(english_message));
+    }
+
+    #[test]
+    fn test_configured_block_messages() {
+        // Add a language and replace the English message
+        let messages = BlockMessages::new(HashMap::from([
+            ("DE".to_string(), "Blockiert".to_string()),
+            ("en".to_string(), "Blocked".to_string()),
+        ]));
+
+        // Verify the configured messages are used along with the built-in ones
+        assert_eq!(messages.message(Some("de-AT")), "Blockiert");
+        assert_eq!(messages.message(Some("it")), "Blocked");
+        assert!(messages.message(Some("fr")).starts_with("Impossible"));
+    }
+
+    #[tokio::test]
+    async fn test_read_body_preview() {
+        // Create a body of ten 100 byte chunks counting how many are pulled
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let pulled_counter = pulled.clone();
+        let chunks = futures::stream::iter(0..10).map(move |_| {
+            pulled_counter.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, std::io::Error>(vec![b'a'; 100])
+        });
+        let body = Body::wrap_stream(chunks);
+
+        // Call the function
+        let (preview, body) = read_body_preview(body, 150).await.unwrap();
+
+        // Verify only the preview was buffered
+        assert_eq!(preview.len(), 150);
+        assert_eq!(pulled.load(Ordering::SeqCst), 2);
+
+        // Verify the whole body is still streamed
+        let body_bytes = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(body_bytes.len(), 1000);
+        assert_eq!(pulled.load(Ordering::SeqCst), 10);
+    }
+
+    #[tokio::test]
+    async fn test_tee_body_endless_stream() {
+        // Create a body that never ends
+        let chunks = futures::stream::repeat_with(|| Ok::<_, std::io::Error>(vec![b'a'; 100]));
+        let body = Body::wrap_stream(chunks);
+
+        // Call the function
+        let (mut body, recorded) = tee_body(body, 250, Some(std::time::Duration::from_secs(5)));
+
+        // Verify the stream goes on for the client past what is recorded
+        let mut streamed = 0;
+        while streamed < 1000 {
+            streamed += body.next().await.unwrap().unwrap().len();
+        }
+
+        // Verify the capture stopped at the limit
+        let (captured, complete) = recorded.await.unwrap();
+        assert_eq!(captured.len(), 250);
+        assert!(!complete);
+    }
+
+    #[tokio::test]
+    async fn test_tee_body_stalled_stream() {
+        // Create a body that sends one event then waits forever
+        let chunks = futures::stream::iter(vec![Ok::<_, std::io::Error>(b"data: 1\n\n".to_vec())])
+            .chain(futures::stream::pending());
+        let body = Body::wrap_stream(chunks);
+
+        // Call the function
+        let (mut body, recorded) =
+            tee_body(body, 1024, Some(std::time::Duration::from_millis(100)));
+
+        // Verify the event was sent on without waiting for the window
+        let chunk = body.next().await.unwrap().unwrap();
+        assert_eq!(&chunk[..], b"data: 1\n\n");
+
+        // Verify what arrived in the window was recorded
+        let (captured, complete) = recorded.await.unwrap();
+        assert_eq!(captured, b"data: 1\n\n");
+        assert!(!complete);
+    }
+
+    #[tokio::test]
+    async fn test_tee_body_finite_stream() {
+        // Call the function
+        let (body, recorded) = tee_body(Body::from("data: [DONE]\n\n"), 1024, None);
+
+        // Verify the whole body is sent and was recorded
+        let body_bytes = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(&body_bytes[..], b"data: [DONE]\n\n");
+        let (captured, complete) = recorded.await.unwrap();
+        assert_eq!(captured, b"data: [DONE]\n\n");
+        assert!(complete);
+    }
+
+    #[test]
+    fn test_is_unbounded_response() {
+        let parts = |content_type: &str, length: Option<&str>| {
+            let mut response = Response::builder().header(CONTENT_TYPE, content_type);
+            if let Some(length) = length {
+                response = response.header("content-length", length);
+            }
+            response.body(()).unwrap().into_parts().0
+        };
+
+        // Call the function and verify only streams are unbounded
+        assert!(is_unbounded_response(&parts(
+            "text/event-stream; charset=utf-8",
+            None
+        )));
+        assert!(is_unbounded_response(&parts(
+            "application/octet-stream",
+            None
+        )));
+        assert!(!is_unbounded_response(&parts(
+            "application/octet-stream",
+            Some("10")
+        )));
+        assert!(!is_unbounded_response(&parts("application/json", None)));
+    }
+
+    #[tokio::test]
+    async fn test_log_blocked_request_bodies_opt_in() {
+        let body_bytes = br#"{"messages":[{"id":"aaa211a5"}]}"#.to_vec();
+        let (parts, _) = Request::post("https://chatgpt.com/backend-api/conversation")
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let client = "127.0.0.1:1234".parse().unwrap();
+        let recording = CaptureOptions {
+            record_bodies: true,
+            ..CaptureOptions::default()
+        };
+
+        // Call the function
+        let (metadata_only, _) = log_blocked_request(
+            &parts,
+            body_bytes.clone(),
+            client,
+            None,
+            &Default::default(),
+            &BlockMessages::default(),
+        )
+        .await;
+        let (with_bodies, _) = log_blocked_request(
+            &parts,
+            body_bytes.clone(),
+            client,
+            None,
+            &recording,
+            &BlockMessages::default(),
+        )
+        .await;
+
+        // Verify the bodies are only recorded when asked for, their sizes always
+        assert_eq!(validate_entry("metadata_only", &metadata_only), vec![]);
+        assert_eq!(validate_entry("with_bodies", &with_bodies), vec![]);
+        assert!(metadata_only.request.post_data.is_none());
+        assert!(metadata_only.response.content.text.is_none());
+        assert_eq!(metadata_only.request.body_size, body_bytes.len() as i64);
+        assert!(metadata_only.response.content.size > 0);
+        assert_eq!(metadata_only.request.url, with_bodies.request.url);
+        assert_eq!(
+            with_bodies
+                .request
+                .post_data
+                .unwrap()
+                .text
+                .unwrap()
+                .as_bytes(),
+            &body_bytes[..]
+        );
+        assert!(with_bodies.response.content.text.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_log_blocked_request_started_date_time() {
+        let (parts, _) = Request::get("https://chatgpt.com/")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let client = "127.0.0.1:1234".parse().unwrap();
+
+        // Call the function
+        let (entry, _) = log_blocked_request(
+            &parts,
+            Vec::new(),
+            client,
+            None,
+            &Default::default(),
+            &BlockMessages::default(),
+        )
+        .await;
+
+        // Verify the start is an RFC 3339 date with milliseconds and an offset
+        let started = chrono::DateTime::parse_from_rfc3339(&entry.started_date_time).unwrap();
+        assert_eq!(
+            har_date_time(started.with_timezone(&chrono::Local)),
+            entry.started_date_time
+        );
+        assert_eq!(entry.started_date_time.split('.').nth(1).unwrap().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn test_log_blocked_request_preview() {
+        // Create a blocked request
+        let body_bytes =
+            br#"{"messages":[{"id":"aaa211a5-24d7-4868-8d8c-b657402be43b"}]}"#.to_vec();
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://chatgpt.com/backend-api/conversation")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let (parts, _) = request.into_parts();
+        let options = CaptureOptions {
+            record_bodies: true,
+            body_preview: Some(16),
+            ..CaptureOptions::default()
+        };
+
+        // Call the function
+        let (entries, response) = log_blocked_request(
+            &parts,
+            body_bytes.clone(),
+            "127.0.0.1:1234".parse().unwrap(),
+            None,
+            &options,
+            &BlockMessages::default(),
+        )
+        .await;
+
+        // Verify only the preview was recorded
+        let post_data = entries.request.post_data.unwrap();
+        assert_eq!(post_data.text.unwrap().len(), 16);
+        assert_eq!(post_data.comment.unwrap(), "preview");
+        assert_eq!(entries.request.body_size, body_bytes.len() as i64);
+        assert_eq!(entries.response.content.text.unwrap().len(), 16);
+        assert_eq!(entries.response.content.comment.unwrap(), "preview");
+
+        // Verify the client still receives the full response
+        let response_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(response_bytes.ends_with(b"data: [DONE]\n\n"));
+    }
+
+    #[tokio::test]
+    async fn test_log_forwarded_request_preview() {
+        // Create a forwarded request
+        let body_bytes =
+            br#"{"messages":[{"id":"aaa211a5-24d7-4868-8d8c-b657402be43b"}]}"#.to_vec();
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://chatgpt.com/backend-api/conversation")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let (parts, _) = request.into_parts();
+        let options = CaptureOptions {
+            record_bodies: true,
+            body_preview: Some(16),
+            ..CaptureOptions::default()
+        };
+
+        // Call the function
+        let entries = log_forwarded_request(
+            &parts,
+            body_bytes.clone(),
+            "127.0.0.1:1234".parse().unwrap(),
+            None,
+            &options,
+        )
+        .await;
+
+        // Verify only the preview was recorded
+        let post_data = entries.request.post_data.unwrap();
+        assert_eq!(post_data.text.unwrap().len(), 16);
+        assert_eq!(post_data.comment.unwrap(), "preview");
+        assert_eq!(entries.request.body_size, body_bytes.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn test_log_blocked_request_external_bodies() {
+        // Create a blocked request with a large body
+        let prompt = "confidential ".repeat(1000);
+        let body_bytes = format!(
+            r#"{{"messages":[{{"id":"aaa211a5-24d7-4868-8d8c-b657402be43b","content":{{"parts":["{}"]}}}}]}}"#,
+            prompt
+        )
+        .into_bytes();
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("https://chatgpt.com/backend-api/conversation")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(RequestId("external-body-test".to_string()));
+        let (parts, _) = request.into_parts();
+        let dir = std::env::temp_dir().join(format!("bodies_test_{}", std::process::id()));
+        let options = CaptureOptions {
+            record_bodies: true,
+            external_bodies: Some(ExternalBodies {
+                dir: dir.clone(),
+                threshold: 4096,
+            }),
+            ..CaptureOptions::default()
+        };
+
+        // Call the function
+        let (entries, _) = log_blocked_request(
+            &parts,
+            body_bytes.clone(),
+            "127.0.0.1:1234".parse().unwrap(),
+            None,
+            &options,
+            &BlockMessages::default(),
+        )
+        .await;
+
+        // Verify the request body was written to its own file and referenced
+        let path = dir.join("external-body-test-request.bin");
+        let post_data = entries.request.post_data.unwrap();
+        assert_eq!(post_data.text.unwrap(), path.display().to_string());
+        assert_eq!(post_data.comment.unwrap(), "stored externally");
+        assert_eq!(entries.request.body_size, body_bytes.len() as i64);
+        assert_eq!(std::fs::read(&path).unwrap(), body_bytes);
+
+        // Verify the small response is still inlined
+        assert!(entries
+            .response
+            .content
+            .text
+            .unwrap()
+            .ends_with("data: [DONE]\n\n"));
+        assert!(entries.response.content.comment.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_external_bodies_traversal_id() {
+        // Create a blocked request whose client sent a path as its id
+        let body_bytes = "confidential ".repeat(1000).into_bytes();
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("https://chatgpt.com/backend-api/conversation")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(RequestId("../../traversal-test".to_string()));
+        let (parts, _) = request.into_parts();
+        let dir = std::env::temp_dir()
+            .join(format!("bodies_traversal_{}", std::process::id()))
+            .join("bodies");
+        let options = CaptureOptions {
+            record_bodies: true,
+            external_bodies: Some(ExternalBodies {
+                dir: dir.clone(),
+                threshold: 4096,
+            }),
+            ..CaptureOptions::default()
+        };
+
+        // Call the functions
+        let stored = store_body_externally(&dir, "../../traversal-test", &body_bytes);
+        let (entries, _) = log_blocked_request(
+            &parts,
+            body_bytes,
+            "127.0.0.1:1234".parse().unwrap(),
+            None,
+            &options,
+            &BlockMessages::default(),
+        )
+        .await;
+
+        // Verify the id was refused as a file name and the body kept in the
+        // directory under a generated name
+        assert_eq!(stored.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        let path = PathBuf::from(entries.request.post_data.unwrap().text.unwrap());
+        assert_eq!(path.parent().unwrap(), dir);
+        assert!(!path.to_string_lossy().contains(".."));
+        assert!(!dir
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("traversal-test-request.bin")
+            .exists());
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_log_connection() {
+        let connection = ConnectionInfo {
+            host: "example.com".to_string(),
+            port: "443".to_string(),
+            client_ip: "127.0.0.1:1234".parse().unwrap(),
+            server_name: None,
+            timestamp: std::time::SystemTime::now(),
+        };
+
+        // Call the function
+        let entries = log_connection(&connection);
+
+        // Verify the entry describes the tunnel without any body
+        assert_eq!(validate_entry("connection", &entries), vec![]);
+        assert_eq!(entries.request.method, "CONNECT");
+        assert_eq!(entries.request.url, "example.com:443");
+        assert_eq!(entries.connection.unwrap(), "127.0.0.1:1234");
+        assert!(entries.server_ip_address.is_none());
+        assert_eq!(entries.comment.unwrap(), "connection only");
+        assert!(entries.request.post_data.is_none());
+        assert!(entries.response.content.text.is_none());
+    }
+
+    /// A HAR 1.2 archive holding `entries`
+    fn archive(entries: Vec<har::v1_2::Entries>) -> har::Har {
+        har::Har {
+            log: har::Spec::V1_2(har::v1_2::Log {
+                creator: har::v1_2::Creator {
+                    name: "test".to_string(),
+                    version: "1".to_string(),
+                    comment: None,
+                },
+                browser: None,
+                pages: None,
+                entries,
+                comment: None,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_har_built_entries() {
+        let (request_parts, _) = Request::post("https://example.com/upload")
+            .header(HOST, "example.com")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let har_request =
+            copy_from_http_request_to_har(&request_parts, vec![0xff, 0xfe, 0x00]).await;
+        let (response_parts, _) = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let har_response = copy_from_http_response_to_har(&response_parts, vec![0x89, 0x50]).await;
+        let client = "127.0.0.1:1234".parse().unwrap();
+        let server = Some("127.0.0.1:443".parse().unwrap());
+        let mut forwarded = har_entry(har_request.clone(), har_response, client, server, None);
+        forwarded.timings.blocked = Some(1.5);
+        forwarded.timings.send = 0.25;
+        forwarded.timings.wait = 12.0;
+        forwarded.timings.receive = 3.0;
+        forwarded.time = 16.75;
+        let error = tls_interceptor_proxy::third_wheel::error::Error::ServerError(
+            "connection reset".to_string(),
+        );
+        let failed = failed_har_entry(har_request, client, server, None, &error);
+        let options = CaptureOptions {
+            record_bodies: true,
+            ..CaptureOptions::default()
+        };
+        let (blocked, _) = log_blocked_request(
+            &request_parts,
+            b"{}".to_vec(),
+            client,
+            None,
+            &options,
+            &BlockMessages::default(),
+        )
+        .await;
+
+        // Call the function
+        let result = validate_har(&archive(vec![forwarded, failed, blocked]));
+
+        // Verify the entries built by the proxy are valid HAR 1.2
+        assert_eq!(result, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_validate_har_errors() {
+        let (parts, _) = Request::get("https://example.com/")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let client = "127.0.0.1:1234".parse().unwrap();
+        let (mut entry, _) = log_blocked_request(
+            &parts,
+            Vec::new(),
+            client,
+            None,
+            &Default::default(),
+            &BlockMessages::default(),
+        )
+        .await;
+        entry.started_date_time = "01/02/2024 10:00:00".to_string();
+        entry.request.url = "/relative".to_string();
+        entry.timings.wait = 10.0;
+        entry.response.content.text = Some("not base64!".to_string());
+        entry.response.content.encoding = Some(BASE64_ENCODING.to_string());
+
+        // Call the function
+        let errors = validate_har(&archive(vec![entry])).unwrap_err();
+
+        // Verify every broken field is reported with its path
+        let paths: Vec<&str> = errors.iter().map(|error| error.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "log.entries[0].startedDateTime",
+                "log.entries[0].request.url",
+                "log.entries[0].response.content.text",
+                "log.entries[0].time",
+            ]
+        );
+        assert_eq!(
+            errors[3].to_string(),
+            "log.entries[0].time: 0 is not the sum of the timings, 10"
+        );
+    }
 }